@@ -0,0 +1,130 @@
+use std::env;
+use std::process::exit;
+use game_protocol::{send_admin_command, send_admin_command_tls, AdminCommand, ClientTls};
+
+/*
+    Command line tool for the game server's admin channel.
+    The server must have admin_port and admin_token set in its config. Pass the token in the GAME_ADMIN_TOKEN environment variable.
+    If the server has TLS set up, pass the PEM certificate authority (or self-signed certificate) to trust in GAME_ADMIN_CA.
+    Example: `GAME_ADMIN_TOKEN=change-me game_admin 127.0.0.1:7880 clients`
+ */
+
+const USAGE: &str = "Usage: game_admin <address> <command> [arguments]
+
+Commands:
+    clients                                  List connected clients
+    lobbies                                  List lobbies
+    game <lobby_id>                          Show the state of the game running in a lobby
+    kick <client_id>                         Disconnect a client
+    ban <client_id> [--minutes <n>] <reason> Ban a client's IP address and disconnect it. Permanent unless minutes are given.
+    ban-account <account_id> [--minutes <n>] <reason>
+                                             Ban an account and disconnect any client logged in to it
+    unban <address or account_id>            Lift a ban
+    bans                                     List bans
+    mute <client_id> [--minutes <n>] <reason>
+                                             Stop a client sending chat messages
+    unmute <account_id or address>           Lift a mute. A logged in client is muted by account and address.
+    close-lobby <lobby_id>                   Remove every player from a lobby
+    end-game <lobby_id>                      Stop the game running in a lobby without recording a result
+    broadcast <message>                      Send every client a message
+    reload-config                            Reload the server's config file
+
+The admin token is read from the GAME_ADMIN_TOKEN environment variable.
+If the server uses TLS, set GAME_ADMIN_CA to the certificate file to trust.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        exit(1);
+    }
+    let token = match env::var("GAME_ADMIN_TOKEN") {
+        Ok(token) => token,
+        Err(_) => {
+            eprintln!("GAME_ADMIN_TOKEN is not set.");
+            exit(1);
+        }
+    };
+
+    let command = match parse_command(&args[1], &args[2..]) {
+        Some(command) => command,
+        None => {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+    };
+
+    let result = match env::var("GAME_ADMIN_CA") {
+        Ok(ca_file) => match ClientTls::with_ca_file(&ca_file) {
+            Ok(tls) => send_admin_command_tls(&args[0], &tls, &token, command),
+            Err(e) => {
+                eprintln!("Could not load GAME_ADMIN_CA. {}", e);
+                exit(1);
+            }
+        },
+        Err(_) => send_admin_command(&args[0], &token, command)
+    };
+    match result {
+        Ok(response) if response.ok => {
+            if !response.data.is_null() {
+                println!("{}", serde_json::to_string_pretty(&response.data).unwrap());
+            }
+        }
+        Ok(response) => {
+            eprintln!("Error: {}", response.error.unwrap_or_default());
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("Could not reach the admin channel at {}. {}", args[0], e);
+            exit(1);
+        }
+    }
+}
+
+// Turn the command name and its arguments into an AdminCommand. None if they don't make a valid command.
+fn parse_command(name: &str, args: &[String]) -> Option<AdminCommand> {
+    let first = args.first().cloned();
+    match name {
+        "clients" => Some(AdminCommand::ListClients),
+        "lobbies" => Some(AdminCommand::ListLobbies),
+        "game" => first.map(|lobby_id| AdminCommand::GetGameState { lobby_id }),
+        "kick" => first.map(|client_id| AdminCommand::Kick { client_id }),
+        "ban" => {
+            let client_id = first?;
+            let (reason, duration_secs) = parse_reason(&args[1..])?;
+            Some(AdminCommand::Ban { client_id, reason, duration_secs })
+        }
+        "ban-account" => {
+            let account_id = first?;
+            let (reason, duration_secs) = parse_reason(&args[1..])?;
+            Some(AdminCommand::BanAccount { account_id, reason, duration_secs })
+        }
+        "unban" => first.map(|target| AdminCommand::Unban { target }),
+        "bans" => Some(AdminCommand::ListBans),
+        "mute" => {
+            let client_id = first?;
+            let (reason, duration_secs) = parse_reason(&args[1..])?;
+            Some(AdminCommand::Mute { client_id, reason, duration_secs })
+        }
+        "unmute" => first.map(|target| AdminCommand::Unmute { target }),
+        "close-lobby" => first.map(|lobby_id| AdminCommand::CloseLobby { lobby_id }),
+        "end-game" => first.map(|lobby_id| AdminCommand::EndGame { lobby_id }),
+        "broadcast" if !args.is_empty() => Some(AdminCommand::Broadcast { message: args.join(" ") }),
+        "reload-config" => Some(AdminCommand::ReloadConfig),
+        _ => None
+    }
+}
+
+// Parse the optional "--minutes <n>" and the reason that follow the target of a ban or mute. None if there is no reason.
+fn parse_reason(args: &[String]) -> Option<(String, Option<u64>)> {
+    let mut rest = args;
+    let mut duration_secs = None;
+    if rest.first().is_some_and(|arg| arg == "--minutes") {
+        duration_secs = Some(rest.get(1)?.parse::<u64>().ok()? * 60);
+        rest = &rest[2..];
+    }
+    if rest.is_empty() {
+        return None;
+    }
+    Some((rest.join(" "), duration_secs))
+}
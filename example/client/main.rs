@@ -2,7 +2,7 @@
 
 use eframe::egui;
 use egui::Button;
-use game_protocol::GameProtocolClient;
+use game_protocol::{FriendStatus, GameProtocolClient, PresenceStatus};
 use game_protocol::ProtocolState;
 use tic_tac_toe::{CellElement, TicTacToe, TicTacToeMove, TicTacToeState};
 
@@ -27,6 +27,11 @@ struct GameClient {
     is_listening_async: bool,
     ip: String,
     port: String,
+    username: String,
+    password: String,
+    display_name: String,
+    friend_username: String,
+    chat_message: String,
 }
 
 // Implement constructor for GameClient
@@ -41,6 +46,11 @@ impl GameClient {
             is_listening_async: false,
             ip: "127.0.0.1".to_string(),
             port: "7878".to_string(),
+            username: String::new(),
+            password: String::new(),
+            display_name: String::new(),
+            friend_username: String::new(),
+            chat_message: String::new(),
         }
     }
 }
@@ -86,12 +96,15 @@ impl eframe::App for GameClient {
                 }
             }
 
-            // While there is a connection established, show where the client is connected to.
+            // While there is a connection established, show where the client is connected to and who it is playing as.
             if !matches!(connection_status, ProtocolState::Closed) &&
                 !matches!(connection_status, ProtocolState::Authenticating) {
                 ui.horizontal(|ui| {
                     ui.label(format!("Connected to server at {}", self.protocol_handler.get_socket_address()));
                 });
+                if let Some(profile) = self.protocol_handler.get_profile() {
+                    ui.label(format!("Playing as {}", profile.display_name));
+                }
             }
 
             // What to do in the GUI while client is not in a lobby or game session.
@@ -113,6 +126,78 @@ impl eframe::App for GameClient {
                     self.protocol_handler.disconnect();
                 }
 
+                // Guests can log in to an account, or register one, so other players see who they are.
+                if self.protocol_handler.get_profile().is_some_and(|profile| profile.account_id.is_none()) {
+                    ui.horizontal(|ui| {
+                        ui.label("Username");
+                        ui.text_edit_singleline(&mut self.username);
+                        ui.label("Password");
+                        ui.add(egui::TextEdit::singleline(&mut self.password).password(true));
+                        if ui.button("Log in").clicked() {
+                            self.protocol_handler.login(&self.username, &self.password);
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Display name");
+                        ui.text_edit_singleline(&mut self.display_name);
+                        if ui.button("Register").clicked() {
+                            self.protocol_handler.register(&self.username, &self.password, &self.display_name);
+                        }
+                    });
+                    if let Some(error) = self.protocol_handler.get_account_error() {
+                        ui.label(format!("Could not log in: {:?}", error));
+                    }
+                }
+
+                // Logged in players can add friends, see what they are doing, and join lobbies they were invited to.
+                if self.protocol_handler.get_profile().is_some_and(|profile| profile.account_id.is_some()) {
+                    ui.horizontal(|ui| {
+                        ui.label("Friend's username");
+                        ui.text_edit_singleline(&mut self.friend_username);
+                        if ui.button("Add friend").clicked() {
+                            self.protocol_handler.send_friend_request(&self.friend_username);
+                        }
+                        if ui.button("Refresh friends").clicked() {
+                            self.protocol_handler.request_friend_list();
+                        }
+                    });
+                    if let Some(error) = self.protocol_handler.get_friend_error() {
+                        ui.label(format!("Friend request failed: {:?}", error));
+                    }
+                    for friend in self.protocol_handler.get_friends().iter() {
+                        ui.horizontal(|ui| {
+                            match friend.status {
+                                FriendStatus::Friends => {
+                                    ui.label(format!("{}: {:?}", friend.display_name, friend.presence.status));
+                                }
+                                FriendStatus::RequestSent => {
+                                    ui.label(format!("{}: request sent", friend.display_name));
+                                }
+                                FriendStatus::RequestReceived => {
+                                    ui.label(format!("{} wants to be friends", friend.display_name));
+                                    if ui.button("Accept").clicked() {
+                                        self.protocol_handler.accept_friend_request(&friend.account_id);
+                                    }
+                                }
+                            }
+                            if ui.button("Remove").clicked() {
+                                self.protocol_handler.remove_friend(&friend.account_id);
+                            }
+                        });
+                    }
+                    for invite in self.protocol_handler.get_lobby_invites().iter() {
+                        ui.horizontal(|ui| {
+                            ui.label(format!("{} invited you to play {}", invite.from.display_name, invite.game_type_id));
+                            if ui.button("Join").clicked() {
+                                self.protocol_handler.join_lobby(&invite.lobby_id);
+                            }
+                            if ui.button("Dismiss").clicked() {
+                                self.protocol_handler.dismiss_lobby_invite(&invite.lobby_id);
+                            }
+                        });
+                    }
+                }
+
                 // Buttons for refreshing lobby list and creating a lobby
                 ui.horizontal(|ui| {
                     if ui.button("Refresh lobby list").clicked() {
@@ -122,7 +207,7 @@ impl eframe::App for GameClient {
                     // Show buttons for creating lobbies of supported games. Or note there are no supported games (games the client and server both support).
                     ui.horizontal(|ui| {
                         let supported_games = self.protocol_handler.get_supported_games();
-                        if supported_games.len() > 0 {
+                        if !supported_games.is_empty() {
                             for game in self.protocol_handler.get_supported_games().iter() {
                                 if ui.button(format!("Create lobby for {}", &game.0)).clicked() {
                                     self.protocol_handler.create_lobby(&game.1);
@@ -136,7 +221,7 @@ impl eframe::App for GameClient {
 
                 // If there are lobbies, render them in a scrollable list so a large number of them can be displayed
                 let lobbies = self.protocol_handler.get_lobby_list();
-                if lobbies.len() > 0 {
+                if !lobbies.is_empty() {
                     egui::ScrollArea::vertical().show(ui, |ui| {
                         for l in lobbies.iter() {
                             ui.horizontal(|ui| {
@@ -144,7 +229,7 @@ impl eframe::App for GameClient {
                                 if ui.button("Join Lobby").clicked() {
                                     self.protocol_handler.join_lobby(&l.id)
                                 };
-                                ui.label(format!("Game: {}. Players: {}/{}. Started: {}", l.game_metadata.game_title, l.player_ids.len(), l.game_metadata.max_players, l.game_started));
+                                ui.label(format!("Game: {}. Host: {}. Players: {}/{}. Started: {}", l.game_metadata.game_title, l.get_display_name(&l.owner), l.player_ids.len(), l.game_metadata.max_players, l.game_started));
                             });
                         }
                     });
@@ -175,11 +260,39 @@ impl eframe::App for GameClient {
                         }
                     }
                     ui.label(format!("Players: {}/{}", lobby.player_ids.len(), lobby.game_metadata.max_players));
+                    for id in lobby.player_ids.iter() {
+                        let host = if lobby.owner.eq(id) { " (host)" } else { "" };
+                        ui.label(format!("{}{}", lobby.get_display_name(id), host));
+                    }
+
+                    // Invite online friends that aren't already in a lobby
+                    for friend in self.protocol_handler.get_friends().iter().filter(|friend| friend.presence.status == PresenceStatus::Online) {
+                        if ui.button(format!("Invite {}", friend.display_name)).clicked() {
+                            self.protocol_handler.invite_to_lobby(&friend.account_id);
+                        }
+                    }
+
+                    // Lobby chat
+                    egui::ScrollArea::vertical().max_height(150.0).stick_to_bottom().show(ui, |ui| {
+                        for message in self.protocol_handler.get_chat_messages().iter() {
+                            ui.label(format!("{}: {}", message.display_name, message.message));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(&mut self.chat_message);
+                        if ui.button("Send").clicked() && !self.chat_message.trim().is_empty() {
+                            self.protocol_handler.send_chat_message(&self.chat_message);
+                            self.chat_message.clear();
+                        }
+                    });
+                    if let Some(error) = self.protocol_handler.get_chat_error() {
+                        ui.label(format!("Message not sent: {:?}", error));
+                    }
                 }
             }
 
-            // UI to show while the game has started.
-            if matches!(connection_status, ProtocolState::GameRunning) {
+            // UI to show while the game has started. Keep showing it while the client resyncs its game state.
+            if matches!(connection_status, ProtocolState::GameRunning | ProtocolState::GettingGameState) {
 
                 // If there is a game state stored by the game_protocol client, then get it and display it.
                 if let Some(game_state) = self.protocol_handler.get_game_state() {
@@ -188,6 +301,10 @@ impl eframe::App for GameClient {
                     // Only supports Tic-tac-toe v1.0 for now. Render its UI and handle the game logic.
                     if game_type_id.eq("Tic-tac-toe v1.0") {
                         let my_id = self.protocol_handler.get_client_id();
+                        let lobby = self.protocol_handler.get_current_lobby().unwrap();
+                        for opponent in lobby.player_ids.iter().filter(|id| **id != my_id) {
+                            ui.label(format!("Playing against {}", lobby.get_display_name(opponent)));
+                        }
                         let mut handle_user_input = true;
                         if let Some(result) = self.protocol_handler.get_game_end_result() {
 
@@ -1,13 +1,32 @@
-use game_protocol::GameProtocolServer;
+use std::env;
+use game_protocol::{GameProtocolServer, ServerConfig};
 use tic_tac_toe::TicTacToe;
+use tracing::error;
+use tracing_subscriber::EnvFilter;
 
 /*
     Simple program that uses the game protocol's server and registers TicTacToe as a playable game.
     Server module is robust enough that not much needs to be done to start it up.
+    Optionally pass the path to a TOML config file as the first argument, e.g. `game_server server.toml`.
+    Logs go to stdout. Set RUST_LOG to change what is logged, e.g. `RUST_LOG=game_protocol=debug` to see every request.
  */
 
 fn main() {
-    let mut server = GameProtocolServer::new("127.0.0.1", "7878");
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    // Use the config file if one was given. Otherwise use the defaults, which listen on 127.0.0.1:7878.
+    let mut server = match env::args().nth(1) {
+        Some(path) => match GameProtocolServer::with_config_file(&path) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+        None => GameProtocolServer::with_config(ServerConfig::default())
+    };
     server.register_game::<TicTacToe>();
     server.start();
 }
@@ -129,13 +129,11 @@ impl GameModule for TicTacToe {
         self.players.push(id.clone());
 
         // Set which player is the X and O player.
-        if self.state.x_player_id.eq("") {
+        if self.state.x_player_id.is_empty() {
             self.state.x_player_id = id;
-        } else if self.state.o_player_id.eq("") {
+        } else if self.state.o_player_id.is_empty() {
             self.state.o_player_id = id;
         }
-
-        println!("ADd len: {}", self.players.len());
     }
 
     // Removes a player from the game.
@@ -247,15 +245,26 @@ impl GameModule for TicTacToe {
     }
 
     // Checks if a given move is valid. Must cast GameMove trait object into TicTacToeMove
-    fn is_valid_move(&self, move_to_test: &Box<dyn GameMove>) -> bool {
+    fn is_valid_move(&self, move_to_test: &dyn GameMove) -> bool {
         let cast_move = move_to_test.as_any().downcast_ref::<TicTacToeMove>().unwrap();
         let correct_turn = cast_move.symbol == self.state.this_turn; // Requested symbol must match the symbol of the upcoming turn
         let empty_space = matches!(self.state.board[cast_move.board_index.0][cast_move.board_index.1], CellElement::None); // Moves can only be made in empty spaces.
         correct_turn && empty_space
     }
 
+    // Checks if a given move is valid and made by the player whose symbol it places.
+    fn is_valid_move_by(&self, player_id: &str, move_to_test: &dyn GameMove) -> bool {
+        let cast_move = move_to_test.as_any().downcast_ref::<TicTacToeMove>().unwrap();
+        let own_symbol = match cast_move.symbol {
+            CellElement::X => self.state.x_player_id == player_id,
+            CellElement::O => self.state.o_player_id == player_id,
+            CellElement::None => false
+        };
+        own_symbol && self.is_valid_move(move_to_test)
+    }
+
     // Apply the move to the game state.
-    fn apply_move(&mut self, move_to_apply: &Box<dyn GameMove>) {
+    fn apply_move(&mut self, move_to_apply: &dyn GameMove) {
 
         // Check if the move is valid first
         if !self.is_valid_move(move_to_apply) {
@@ -1,7 +1,9 @@
+use std::collections::HashMap;
+use crate::codec::Codec;
 use crate::common_message_utils::{build_message_body, parse_message_type, parse_status_code};
 use crate::enums::{MessageType, StatusCode};
 use crate::game_module::{GameMove};
-use crate::shared_data::{CreateLobbyRequest, JoinLobbyRequest, StartGameRequest};
+use crate::shared_data::{ChatRequest, ConnectRequestBody, CreateLobbyRequest, CreateTournamentRequest, FriendAccountRequest, FriendRequest, JoinLobbyRequest, LeaderboardRequest, LoginRequest, MatchmakingRequest, RatingRequest, RegisterRequest, StartGameRequest, TournamentFormat, TournamentRequest, UpdateProfileRequest};
 
 /*
     Contains helpers for building client requests and parsing server responses.
@@ -29,36 +31,123 @@ pub fn build_client_headers(next_in_sequence: u32, message_type: MessageType) ->
     byte_vec
 }
 
-pub fn build_connect_request(next_in_sequence: u32, body: Option<String>) -> Vec<u8> {
+// Request that only has headers. Still sends a body size of 0 so the server knows where the message ends.
+pub fn build_empty_request(next_in_sequence: u32, message_type: MessageType) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, message_type);
+    byte_vec.extend_from_slice(&build_message_body(None));
+    byte_vec
+}
+
+// Connect request with the client's protocol version and what it supports, and the client ID to resume if reconnecting. Always JSON.
+pub fn build_connect_request(next_in_sequence: u32, body: &ConnectRequestBody) -> Vec<u8> {
     let mut byte_vec = build_client_headers(next_in_sequence, MessageType::ConnectRequest);
-    byte_vec.extend_from_slice(&build_message_body(body));
+    byte_vec.extend_from_slice(&build_message_body(Some(Codec::Json.encode(body))));
     byte_vec
 }
 
-pub fn build_join_lobby_request(next_in_sequence: u32, lobby_id: String) -> Vec<u8> {
+pub fn build_join_lobby_request(codec: Codec, next_in_sequence: u32, lobby_id: String) -> Vec<u8> {
     let mut byte_vec = build_client_headers(next_in_sequence, MessageType::JoinLobbyRequest);
-    let join_json = serde_json::to_string(&JoinLobbyRequest { lobby_id }).unwrap();
-    byte_vec.extend_from_slice(&build_message_body(Some(join_json)));
+    let join_body = codec.encode(&JoinLobbyRequest { lobby_id });
+    byte_vec.extend_from_slice(&build_message_body(Some(join_body)));
     byte_vec
 }
 
-pub fn build_create_lobby_request(next_in_sequence: u32, game_type_id: String) -> Vec<u8> {
+pub fn build_create_lobby_request(codec: Codec, next_in_sequence: u32, game_type_id: String) -> Vec<u8> {
     let mut byte_vec = build_client_headers(next_in_sequence, MessageType::CreateLobbyRequest);
-    let create_lobby_json = serde_json::to_string(&CreateLobbyRequest { game_type_id }).unwrap();
-    byte_vec.extend_from_slice(&build_message_body(Some(create_lobby_json)));
+    let create_lobby_body = codec.encode(&CreateLobbyRequest { game_type_id });
+    byte_vec.extend_from_slice(&build_message_body(Some(create_lobby_body)));
     byte_vec
 }
 
-pub fn build_start_game_request(next_in_sequence: u32, lobby_id: String) -> Vec<u8> {
+pub fn build_start_game_request(codec: Codec, next_in_sequence: u32, lobby_id: String) -> Vec<u8> {
     let mut byte_vec = build_client_headers(next_in_sequence, MessageType::StartGameRequest);
-    let start_game = serde_json::to_string(&StartGameRequest { lobby_id }).unwrap();
+    let start_game = codec.encode(&StartGameRequest { lobby_id });
     byte_vec.extend_from_slice(&build_message_body(Some(start_game)));
     byte_vec
 }
 
-pub fn build_move_request(next_in_sequence: u32, game_move: &dyn GameMove) -> Vec<u8> {
+pub fn build_move_request(codec: Codec, next_in_sequence: u32, game_move: &dyn GameMove) -> Vec<u8> {
     let mut byte_vec = build_client_headers(next_in_sequence, MessageType::MoveRequest);
-    let serialized = serde_json::to_string(game_move).unwrap();
+    let serialized = codec.encode(game_move);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
     byte_vec
-}
\ No newline at end of file
+}
+
+pub fn build_rating_request(codec: Codec, next_in_sequence: u32, game_type_id: String, player_id: Option<String>) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::RatingRequest);
+    let rating_body = codec.encode(&RatingRequest { game_type_id, player_id });
+    byte_vec.extend_from_slice(&build_message_body(Some(rating_body)));
+    byte_vec
+}
+
+pub fn build_leaderboard_request(codec: Codec, next_in_sequence: u32, game_type_id: String, limit: usize) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::LeaderboardRequest);
+    let leaderboard_body = codec.encode(&LeaderboardRequest { game_type_id, limit });
+    byte_vec.extend_from_slice(&build_message_body(Some(leaderboard_body)));
+    byte_vec
+}
+
+pub fn build_matchmaking_request(codec: Codec, next_in_sequence: u32, game_type_id: String) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::MatchmakingRequest);
+    let matchmaking_body = codec.encode(&MatchmakingRequest { game_type_id });
+    byte_vec.extend_from_slice(&build_message_body(Some(matchmaking_body)));
+    byte_vec
+}
+
+pub fn build_create_tournament_request(codec: Codec, next_in_sequence: u32, name: String, game_type_id: String, format: TournamentFormat) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::CreateTournamentRequest);
+    let tournament_body = codec.encode(&CreateTournamentRequest { name, game_type_id, format });
+    byte_vec.extend_from_slice(&build_message_body(Some(tournament_body)));
+    byte_vec
+}
+
+// Used for joining, leaving and starting tournaments since they only need the tournament ID.
+pub fn build_tournament_request(codec: Codec, next_in_sequence: u32, message_type: MessageType, tournament_id: String) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, message_type);
+    let tournament_body = codec.encode(&TournamentRequest { tournament_id });
+    byte_vec.extend_from_slice(&build_message_body(Some(tournament_body)));
+    byte_vec
+}
+
+pub fn build_register_request(codec: Codec, next_in_sequence: u32, username: String, password: String, display_name: String) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::RegisterRequest);
+    let register_body = codec.encode(&RegisterRequest { username, password, display_name });
+    byte_vec.extend_from_slice(&build_message_body(Some(register_body)));
+    byte_vec
+}
+
+pub fn build_login_request(codec: Codec, next_in_sequence: u32, username: String, password: String) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::LoginRequest);
+    let login_body = codec.encode(&LoginRequest { username, password });
+    byte_vec.extend_from_slice(&build_message_body(Some(login_body)));
+    byte_vec
+}
+
+pub fn build_update_profile_request(codec: Codec, next_in_sequence: u32, display_name: Option<String>, avatar_url: Option<String>, metadata: Option<HashMap<String, String>>) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::UpdateProfileRequest);
+    let profile_body = codec.encode(&UpdateProfileRequest { display_name, avatar_url, metadata });
+    byte_vec.extend_from_slice(&build_message_body(Some(profile_body)));
+    byte_vec
+}
+
+pub fn build_friend_request(codec: Codec, next_in_sequence: u32, username: String) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::FriendRequest);
+    let friend_body = codec.encode(&FriendRequest { username });
+    byte_vec.extend_from_slice(&build_message_body(Some(friend_body)));
+    byte_vec
+}
+
+// Used for accepting and removing friends, and inviting them to a lobby, since they only need the friend's account ID.
+pub fn build_friend_account_request(codec: Codec, next_in_sequence: u32, message_type: MessageType, account_id: String) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, message_type);
+    let friend_body = codec.encode(&FriendAccountRequest { account_id });
+    byte_vec.extend_from_slice(&build_message_body(Some(friend_body)));
+    byte_vec
+}
+
+pub fn build_chat_request(codec: Codec, next_in_sequence: u32, message: String) -> Vec<u8> {
+    let mut byte_vec = build_client_headers(next_in_sequence, MessageType::ChatRequest);
+    let chat_body = codec.encode(&ChatRequest { message });
+    byte_vec.extend_from_slice(&build_message_body(Some(chat_body)));
+    byte_vec
+}
@@ -1,16 +1,26 @@
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::io;
+use std::mem::size_of;
+use std::net::TcpStream;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use crate::client::client_message_utils::{build_client_headers, build_connect_request, build_create_lobby_request, build_join_lobby_request, build_move_request, build_start_game_request, parse_server_message_header};
-use crate::common_message_utils::parse_message_data;
-use crate::enums::{MessageType, ProtocolState, StatusCode};
-use crate::game_module::{GameModule, GameMove, GameState};
-use crate::shared_data::{ConnectResponse, Lobby, LobbyInfoResponse, LobbyListResponse, MissingMessageResponse, SupportedGamesResponse, UnsolicitedMessage};
+use tracing::{debug, debug_span, warn};
+use crate::client::client_message_utils::{build_chat_request, build_connect_request, build_create_lobby_request, build_empty_request, build_friend_account_request, build_friend_request, build_join_lobby_request, build_leaderboard_request, build_create_tournament_request, build_login_request, build_matchmaking_request, build_move_request, build_rating_request, build_register_request, build_start_game_request, build_tournament_request, build_update_profile_request, parse_server_message_header};
+use crate::codec::Codec;
+use crate::common_message_utils::{compress_message, negotiate_protocol_version, parse_message_data, parse_message_type, read_message, CLIENT_HEADER_SIZE, GAME_STATE_REQUEST_VERSION, PROTOCOL_VERSION, SERVER_HEADER_SIZE, UNVERSIONED_PROTOCOL_VERSION};
+use crate::compression::Compression;
+use crate::enums::{Feature, MessageType, ProtocolState, StatusCode};
+use crate::game_module::{delta, GameModule, GameMove, GameState};
+use crate::transport::{connect_unix_socket, Connection};
+use crate::transport::tls::ClientTls;
+use crate::transport::websocket::WebSocketConnection;
+use crate::shared_data::{ChatMessage, ConnectRequestBody, ConnectResponse, FriendInfo, FriendListResponse, GameStateDelta, LeaderboardResponse, Lobby, LobbyInvite, LobbyInfoResponse, LobbyListResponse, MissingMessageResponse, PlayerProfile, PlayerRating, PresenceUpdate, ProfileResponse, RatingResponse, SupportedGamesResponse, TournamentFormat, TournamentInfo, TournamentInfoResponse, TournamentListResponse, UnsolicitedMessage};
 
 mod client_message_utils;
 
+// Most chat messages the client keeps. Older ones are dropped as new ones arrive.
+const MAX_CHAT_MESSAGES: usize = 100;
+
 /*
     Represents the client portion of the game protocol.
     Handles client operations and communications with server.
@@ -23,23 +33,52 @@ struct GameProtocolClientState {
     next_message_num: u32, // Next message ID client will send
     client_id: String, // UUID for client which is generated by server
     unsolicited_message: String, // Store the most recent unsolicited message from the server.
-    socket: Option<Arc<TcpStream>>, // Store the TCP socket on which the client is communicating if a connection with a server is established
+    socket: Option<Arc<dyn Connection>>, // Store the connection on which the client is communicating if a connection with a server is established
     current_lobby: Option<Lobby>, // Store lobby info if the client is in a lobby
     lobbies: Vec<Lobby>, // Store list of lobbies obtained from server
     is_listening_async: bool, // Know whether or not client is listening for server responses on a separate thread
     game_in_progress: Option<Box<dyn GameModule>>, // If client is in the middle of a game, store the game module
+    resuming_session: bool, // Set when the server resumed the client's session, until it sends the lobby the client is back in
+    reconnect_token: Option<String>, // Secret the server gave this session, sent along with the client ID when reconnecting
+    game_state_version: Option<u64>, // Version of the in-progress game's state when the server sends delta updates. None if it isn't known.
     previous_message_cache: HashMap<u32, Vec<u8>>, // Cache previous message byte data indexed by message ID. TODO need to limit how many messages this stores since it could easily take up a lot of memory.
     supported_games: HashMap<String, Arc<dyn GameModule>>, // Hash map of supported game module instances, indexed by game module ID
     matching_supported_games: Vec<(String, String)>, // List of games that both client and server support. Tuples are (game title, game module ID)
     on_message_received: Option<Box<dyn Fn() + Send + Sync>>, // Callback function to use when a message is received from the server
+    ratings: HashMap<(String, String), PlayerRating>, // Ratings received from the server, indexed by (player ID, game type ID)
+    leaderboard: Vec<PlayerRating>, // Most recent leaderboard received from the server
+    tournaments: Vec<TournamentInfo>, // Store list of tournaments obtained from server
+    current_tournament: Option<TournamentInfo>, // Store tournament info if the client is registered for or organizing a tournament
+    profile: Option<PlayerProfile>, // This client's profile as the server last sent it. A guest profile until the client logs in.
+    account_error: Option<StatusCode>, // Why the server refused the most recent register, login or profile update. None if it succeeded.
+    friends: Vec<FriendInfo>, // Friends and pending friend requests as the server last sent them, kept up to date with presence updates
+    lobby_invites: Vec<LobbyInvite>, // Invites from friends to join their lobby that haven't been accepted or dismissed
+    friend_error: Option<StatusCode>, // Why the server refused the most recent friend request, accept, removal or invite. None if it succeeded.
+    chat_messages: Vec<ChatMessage>, // Most recent chat messages in the current lobby, oldest first
+    chat_error: Option<StatusCode>, // Why the server refused the most recent chat message, e.g. Muted. None if it was sent.
+    codec: Codec, // Codec message bodies are encoded with, as picked by the server when connecting
+    compression: Option<(Compression, usize)>, // Compression for large message bodies as picked by the server when connecting, and the body size above which it is used
+    protocol_version: u32, // Protocol version spoken with the server, the lower of the client's and server's
+    features: Vec<Feature>, // Features both the client and server support. Messages of other features are never sent.
+    connect_error: Option<StatusCode>, // Why the most recent connect failed, e.g. ServerFull or IncompatibleProtocolVersion. None if it succeeded.
 }
 
 pub struct GameProtocolClient {
     ip: Option<String>, // IP address client is connected to
     port: Option<String>, // Port client is connected to on the IP address
+    tls: Option<ClientTls>, // Which servers to trust when connecting over TLS. Connections are plain TCP if None.
+    codec: Codec, // Codec the client asks the server to encode message bodies with
+    compression: Option<Compression>, // Compression the client asks the server to use for large message bodies. Bodies are never compressed if None.
+    features: Vec<Feature>, // Features the client tells the server it supports
     state: Arc<Mutex<GameProtocolClientState>>, // Thread safe pointer to a mutex of the game protocol client state. Needs to be thread safe of course, and use a mutex to avoid data races.
 }
 
+impl Default for GameProtocolClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Implementing the various functions game protocol clients should have
 impl GameProtocolClient {
     pub fn new() -> Self {
@@ -52,17 +91,40 @@ impl GameProtocolClient {
             lobbies: vec![],
             is_listening_async: false,
             game_in_progress: None,
+            resuming_session: false,
+            reconnect_token: None,
+            game_state_version: None,
             previous_message_cache: HashMap::new(),
             supported_games: HashMap::new(),
             matching_supported_games: vec![],
             next_message_num: 0,
             on_message_received: None,
-            unsolicited_message: "".to_string()
+            unsolicited_message: "".to_string(),
+            ratings: HashMap::new(),
+            leaderboard: vec![],
+            tournaments: vec![],
+            current_tournament: None,
+            profile: None,
+            account_error: None,
+            friends: vec![],
+            lobby_invites: vec![],
+            friend_error: None,
+            chat_messages: vec![],
+            chat_error: None,
+            codec: Codec::Json,
+            compression: None,
+            protocol_version: PROTOCOL_VERSION,
+            features: vec![],
+            connect_error: None
         }));
         Self {
             state,
             ip: None,
             port: None,
+            tls: None,
+            codec: Codec::Json,
+            compression: None,
+            features: Feature::ALL.to_vec()
         }
     }
 
@@ -75,39 +137,152 @@ impl GameProtocolClient {
         self.state.lock().unwrap().supported_games.insert(game.get_metadata().get_game_type_id(), game);
     }
 
-    // Send request to establish a session between a server and client
-    pub fn connect(&mut self, ip: &String, port: &String) {
-        // Return if socket is Some (has a value, already connected).
+    // Connect to servers over TLS, trusting the servers the given ClientTls does. Takes effect on the next connect.
+    pub fn set_tls(&mut self, tls: ClientTls) {
+        self.tls = Some(tls);
+    }
+
+    // Ask servers to encode message bodies with a codec other than JSON, e.g. the more compact MessagePack. Takes effect on the next connect.
+    // Servers that don't know the codec keep using JSON. get_codec returns the codec the server picked.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    // Ask servers to compress large message bodies, both ways. Takes effect on the next connect.
+    // Servers that don't know the compression or have it disabled never compress. get_compression returns the compression the server picked.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    // Tell servers which features the client supports, e.g. leave out Chat if it has no chat UI. Supports every feature by default.
+    // The server never sends messages of other features. Takes effect on the next connect.
+    pub fn set_features(&mut self, features: &[Feature]) {
+        self.features = features.to_vec();
+    }
+
+    // Send request to establish a session between a server and client.
+    // An IP address of "unix:<path>" connects to the server's Unix domain socket at the path instead, and the port is ignored.
+    pub fn connect(&mut self, ip: &str, port: &str) {
+        self.open_session(ip, port, None);
+    }
+
+    // Connect to a server again after the connection was lost, asking to resume the session under the same client ID.
+    // The reconnect token the server gave the session is sent along to prove the client owns it.
+    // If the server restored this client's lobby from a snapshot and the grace period hasn't ended, the client is put back
+    // into its lobby and game. Otherwise the server starts a new session, same as connect.
+    pub fn reconnect(&mut self, ip: &str, port: &str) {
+        let previous_client_id = self.previous_client_id();
+        self.open_session(ip, port, previous_client_id);
+    }
+
+    // Connect to a server's WebSocket port at a ws:// URL, e.g. "ws://127.0.0.1:7879/". TLS set with set_tls isn't used,
+    // so use a TLS-terminating proxy in front of the server for encrypted WebSocket connections.
+    pub fn connect_websocket(&mut self, url: &str) {
+        self.open_websocket_session(url, None);
+    }
+
+    // Same as reconnect, over WebSocket.
+    pub fn reconnect_websocket(&mut self, url: &str) {
+        let previous_client_id = self.previous_client_id();
+        self.open_websocket_session(url, previous_client_id);
+    }
+
+    // Open a session over a connection that is already open, e.g. one from a transport other than TCP.
+    pub fn connect_with<T: 'static + Connection>(&mut self, connection: T) {
+        self.ip = None;
+        self.port = None;
+        self.begin_session(None, move || Ok(Box::new(connection)));
+    }
+
+    // Connect to a server over TCP, or TLS if it was set up, or over a Unix socket, and send a connect request, including the previous client ID when reconnecting.
+    fn open_session(&mut self, ip: &str, port: &str, previous_client_id: Option<String>) {
+        if self.state.lock().unwrap().socket.is_some() {
+            return;
+        }
+        if let Some(path) = ip.strip_prefix("unix:") {
+            self.ip = Some(ip.to_string());
+            self.port = None;
+            let path = path.to_string();
+            self.begin_session(previous_client_id, move || connect_unix_socket(&path));
+            return;
+        }
+        self.ip = Some(ip.to_string());
+        self.port = Some(port.to_string());
+
+        // Get the socket address to connect to so it can be used in the thread that attempts to connect.
+        let target_socket_address = self.get_socket_address();
+        let tls = self.tls.clone();
+        let ip = ip.to_string();
+        self.begin_session(previous_client_id, move || {
+            let tcp_stream = TcpStream::connect(target_socket_address)?;
+            match &tls {
+                Some(tls) => Ok(Box::new(tls.connect(tcp_stream, &ip)?) as Box<dyn Connection>),
+                None => Ok(Box::new(tcp_stream))
+            }
+        });
+    }
+
+    fn open_websocket_session(&mut self, url: &str, previous_client_id: Option<String>) {
         if self.state.lock().unwrap().socket.is_some() {
             return;
         }
+        self.ip = None;
+        self.port = None;
+        let url = url.to_string();
+        self.begin_session(previous_client_id, move || Ok(Box::new(WebSocketConnection::connect(&url)?)));
+    }
 
-        // Set the game_protocol state and save the ip and port. Drop the mutex so the below thread can use it.
+    // Client ID to resume the session of when reconnecting, if this client had one.
+    fn previous_client_id(&self) -> Option<String> {
+        let client_id = self.state.lock().unwrap().client_id.clone();
+        if client_id.is_empty() { None } else { Some(client_id) }
+    }
+
+    // Open a connection with the given function and send a connect request over it.
+    fn begin_session(&mut self, previous_client_id: Option<String>, open: impl FnOnce() -> io::Result<Box<dyn Connection>> + Send + 'static) {
+        // Return if socket is Some (has a value, already connected).
+        // Otherwise set the game_protocol state. Drop the mutex so the below thread can use it.
         let mut state_lock = self.state.lock().unwrap();
+        if state_lock.socket.is_some() {
+            return;
+        }
         state_lock.previous_protocol_state = state_lock.protocol_state;
         state_lock.protocol_state = ProtocolState::Authenticating;
-        self.ip = Some(ip.clone());
-        self.port = Some(port.clone());
+        state_lock.connect_error = None;
+        let reconnect_token = previous_client_id.as_ref().and(state_lock.reconnect_token.clone());
         drop(state_lock);
 
-        // If stored socket is None (unassigned, not connected), then try to connect.
-        // Get the socket address to connect to and clone the state pointer so it can be used in
-        // the thread that attempts to connect.
-        let target_socket_address = self.get_socket_address();
-        let state_clone = self.state.clone();
+        // Everything the client supports, for the server to pick from
+        let body = ConnectRequestBody {
+            previous_client_id,
+            reconnect_token,
+            codecs: vec![self.codec.name().to_string()],
+            compression: self.compression.iter().map(|compression| compression.name().to_string()).collect(),
+            protocol_version: Some(PROTOCOL_VERSION),
+            features: Some(self.features.iter().map(|feature| feature.name().to_string()).collect())
+        };
+        let features = self.features.clone();
 
         // Spawn a thread that attempts to connect to server. Put this in a separate thread
         // so it doesn't block main thread which the UI is running on.
+        let state_clone = self.state.clone();
         thread::spawn(move || {
-            match TcpStream::connect(target_socket_address) {
-                Ok(tcp_stream) => {
+            // Finish setting up the connection, e.g. the TLS handshake, before anything is sent
+            let connected = open().and_then(|connection| connection.handshake().map(|_| connection));
+            match connected {
+                Ok(connection) => {
                     // Store the socket and send a connect request.
                     let mut state_lock = state_clone.lock().unwrap();
-                    let socket = Arc::new(tcp_stream);
+                    let socket: Arc<dyn Connection> = Arc::from(connection);
                     state_lock.socket = Some(socket.clone());
 
                     // Build connect request and send it
-                    let connect_request = build_connect_request(state_lock.next_message_num, None);
+                    // Messages are JSON and uncompressed until the server answers with the codec and compression it picked.
+                    // Servers from before features were exchanged only support the features that existed then.
+                    state_lock.codec = Codec::Json;
+                    state_lock.compression = None;
+                    state_lock.features = features;
+                    let connect_request = build_connect_request(state_lock.next_message_num, &body);
                     drop(state_lock);
 
                     send_message(connect_request, state_clone.clone());
@@ -115,9 +290,9 @@ impl GameProtocolClient {
                 }
                 Err(e) => {
                     let mut state_lock = state_clone.lock().unwrap();
-                    state_lock.protocol_state = state_lock.protocol_state;
+                    state_lock.previous_protocol_state = state_lock.protocol_state;
                     state_lock.protocol_state = ProtocolState::Closed;
-                    println!("Connect error: {}", e);
+                    warn!(error = %e, "Connect error");
                 }
             }
         });
@@ -164,13 +339,13 @@ impl GameProtocolClient {
             return;
         }
 
-        state_lock.protocol_state = state_lock.protocol_state;
+        state_lock.previous_protocol_state = state_lock.protocol_state;
         state_lock.protocol_state = ProtocolState::ClosingConnection;
         drop(state_lock);
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_client_headers(next_message_num, MessageType::DisconnectRequest),
+            build_empty_request(next_message_num, MessageType::DisconnectRequest),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -185,10 +360,41 @@ impl GameProtocolClient {
 
     // Returns socket address client is connected to
     pub fn get_socket_address(&self) -> String {
-        if let (Some(ip), Some(port)) = (&self.ip, &self.port) {
-            return format!("{}:{}", ip, port);
+        match (&self.ip, &self.port) {
+            (Some(ip), Some(port)) => format!("{}:{}", ip, port),
+            (Some(address), None) => address.clone(), // Unix socket addresses have no port
+            _ => "".to_string()
         }
-        "".to_string()
+    }
+
+    // Get the codec message bodies are encoded with in the current session
+    pub fn get_codec(&self) -> Codec {
+        self.state.lock().unwrap().codec
+    }
+
+    // Get the compression large message bodies are compressed with in the current session, if any
+    pub fn get_compression(&self) -> Option<Compression> {
+        self.state.lock().unwrap().compression.map(|(compression, _)| compression)
+    }
+
+    // Get the protocol version spoken with the server in the current session
+    pub fn get_protocol_version(&self) -> u32 {
+        self.state.lock().unwrap().protocol_version
+    }
+
+    // Get the features both this client and the server support in the current session
+    pub fn get_features(&self) -> Vec<Feature> {
+        self.state.lock().unwrap().features.clone()
+    }
+
+    // Whether both this client and the server support a feature. Requests of features they don't both support are never sent.
+    pub fn supports_feature(&self, feature: Feature) -> bool {
+        self.state.lock().unwrap().features.contains(&feature)
+    }
+
+    // Get why the server refused the most recent connect, e.g. ServerFull, or IncompatibleProtocolVersion if the client and server can't talk to each other.
+    pub fn get_connect_error(&self) -> Option<StatusCode> {
+        self.state.lock().unwrap().connect_error
     }
 
     // Get which protocol state the client is in
@@ -214,7 +420,7 @@ impl GameProtocolClient {
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_client_headers(next_message_num, MessageType::LobbyListRequest),
+            build_empty_request(next_message_num, MessageType::LobbyListRequest),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -234,13 +440,14 @@ impl GameProtocolClient {
         let mut state_lock = state_clone.lock().unwrap();
         let socket = state_lock.socket.as_ref().unwrap().clone();
         let next_message_num = state_lock.next_message_num;
+        let codec = state_lock.codec;
         state_lock.previous_protocol_state = state_lock.protocol_state;
         state_lock.protocol_state = ProtocolState::CreatingLobby;
         drop(state_lock);
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_create_lobby_request(next_message_num, game_type_id.to_string()),
+            build_create_lobby_request(codec, next_message_num, game_type_id.to_string()),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -255,13 +462,14 @@ impl GameProtocolClient {
         let mut state_lock = state_clone.lock().unwrap();
         let socket = state_lock.socket.as_ref().unwrap().clone();
         let next_message_num = state_lock.next_message_num;
+        let codec = state_lock.codec;
         state_lock.previous_protocol_state = state_lock.protocol_state;
         state_lock.protocol_state = ProtocolState::JoiningLobby;
         drop(state_lock);
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_join_lobby_request(next_message_num, lobby_id.to_string()),
+            build_join_lobby_request(codec, next_message_num, lobby_id.to_string()),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -281,6 +489,7 @@ impl GameProtocolClient {
         let mut state_lock = state_clone.lock().unwrap();
         let socket = state_lock.socket.as_ref().unwrap().clone();
         let next_message_num = state_lock.next_message_num;
+        let codec = state_lock.codec;
         state_lock.previous_protocol_state = state_lock.protocol_state;
         state_lock.protocol_state = ProtocolState::CreatingGameSession;
 
@@ -297,7 +506,7 @@ impl GameProtocolClient {
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_start_game_request(next_message_num, lobby_id),
+            build_start_game_request(codec, next_message_num, lobby_id),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -312,11 +521,12 @@ impl GameProtocolClient {
         let state_lock = state_clone.lock().unwrap();
         let socket = state_lock.socket.as_ref().unwrap().clone();
         let next_message_num = state_lock.next_message_num;
+        let codec = state_lock.codec;
         drop(state_lock);
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_move_request(next_message_num, game_move),
+            build_move_request(codec, next_message_num, game_move),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -334,14 +544,34 @@ impl GameProtocolClient {
         }
     }
 
+    // Send message to server to request the current state of the game the client is playing, e.g. if it looks out of date.
+    // Done automatically after reconnecting to a game and when a delta update doesn't match the client's state.
+    pub fn request_game_state(&self) {
+        let state_clone = self.state.clone();
+        let mut state_lock = state_clone.lock().unwrap();
+        let socket = state_lock.socket.as_ref().unwrap().clone();
+        let Some(request) = build_game_state_request(&mut state_lock) else {
+            warn!(protocol_version = state_lock.protocol_version, "Server doesn't support game state requests, not sending");
+            return;
+        };
+        drop(state_lock);
+
+        // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
+        send_message(request, self.state.clone());
+        if !self.state.lock().unwrap().is_listening_async {
+            listen(socket, self.state.clone());
+        }
+    }
+
+    // Get the version of the in-progress game's state. Only known when the server sends delta updates.
+    pub fn get_game_state_version(&self) -> Option<u64> {
+        self.state.lock().unwrap().game_state_version
+    }
+
     // Get end condition data of the game.
     pub fn get_game_end_result(&self) -> Option<(bool, Option<String>)> {
         let state_lock = self.state.lock().unwrap();
-        if let Some(game) = &state_lock.game_in_progress {
-            Some(game.end_condition_met())
-        } else {
-            None
-        }
+        state_lock.game_in_progress.as_ref().map(|game| game.end_condition_met())
     }
 
     // Send message to server to request list of server's supported games
@@ -357,7 +587,7 @@ impl GameProtocolClient {
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_client_headers(next_message_num, MessageType::SupportedGamesRequest),
+            build_empty_request(next_message_num, MessageType::SupportedGamesRequest),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -378,7 +608,7 @@ impl GameProtocolClient {
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_client_headers(next_message_num, MessageType::LobbyInfoRequest),
+            build_empty_request(next_message_num, MessageType::LobbyInfoRequest),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -399,7 +629,7 @@ impl GameProtocolClient {
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_client_headers(next_message_num, MessageType::LeaveLobbyRequest),
+            build_empty_request(next_message_num, MessageType::LeaveLobbyRequest),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -407,18 +637,281 @@ impl GameProtocolClient {
         }
     }
 
+    // Get the most recent message the server sent without being asked.
+    pub fn get_unsolicited_message(&self) -> String {
+        self.state.lock().unwrap().unsolicited_message.clone()
+    }
+
+    // Send request for a player's rating in a game. If player_id is None, request this client's own rating.
+    pub fn request_rating(&self, game_type_id: &str, player_id: Option<&str>) {
+        // Lock state object to get required message data.
+        let state_clone = self.state.clone();
+        let state_lock = state_clone.lock().unwrap();
+        let socket = state_lock.socket.as_ref().unwrap().clone();
+        let next_message_num = state_lock.next_message_num;
+        let codec = state_lock.codec;
+        drop(state_lock);
+
+        // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
+        let sent = send_message(
+            build_rating_request(codec, next_message_num, game_type_id.to_string(), player_id.map(|id| id.to_string())),
+            self.state.clone()
+        );
+        if sent && !self.state.lock().unwrap().is_listening_async {
+            listen(socket, self.state.clone());
+        }
+    }
+
+    // Get clone of a player's rating in a game if it has been received from the server.
+    // The server also sends each player their new rating whenever a game they played ends.
+    pub fn get_rating(&self, player_id: &str, game_type_id: &str) -> Option<PlayerRating> {
+        self.state.lock().unwrap().ratings.get(&(player_id.to_string(), game_type_id.to_string())).cloned()
+    }
+
+    // Send request for the highest rated players of a game.
+    pub fn request_leaderboard(&self, game_type_id: &str, limit: usize) {
+        // Lock state object to get required message data.
+        let state_clone = self.state.clone();
+        let state_lock = state_clone.lock().unwrap();
+        let socket = state_lock.socket.as_ref().unwrap().clone();
+        let next_message_num = state_lock.next_message_num;
+        let codec = state_lock.codec;
+        drop(state_lock);
+
+        // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
+        let sent = send_message(
+            build_leaderboard_request(codec, next_message_num, game_type_id.to_string(), limit),
+            self.state.clone()
+        );
+        if sent && !self.state.lock().unwrap().is_listening_async {
+            listen(socket, self.state.clone());
+        }
+    }
+
+    // Get clone of the most recently received leaderboard, best player first.
+    pub fn get_leaderboard(&self) -> Vec<PlayerRating> {
+        self.state.lock().unwrap().leaderboard.clone()
+    }
+
+    // Send request for the server to place the client in a lobby with players of a similar rating.
+    // The server creates a new lobby if no suitable one is open.
+    pub fn find_match(&self, game_type_id: &str) {
+        // Lock state object to get required message data and change protocol state.
+        let state_clone = self.state.clone();
+        let mut state_lock = state_clone.lock().unwrap();
+        let socket = state_lock.socket.as_ref().unwrap().clone();
+        let next_message_num = state_lock.next_message_num;
+        let codec = state_lock.codec;
+        if !state_lock.features.contains(&Feature::Matchmaking) {
+            warn!("Server doesn't support matchmaking");
+            return;
+        }
+        state_lock.previous_protocol_state = state_lock.protocol_state;
+        state_lock.protocol_state = ProtocolState::JoiningLobby;
+        drop(state_lock);
+
+        // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
+        let sent = send_message(
+            build_matchmaking_request(codec, next_message_num, game_type_id.to_string()),
+            self.state.clone()
+        );
+        if sent && !self.state.lock().unwrap().is_listening_async {
+            listen(socket, self.state.clone());
+        }
+    }
+
+    // Send message to server to request the list of tournaments.
+    pub fn request_tournament_list(&self) {
+        // Lock state object to get required message data.
+        let state_clone = self.state.clone();
+        let state_lock = state_clone.lock().unwrap();
+        let socket = state_lock.socket.as_ref().unwrap().clone();
+        let next_message_num = state_lock.next_message_num;
+        drop(state_lock);
+
+        // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
+        let sent = send_message(
+            build_empty_request(next_message_num, MessageType::TournamentListRequest),
+            self.state.clone()
+        );
+        if sent && !self.state.lock().unwrap().is_listening_async {
+            listen(socket, self.state.clone());
+        }
+    }
+
+    // Get clone of tournaments the server has. Can't return a reference since data is behind a mutex.
+    pub fn get_tournament_list(&self) -> Vec<TournamentInfo> {
+        self.state.lock().unwrap().tournaments.clone()
+    }
+
+    // Get clone of the tournament the client is registered for or organizing, including its standings.
+    // The server sends updates whenever participants register, matches finish, or a new round starts.
+    pub fn get_current_tournament(&self) -> Option<TournamentInfo> {
+        self.state.lock().unwrap().current_tournament.clone()
+    }
+
+    // Send request to create a tournament for a game. The client becomes the organizer, which allows it to start the tournament.
+    pub fn create_tournament(&self, name: &str, game_type_id: &str, format: TournamentFormat) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_tournament_message(build_create_tournament_request(codec, next_message_num, name.to_string(), game_type_id.to_string(), format));
+    }
+
+    // Send request to register for a tournament.
+    pub fn join_tournament(&self, tournament_id: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_tournament_message(build_tournament_request(codec, next_message_num, MessageType::JoinTournamentRequest, tournament_id.to_string()));
+    }
+
+    // Send request to withdraw from a tournament before it starts.
+    pub fn leave_tournament(&self, tournament_id: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_tournament_message(build_tournament_request(codec, next_message_num, MessageType::LeaveTournamentRequest, tournament_id.to_string()));
+    }
+
+    // Send request to start a tournament this client organizes. The server then creates lobbies and starts games for each round automatically.
+    pub fn start_tournament(&self, tournament_id: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_tournament_message(build_tournament_request(codec, next_message_num, MessageType::StartTournamentRequest, tournament_id.to_string()));
+    }
+
+    // ID and body codec of the next message to send.
+    fn next_message_info(&self) -> (u32, Codec) {
+        let state_lock = self.state.lock().unwrap();
+        (state_lock.next_message_num, state_lock.codec)
+    }
+
+    // Send one of the tournament messages. Synchronously listen if the client isn't asynchronously listening for server messages.
+    fn send_tournament_message(&self, message: Vec<u8>) {
+        let socket = self.state.lock().unwrap().socket.as_ref().unwrap().clone();
+        if send_message(message, self.state.clone()) && !self.state.lock().unwrap().is_listening_async {
+            listen(socket, self.state.clone());
+        }
+    }
+
+    // Send request to create an account and log in to it. Usernames must be unique.
+    pub fn register(&self, username: &str, password: &str, display_name: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_register_request(codec, next_message_num, username.to_string(), password.to_string(), display_name.to_string()));
+    }
+
+    // Send request to log in to an existing account. The client keeps its client ID, and other players see the account's profile.
+    pub fn login(&self, username: &str, password: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_login_request(codec, next_message_num, username.to_string(), password.to_string()));
+    }
+
+    // Send request to change this client's display name, avatar or metadata. Fields that are None are left as they are.
+    // Changes are saved to the account if the client is logged in. An empty avatar URL removes the avatar.
+    pub fn update_profile(&self, display_name: Option<&str>, avatar_url: Option<&str>, metadata: Option<HashMap<String, String>>) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_update_profile_request(
+            codec, next_message_num,
+            display_name.map(|name| name.to_string()),
+            avatar_url.map(|url| url.to_string()),
+            metadata
+        ));
+    }
+
+    // Get clone of this client's profile, once connected.
+    pub fn get_profile(&self) -> Option<PlayerProfile> {
+        self.state.lock().unwrap().profile.clone()
+    }
+
+    // Get why the server refused the most recent register, login or profile update, e.g. InvalidCredentials. None if it succeeded.
+    pub fn get_account_error(&self) -> Option<StatusCode> {
+        self.state.lock().unwrap().account_error
+    }
+
+    // Send request to be friends with the player who has the given username. If they already asked to be friends with this client, they become friends.
+    // Only clients logged in to an account can have friends.
+    pub fn send_friend_request(&self, username: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_friend_request(codec, next_message_num, username.to_string()));
+    }
+
+    // Send request to accept a friend request from the account with the given ID.
+    pub fn accept_friend_request(&self, account_id: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_friend_account_request(codec, next_message_num, MessageType::AcceptFriendRequest, account_id.to_string()));
+    }
+
+    // Send request to remove a friend. Also declines or withdraws a pending friend request.
+    pub fn remove_friend(&self, account_id: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_friend_account_request(codec, next_message_num, MessageType::RemoveFriendRequest, account_id.to_string()));
+    }
+
+    // Send request for this client's friends and pending friend requests.
+    pub fn request_friend_list(&self) {
+        let next_message_num = self.state.lock().unwrap().next_message_num;
+        self.send_account_message(build_empty_request(next_message_num, MessageType::FriendListRequest));
+    }
+
+    // Get clone of this client's friends and pending friend requests, including what each friend is doing.
+    pub fn get_friends(&self) -> Vec<FriendInfo> {
+        self.state.lock().unwrap().friends.clone()
+    }
+
+    // Send request to invite an online friend to the lobby this client is in.
+    pub fn invite_to_lobby(&self, account_id: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_friend_account_request(codec, next_message_num, MessageType::InviteToLobbyRequest, account_id.to_string()));
+    }
+
+    // Get clone of the lobby invites this client has received from friends. Join the lobby with join_lobby to accept one.
+    pub fn get_lobby_invites(&self) -> Vec<LobbyInvite> {
+        self.state.lock().unwrap().lobby_invites.clone()
+    }
+
+    // Forget a lobby invite without joining the lobby.
+    pub fn dismiss_lobby_invite(&self, lobby_id: &str) {
+        self.state.lock().unwrap().lobby_invites.retain(|invite| invite.lobby_id != lobby_id);
+    }
+
+    // Get why the server refused the most recent friend request, accept, removal or invite, e.g. NotLoggedIn. None if it succeeded.
+    pub fn get_friend_error(&self) -> Option<StatusCode> {
+        self.state.lock().unwrap().friend_error
+    }
+
+    // Send a chat message to everyone in the client's lobby.
+    pub fn send_chat_message(&self, message: &str) {
+        let (next_message_num, codec) = self.next_message_info();
+        self.send_account_message(build_chat_request(codec, next_message_num, message.to_string()));
+    }
+
+    // Get clone of the most recent chat messages in the client's lobby, oldest first.
+    pub fn get_chat_messages(&self) -> Vec<ChatMessage> {
+        self.state.lock().unwrap().chat_messages.clone()
+    }
+
+    // Get why the server refused the most recent chat message, e.g. Muted. None if it was sent.
+    pub fn get_chat_error(&self) -> Option<StatusCode> {
+        self.state.lock().unwrap().chat_error
+    }
+
+    // Send one of the account messages. Synchronously listen if the client isn't asynchronously listening for server messages.
+    fn send_account_message(&self, message: Vec<u8>) {
+        let socket = self.state.lock().unwrap().socket.as_ref().unwrap().clone();
+        if send_message(message, self.state.clone()) && !self.state.lock().unwrap().is_listening_async {
+            listen(socket, self.state.clone());
+        }
+    }
+
     // Send request to return to the lobby from a game session
     pub fn return_to_lobby(&self) {
         // Lock state object to get required message data and change protocol state.
+        // Leaving the game session tells the lobby info that answers this apart from lobby updates sent during the game.
         let state_clone = self.state.clone();
-        let state_lock = state_clone.lock().unwrap();
+        let mut state_lock = state_clone.lock().unwrap();
         let socket = state_lock.socket.as_ref().unwrap().clone();
         let next_message_num = state_lock.next_message_num;
+        state_lock.previous_protocol_state = state_lock.protocol_state;
+        state_lock.protocol_state = ProtocolState::LeavingGameSession;
         drop(state_lock);
 
         // Send message. Synchronously listen if the client isn't asynchronously listening for server messages.
         send_message(
-            build_client_headers(next_message_num, MessageType::ReturnToLobbyRequest),
+            build_empty_request(next_message_num, MessageType::ReturnToLobbyRequest),
             self.state.clone()
         );
         if !self.state.lock().unwrap().is_listening_async {
@@ -427,16 +920,60 @@ impl GameProtocolClient {
     }
 }
 
+// Set the state of the in-progress game. A state arriving while the client waits for its game to start moves it into the game session.
+fn set_game_state(state_lock: &mut GameProtocolClientState, game_state: Box<dyn GameState>) {
+    let starting = matches!(state_lock.protocol_state, ProtocolState::CreatingGameSession | ProtocolState::InLobby) ||
+        (matches!(state_lock.protocol_state, ProtocolState::GettingGameState) && state_lock.game_in_progress.is_none());
+    if starting {
+        let Some(game_type_id) = state_lock.current_lobby.as_ref().map(|lobby| lobby.game_metadata.get_game_type_id()) else { return };
+        let mut new_game = state_lock.supported_games.get(&game_type_id).unwrap().init_new();
+        new_game.set_game_state(game_state);
+        state_lock.game_in_progress = Some(new_game);
+        state_lock.protocol_state = ProtocolState::GameRunning;
+    } else if let Some(game) = state_lock.game_in_progress.as_mut() {
+        // Update the game state for the game that is ongoing.
+        game.set_game_state(game_state);
+        if matches!(state_lock.protocol_state, ProtocolState::GettingGameState) {
+            state_lock.protocol_state = ProtocolState::GameRunning;
+        }
+    }
+}
+
+// Request for the current state of the client's game, moving the client into GettingGameState until it arrives.
+// None if the server is too old to answer it.
+fn build_game_state_request(state_lock: &mut GameProtocolClientState) -> Option<Vec<u8>> {
+    if state_lock.protocol_version < GAME_STATE_REQUEST_VERSION {
+        return None;
+    }
+    state_lock.previous_protocol_state = state_lock.protocol_state;
+    state_lock.protocol_state = ProtocolState::GettingGameState;
+    Some(build_empty_request(state_lock.next_message_num, MessageType::GameStateRequest))
+}
+
 // Function for client to send a message to the server.
 // Decoupled from client object since accessing "self" in a thread causes some issues, so it's just easier to make a separate function.
-fn send_message(data: Vec<u8>, state: Arc<Mutex<GameProtocolClientState>>) {
+// Returns whether the message was sent. Messages of features the server didn't agree on when connecting aren't.
+fn send_message(data: Vec<u8>, state: Arc<Mutex<GameProtocolClientState>>) -> bool {
     let mut state_lock = state.lock().unwrap();
+    let (message_type, _) = parse_message_type(&data[size_of::<u32>()..]);
+    if let Some(feature) = Feature::of_message_type(message_type).filter(|feature| !state_lock.features.contains(feature)) {
+        warn!(?message_type, ?feature, "Server doesn't support the feature, not sending");
+        return false;
+    }
 
     // Only send if the socket has bbeen set
     if state_lock.socket.is_some() {
+        // Stamp the message ID while holding the lock it's incremented under, so messages built on different threads,
+        // like a heartbeat reply and a move, never go out with the same ID.
+        let id  = state_lock.next_message_num;
+        let mut data = data;
+        data[..size_of::<u32>()].copy_from_slice(&id.to_be_bytes());
+        let data = match state_lock.compression {
+            Some((compression, threshold)) => compress_message(data, CLIENT_HEADER_SIZE, compression, threshold),
+            None => data
+        };
 
         // Cache this message in case we need to resend it.
-        let id  = state_lock.next_message_num;
         state_lock.previous_message_cache.insert(id, data.clone());
 
         // Handle incrementing message ID
@@ -445,46 +982,85 @@ fn send_message(data: Vec<u8>, state: Arc<Mutex<GameProtocolClientState>>) {
         } else {
             state_lock.next_message_num += 1;
         }
-        match state_lock.socket.as_ref().unwrap().as_ref().write(data.as_slice()) {
+        match state_lock.socket.as_ref().unwrap().write_all(data.as_slice()) {
             Ok(_) => {}
             Err(e) => {
-                println!("Socket write error. {:?}", e);
+                warn!(error = %e, "Socket write error");
             }
         };
+        true
+    } else {
+        false
     }
 }
 
 // Listen for server messages.
 // Decoupled from client object since accessing "self" in a thread causes some issues, so it's just easier to make a separate function.
-fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
-    if state.lock().unwrap().socket.is_some() {
-        let mut buffer = [0; 4096];
-        match socket.as_ref().read(&mut buffer) {
+fn listen(socket: Arc<dyn Connection>, state: Arc<Mutex<GameProtocolClientState>>) {
+    let state_lock = state.lock().unwrap();
+    if state_lock.socket.is_some() {
+        let compression = state_lock.compression.map(|(compression, _)| compression);
+        drop(state_lock);
+        let mut buffer = vec![];
+        match read_message(&mut socket.as_ref(), SERVER_HEADER_SIZE, None, compression, &mut buffer) {
             Ok(size) => {
                 // If size is more than 0, then this is a legit message we are receiving.
                 // If size is 0, then socket is closed, so formally shut it down.
                 if size > 0 {
                     let (status_code, message_type, remainder) = parse_server_message_header(&buffer);
-                    println!("Message from server: {:?}, {:?}", status_code, message_type);
 
                     // Lock state mutex and match the message type to handle the message appropriately
                     let mut state_lock = state.lock().unwrap();
+                    let message_span = debug_span!("message", client_id = %state_lock.client_id, ?status_code, ?message_type);
+                    let _message_guard = message_span.enter();
+                    debug!("Message from server");
+                    let mut reply = None;
                     match message_type {
                         MessageType::ConnectResponse => {
                             // Only accept the ConnectResponse if it was successful and this client was in the correct state: Authenticating.
                             if matches!(status_code, StatusCode::Success) {
-                                match parse_message_data::<ConnectResponse>(remainder) {
+                                match parse_message_data::<ConnectResponse>(Codec::Json, remainder) {
+                                    Ok(res) if negotiate_protocol_version(res.protocol_version).is_none() => {
+                                        // The server is too old for this client to talk to, so close the connection like the server would for an old client
+                                        warn!(protocol_version = res.protocol_version, "Server protocol version too old, closing connection");
+                                        if let Some(socket) = state_lock.socket.take() {
+                                            if let Err(e) = socket.shutdown() {
+                                                debug!(error = %e, "Socket with server shutdown error");
+                                            }
+                                        }
+                                        state_lock.protocol_state = ProtocolState::Closed;
+                                        state_lock.is_listening_async = false;
+                                        state_lock.connect_error = Some(StatusCode::IncompatibleProtocolVersion);
+                                    }
                                     Ok(res) => {
+                                        state_lock.resuming_session = !state_lock.client_id.is_empty() && state_lock.client_id == res.client_id;
                                         state_lock.client_id = res.client_id;
+                                        state_lock.reconnect_token = Some(res.reconnect_token).filter(|token| !token.is_empty());
+                                        state_lock.profile = Some(res.profile);
+                                        state_lock.codec = res.codec;
+                                        state_lock.compression = res.compression.map(|compression| (compression, res.compression_threshold));
+                                        state_lock.protocol_version = res.protocol_version.unwrap_or(UNVERSIONED_PROTOCOL_VERSION);
+                                        match res.features {
+                                            Some(names) => state_lock.features.retain(|feature| names.iter().any(|name| name == feature.name())),
+                                            None => state_lock.features.retain(|feature| Feature::UNVERSIONED.contains(feature))
+                                        }
                                         state_lock.protocol_state = ProtocolState::Idle;
                                     },
                                     Err(e) => {
-                                        println!("Message parse error. {:?}", e);
+                                        warn!(error = ?e, "Message parse error");
                                     }
                                 }
 
+                            } else {
+                                // Server refused the session, for example because it is full
+                                state_lock.protocol_state = ProtocolState::Closed;
+                                state_lock.connect_error = Some(status_code);
                             }
                         }
+                        MessageType::Heartbeat => {
+                            // Answer so the server knows this client is still there. Replies are sent after the state mutex is released below, and get their message ID when they are.
+                            reply = Some(build_empty_request(state_lock.next_message_num, MessageType::Heartbeat));
+                        }
                         MessageType::DisconnectResponse => {
                             // If successfully disconnected, reset the state to an initialized value
                             if matches!(status_code, StatusCode::Success) {
@@ -492,6 +1068,10 @@ fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
                                 state_lock.socket = None;
                                 state_lock.current_lobby = None;
                                 state_lock.client_id = "".to_string();
+                                state_lock.profile = None;
+                                state_lock.friends = vec![];
+                                state_lock.lobby_invites = vec![];
+                                state_lock.chat_messages = vec![];
                                 state_lock.lobbies = vec![];
                                 state_lock.matching_supported_games = vec![];
                                 state_lock.next_message_num = 0;
@@ -500,20 +1080,20 @@ fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
                         MessageType::LobbyListResponse => {
                             // Simply set list of lobbies retrieved from server to the state so it's accessible from the client.
                             if matches!(status_code, StatusCode::Success) {
-                                match parse_message_data::<LobbyListResponse>(remainder) {
+                                match parse_message_data::<LobbyListResponse>(state_lock.codec, remainder) {
                                     Ok(res) => {
                                         state_lock.protocol_state = ProtocolState::Idle;
                                         state_lock.lobbies = res.lobbies;
                                     }
                                     Err(e) => {
-                                        println!("Message parse error. {:?}", e);
+                                        warn!(error = ?e, "Message parse error");
                                     }
                                 }
                             }
                         }
                         MessageType::SupportedGamesResponse => {
                             if matches!(status_code, StatusCode::Success) {
-                                match parse_message_data::<SupportedGamesResponse>(remainder) {
+                                match parse_message_data::<SupportedGamesResponse>(state_lock.codec, remainder) {
                                     Ok(res) => {
                                         // Compare list of server supported games with client supported games.
                                         // Collect the matching games and store them since these are the ones the client should only be able to create lobbies for and join.
@@ -533,21 +1113,42 @@ fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
                                         state_lock.matching_supported_games = matching_games;
                                     }
                                     Err(e) => {
-                                        println!("Message parse error. {:?}", e);
+                                        warn!(error = ?e, "Message parse error");
                                     }
                                 }
                             }
                         }
                         MessageType::LobbyInfoResponse => {
-                            // Simple, set current lobby upon receiving lobby info
+                            // Simple, set current lobby upon receiving lobby info.
+                            // Lobby updates sent during the client's game (profile changes, resumed seats) leave it in the game.
                             if matches!(status_code, StatusCode::Success) {
-                                match parse_message_data::<LobbyInfoResponse>(remainder) {
+                                match parse_message_data::<LobbyInfoResponse>(state_lock.codec, remainder) {
                                     Ok(res) => {
-                                        state_lock.protocol_state = ProtocolState::InLobby;
+                                        let in_game = match state_lock.protocol_state {
+                                            ProtocolState::GameRunning | ProtocolState::GettingGameState => true,
+                                            ProtocolState::GettingLobbyInfo => matches!(state_lock.previous_protocol_state, ProtocolState::GameRunning),
+                                            _ => false
+                                        };
+                                        let in_same_game = in_game && res.lobby.game_started && state_lock.game_in_progress.is_some() &&
+                                            state_lock.current_lobby.as_ref().is_some_and(|lobby| lobby.id == res.lobby.id);
+                                        if !in_same_game {
+                                            state_lock.protocol_state = ProtocolState::InLobby;
+                                        } else if matches!(state_lock.protocol_state, ProtocolState::GettingLobbyInfo) {
+                                            state_lock.protocol_state = ProtocolState::GameRunning;
+                                        }
+                                        state_lock.lobby_invites.retain(|invite| invite.lobby_id != res.lobby.id);
+                                        if state_lock.current_lobby.as_ref().is_none_or(|lobby| lobby.id != res.lobby.id) {
+                                            state_lock.chat_messages = vec![];
+                                        }
+                                        // The server sends the lobby of a resumed session, but the client asks for the state of the lobby's game itself
+                                        let resumed_game = std::mem::take(&mut state_lock.resuming_session) && res.lobby.game_started;
                                         state_lock.current_lobby = Some(res.lobby);
+                                        if resumed_game {
+                                            reply = build_game_state_request(&mut state_lock);
+                                        }
                                     }
                                     Err(e) => {
-                                        println!("Message parse error. {:?}", e);
+                                        warn!(error = ?e, "Message parse error");
                                     }
                                 }
                             }
@@ -557,42 +1158,206 @@ fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
                             if matches!(status_code, StatusCode::Success) {
                                 state_lock.protocol_state = ProtocolState::Idle;
                                 state_lock.current_lobby = None;
+                                state_lock.chat_messages = vec![];
                             }
                         }
                         MessageType::GameStateResponse => {
                             // Handle receiving game state response. This will move the client into a game session or update it's existing game state.
-                            match parse_message_data::<Box<dyn GameState>>(remainder) {
+                            match parse_message_data::<Box<dyn GameState>>(state_lock.codec, remainder) {
+                                Ok(res) => {
+                                    set_game_state(&mut state_lock, res);
+                                    state_lock.game_state_version = None;
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Message parse error");
+                                }
+                            }
+                        }
+                        MessageType::GameStateDelta => {
+                            // Apply the changes to the game state. If they were made against a different version of it than the client has,
+                            // the client's copy is out of sync, so ask the server for the full state instead.
+                            match parse_message_data::<GameStateDelta>(state_lock.codec, remainder) {
                                 Ok(res) => {
-                                    if matches!(state_lock.protocol_state, ProtocolState::CreatingGameSession) || matches!(state_lock.protocol_state, ProtocolState::InLobby) {
-                                        let game_type_id = state_lock.current_lobby.as_ref().unwrap().game_metadata.get_game_type_id();
-                                        let mut new_game = state_lock.supported_games.get(&game_type_id).unwrap().init_new();
-                                        new_game.set_game_state(res);
-                                        state_lock.game_in_progress = Some(new_game);
-                                        state_lock.protocol_state = ProtocolState::GameRunning;
-                                    } else if state_lock.game_in_progress.is_some() {
-                                        // Update the game state for the game that is ongoing.
-                                        state_lock.game_in_progress.as_mut().unwrap().set_game_state(res);
+                                    let base_state = match res.base_version {
+                                        None => Some(serde_json::Value::Null),
+                                        Some(base_version) if state_lock.game_state_version == Some(base_version) => {
+                                            state_lock.game_in_progress.as_ref().and_then(|game| serde_json::to_value(game.get_game_state()).ok())
+                                        }
+                                        Some(_) => None
+                                    };
+                                    let new_state = base_state
+                                        .and_then(|mut game_state| delta::apply(&mut game_state, &res.changes).then_some(game_state))
+                                        .and_then(|game_state| serde_json::from_value::<Box<dyn GameState>>(game_state).ok());
+                                    match new_state {
+                                        Some(new_state) => {
+                                            set_game_state(&mut state_lock, new_state);
+                                            state_lock.game_state_version = Some(res.version);
+                                        }
+                                        // Deltas arriving while the full state is on its way are dropped, the full state will include them
+                                        None if matches!(state_lock.protocol_state, ProtocolState::GettingGameState) => {}
+                                        None => {
+                                            warn!(base_version = res.base_version, version = state_lock.game_state_version, "Game state out of sync, requesting full state");
+                                            state_lock.game_state_version = None;
+                                            reply = build_game_state_request(&mut state_lock);
+                                        }
                                     }
                                 }
                                 Err(e) => {
-                                    println!("Message parse error. {:?}", e);
+                                    warn!(error = ?e, "Message parse error");
+                                }
+                            }
+                        }
+                        MessageType::RatingResponse => {
+                            // Store the rating so it can be looked up by player and game
+                            match parse_message_data::<RatingResponse>(state_lock.codec, remainder) {
+                                Ok(res) => {
+                                    let key = (res.rating.player_id.clone(), res.rating.game_type_id.clone());
+                                    state_lock.ratings.insert(key, res.rating);
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Message parse error");
                                 }
                             }
                         }
+                        MessageType::LeaderboardResponse => {
+                            match parse_message_data::<LeaderboardResponse>(state_lock.codec, remainder) {
+                                Ok(res) => {
+                                    state_lock.leaderboard = res.ratings;
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Message parse error");
+                                }
+                            }
+                        }
+                        MessageType::TournamentListResponse => {
+                            match parse_message_data::<TournamentListResponse>(state_lock.codec, remainder) {
+                                Ok(res) => {
+                                    state_lock.tournaments = res.tournaments;
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Message parse error");
+                                }
+                            }
+                        }
+                        MessageType::TournamentInfoResponse => {
+                            // Keep the tournament if this client is taking part in or organizing it. Otherwise the client has left it.
+                            match parse_message_data::<TournamentInfoResponse>(state_lock.codec, remainder) {
+                                Ok(res) => {
+                                    let tournament = res.tournament;
+                                    let is_involved = tournament.participant_ids.contains(&state_lock.client_id) ||
+                                        tournament.organizer.as_ref() == Some(&state_lock.client_id);
+                                    if let Some(listed) = state_lock.tournaments.iter_mut().find(|t| t.id == tournament.id) {
+                                        *listed = tournament.clone();
+                                    }
+                                    if is_involved {
+                                        state_lock.current_tournament = Some(tournament);
+                                    } else if state_lock.current_tournament.as_ref().map(|t| &t.id) == Some(&tournament.id) {
+                                        state_lock.current_tournament = None;
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Message parse error");
+                                }
+                            }
+                        }
+                        MessageType::ProfileResponse => {
+                            // Keep the new profile, and update this client's seat in its lobby since the server only sends the lobby to the other players.
+                            if matches!(status_code, StatusCode::Success) {
+                                match parse_message_data::<ProfileResponse>(state_lock.codec, remainder) {
+                                    Ok(res) => {
+                                        if let Some(lobby) = state_lock.current_lobby.as_mut() {
+                                            if let Some(seat) = lobby.players.iter_mut().find(|seat| seat.player_id == res.profile.player_id) {
+                                                *seat = res.profile.clone();
+                                            }
+                                        }
+                                        state_lock.profile = Some(res.profile);
+                                        state_lock.account_error = None;
+                                    }
+                                    Err(e) => {
+                                        warn!(error = ?e, "Message parse error");
+                                    }
+                                }
+                            } else {
+                                state_lock.account_error = Some(status_code);
+                            }
+                        }
+                        MessageType::FriendListResponse => {
+                            // Sent in answer to friend requests, and whenever another player sends, accepts or removes a friendship with this client.
+                            if matches!(status_code, StatusCode::Success) {
+                                match parse_message_data::<FriendListResponse>(state_lock.codec, remainder) {
+                                    Ok(res) => {
+                                        state_lock.friends = res.friends;
+                                        state_lock.friend_error = None;
+                                    }
+                                    Err(e) => {
+                                        warn!(error = ?e, "Message parse error");
+                                    }
+                                }
+                            } else {
+                                state_lock.friend_error = Some(status_code);
+                            }
+                        }
+                        MessageType::PresenceUpdate => {
+                            // A friend connected, disconnected, or moved between being idle, in a lobby and in a game
+                            match parse_message_data::<PresenceUpdate>(state_lock.codec, remainder) {
+                                Ok(res) => {
+                                    if let Some(friend) = state_lock.friends.iter_mut().find(|friend| friend.account_id == res.account_id) {
+                                        friend.presence = res.presence;
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Message parse error");
+                                }
+                            }
+                        }
+                        MessageType::InviteToLobby => {
+                            // Either an invite from a friend, or an empty answer to this client's own invite that only carries its status.
+                            if matches!(status_code, StatusCode::Success) {
+                                if let Ok(invite) = parse_message_data::<LobbyInvite>(state_lock.codec, remainder) {
+                                    state_lock.lobby_invites.retain(|existing| existing.lobby_id != invite.lobby_id);
+                                    state_lock.lobby_invites.push(invite);
+                                } else {
+                                    state_lock.friend_error = None;
+                                }
+                            } else {
+                                state_lock.friend_error = Some(status_code);
+                            }
+                        }
+                        MessageType::ChatMessage => {
+                            // Messages from everyone in the lobby, including this client's own, which is how it knows they were sent
+                            if matches!(status_code, StatusCode::Success) {
+                                match parse_message_data::<ChatMessage>(state_lock.codec, remainder) {
+                                    Ok(res) => {
+                                        if res.player_id == state_lock.client_id {
+                                            state_lock.chat_error = None;
+                                        }
+                                        if state_lock.chat_messages.len() >= MAX_CHAT_MESSAGES {
+                                            state_lock.chat_messages.remove(0);
+                                        }
+                                        state_lock.chat_messages.push(res);
+                                    }
+                                    Err(e) => {
+                                        warn!(error = ?e, "Message parse error");
+                                    }
+                                }
+                            } else {
+                                state_lock.chat_error = Some(status_code);
+                            }
+                        }
                         MessageType::UnsolicitedMessage => {
                             // Set client state's message to the message received from the server
-                            match parse_message_data::<UnsolicitedMessage>(remainder) {
+                            match parse_message_data::<UnsolicitedMessage>(state_lock.codec, remainder) {
                                 Ok(res) => {
                                     state_lock.unsolicited_message = res.message;
                                 }
                                 Err(e) => {
-                                    println!("Message parse error. {:?}", e);
+                                    warn!(error = ?e, "Message parse error");
                                 }
                             }
                         }
                         MessageType::MissingMessageResponse => {
                             // Check which messages are missing and resend them to the server
-                            match parse_message_data::<MissingMessageResponse>(remainder) {
+                            match parse_message_data::<MissingMessageResponse>(state_lock.codec, remainder) {
                                 Ok(res) => {
                                     // Copy the message cache so we don't need to use the state mutex to directly access the state
                                     let previous_message_cache = state_lock.previous_message_cache.clone();
@@ -601,10 +1366,10 @@ fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
                                     for id in res.missing_message_ids.iter() {
                                         // If the ID is in the message cache, attempt to resend it
                                         if let Some(message) = previous_message_cache.get(id) {
-                                            match socket.as_ref().write(message.as_slice()) {
+                                            match socket.write_all(message.as_slice()) {
                                                 Ok(_) => {}
                                                 Err(e) => {
-                                                    println!("Resending previous message error. {:?}", e);
+                                                    warn!(error = %e, message_id = id, "Resending previous message error");
                                                 }
                                             }
                                         } else {
@@ -616,7 +1381,7 @@ fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
                                     }
                                 }
                                 Err(e) => {
-                                    println!("Message parse error. {:?}", e);
+                                    warn!(error = ?e, "Message parse error");
                                 }
                             }
                         }
@@ -633,19 +1398,92 @@ fn listen(socket: Arc<TcpStream>, state: Arc<Mutex<GameProtocolClientState>>) {
                     if let Some(callback) = &state_lock.on_message_received {
                         callback();
                     }
+                    drop(state_lock);
+                    if let Some(reply) = reply {
+                        send_message(reply, state.clone());
+                    }
                 } else {
                     // If read size is 0 then server terminated connect, so clean things up on the client side.
-                    match socket.shutdown(Shutdown::Both) {
+                    match socket.shutdown() {
                         Ok(_) => {}
                         Err(e) => {
-                            println!("Socket with server shutdown error. {:?}", e);
+                            debug!(error = %e, "Socket with server shutdown error");
                         }
                     }
+
+                    // Forget the socket so the client can reconnect. Client ID is kept so it can ask to resume its session.
+                    let mut state_lock = state.lock().unwrap();
+                    state_lock.previous_protocol_state = state_lock.protocol_state;
+                    state_lock.protocol_state = ProtocolState::Closed;
+                    state_lock.socket = None;
+                    state_lock.is_listening_async = false;
                 }
             }
             Err(e) => {
-                println!("Listen error: {}", e);
+                warn!(error = %e, "Listen error");
             }
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::common_message_utils::build_message_body;
+    use crate::game_module::delta::StateChange;
+    use crate::transport::loopback::LoopbackConnection;
+    use super::*;
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Send a message from the server's end of the connection, with a JSON body.
+    fn send_from_server(server: &dyn Connection, message_type: MessageType, body: Vec<u8>) {
+        let mut message = vec![];
+        message.extend_from_slice(&(StatusCode::Success as u16).to_be_bytes());
+        message.extend_from_slice(&(message_type as u16).to_be_bytes());
+        message.extend_from_slice(&build_message_body(Some(body)));
+        server.write_all(&message).unwrap();
+    }
+
+    // Read the next message the client sent and return its type.
+    fn read_from_client(mut server: &dyn Connection) -> MessageType {
+        let mut buffer = vec![];
+        assert!(read_message(&mut server, CLIENT_HEADER_SIZE, None, None, &mut buffer).unwrap() > 0);
+        parse_message_type(&buffer[size_of::<u32>()..]).0
+    }
+
+    #[test]
+    fn out_of_order_delta_requests_the_full_state() {
+        let (client_end, server_end) = LoopbackConnection::pair();
+        let server: &dyn Connection = &server_end;
+        let mut client = GameProtocolClient::new();
+        client.connect_with(client_end);
+
+        assert!(matches!(read_from_client(server), MessageType::ConnectRequest));
+        send_from_server(server, MessageType::ConnectResponse, Codec::Json.encode(&ConnectResponse {
+            client_id: "client".to_string(),
+            reconnect_token: String::new(),
+            profile: PlayerProfile::guest("client"),
+            codec: Codec::Json,
+            compression: None,
+            compression_threshold: 0,
+            protocol_version: Some(PROTOCOL_VERSION),
+            features: Some(vec![Feature::GameStateDeltas.name().to_string()])
+        }));
+        wait_for(|| client.get_protocol_state() == ProtocolState::Idle);
+        client.async_listen();
+
+        // The client has no version of the state yet, so a delta against version 7 can't be applied
+        let delta = GameStateDelta { base_version: Some(7), version: 8, changes: vec![StateChange::Set { path: "/turn".to_string(), value: 1.into() }] };
+        send_from_server(server, MessageType::GameStateDelta, Codec::Json.encode(&delta));
+        assert!(matches!(read_from_client(server), MessageType::GameStateRequest));
+        assert_eq!(client.get_protocol_state(), ProtocolState::GettingGameState);
+        assert_eq!(client.get_game_state_version(), None);
+    }
+}
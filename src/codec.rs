@@ -0,0 +1,53 @@
+use std::str;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::enums::ServerError;
+
+/*
+    How message bodies are encoded. JSON is the default and what every client and server understands.
+    MessagePack is a compact binary encoding, useful for games with large states. Structs are encoded with their field names,
+    so optional and defaulted fields work the same as in JSON, and GameState and GameMove trait objects still go through typetag.
+    A client asks for a codec in its connect request and the server answers with the one it picked in the connect response.
+    Both of those are always JSON. Everything after them uses the picked codec.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack
+}
+
+impl Codec {
+    // Name the codec is asked for by in connect requests, e.g. "MessagePack".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Json => "Json",
+            Codec::MessagePack => "MessagePack"
+        }
+    }
+
+    // Look up a codec by name. Names from newer clients that aren't known here give None.
+    pub fn from_name(name: &str) -> Option<Codec> {
+        [Codec::Json, Codec::MessagePack].into_iter().find(|codec| codec.name() == name)
+    }
+
+    // Encode a message body. Only fails for types that can't be serialized at all, which message bodies never are.
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Vec<u8> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).expect("Message body could not be encoded as JSON"),
+            Codec::MessagePack => rmp_serde::to_vec_named(value).expect("Message body could not be encoded as MessagePack")
+        }
+    }
+
+    // Decode a message body into a data type.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, ServerError> {
+        match self {
+            Codec::Json => {
+                let text = str::from_utf8(bytes).map_err(|_| ServerError::BytesToStringError)?;
+                serde_json::from_str(text).map_err(|_| ServerError::DeserializeError)
+            }
+            Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(|_| ServerError::DeserializeError)
+        }
+    }
+}
@@ -1,23 +1,122 @@
+use std::io::{Error, ErrorKind, Read};
 use std::mem::size_of;
-use std::str;
 use serde::de::DeserializeOwned;
+use crate::codec::Codec;
+use crate::compression::Compression;
 use crate::enums::{ServerError, MessageType, StatusCode};
 
 /*
     Contains message parsing functions common between client and server.
  */
 
+// Size in bytes of the headers at the start of each message. Client messages have a message ID and message type.
+// Server messages have a status code and message type.
+pub const CLIENT_HEADER_SIZE: usize = size_of::<u32>() + size_of::<u16>();
+pub const SERVER_HEADER_SIZE: usize = size_of::<u16>() + size_of::<u16>();
 
-// Parse size and body of message into u32 and JSON string.
+// Version of the protocol this crate speaks. Bump it whenever message type or status code numbers, headers or message bodies change
+// in a way older peers can't handle. Peers speak the lower of their two versions, so each version has to stay able to talk to older ones
+// down to the minimum. Clients and servers from before versions were exchanged speak version 1, which sent header-only messages without
+// a body size. They can't read messages framed the way they are now, so they're turned away. Their connect request has a body size,
+// so the server can still read it and answer with a version error.
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+pub const UNVERSIONED_PROTOCOL_VERSION: u32 = 1;
+
+// Pick the protocol version to speak with a peer that offered the given version, or None if the peer is too old to talk to.
+// Peers that don't send a version are from before versions were exchanged. The minimum is checked before clamping to this crate's
+// version, so a peer newer than this crate is never turned away.
+pub fn negotiate_protocol_version(peer_version: Option<u32>) -> Option<u32> {
+    let peer_version = peer_version.unwrap_or(UNVERSIONED_PROTOCOL_VERSION);
+    if peer_version < MIN_PROTOCOL_VERSION {
+        return None;
+    }
+    Some(peer_version.min(PROTOCOL_VERSION))
+}
+
+// First version with GameStateRequest. Servers before it push a reconnected client's game state instead of waiting to be asked.
+pub const GAME_STATE_REQUEST_VERSION: u32 = 3;
+
+// Set in the body size of a message whose body is compressed. Bodies never come close to 2 GiB, so the highest bit is free.
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+
+// Read exactly one message from a stream into the buffer: the headers, the body size, and the checksum and body if there is one.
+// Messages are sent back to back on the same stream, so reading one at a time keeps them from running together.
+// Returns the number of bytes read, which is 0 if the stream was closed before a new message started.
+// If a max body size is given, messages with a larger body are rejected with an InvalidData error before the body is read.
+// Compressed bodies are decompressed with the compression agreed on at connect, so the buffer always holds the message as it was built.
+// A compressed body is an InvalidData error if no compression was agreed on, or if it is larger than the max body size once decompressed.
+pub fn read_message(stream: &mut impl Read, header_size: usize, max_body_size: Option<usize>, compression: Option<Compression>, buffer: &mut Vec<u8>) -> std::io::Result<usize> {
+    // Headers and body size
+    buffer.resize(header_size + size_of::<u32>(), 0);
+    if let Err(e) = stream.read_exact(buffer) {
+        return if matches!(e.kind(), ErrorKind::UnexpectedEof) { Ok(0) } else { Err(e) };
+    }
+
+    // Checksum and body
+    let size_bytes = &buffer[header_size..];
+    let raw_size = u32::from_be_bytes(size_bytes.try_into().unwrap());
+    let size = (raw_size & !COMPRESSED_FLAG) as usize;
+    let compressed = raw_size & COMPRESSED_FLAG != 0;
+    if compressed && compression.is_none() {
+        return Err(Error::new(ErrorKind::InvalidData, "Message body is compressed, but no compression was agreed on"));
+    }
+    if let Some(max_body_size) = max_body_size {
+        if size > max_body_size {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Message body of {} bytes is larger than the limit of {} bytes", size, max_body_size)));
+        }
+    }
+    if size > 0 {
+        let body_start = buffer.len();
+        buffer.resize(body_start + size_of::<u32>() + size, 0);
+        stream.read_exact(&mut buffer[body_start..])?;
+    }
+    let bytes_read = buffer.len();
+
+    // Replace a compressed body with the decompressed one and its checksum. A body that doesn't match its checksum is left as it is
+    // with the flag cleared, so it is reported as a checksum error like any other.
+    if let Some(compression) = compression.filter(|_| compressed) {
+        let (checksum_bytes, body) = buffer[header_size + size_of::<u32>()..].split_at(size_of::<u32>());
+        if u32::from_be_bytes(checksum_bytes.try_into().unwrap()) == crc32fast::hash(body) {
+            let decompressed = compression.decompress(body, max_body_size.unwrap_or((!COMPRESSED_FLAG) as usize))?;
+            buffer.truncate(header_size);
+            buffer.extend_from_slice(&build_message_body(Some(decompressed)));
+        } else {
+            buffer[header_size..header_size + size_of::<u32>()].copy_from_slice(&(size as u32).to_be_bytes());
+        }
+    }
+    Ok(bytes_read)
+}
+
+// Compress the body of a built message if it is larger than the threshold and gets smaller, flagging it in the body size.
+// Other messages are returned as they are.
+pub fn compress_message(message: Vec<u8>, header_size: usize, compression: Compression, threshold: usize) -> Vec<u8> {
+    let body_start = header_size + size_of::<u32>() * 2;
+    if message.len() <= body_start || message.len() - body_start <= threshold {
+        return message;
+    }
+    let body = &message[body_start..];
+    let compressed = compression.compress(body);
+    if compressed.len() >= body.len() {
+        return message;
+    }
+    let mut byte_vec = message[..header_size].to_vec();
+    byte_vec.extend_from_slice(&(compressed.len() as u32 | COMPRESSED_FLAG).to_be_bytes()); // body size in bytes, flagged as compressed
+    byte_vec.extend_from_slice(&crc32fast::hash(&compressed).to_be_bytes());
+    byte_vec.extend_from_slice(&compressed);
+    byte_vec
+}
+
+// Parse size and body of message, returning the body's bytes.
 // Throw errors if the body checksum or size in bytes between
 // incoming message data and local calculations does not match
-pub fn parse_message_payload(data: &[u8]) -> Result<String, ServerError> {
+pub fn parse_message_payload(data: &[u8]) -> Result<&[u8], ServerError> {
     // Data size
     let (size_bytes, remainder) = data.split_at(size_of::<u32>());
     let size = u32::from_be_bytes(size_bytes.try_into().unwrap());
 
     // Get body and compare checksum
-    let mut body = "";
+    let mut body: &[u8] = &[];
     if size > 0 {
         // Extract remote checksum
         let (checksum_bytes, remainder) = remainder.split_at(size_of::<u32>());
@@ -25,9 +124,9 @@ pub fn parse_message_payload(data: &[u8]) -> Result<String, ServerError> {
 
         // Extract data and get checksum for the data
         let (data_bytes, _) = remainder.split_at(size as usize);
-        let local_checksum = crc32fast::hash(&data_bytes);
+        let local_checksum = crc32fast::hash(data_bytes);
 
-        // If the checksums don't match, throw error. Otherwise parse the data into a string
+        // If the checksums don't match, throw error.
         if remote_checksum != local_checksum {
             return Err(ServerError::ChecksumError);
         }
@@ -36,26 +135,16 @@ pub fn parse_message_payload(data: &[u8]) -> Result<String, ServerError> {
         if size as usize > data_bytes.len() {
             return Err(ServerError::BodySizeError);
         }
-
-        // Check for errors when parsing raw bytes into a string
-        match str::from_utf8(data_bytes) {
-            Ok(data) => {
-                body = data;
-            }
-            Err(_) => {
-                return Err(ServerError::BytesToStringError)
-            }
-        }
+        body = data_bytes;
     }
-    Ok(body.to_string())
+    Ok(body)
 }
 
-// Build the body into a byte vector to send.
-pub fn build_message_body(body: Option<String>) -> Vec<u8> {
+// Build the body, already encoded by a codec, into a byte vector to send.
+pub fn build_message_body(body: Option<Vec<u8>>) -> Vec<u8> {
     let mut byte_vec = vec![];
 
-    if let Some(data) = body {
-        let data_bytes = data.as_bytes();
+    if let Some(data_bytes) = body {
         // If data size is larger than we can transfer, return an empty vec to indicate such.
         if data_bytes.len() > u32::MAX as usize {
             return byte_vec;
@@ -69,22 +158,9 @@ pub fn build_message_body(body: Option<String>) -> Vec<u8> {
     byte_vec
 }
 
-// Generic function for parsing byte array into a data type
-pub fn parse_message_data<'a, T>(raw_message: &[u8]) -> Result<T, ServerError> where T: DeserializeOwned {
-    return match parse_message_payload(raw_message) {
-        Ok(data_string) => {
-            // Make sure parsing string into a data type is successful. Throw error if not.
-            let parse_result = serde_json::from_str(&data_string);
-            if parse_result.is_err() {
-                Err(ServerError::DeserializeError)
-            } else {
-                Ok(parse_result.unwrap())
-            }
-        }
-        Err(e) => {
-            Err(e)
-        }
-    }
+// Generic function for parsing byte array into a data type, decoding the body with the codec the connection uses
+pub fn parse_message_data<T>(codec: Codec, raw_message: &[u8]) -> Result<T, ServerError> where T: DeserializeOwned {
+    codec.decode(parse_message_payload(raw_message)?)
 }
 
 // Maps integers to the message type enumeration
@@ -111,12 +187,49 @@ pub fn parse_message_type(data: &[u8]) -> (MessageType, &[u8]) {
         17 => MessageType::StartGameRequest,
         18 => MessageType::MoveRequest,
         19 => MessageType::GameStateResponse,
-        20 => MessageType::UnsolicitedMessage,
+        20 => MessageType::MissingMessageResponse,
+        21 => MessageType::UnsolicitedMessage,
+        22 => MessageType::RatingRequest,
+        23 => MessageType::RatingResponse,
+        24 => MessageType::LeaderboardRequest,
+        25 => MessageType::LeaderboardResponse,
+        26 => MessageType::MatchmakingRequest,
+        27 => MessageType::TournamentListRequest,
+        28 => MessageType::TournamentListResponse,
+        29 => MessageType::CreateTournamentRequest,
+        30 => MessageType::JoinTournamentRequest,
+        31 => MessageType::LeaveTournamentRequest,
+        32 => MessageType::StartTournamentRequest,
+        33 => MessageType::TournamentInfoResponse,
+        34 => MessageType::Heartbeat,
+        35 => MessageType::RegisterRequest,
+        36 => MessageType::LoginRequest,
+        37 => MessageType::UpdateProfileRequest,
+        38 => MessageType::ProfileResponse,
+        39 => MessageType::FriendRequest,
+        40 => MessageType::AcceptFriendRequest,
+        41 => MessageType::RemoveFriendRequest,
+        42 => MessageType::FriendListRequest,
+        43 => MessageType::FriendListResponse,
+        44 => MessageType::PresenceUpdate,
+        45 => MessageType::InviteToLobbyRequest,
+        46 => MessageType::InviteToLobby,
+        47 => MessageType::ChatRequest,
+        48 => MessageType::ChatMessage,
+        49 => MessageType::GameStateDelta,
+        50 => MessageType::GameStateRequest,
         _ => MessageType::Unsupported
     };
     (message_type, remainder)
 }
 
+// Look up a message type by its name, e.g. "CreateLobbyRequest". Message type numbers are contiguous, so the search stops at the first unknown one.
+pub fn message_type_from_name(name: &str) -> Option<MessageType> {
+    (1..=u16::MAX).map(|n| parse_message_type(&n.to_be_bytes()).0)
+        .take_while(|message_type| *message_type != MessageType::Unsupported)
+        .find(|message_type| format!("{:?}", message_type) == name)
+}
+
 // Map integers to status code enumeration
 pub fn parse_status_code(data: &[u8]) -> (StatusCode, &[u8]) {
     let (status_bytes, remainder) = data.split_at(size_of::<u16>());
@@ -140,7 +253,73 @@ pub fn parse_status_code(data: &[u8]) -> (StatusCode, &[u8]) {
         16 => StatusCode::GameStartCriteriaNotMet,
         17 => StatusCode::GameOver,
         18 => StatusCode::InvalidMove,
+        19 => StatusCode::TournamentNotFound,
+        20 => StatusCode::TournamentRegistrationClosed,
+        21 => StatusCode::AlreadyInTournament,
+        22 => StatusCode::NotInTournament,
+        23 => StatusCode::NotTournamentOrganizer,
+        24 => StatusCode::ServerFull,
+        25 => StatusCode::LobbyLimitReached,
+        26 => StatusCode::ServerShuttingDown,
+        27 => StatusCode::RejectedByPolicy,
+        28 => StatusCode::Banned,
+        29 => StatusCode::UsernameTaken,
+        30 => StatusCode::InvalidCredentials,
+        31 => StatusCode::InvalidProfile,
+        32 => StatusCode::AlreadyLoggedIn,
+        33 => StatusCode::NotLoggedIn,
+        34 => StatusCode::AccountNotFound,
+        35 => StatusCode::AlreadyFriends,
+        36 => StatusCode::NotFriends,
+        37 => StatusCode::FriendOffline,
+        38 => StatusCode::RateLimited,
+        39 => StatusCode::Muted,
+        40 => StatusCode::IncompatibleProtocolVersion,
+        41 => StatusCode::TooManyMissingMessages,
         _ => StatusCode::UnexpectedError
     };
     (status_type, remainder)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A client message with a JSON body, compressed the way a client that agreed on Deflate sends it.
+    fn compressed_message(body: &[u8]) -> Vec<u8> {
+        let mut message = vec![0, 0, 0, 1, 0, 8];
+        message.extend_from_slice(&build_message_body(Some(body.to_vec())));
+        compress_message(message, CLIENT_HEADER_SIZE, Compression::Deflate, 0)
+    }
+
+    #[test]
+    fn compressed_message_round_trips() {
+        let body = vec![b'a'; 2048];
+        let message = compressed_message(&body);
+        assert!(message.len() < body.len());
+
+        let mut buffer = vec![];
+        let size = read_message(&mut message.as_slice(), CLIENT_HEADER_SIZE, Some(4096), Some(Compression::Deflate), &mut buffer).unwrap();
+        assert_eq!(size, message.len());
+        assert_eq!(&buffer[..CLIENT_HEADER_SIZE], &message[..CLIENT_HEADER_SIZE]);
+        assert_eq!(parse_message_payload(&buffer[CLIENT_HEADER_SIZE..]).unwrap(), body.as_slice());
+    }
+
+    #[test]
+    fn body_inflating_past_the_limit_is_rejected() {
+        let message = compressed_message(&vec![0u8; 1024 * 1024]);
+        assert!(message.len() < 4096);
+
+        let mut buffer = vec![];
+        let error = read_message(&mut message.as_slice(), CLIENT_HEADER_SIZE, Some(4096), Some(Compression::Deflate), &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(buffer.len(), message.len()); // Only the compressed body was buffered
+    }
+
+    #[test]
+    fn compressed_message_without_agreed_compression_is_rejected() {
+        let message = compressed_message(&vec![b'a'; 2048]);
+        let mut buffer = vec![];
+        let error = read_message(&mut message.as_slice(), CLIENT_HEADER_SIZE, None, None, &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,51 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+
+/*
+    Compression for large message bodies, e.g. the full game state sent after every move.
+    A client asks for compression in its connect request and the server answers with the one it picked, if any, and the body size
+    above which bodies are compressed. Only bodies over that size that actually get smaller are compressed, and those are flagged
+    in the body size of the message's header. Peers that never agreed on compression never see the flag.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    Deflate
+}
+
+impl Compression {
+    // Name the compression is asked for by in connect requests, e.g. "Deflate".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Deflate => "Deflate"
+        }
+    }
+
+    // Look up a compression by name. Names from newer clients that aren't known here give None.
+    pub fn from_name(name: &str) -> Option<Compression> {
+        [Compression::Deflate].into_iter().find(|compression| compression.name() == name)
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(bytes).and_then(|_| encoder.finish()).expect("Writing to a vector can't fail")
+            }
+        }
+    }
+
+    // Decompress a body, failing if it isn't valid or would be larger than the limit in bytes.
+    pub fn decompress(&self, bytes: &[u8], limit: usize) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = vec![];
+        match self {
+            Compression::Deflate => DeflateDecoder::new(bytes).take(limit as u64 + 1).read_to_end(&mut decompressed)?
+        };
+        if decompressed.len() > limit {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Decompressed message body is larger than the limit of {} bytes", limit)));
+        }
+        Ok(decompressed)
+    }
+}
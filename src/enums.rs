@@ -1,9 +1,11 @@
+use std::fmt;
+
 /*
     Creates enums for messages, status, and protocol state
  */
 
 // For types of messages
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum MessageType {
     Unsupported,
     ProtocolError,
@@ -26,7 +28,36 @@ pub enum MessageType {
     MoveRequest,
     GameStateResponse,
     MissingMessageResponse,
-    UnsolicitedMessage
+    UnsolicitedMessage,
+    RatingRequest,
+    RatingResponse,
+    LeaderboardRequest,
+    LeaderboardResponse,
+    MatchmakingRequest,
+    TournamentListRequest,
+    TournamentListResponse,
+    CreateTournamentRequest,
+    JoinTournamentRequest,
+    LeaveTournamentRequest,
+    StartTournamentRequest,
+    TournamentInfoResponse,
+    Heartbeat,
+    RegisterRequest,
+    LoginRequest,
+    UpdateProfileRequest,
+    ProfileResponse,
+    FriendRequest,
+    AcceptFriendRequest,
+    RemoveFriendRequest,
+    FriendListRequest,
+    FriendListResponse,
+    PresenceUpdate,
+    InviteToLobbyRequest,
+    InviteToLobby,
+    ChatRequest,
+    ChatMessage,
+    GameStateDelta,
+    GameStateRequest
 }
 
 // For server message status
@@ -50,7 +81,86 @@ pub enum StatusCode {
     LobbyNotFound,
     GameStartCriteriaNotMet,
     GameOver,
-    InvalidMove
+    InvalidMove,
+    TournamentNotFound,
+    TournamentRegistrationClosed,
+    AlreadyInTournament,
+    NotInTournament,
+    NotTournamentOrganizer,
+    ServerFull,
+    LobbyLimitReached,
+    ServerShuttingDown,
+    RejectedByPolicy,
+    Banned,
+    UsernameTaken,
+    InvalidCredentials,
+    InvalidProfile,
+    AlreadyLoggedIn,
+    NotLoggedIn,
+    AccountNotFound,
+    AlreadyFriends,
+    NotFriends,
+    FriendOffline,
+    RateLimited,
+    Muted,
+    IncompatibleProtocolVersion,
+    TooManyMissingMessages
+}
+
+// Optional parts of the protocol. Clients and servers tell each other which they support when connecting and only use the ones they have in common.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Feature {
+    Accounts,
+    Friends,
+    Chat,
+    Ratings,
+    Matchmaking,
+    Tournaments,
+    GameStateDeltas,
+    Heartbeats
+}
+
+impl Feature {
+    // Every feature this version of the protocol has.
+    pub const ALL: [Feature; 8] = [Feature::Accounts, Feature::Friends, Feature::Chat, Feature::Ratings, Feature::Matchmaking, Feature::Tournaments, Feature::GameStateDeltas, Feature::Heartbeats];
+
+    // Features peers from before features were exchanged support. Features added since have to be agreed on by name.
+    pub const UNVERSIONED: [Feature; 6] = [Feature::Accounts, Feature::Friends, Feature::Chat, Feature::Ratings, Feature::Matchmaking, Feature::Tournaments];
+
+    // Name the feature is exchanged by when connecting, e.g. "Chat".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Accounts => "Accounts",
+            Feature::Friends => "Friends",
+            Feature::Chat => "Chat",
+            Feature::Ratings => "Ratings",
+            Feature::Matchmaking => "Matchmaking",
+            Feature::Tournaments => "Tournaments",
+            Feature::GameStateDeltas => "GameStateDeltas",
+            Feature::Heartbeats => "Heartbeats"
+        }
+    }
+
+    // Look up a feature by name. Names from newer peers that aren't known here give None.
+    pub fn from_name(name: &str) -> Option<Feature> {
+        Feature::ALL.into_iter().find(|feature| feature.name() == name)
+    }
+
+    // The feature a type of message belongs to, if it isn't part of the core protocol every peer supports.
+    pub fn of_message_type(message_type: MessageType) -> Option<Feature> {
+        match message_type {
+            MessageType::RegisterRequest | MessageType::LoginRequest | MessageType::UpdateProfileRequest | MessageType::ProfileResponse => Some(Feature::Accounts),
+            MessageType::FriendRequest | MessageType::AcceptFriendRequest | MessageType::RemoveFriendRequest | MessageType::FriendListRequest |
+            MessageType::FriendListResponse | MessageType::PresenceUpdate | MessageType::InviteToLobbyRequest | MessageType::InviteToLobby => Some(Feature::Friends),
+            MessageType::ChatRequest | MessageType::ChatMessage => Some(Feature::Chat),
+            MessageType::RatingRequest | MessageType::RatingResponse | MessageType::LeaderboardRequest | MessageType::LeaderboardResponse => Some(Feature::Ratings),
+            MessageType::MatchmakingRequest => Some(Feature::Matchmaking),
+            MessageType::TournamentListRequest | MessageType::TournamentListResponse | MessageType::CreateTournamentRequest | MessageType::JoinTournamentRequest |
+            MessageType::LeaveTournamentRequest | MessageType::StartTournamentRequest | MessageType::TournamentInfoResponse => Some(Feature::Tournaments),
+            MessageType::GameStateDelta => Some(Feature::GameStateDeltas),
+            _ => None
+        }
+    }
 }
 
 // For general game_protocol state
@@ -73,7 +183,57 @@ pub enum ProtocolState {
     LeavingGameSession
 }
 
+// For errors returned by storage backends. Carries the backend's own error message.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StorageError {
+    OpenError(String),
+    MigrationError(String),
+    QueryError(String),
+    SerializeError(String)
+}
+
+// For errors found while loading or validating a server config. Carries a description of what is wrong.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ConfigError {
+    ReadError(String),
+    ParseError(String),
+    InvalidValue(String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::ReadError(e) => write!(f, "Could not read server config. {}", e),
+            ConfigError::ParseError(e) => write!(f, "Could not parse server config. {}", e),
+            ConfigError::InvalidValue(e) => write!(f, "Invalid server config. {}", e)
+        }
+    }
+}
+
+// For errors found while loading TLS certificates and keys or setting up a TLS connection. Carries a description of what is wrong.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TlsError {
+    ReadError(String),
+    InvalidCertificate(String),
+    InvalidKey(String),
+    SetupError(String)
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::ReadError(e) => write!(f, "Could not read TLS file. {}", e),
+            TlsError::InvalidCertificate(e) => write!(f, "Invalid TLS certificate. {}", e),
+            TlsError::InvalidKey(e) => write!(f, "Invalid TLS private key. {}", e),
+            TlsError::SetupError(e) => write!(f, "Could not set up TLS. {}", e)
+        }
+    }
+}
+
 // For errors to be used with Result return types
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ServerError {
     ChecksumError,
@@ -0,0 +1,154 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+/*
+    Changes between two versions of a game state, so the server only has to send players what a move changed.
+    Game states are compared as JSON, with paths given as JSON pointers, e.g. "/TicTacToeState/board/0/2".
+ */
+
+// A single change to a game state.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum StateChange {
+    Set { path: String, value: Value }, // Set the value at path, adding it if its parent is an object that doesn't have it. An empty path replaces the whole state.
+    Remove { path: String } // Remove a field from an object
+}
+
+// Find the changes that turn the previous game state into the current one.
+// Objects and arrays of the same length are compared field by field, anything else that differs is set as a whole.
+pub fn diff(previous: &Value, current: &Value) -> Vec<StateChange> {
+    let mut changes = vec![];
+    diff_at(String::new(), previous, current, &mut changes);
+    changes
+}
+
+fn diff_at(path: String, previous: &Value, current: &Value, changes: &mut Vec<StateChange>) {
+    match (previous, current) {
+        (Value::Object(previous_fields), Value::Object(current_fields)) => {
+            for key in previous_fields.keys().filter(|key| !current_fields.contains_key(*key)) {
+                changes.push(StateChange::Remove { path: child_path(&path, key) });
+            }
+            for (key, value) in current_fields.iter() {
+                match previous_fields.get(key) {
+                    Some(previous_value) => diff_at(child_path(&path, key), previous_value, value, changes),
+                    None => changes.push(StateChange::Set { path: child_path(&path, key), value: value.clone() })
+                }
+            }
+        }
+        (Value::Array(previous_items), Value::Array(current_items)) if previous_items.len() == current_items.len() => {
+            for (i, (previous_item, current_item)) in previous_items.iter().zip(current_items.iter()).enumerate() {
+                diff_at(format!("{}/{}", path, i), previous_item, current_item, changes);
+            }
+        }
+        _ if previous != current => changes.push(StateChange::Set { path, value: current.clone() }),
+        _ => {}
+    }
+}
+
+// Apply changes to a game state in order. Returns false if a change's path doesn't exist in the state,
+// which means the changes were made against a different version of it.
+pub fn apply(state: &mut Value, changes: &[StateChange]) -> bool {
+    changes.iter().all(|change| apply_change(state, change))
+}
+
+fn apply_change(state: &mut Value, change: &StateChange) -> bool {
+    match change {
+        StateChange::Set { path, value } if path.is_empty() => {
+            *state = value.clone();
+            true
+        }
+        StateChange::Set { path, value } => {
+            let Some((parent, key)) = path.rsplit_once('/') else { return false };
+            match state.pointer_mut(parent) {
+                Some(Value::Object(fields)) => {
+                    fields.insert(unescape(key), value.clone());
+                    true
+                }
+                Some(Value::Array(items)) => match key.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                    Some(item) => {
+                        *item = value.clone();
+                        true
+                    }
+                    None => false
+                },
+                _ => false
+            }
+        }
+        StateChange::Remove { path } => {
+            let Some((parent, key)) = path.rsplit_once('/') else { return false };
+            match state.pointer_mut(parent) {
+                Some(Value::Object(fields)) => fields.remove(&unescape(key)).is_some(),
+                _ => false
+            }
+        }
+    }
+}
+
+// JSON pointers escape "~" and "/" in field names as "~0" and "~1"
+fn child_path(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn unescape(key: &str) -> String {
+    key.replace("~1", "/").replace("~0", "~")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    // Diff two states, check the changes turn the first into the second, and return them.
+    fn round_trip(previous: Value, current: Value) -> Vec<StateChange> {
+        let changes = diff(&previous, &current);
+        let mut state = previous;
+        assert!(apply(&mut state, &changes), "changes {:?} didn't apply", changes);
+        assert_eq!(state, current);
+        changes
+    }
+
+    #[test]
+    fn nested_objects_only_send_what_changed() {
+        let changes = round_trip(
+            json!({"TicTacToeState": {"board": [["X", null], [null, null]], "players": {"x": "a", "o": "b"}, "turn": 1}}),
+            json!({"TicTacToeState": {"board": [["X", null], [null, "O"]], "players": {"x": "a", "o": "b"}, "turn": 2}})
+        );
+        assert_eq!(changes, vec![
+            StateChange::Set { path: "/TicTacToeState/board/1/1".to_string(), value: json!("O") },
+            StateChange::Set { path: "/TicTacToeState/turn".to_string(), value: json!(2) }
+        ]);
+    }
+
+    #[test]
+    fn arrays_that_change_length_are_replaced() {
+        let changes = round_trip(json!({"hand": [1, 2, 3], "deck": [4]}), json!({"hand": [1, 3], "deck": [4, 2]}));
+        assert_eq!(changes, vec![
+            StateChange::Set { path: "/deck".to_string(), value: json!([4, 2]) },
+            StateChange::Set { path: "/hand".to_string(), value: json!([1, 3]) }
+        ]);
+        round_trip(json!({"hand": [1, 2, 3]}), json!({"hand": []}));
+    }
+
+    #[test]
+    fn removed_and_added_keys() {
+        let changes = round_trip(json!({"winner": null, "pending": {"a/b": 1, "c~d": 2}}), json!({"pending": {"c~d": 2, "e": 3}}));
+        assert_eq!(changes, vec![
+            StateChange::Remove { path: "/winner".to_string() },
+            StateChange::Remove { path: "/pending/a~1b".to_string() },
+            StateChange::Set { path: "/pending/e".to_string(), value: json!(3) }
+        ]);
+    }
+
+    #[test]
+    fn states_of_different_types_are_replaced_whole() {
+        assert_eq!(round_trip(json!(null), json!({"turn": 0})), vec![StateChange::Set { path: String::new(), value: json!({"turn": 0}) }]);
+        assert!(round_trip(json!({"turn": 0}), json!({"turn": 0})).is_empty());
+    }
+
+    #[test]
+    fn changes_against_another_version_do_not_apply() {
+        let mut state = json!({"board": [[null, null]]});
+        assert!(!apply(&mut state, &[StateChange::Set { path: "/board/0/5".to_string(), value: json!("X") }]));
+        assert!(!apply(&mut state, &[StateChange::Set { path: "/players/x".to_string(), value: json!("a") }]));
+        assert!(!apply(&mut state, &[StateChange::Remove { path: "/winner".to_string() }]));
+    }
+}
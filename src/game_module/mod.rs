@@ -1,5 +1,9 @@
 use std::any::Any;
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::game_module::delta::StateChange;
+
+pub mod delta;
 
 
 /*
@@ -18,8 +22,26 @@ pub trait GameModule: Send + Sync {
     fn get_game_state(&self) -> &dyn GameState; // Returns an immutable reference of the internal game state.
     fn set_game_state(&mut self, new_state: Box<dyn GameState>); // Set the game state to whatever is passed in. You will have to downcast the trait object into the implementing type
     fn end_condition_met(&self) -> (bool, Option<String>); // Has the game reached a termination state yet? Return whether or not the game has ended, and the ID of the player that one if applicable to the game
-    fn is_valid_move(&self, move_to_test: &Box<dyn GameMove>) -> bool; // Checks if a GameMove is valid in the current game state.
-    fn apply_move(&mut self, move_to_apply: &Box<dyn GameMove>); // Applies a given move to the GameState. Not necessary to check if move is valid in this function, but it is recommended.
+    fn is_valid_move(&self, move_to_test: &dyn GameMove) -> bool; // Checks if a GameMove is valid in the current game state.
+    fn apply_move(&mut self, move_to_apply: &dyn GameMove); // Applies a given move to the GameState. Not necessary to check if move is valid in this function, but it is recommended.
+
+    // Checks if a player may make a GameMove in the current game state. This is what the server validates moves with.
+    // Defaults to is_valid_move, which can't tell who sent the move, so games with turns should override it to check the player's turn.
+    fn is_valid_move_by(&self, _player_id: &str, move_to_test: &dyn GameMove) -> bool {
+        self.is_valid_move(move_to_test)
+    }
+
+    // The game state as a player is allowed to see it. This is what the player is sent, e.g. without other players' hidden cards.
+    // Every player sees the full state by default.
+    fn get_player_game_state(&self, _player_id: &str) -> Box<dyn GameState> {
+        self.get_game_state().clone()
+    }
+
+    // Changes between two serialized game states, sent to players that take delta updates instead of the full state.
+    // The generic JSON diff works for any game. Override it if the game can describe what a move changed more compactly.
+    fn diff_game_state(&self, previous: &Value, current: &Value) -> Vec<StateChange> {
+        delta::diff(previous, current)
+    }
 }
 
 // Functions to implement for game state objects. Also must be serializable and deserializable.
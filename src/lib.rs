@@ -1,10 +1,30 @@
 pub use client::GameProtocolClient;
 pub use server::GameProtocolServer;
-pub use enums::ProtocolState;
+pub use server::ratings::RatingSystem;
+pub use server::config::{RateLimit, ServerConfig, ServerConfigBuilder};
+pub use server::shutdown::{ServerHandle, ShutdownHandle};
+pub use server::events::ServerEventHandler;
+pub use server::admission::{AdmissionContext, AdmissionPolicy, DefaultAdmissionPolicy};
+pub use server::admin::{send_admin_command, send_admin_command_tls, AdminCommand, AdminRequest, AdminResponse};
+pub use enums::{ConfigError, Feature, ProtocolState, StatusCode, StorageError, TlsError};
+pub use common_message_utils::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+pub use codec::Codec;
+pub use compression::Compression;
+pub use transport::{Connection, Listener};
+pub use transport::tls::{ClientTls, ServerTls};
+pub use transport::loopback::{LoopbackConnection, LoopbackConnector, LoopbackListener};
+pub use transport::websocket::{WebSocketConnection, WebSocketListener};
+#[cfg(unix)]
+pub use transport::unix::UnixSocketListener;
+pub use shared_data::{ChatMessage, FriendInfo, FriendStatus, Lobby, LobbyInvite, PlayerProfile, PlayerRating, Presence, PresenceStatus, TournamentFormat, TournamentInfo, TournamentMatch, TournamentStanding, TournamentStatus};
 pub mod game_module;
+pub mod storage;
 
 mod enums;
+mod codec;
+mod compression;
 mod client;
 mod server;
 mod common_message_utils;
+mod transport;
 mod shared_data;
\ No newline at end of file
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::ops::DerefMut;
+use std::sync::{Mutex, OnceLock};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use rand_core::OsRng;
+use tracing::{error, info};
+use uuid::Uuid;
+use crate::enums::{MessageType, StatusCode};
+use crate::server::friends::load_friends;
+use crate::server::server_message_utils::{build_empty_response, build_lobby_info_response, build_profile_response};
+use crate::server::{ban_active, unix_time, ClientSocket, GameProtocolServerState, SocketSend};
+use crate::shared_data::{LoginRequest, PlayerProfile, RegisterRequest, UpdateProfileRequest};
+use crate::storage::AccountRecord;
+
+/*
+    Player accounts. Every connection starts out as a guest with a generated display name. Registering or logging in ties the
+    connection to an account, which gives the player a stable account ID that outlives the connection, and a profile that is kept in storage.
+    The connection keeps its client ID, which is still what identifies the player in lobbies and game sessions.
+    Ratings of logged in players are kept under their account ID so they carry over between connections.
+    Passwords are hashed with Argon2 and only the hash is stored. Hashing is slow on purpose, so it is done without holding the server state lock.
+ */
+
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 32;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+const MAX_AVATAR_URL_LENGTH: usize = 512;
+const MAX_METADATA_ENTRIES: usize = 16;
+const MAX_METADATA_KEY_LENGTH: usize = 32;
+const MAX_METADATA_VALUE_LENGTH: usize = 256;
+
+// Register and login requests, which are answered after the server state lock is released.
+pub enum AccountRequest {
+    Register(RegisterRequest),
+    Login(LoginRequest)
+}
+
+// Answer a register or login request with the client's new profile. Logged in clients are also sent their friends list.
+pub fn answer_account_request(state: &Mutex<GameProtocolServerState>, socket: &ClientSocket, client_id: &str, request: AccountRequest) {
+    let (result, logging_in) = match request {
+        AccountRequest::Register(req) => (register(state, client_id, req), false),
+        AccountRequest::Login(req) => (login(state, client_id, req), true)
+    };
+    match result {
+        Ok(profile) => {
+            socket.send_message(build_profile_response(socket.codec(), StatusCode::Success, profile));
+            if logging_in {
+                load_friends(state.lock().unwrap().deref_mut(), client_id);
+            }
+        }
+        Err(status) => socket.send_message(build_empty_response(status, MessageType::ProfileResponse))
+    }
+}
+
+// Create an account and log the client in to it. Usernames are unique, and the client must still be a guest.
+fn register(state: &Mutex<GameProtocolServerState>, client_id: &str, req: RegisterRequest) -> Result<PlayerProfile, StatusCode> {
+    let display_name = req.display_name.trim().to_string();
+    if !valid_username(&req.username) || req.password.chars().count() < MIN_PASSWORD_LENGTH || !valid_display_name(&display_name) {
+        return Err(StatusCode::InvalidProfile);
+    }
+    check_registration(&state.lock().unwrap(), client_id, &req.username)?;
+
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = match Argon2::default().hash_password(req.password.as_bytes(), &salt) {
+        Ok(hash) => hash.to_string(),
+        Err(e) => {
+            error!(error = %e, "Password hashing error");
+            return Err(StatusCode::UnexpectedError);
+        }
+    };
+
+    // The username could have been taken, or the client logged in, while the password was hashed
+    let mut state_lock = state.lock().unwrap();
+    let state = state_lock.deref_mut();
+    check_registration(state, client_id, &req.username)?;
+
+    // Account IDs are never reused, even if they are only checked against storage
+    let mut account_id = Uuid::new_v4().to_string();
+    while matches!(state.storage.load_account(&account_id), Ok(Some(_))) {
+        account_id = Uuid::new_v4().to_string();
+    }
+    let account = AccountRecord {
+        account_id,
+        username: req.username,
+        password_hash,
+        display_name,
+        created_at: unix_time(),
+        avatar_url: None,
+        metadata: HashMap::new()
+    };
+    if let Err(e) = state.storage.save_account(&account) {
+        error!(error = ?e, "Storage error saving account");
+        return Err(StatusCode::UnexpectedError);
+    }
+    info!(account_id = %account.account_id, username = %account.username, "Account registered");
+    Ok(set_profile(state, client_id, profile_from_account(client_id, &account)))
+}
+
+// A hash to check passwords against when the username doesn't exist, so unknown usernames take as long to reject as wrong passwords.
+fn dummy_password_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(b"not a real password", &salt).map(|hash| hash.to_string()).unwrap_or_default()
+    })
+}
+
+// Log the client in to an existing account. An account can only be logged in on one connection at a time, and banned accounts can't log in.
+fn login(state: &Mutex<GameProtocolServerState>, client_id: &str, req: LoginRequest) -> Result<PlayerProfile, StatusCode> {
+    let account = {
+        let state = state.lock().unwrap();
+        check_guest(&state, client_id)?;
+        match state.storage.find_account_by_username(&req.username) {
+            Ok(Some(account)) => Some(account),
+            Ok(None) => None,
+            Err(e) => {
+                error!(error = ?e, "Storage error looking up account");
+                return Err(StatusCode::UnexpectedError);
+            }
+        }
+    };
+    let password_hash = match &account {
+        Some(account) => account.password_hash.as_str(),
+        None => dummy_password_hash()
+    };
+    let verified = PasswordHash::new(password_hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(req.password.as_bytes(), &hash).is_ok());
+    let account = match account {
+        Some(account) if verified => account,
+        _ => {
+            info!(username = %req.username, "Failed login");
+            return Err(StatusCode::InvalidCredentials);
+        }
+    };
+
+    let mut state_lock = state.lock().unwrap();
+    let state = state_lock.deref_mut();
+    check_guest(state, client_id)?;
+    if ban_active(&state.bans, &account.account_id) {
+        info!(account_id = %account.account_id, "Banned account turned away");
+        return Err(StatusCode::Banned);
+    }
+    if state.clients.values().any(|client| client.profile.account_id.as_ref() == Some(&account.account_id)) {
+        return Err(StatusCode::AlreadyLoggedIn);
+    }
+    info!(account_id = %account.account_id, "Client logged in");
+    Ok(set_profile(state, client_id, profile_from_account(client_id, &account)))
+}
+
+// Change the client's display name, avatar or metadata. Changes to a logged in client's profile are saved to its account.
+pub fn update_profile(state: &mut GameProtocolServerState, client_id: &str, req: UpdateProfileRequest) -> Result<PlayerProfile, StatusCode> {
+    let mut profile = state.clients.get(client_id).unwrap().profile.clone();
+    if let Some(display_name) = req.display_name {
+        profile.display_name = display_name.trim().to_string();
+    }
+    if let Some(avatar_url) = req.avatar_url {
+        profile.avatar_url = if avatar_url.is_empty() { None } else { Some(avatar_url) };
+    }
+    if let Some(metadata) = req.metadata {
+        profile.metadata = metadata;
+    }
+    if !valid_display_name(&profile.display_name) || !valid_avatar_url(profile.avatar_url.as_deref()) || !valid_metadata(&profile.metadata) {
+        return Err(StatusCode::InvalidProfile);
+    }
+
+    if let Some(account_id) = &profile.account_id {
+        let mut account = match state.storage.load_account(account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => {
+                error!(%account_id, "Logged in account is missing from storage");
+                return Err(StatusCode::UnexpectedError);
+            }
+            Err(e) => {
+                error!(error = ?e, "Storage error loading account");
+                return Err(StatusCode::UnexpectedError);
+            }
+        };
+        account.display_name = profile.display_name.clone();
+        account.avatar_url = profile.avatar_url.clone();
+        account.metadata = profile.metadata.clone();
+        if let Err(e) = state.storage.save_account(&account) {
+            error!(error = ?e, "Storage error saving account");
+            return Err(StatusCode::UnexpectedError);
+        }
+    }
+    Ok(set_profile(state, client_id, profile))
+}
+
+// Only guests can register or log in. The client may also have been kicked while its password was being checked.
+fn check_guest(state: &GameProtocolServerState, client_id: &str) -> Result<(), StatusCode> {
+    match state.clients.get(client_id) {
+        Some(client) if client.profile.account_id.is_none() => Ok(()),
+        Some(_) => Err(StatusCode::AlreadyLoggedIn),
+        None => Err(StatusCode::NoActiveSession)
+    }
+}
+
+// A guest can register a username no account has yet.
+fn check_registration(state: &GameProtocolServerState, client_id: &str, username: &str) -> Result<(), StatusCode> {
+    check_guest(state, client_id)?;
+    match state.storage.find_account_by_username(username) {
+        Ok(Some(_)) => Err(StatusCode::UsernameTaken),
+        Ok(None) => Ok(()),
+        Err(e) => {
+            error!(error = ?e, "Storage error looking up account");
+            Err(StatusCode::UnexpectedError)
+        }
+    }
+}
+
+// Profile of a client logged in to an account.
+fn profile_from_account(client_id: &str, account: &AccountRecord) -> PlayerProfile {
+    PlayerProfile {
+        player_id: client_id.to_string(),
+        account_id: Some(account.account_id.clone()),
+        display_name: account.display_name.clone(),
+        avatar_url: account.avatar_url.clone(),
+        metadata: account.metadata.clone()
+    }
+}
+
+// Give a client a new profile. If it is in a lobby, the lobby's copy is updated and the other players are sent the updated lobby info.
+// The client itself is answered with its profile, so it isn't sent the lobby info too.
+fn set_profile(state: &mut GameProtocolServerState, client_id: &str, profile: PlayerProfile) -> PlayerProfile {
+    let client = state.clients.get_mut(client_id).unwrap();
+    client.profile = profile.clone();
+    if let Some(lobby) = client.lobby_id.as_ref().and_then(|lobby_id| state.lobbies.get_mut(lobby_id)) {
+        if let Some(seat) = lobby.players.iter_mut().find(|seat| seat.player_id == client_id) {
+            *seat = profile.clone();
+        }
+        for id in lobby.player_ids.iter().filter(|id| *id != client_id) {
+            if let Some(other) = state.clients.get(id) {
+                other.socket.send_message(build_lobby_info_response(other.socket.codec(), StatusCode::Success, lobby.clone()));
+            }
+        }
+    }
+    profile
+}
+
+// Usernames are used to log in, so they are limited to characters that are easy to type.
+fn valid_username(username: &str) -> bool {
+    (MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&username.chars().count()) &&
+        username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+fn valid_display_name(display_name: &str) -> bool {
+    let length = display_name.chars().count();
+    length > 0 && length <= MAX_DISPLAY_NAME_LENGTH && !display_name.chars().any(char::is_control)
+}
+
+fn valid_avatar_url(avatar_url: Option<&str>) -> bool {
+    avatar_url.is_none_or(|url| url.len() <= MAX_AVATAR_URL_LENGTH && (url.starts_with("https://") || url.starts_with("http://")))
+}
+
+fn valid_metadata(metadata: &HashMap<String, String>) -> bool {
+    metadata.len() <= MAX_METADATA_ENTRIES &&
+        metadata.iter().all(|(key, value)| !key.is_empty() && key.len() <= MAX_METADATA_KEY_LENGTH && value.len() <= MAX_METADATA_VALUE_LENGTH)
+}
@@ -0,0 +1,395 @@
+use std::io::{self, BufRead, BufReader, ErrorKind};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+use crate::enums::{MessageType, StatusCode};
+use crate::server::config::ServerConfig;
+use crate::server::server_message_utils::{build_empty_response, build_lobby_info_response, build_unsolicited_message};
+use crate::server::shutdown::ShutdownHandle;
+use crate::server::chat::moderation_targets;
+use crate::server::friends::update_presence;
+use crate::server::{ban_active, forfeit_tournament_matches, leave_lobby, secrets_match, send_to_players, unix_time, GameProtocolServerState, SocketSend};
+use crate::shared_data::{Lobby, TournamentStatus};
+use crate::storage::BanRecord;
+use crate::transport::tls::ClientTls;
+use crate::transport::{Connection, Listener};
+
+/*
+    Admin channel for inspecting and managing a running server. Enabled by setting an admin port and token in the config.
+    Anyone who can reach it can try tokens against it, and a valid token can kick, ban and shut out every player, so it listens on
+    loopback by default. Never expose it to the internet. Reach it from elsewhere through an SSH tunnel or a private network.
+    If the server has TLS set up, the admin channel is served over TLS with the same certificate.
+    Each request is a single line of JSON holding the admin token and a command, and is answered with a single line of JSON. For example:
+
+        {"token": "secret", "command": "kick", "client_id": "..."}
+        {"ok": true, "data": null}
+
+    send_admin_command and send_admin_command_tls send a command and wait for the response. The example admin CLI is built on them.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    ListClients, // Every connected client's ID, address and lobby
+    ListLobbies, // Every lobby
+    GetGameState { lobby_id: String }, // The state of the game running in a lobby as JSON
+    Kick { client_id: String }, // Disconnect a client
+    Ban { client_id: String, reason: String, duration_secs: Option<u64> }, // Ban a client's IP address and disconnect it. Permanent if no duration is given.
+    BanAccount { account_id: String, reason: String, duration_secs: Option<u64> }, // Ban an account and disconnect any client logged in to it. Permanent if no duration is given.
+    Unban { target: String }, // Lift the ban on an IP address or account ID
+    ListBans, // Every ban that hasn't expired
+    Mute { client_id: String, reason: String, duration_secs: Option<u64> }, // Stop a client sending chat messages, by account if it is logged in and by IP address
+    Unmute { target: String }, // Lift the mute on an account ID or IP address
+    CloseLobby { lobby_id: String }, // Remove every player from a lobby, which removes the lobby
+    EndGame { lobby_id: String }, // Stop the game running in a lobby without recording a result and send its players back to the lobby
+    Broadcast { message: String }, // Send every client an unsolicited message
+    ReloadConfig // Reload the config file the server was created from
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdminRequest {
+    pub token: String,
+    #[serde(flatten)]
+    pub command: AdminCommand
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdminResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>, // Why the command failed, if it did
+    #[serde(default)]
+    pub data: Value // What the command returned. Null for commands that only do something.
+}
+
+impl AdminResponse {
+    fn from_result(result: Result<Value, String>) -> Self {
+        match result {
+            Ok(data) => Self { ok: true, error: None, data },
+            Err(e) => Self { ok: false, error: Some(e), data: Value::Null }
+        }
+    }
+}
+
+// Send a command to a server's admin port and wait for its response.
+pub fn send_admin_command(address: &str, token: &str, command: AdminCommand) -> io::Result<AdminResponse> {
+    let stream = TcpStream::connect(address)?;
+    exchange(&stream, token, command)
+}
+
+// Send a command to the admin port of a server with TLS set up and wait for its response.
+// The certificate is checked against the host part of the address unless the ClientTls was given a server name.
+pub fn send_admin_command_tls(address: &str, tls: &ClientTls, token: &str, command: AdminCommand) -> io::Result<AdminResponse> {
+    let host = address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address);
+    let stream = tls.connect(TcpStream::connect(address)?, host.trim_start_matches('[').trim_end_matches(']'))?;
+    stream.handshake()?;
+    exchange(&stream, token, command)
+}
+
+// Send one request line over an admin connection and read the response line.
+fn exchange(connection: &dyn Connection, token: &str, command: AdminCommand) -> io::Result<AdminResponse> {
+    let request = AdminRequest { token: token.to_string(), command };
+    let mut line = serde_json::to_string(&request).map_err(io::Error::other)?;
+    line.push('\n');
+    connection.write_all(line.as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(connection).read_line(&mut response)?;
+    serde_json::from_str(&response).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+// Spawn a thread that accepts admin connections on the listener until the server shuts down. Each connection gets its own thread.
+pub(super) fn serve_admin(listener: Box<dyn Listener>, state: Arc<Mutex<GameProtocolServerState>>, shutdown: ShutdownHandle) {
+    thread::spawn(move || {
+        if let Err(e) = listener.start() {
+            error!(error = %e, "Admin listener start error");
+            return;
+        }
+        while !shutdown.is_shutdown_requested() {
+            match listener.accept() {
+                Ok(Some(connection)) => {
+                    let state_clone = state.clone();
+                    thread::spawn(move || {
+                        let peer = connection.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+                        if let Err(e) = answer_requests(connection.as_ref(), &state_clone) {
+                            warn!(error = %e, %peer, "Admin connection error");
+                        }
+                    });
+                }
+                Ok(None) => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => {
+                    warn!(error = %e, "Admin incoming stream error");
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+        }
+    });
+}
+
+// Number of requests with the wrong token an admin connection may send before it's closed.
+const MAX_TOKEN_FAILURES: u32 = 3;
+
+// Answer each request line on an admin connection until it closes. Requests with the wrong token are refused,
+// and the connection is closed after MAX_TOKEN_FAILURES of them.
+fn answer_requests(connection: &dyn Connection, state: &Arc<Mutex<GameProtocolServerState>>) -> io::Result<()> {
+    connection.handshake()?;
+    connection.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut token_failures = 0;
+    for line in BufReader::new(connection).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(request) => {
+                // Only copy the token under the lock, so wrong guesses don't hold up the game
+                let admin_token = state.lock().unwrap().config.admin_token.clone();
+                if admin_token.is_some_and(|expected| secrets_match(&expected, &request.token)) {
+                    info!(command = ?request.command, "Admin command");
+                    run_command(&mut state.lock().unwrap(), request.command)
+                } else {
+                    token_failures += 1;
+                    warn!(token_failures, "Admin request with an invalid token");
+                    Err("Invalid admin token".to_string())
+                }
+            }
+            Err(e) => Err(format!("Could not parse admin request. {}", e))
+        };
+        let mut response = serde_json::to_string(&AdminResponse::from_result(result)).map_err(io::Error::other)?;
+        response.push('\n');
+        connection.write_all(response.as_bytes())?;
+        if token_failures >= MAX_TOKEN_FAILURES {
+            warn!("Closing admin connection after repeated invalid tokens");
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn run_command(state: &mut GameProtocolServerState, command: AdminCommand) -> Result<Value, String> {
+    match command {
+        AdminCommand::ListClients => {
+            let clients: Vec<Value> = state.clients.values().map(|client| json!({
+                "client_id": client.id,
+                "account_id": client.profile.account_id,
+                "display_name": client.profile.display_name,
+                "address": client.socket.peer_addr().map(|address| address.to_string()).ok(),
+                "lobby_id": client.lobby_id
+            })).collect();
+            Ok(Value::Array(clients))
+        }
+        AdminCommand::ListLobbies => {
+            let lobbies: Vec<&Lobby> = state.lobbies.values().collect();
+            serde_json::to_value(lobbies).map_err(|e| e.to_string())
+        }
+        AdminCommand::GetGameState { lobby_id } => {
+            match state.games_in_progress.get(&lobby_id) {
+                Some(game) => serde_json::to_value(game.get_game_state()).map_err(|e| e.to_string()),
+                None => Err(format!("No game is running in lobby {}", lobby_id))
+            }
+        }
+        AdminCommand::Kick { client_id } => {
+            disconnect_client(state, &client_id, "You have been disconnected by an administrator.")?;
+            Ok(Value::Null)
+        }
+        AdminCommand::Ban { client_id, reason, duration_secs } => {
+            // Client IDs change with every connection, so the ban is on the address the client connects from
+            let address = match state.clients.get(&client_id).map(|client| client.socket.peer_addr()) {
+                Some(Ok(address)) => address.ip().to_string(),
+                Some(Err(e)) => return Err(format!("Could not get the address of client {}. {}", client_id, e)),
+                None => return Err(format!("Client {} is not connected", client_id))
+            };
+            let ban = BanRecord {
+                target: address.clone(),
+                reason: reason.clone(),
+                expires_at: duration_secs.map(|duration| unix_time() + duration)
+            };
+            state.storage.save_ban(&ban).map_err(|e| format!("Could not save ban. {:?}", e))?;
+            state.bans.insert(address.clone(), ban);
+            disconnect_client(state, &client_id, &format!("You have been banned. {}", reason))?;
+            Ok(json!({ "banned": address }))
+        }
+        AdminCommand::BanAccount { account_id, reason, duration_secs } => {
+            match state.storage.load_account(&account_id) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(format!("Account {} not found", account_id)),
+                Err(e) => return Err(format!("Could not load account. {:?}", e))
+            }
+            let ban = BanRecord {
+                target: account_id.clone(),
+                reason: reason.clone(),
+                expires_at: duration_secs.map(|duration| unix_time() + duration)
+            };
+            state.storage.save_ban(&ban).map_err(|e| format!("Could not save ban. {:?}", e))?;
+            state.bans.insert(account_id.clone(), ban);
+            let logged_in = state.clients.values().find(|client| client.profile.account_id.as_ref() == Some(&account_id)).map(|client| client.id.clone());
+            if let Some(client_id) = logged_in {
+                disconnect_client(state, &client_id, &format!("You have been banned. {}", reason))?;
+            }
+            Ok(json!({ "banned": account_id }))
+        }
+        AdminCommand::Unban { target } => {
+            if state.bans.remove(&target).is_none() {
+                return Err(format!("{} is not banned", target));
+            }
+            state.storage.remove_ban(&target).map_err(|e| format!("Could not remove ban. {:?}", e))?;
+            Ok(Value::Null)
+        }
+        AdminCommand::ListBans => {
+            let bans: Vec<&BanRecord> = state.bans.values().filter(|ban| ban_active(&state.bans, &ban.target)).collect();
+            serde_json::to_value(bans).map_err(|e| e.to_string())
+        }
+        AdminCommand::Mute { client_id, reason, duration_secs } => {
+            let client = state.clients.get(&client_id).ok_or(format!("Client {} is not connected", client_id))?;
+            let targets = moderation_targets(client);
+            if targets.is_empty() {
+                return Err(format!("Could not get the address of client {}", client_id));
+            }
+            client.socket.send_message(build_unsolicited_message(client.socket.codec(), StatusCode::Muted, &format!("You have been muted. {}", reason)));
+            let expires_at = duration_secs.map(|duration| unix_time() + duration);
+            for target in targets.iter() {
+                state.mutes.insert(target.clone(), BanRecord {
+                    target: target.clone(),
+                    reason: reason.clone(),
+                    expires_at
+                });
+            }
+            Ok(json!({ "muted": targets }))
+        }
+        AdminCommand::Unmute { target } => {
+            match state.mutes.remove(&target) {
+                Some(_) => Ok(Value::Null),
+                None => Err(format!("{} is not muted", target))
+            }
+        }
+        AdminCommand::CloseLobby { lobby_id } => {
+            let player_ids = match state.lobbies.get(&lobby_id) {
+                Some(lobby) => lobby.player_ids.clone(),
+                None => return Err(format!("Lobby {} not found", lobby_id))
+            };
+            for id in player_ids.iter() {
+                if state.clients.contains_key(id) {
+                    leave_lobby(state, id);
+                    let client = state.clients.get(id).unwrap();
+                    client.socket.send_message(build_unsolicited_message(client.socket.codec(), StatusCode::Success, "The lobby was closed by an administrator."));
+                    client.socket.send_message(build_empty_response(StatusCode::Success, MessageType::LeaveLobbyResponse));
+                    forfeit_tournament_matches(state, id);
+                }
+            }
+
+            // Players of a restored snapshot that haven't reconnected are still seated, which keeps the lobby and its game around
+            state.lobbies.remove(&lobby_id);
+            state.games_in_progress.remove(&lobby_id);
+            state.game_records.remove(&lobby_id);
+            state.game_state_versions.remove(&lobby_id);
+            Ok(Value::Null)
+        }
+        AdminCommand::EndGame { lobby_id } => {
+            let lobby = match state.lobbies.get_mut(&lobby_id) {
+                Some(lobby) if state.games_in_progress.contains_key(&lobby_id) => lobby,
+                _ => return Err(format!("No game is running in lobby {}", lobby_id))
+            };
+            if lobby.tournament_id.is_some() {
+                return Err("The game is a tournament match. Close the lobby instead so its players forfeit.".to_string());
+            }
+            state.games_in_progress.remove(&lobby_id);
+            state.game_records.remove(&lobby_id);
+            state.game_state_versions.remove(&lobby_id);
+            lobby.game_started = false;
+            send_to_players(&state.clients, &lobby.player_ids, |codec| build_unsolicited_message(codec, StatusCode::Success, "The game was ended by an administrator."));
+            send_to_players(&state.clients, &lobby.player_ids, |codec| build_lobby_info_response(codec, StatusCode::Success, lobby.clone()));
+            for id in lobby.player_ids.clone() {
+                update_presence(state, &id);
+            }
+            Ok(Value::Null)
+        }
+        AdminCommand::Broadcast { message } => {
+            for client in state.clients.values() {
+                client.socket.send_message(build_unsolicited_message(client.socket.codec(), StatusCode::Success, &message));
+            }
+            Ok(json!({ "sent_to": state.clients.len() }))
+        }
+        AdminCommand::ReloadConfig => reload_config(state)
+    }
+}
+
+// Tell a client why it is being disconnected, then close its socket. Its thread cleans up after it like any other disconnect.
+fn disconnect_client(state: &GameProtocolServerState, client_id: &str, reason: &str) -> Result<(), String> {
+    match state.clients.get(client_id) {
+        Some(client) => {
+            client.socket.send_message(build_unsolicited_message(client.socket.codec(), StatusCode::Success, reason));
+            let _ = client.socket.shutdown();
+            Ok(())
+        }
+        None => Err(format!("Client {} is not connected", client_id))
+    }
+}
+
+// Load the config file again and apply it. Settings the server only reads when it starts keep their current values and are listed in the response.
+// Connection settings like the idle timeout and max message size apply to clients that connect after the reload.
+// A reload can't stop allowing a game type that open lobbies or unfinished tournaments use.
+fn reload_config(state: &mut GameProtocolServerState) -> Result<Value, String> {
+    let path = state.config_path.clone().ok_or("The server was not created from a config file")?;
+    let mut config = ServerConfig::from_toml_file(&path).map_err(|e| e.to_string())?;
+    if let Some(allowed) = &config.allowed_game_types {
+        if let Some(missing) = allowed.iter().find(|id| !state.supported_games.contains_key(*id)) {
+            return Err(format!("Allowed game type \"{}\" has not been registered with the server", missing));
+        }
+
+        // Open lobbies and unfinished tournaments keep using their game type, so it can't be taken away from under them
+        let lobby_types = state.lobbies.values().map(|lobby| lobby.game_metadata.get_game_type_id());
+        let tournament_types = state.tournaments.values()
+            .filter(|tournament| !matches!(tournament.info.status, TournamentStatus::Finished))
+            .map(|tournament| tournament.info.game_type_id.clone());
+        if let Some(in_use) = lobby_types.chain(tournament_types).find(|id| !allowed.contains(id)) {
+            return Err(format!("Game type \"{}\" still has open lobbies or tournaments. Close them before removing it from allowed_game_types", in_use));
+        }
+    }
+
+    let current = &state.config;
+    let mut restart_required = vec![];
+    if config.ip != current.ip || config.port != current.port {
+        restart_required.push("ip and port");
+    }
+    if config.tcp_enabled != current.tcp_enabled {
+        restart_required.push("tcp_enabled");
+    }
+    if config.unix_socket_path != current.unix_socket_path {
+        restart_required.push("unix_socket_path");
+    }
+    if config.websocket_port != current.websocket_port {
+        restart_required.push("websocket_port");
+    }
+    if config.tls_cert_file != current.tls_cert_file || config.tls_key_file != current.tls_key_file {
+        restart_required.push("tls_cert_file and tls_key_file");
+    }
+    if config.heartbeat_interval != current.heartbeat_interval {
+        restart_required.push("heartbeat_interval");
+    }
+    if config.metrics_port != current.metrics_port {
+        restart_required.push("metrics_port");
+    }
+    if config.admin_ip != current.admin_ip || config.admin_port != current.admin_port {
+        restart_required.push("admin_ip and admin_port");
+    }
+    config.ip = current.ip.clone();
+    config.port = current.port;
+    config.tcp_enabled = current.tcp_enabled;
+    config.unix_socket_path = current.unix_socket_path.clone();
+    config.websocket_port = current.websocket_port;
+    config.tls_cert_file = current.tls_cert_file.clone();
+    config.tls_key_file = current.tls_key_file.clone();
+    config.heartbeat_interval = current.heartbeat_interval;
+    config.metrics_port = current.metrics_port;
+    config.admin_ip = current.admin_ip.clone();
+    config.admin_port = current.admin_port;
+    state.config = config;
+    info!(%path, "Config reloaded");
+    Ok(json!({ "restart_required": restart_required }))
+}
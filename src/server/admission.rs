@@ -0,0 +1,93 @@
+use std::net::SocketAddr;
+use crate::enums::StatusCode;
+use crate::game_module::GameMove;
+use crate::server::GameProtocolServerState;
+use crate::shared_data::{Lobby, PlayerProfile, PlayerRating};
+
+/*
+    Rules for who may create and join lobbies, start games and make moves. The server consults its admission policy
+    before each of these and sends the client the StatusCode the policy rejects with. Set one with GameProtocolServer::set_admission_policy.
+    The trait's default methods are the server's built-in rules, so a policy only overrides the decisions it changes.
+    A policy that adds to a built-in rule rather than replacing it can call the same method on DefaultAdmissionPolicy first.
+    Checks that keep server state consistent, like a client only being in one lobby at a time, are always made by the server.
+ */
+
+// Trait for admission policies. Must be thread safe since checks are made on every client's thread.
+// Checks run while the server state is locked. Policies that keep their own state need interior mutability.
+pub trait AdmissionPolicy: Send {
+    // Whether a client may create a lobby for a game type. Also checked when matchmaking would create one.
+    fn check_create_lobby(&self, _context: &AdmissionContext, _client_id: &str, _game_type_id: &str) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    // Whether a client may join a lobby. Matchmaking only considers lobbies this admits. By default a lobby can be joined until it is full or its game has started.
+    fn check_join_lobby(&self, _context: &AdmissionContext, _client_id: &str, lobby: &Lobby) -> Result<(), StatusCode> {
+        if lobby.game_started {
+            Err(StatusCode::GameStarted)
+        } else if lobby.is_full() {
+            Err(StatusCode::LobbyFull)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Whether a client may start the game in its lobby. By default only the owner can, once the lobby has enough players for the game.
+    // Only asked about lobbies without a running game that aren't hosting a tournament match, since the server turns those away itself.
+    fn check_start_game(&self, _context: &AdmissionContext, client_id: &str, lobby: &Lobby) -> Result<(), StatusCode> {
+        let player_req_met =
+            lobby.player_ids.len() >= lobby.game_metadata.min_required_players &&
+                lobby.player_ids.len() <= lobby.game_metadata.max_players;
+        if lobby.owner.eq(client_id) && player_req_met {
+            Ok(())
+        } else {
+            Err(StatusCode::GameStartCriteriaNotMet)
+        }
+    }
+
+    // Whether a client may make a move in its lobby's game. Checked before the game module validates the move itself.
+    fn check_move(&self, _context: &AdmissionContext, _client_id: &str, _lobby: &Lobby, _game_move: &dyn GameMove) -> Result<(), StatusCode> {
+        Ok(())
+    }
+}
+
+// The server's built-in rules. Used until another policy is set.
+pub struct DefaultAdmissionPolicy;
+
+impl AdmissionPolicy for DefaultAdmissionPolicy {}
+
+// Read only view of the server state for policies to base their decisions on.
+pub struct AdmissionContext<'a> {
+    state: &'a GameProtocolServerState
+}
+
+impl<'a> AdmissionContext<'a> {
+    pub(super) fn new(state: &'a GameProtocolServerState) -> Self {
+        Self {
+            state
+        }
+    }
+
+    pub fn get_lobbies(&self) -> impl Iterator<Item = &Lobby> {
+        self.state.lobbies.values()
+    }
+
+    pub fn get_lobby(&self, lobby_id: &str) -> Option<&Lobby> {
+        self.state.lobbies.get(lobby_id)
+    }
+
+    // A player's rating for a game type. Players that haven't played it yet get the starting rating.
+    // Ratings are kept under a profile's rating_id, which is the account ID for logged in players.
+    pub fn get_rating(&self, player_id: &str, game_type_id: &str) -> PlayerRating {
+        self.state.ratings.get(player_id, game_type_id)
+    }
+
+    // Profile of a connected client, including the account it is logged in to if any. None if the client isn't connected.
+    pub fn get_profile(&self, client_id: &str) -> Option<&PlayerProfile> {
+        self.state.clients.get(client_id).map(|client| &client.profile)
+    }
+
+    // Address a connected client is connecting from. None if the client isn't connected.
+    pub fn get_client_address(&self, client_id: &str) -> Option<SocketAddr> {
+        self.state.clients.get(client_id).and_then(|client| client.socket.peer_addr().ok())
+    }
+}
@@ -0,0 +1,43 @@
+use crate::enums::StatusCode;
+use crate::server::server_message_utils::build_chat_message;
+use crate::server::{ban_active, send_to_players, unix_time, Client, GameProtocolServerState};
+use crate::shared_data::ChatMessage;
+
+/*
+    Lobby chat. Messages are sent to everyone in the sender's lobby, including the sender, which is how it knows the message went through.
+    Administrators can mute players, who can still read the chat but not send to it. A logged in player is muted by both account
+    and IP address, and a guest by IP address, so neither reconnecting nor coming back as a guest lifts a mute. Mutes only last while the server runs.
+ */
+
+const MAX_CHAT_MESSAGE_LENGTH: usize = 500;
+
+// Send a chat message from a client to everyone in its lobby.
+pub fn send_chat_message(state: &GameProtocolServerState, client_id: &str, message: &str) -> Result<(), StatusCode> {
+    let client = state.clients.get(client_id).unwrap();
+    let lobby = match client.lobby_id.as_ref().and_then(|lobby_id| state.lobbies.get(lobby_id)) {
+        Some(lobby) => lobby,
+        None => return Err(StatusCode::NotInLobby)
+    };
+    if moderation_targets(client).iter().any(|target| ban_active(&state.mutes, target)) {
+        return Err(StatusCode::Muted);
+    }
+    let message = message.trim();
+    if message.is_empty() || message.chars().count() > MAX_CHAT_MESSAGE_LENGTH || message.chars().any(|c| c.is_control() && c != '\n') {
+        return Err(StatusCode::MalformedBody);
+    }
+
+    let chat_message = ChatMessage {
+        player_id: client_id.to_string(),
+        display_name: client.profile.display_name.clone(),
+        message: message.to_string(),
+        sent_at: unix_time()
+    };
+    send_to_players(&state.clients, &lobby.player_ids, |codec| build_chat_message(codec, &chat_message));
+    Ok(())
+}
+
+// What mutes on a connected client are held against. Its account ID if it is logged in, and its IP address if it has one.
+pub fn moderation_targets(client: &Client) -> Vec<String> {
+    let address = client.socket.peer_addr().ok().map(|address| address.ip().to_string());
+    client.profile.account_id.iter().cloned().chain(address).collect()
+}
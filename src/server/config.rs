@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use serde::{Serialize, Deserialize};
+use crate::common_message_utils::message_type_from_name;
+use crate::enums::{ConfigError, MessageType};
+
+/*
+    Server configuration. Build one in code with ServerConfig::builder(), or load one from a TOML file.
+    Every setting has a default, so a TOML file only needs the settings it changes. For example:
+
+        ip = "0.0.0.0"
+        port = 7878
+        max_clients = 500
+        max_lobbies_per_game_type = 50
+        idle_timeout = 60
+        compression_threshold = 1024
+        unix_socket_path = "/run/game_server.sock"
+        websocket_port = 7879
+        tls_cert_file = "cert.pem"
+        tls_key_file = "key.pem"
+        allowed_game_types = ["Tic-tac-toe v1.0"]
+
+        [rate_limit]
+        burst = 20
+        per_second = 10
+
+        [account_rate_limit]
+        burst = 5
+        per_second = 0.2
+
+        [message_rate_limits.CreateLobbyRequest]
+        burst = 3
+        per_second = 0.2
+
+    Limits that are not set are unlimited, except account_rate_limit, which limits login and register requests by default.
+ */
+
+// A token bucket limit on how often a client can send a type of message. A client can send a burst of messages at once,
+// then has to wait for tokens to come back at the given rate.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub burst: u32, // Most messages that can be sent at once
+    pub per_second: f64 // Rate tokens come back at
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub ip: String, // IP address the server binds to
+    pub port: u16, // Port the server binds to
+    pub tcp_enabled: bool, // Whether the server listens on the IP address and port. Turn off to only accept clients through other listeners, e.g. the Unix socket.
+    pub unix_socket_path: Option<String>, // Path of a Unix domain socket the server also listens on. The file is removed on shutdown. Disabled if None.
+    pub max_clients: Option<usize>, // Most clients that can be connected at once
+    pub max_lobbies: Option<usize>, // Most lobbies that can exist at once
+    pub max_lobbies_per_game_type: Option<usize>, // Most lobbies that can exist at once for a single game type
+    pub max_message_size: usize, // Largest message body in bytes the server accepts. Clients that send larger ones are disconnected.
+    pub read_buffer_size: usize, // Size in bytes of the buffer each client's socket is read through
+    pub compression_threshold: Option<usize>, // Message bodies larger than this many bytes are compressed for clients that ask for compression. Disabled if None.
+    pub heartbeat_interval: Option<u64>, // Seconds between heartbeats sent to every client that agreed on the Heartbeats feature. Clients answer them automatically.
+    pub idle_timeout: Option<u64>, // Seconds without hearing anything from a client before it is disconnected
+    pub allowed_game_types: Option<Vec<String>>, // Game type IDs the server offers. No new lobbies or tournaments are created for registered games not in the list. All registered games if None.
+    pub metrics_port: Option<u16>, // Port an HTTP /metrics endpoint is served on, on the same IP address as the server. Disabled if None.
+    pub websocket_port: Option<u16>, // Port WebSocket clients connect to, on the same IP address as the server. Not covered by TLS. Disabled if None.
+    pub admin_ip: String, // IP address the admin channel listens on. Loopback by default. Never make the admin channel reachable from the internet.
+    pub admin_port: Option<u16>, // Port the admin channel listens on. Served over TLS if the server has TLS set up. Disabled if None.
+    pub admin_token: Option<String>, // Token every admin request must include. Required if the admin port is set.
+    pub rate_limit: Option<RateLimit>, // Limit on each type of message a client sends, applied per connection and per account. Disconnects are never limited.
+    pub account_rate_limit: Option<RateLimit>, // Limit on login and register requests, which are slow to answer and can be used to guess passwords. Used instead of rate_limit.
+    pub message_rate_limits: HashMap<String, RateLimit>, // Limits for single message types, indexed by message type name e.g. "MoveRequest". Used instead of rate_limit.
+    pub rate_limit_strikes: u32, // Messages a client can have rejected for going over a rate limit within a minute before it is disconnected
+    pub max_missing_messages: u32, // Most message IDs a client can skip at once. Clients that skip more, or send an ID from before the expected one, are disconnected.
+    pub tls_cert_file: Option<String>, // PEM certificate chain clients connecting over TLS are shown. Connections are plain TCP if None.
+    pub tls_key_file: Option<String> // PEM private key for the TLS certificate. Required if the certificate file is set.
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            ip: "127.0.0.1".to_string(),
+            port: 7878,
+            tcp_enabled: true,
+            unix_socket_path: None,
+            max_clients: None,
+            max_lobbies: None,
+            max_lobbies_per_game_type: None,
+            max_message_size: 1024 * 1024,
+            read_buffer_size: 4096,
+            compression_threshold: None,
+            heartbeat_interval: None,
+            idle_timeout: None,
+            allowed_game_types: None,
+            metrics_port: None,
+            websocket_port: None,
+            admin_ip: "127.0.0.1".to_string(),
+            admin_port: None,
+            admin_token: None,
+            rate_limit: None,
+            account_rate_limit: Some(RateLimit { burst: 5, per_second: 0.2 }),
+            message_rate_limits: HashMap::new(),
+            rate_limit_strikes: 10,
+            max_missing_messages: 256,
+            tls_cert_file: None,
+            tls_key_file: None
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder {
+            config: ServerConfig::default()
+        }
+    }
+
+    // Load and validate a config from a TOML file.
+    pub fn from_toml_file(path: &str) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::from_toml_str(&contents),
+            Err(e) => Err(ConfigError::ReadError(format!("{}: {}", path, e)))
+        }
+    }
+
+    // Load and validate a config from a string of TOML.
+    pub fn from_toml_str(contents: &str) -> Result<Self, ConfigError> {
+        let config: ServerConfig = toml::from_str(contents).map_err(|e| ConfigError::ParseError(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // The rate limit that applies to a type of message, if any.
+    pub fn rate_limit_for(&self, message_type: MessageType) -> Option<RateLimit> {
+        if matches!(message_type, MessageType::DisconnectRequest) {
+            return None;
+        }
+        if let Some(limit) = self.message_rate_limits.get(&format!("{:?}", message_type)) {
+            return Some(*limit);
+        }
+        if matches!(message_type, MessageType::LoginRequest | MessageType::RegisterRequest) && self.account_rate_limit.is_some() {
+            return self.account_rate_limit;
+        }
+        self.rate_limit
+    }
+
+    // Check that every setting makes sense on its own and alongside the others.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.ip.parse::<IpAddr>().is_err() {
+            return Err(ConfigError::InvalidValue(format!("ip \"{}\" is not a valid IP address", self.ip)));
+        }
+        if self.admin_ip.parse::<IpAddr>().is_err() {
+            return Err(ConfigError::InvalidValue(format!("admin_ip \"{}\" is not a valid IP address", self.admin_ip)));
+        }
+
+        if self.unix_socket_path.as_ref().is_some_and(|path| path.is_empty()) {
+            return Err(ConfigError::InvalidValue("unix_socket_path can't be empty".to_string()));
+        }
+
+        // Limits of zero would make the server unusable, so they are almost certainly mistakes
+        let limits = [
+            ("max_clients", self.max_clients),
+            ("max_lobbies", self.max_lobbies),
+            ("max_lobbies_per_game_type", self.max_lobbies_per_game_type),
+            ("max_message_size", Some(self.max_message_size)),
+            ("read_buffer_size", Some(self.read_buffer_size)),
+            ("heartbeat_interval", self.heartbeat_interval.map(|i| i as usize)),
+            ("idle_timeout", self.idle_timeout.map(|t| t as usize)),
+            ("rate_limit_strikes", Some(self.rate_limit_strikes as usize)),
+            ("max_missing_messages", Some(self.max_missing_messages as usize))
+        ];
+        for (name, limit) in limits {
+            if limit == Some(0) {
+                return Err(ConfigError::InvalidValue(format!("{} must be more than 0", name)));
+            }
+        }
+
+        if let (Some(per_game_type), Some(total)) = (self.max_lobbies_per_game_type, self.max_lobbies) {
+            if per_game_type > total {
+                return Err(ConfigError::InvalidValue(format!("max_lobbies_per_game_type ({}) can't be more than max_lobbies ({})", per_game_type, total)));
+            }
+        }
+
+        // Clients only send something while idle when answering a heartbeat, so they would be dropped between heartbeats
+        if let (Some(interval), Some(timeout)) = (self.heartbeat_interval, self.idle_timeout) {
+            if timeout <= interval {
+                return Err(ConfigError::InvalidValue(format!("idle_timeout ({}) must be longer than heartbeat_interval ({})", timeout, interval)));
+            }
+        }
+
+        if self.metrics_port.is_some_and(|port| port != 0 && port == self.port) {
+            return Err(ConfigError::InvalidValue(format!("metrics_port ({}) must be different from port", self.port)));
+        }
+
+        if let Some(websocket_port) = self.websocket_port.filter(|port| *port != 0) {
+            if websocket_port == self.port || Some(websocket_port) == self.metrics_port || Some(websocket_port) == self.admin_port {
+                return Err(ConfigError::InvalidValue(format!("websocket_port ({}) must be different from port, metrics_port and admin_port", websocket_port)));
+            }
+        }
+
+        if self.admin_port.is_some() && self.admin_token.as_ref().is_none_or(|token| token.is_empty()) {
+            return Err(ConfigError::InvalidValue("admin_token must be set when admin_port is".to_string()));
+        }
+        if let Some(admin_port) = self.admin_port.filter(|port| *port != 0) {
+            if admin_port == self.port || Some(admin_port) == self.metrics_port {
+                return Err(ConfigError::InvalidValue(format!("admin_port ({}) must be different from port and metrics_port", admin_port)));
+            }
+        }
+
+        let rate_limits = self.rate_limit.iter().map(|limit| ("rate_limit".to_string(), limit))
+            .chain(self.account_rate_limit.iter().map(|limit| ("account_rate_limit".to_string(), limit)))
+            .chain(self.message_rate_limits.iter().map(|(name, limit)| (format!("message_rate_limits.{}", name), limit)));
+        for (name, limit) in rate_limits {
+            if limit.burst == 0 || !(limit.per_second.is_finite() && limit.per_second > 0.0) {
+                return Err(ConfigError::InvalidValue(format!("{} must have a burst and per_second of more than 0", name)));
+            }
+        }
+        if let Some(name) = self.message_rate_limits.keys().find(|name| message_type_from_name(name).is_none()) {
+            return Err(ConfigError::InvalidValue(format!("message_rate_limits has a limit for \"{}\", which is not a message type", name)));
+        }
+
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return Err(ConfigError::InvalidValue("tls_cert_file and tls_key_file must be set together".to_string()));
+        }
+
+        if let Some(allowed) = &self.allowed_game_types {
+            if allowed.is_empty() {
+                return Err(ConfigError::InvalidValue("allowed_game_types can't be empty. Leave it out to allow every registered game".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Builds a ServerConfig one setting at a time, starting from the defaults.
+pub struct ServerConfigBuilder {
+    config: ServerConfig
+}
+
+impl ServerConfigBuilder {
+    pub fn bind(mut self, ip: &str, port: u16) -> Self {
+        self.config.ip = ip.to_string();
+        self.config.port = port;
+        self
+    }
+
+    // Don't listen on the IP address and port, e.g. when clients only connect through the Unix socket.
+    pub fn disable_tcp(mut self) -> Self {
+        self.config.tcp_enabled = false;
+        self
+    }
+
+    // Also listen on a Unix domain socket at this path. Clients connect to it with the address "unix:<path>".
+    pub fn unix_socket(mut self, path: &str) -> Self {
+        self.config.unix_socket_path = Some(path.to_string());
+        self
+    }
+
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.config.max_clients = Some(max_clients);
+        self
+    }
+
+    pub fn max_lobbies(mut self, max_lobbies: usize) -> Self {
+        self.config.max_lobbies = Some(max_lobbies);
+        self
+    }
+
+    pub fn max_lobbies_per_game_type(mut self, max_lobbies: usize) -> Self {
+        self.config.max_lobbies_per_game_type = Some(max_lobbies);
+        self
+    }
+
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.config.max_message_size = bytes;
+        self
+    }
+
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.read_buffer_size = bytes;
+        self
+    }
+
+    // Compress message bodies larger than this many bytes, both ways, for clients that ask for compression when connecting.
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.config.compression_threshold = Some(bytes);
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, seconds: u64) -> Self {
+        self.config.heartbeat_interval = Some(seconds);
+        self
+    }
+
+    pub fn idle_timeout(mut self, seconds: u64) -> Self {
+        self.config.idle_timeout = Some(seconds);
+        self
+    }
+
+    pub fn allowed_game_types(mut self, game_type_ids: &[&str]) -> Self {
+        self.config.allowed_game_types = Some(game_type_ids.iter().map(|id| id.to_string()).collect());
+        self
+    }
+
+    pub fn metrics_port(mut self, port: u16) -> Self {
+        self.config.metrics_port = Some(port);
+        self
+    }
+
+    // Also accept clients over WebSocket on this port, e.g. from browsers.
+    pub fn websocket_port(mut self, port: u16) -> Self {
+        self.config.websocket_port = Some(port);
+        self
+    }
+
+    pub fn admin(mut self, port: u16, token: &str) -> Self {
+        self.config.admin_port = Some(port);
+        self.config.admin_token = Some(token.to_string());
+        self
+    }
+
+    // Listen for admin commands on this IP address instead of loopback. Only use an address on a private network.
+    pub fn admin_ip(mut self, ip: &str) -> Self {
+        self.config.admin_ip = ip.to_string();
+        self
+    }
+
+    pub fn rate_limit(mut self, burst: u32, per_second: f64) -> Self {
+        self.config.rate_limit = Some(RateLimit { burst, per_second });
+        self
+    }
+
+    // Limit login and register requests. They are limited to a burst of 5 and one every 5 seconds by default.
+    pub fn account_rate_limit(mut self, burst: u32, per_second: f64) -> Self {
+        self.config.account_rate_limit = Some(RateLimit { burst, per_second });
+        self
+    }
+
+    // Don't limit login and register requests apart from the limit set with rate_limit.
+    pub fn disable_account_rate_limit(mut self) -> Self {
+        self.config.account_rate_limit = None;
+        self
+    }
+
+    // Limit a single type of message, e.g. "CreateLobbyRequest". Used instead of the limit set with rate_limit or account_rate_limit.
+    pub fn message_rate_limit(mut self, message_type: &str, burst: u32, per_second: f64) -> Self {
+        self.config.message_rate_limits.insert(message_type.to_string(), RateLimit { burst, per_second });
+        self
+    }
+
+    pub fn rate_limit_strikes(mut self, strikes: u32) -> Self {
+        self.config.rate_limit_strikes = strikes;
+        self
+    }
+
+    pub fn max_missing_messages(mut self, max_missing_messages: u32) -> Self {
+        self.config.max_missing_messages = max_missing_messages;
+        self
+    }
+
+    // Serve clients over TLS with a PEM certificate chain and private key loaded from files when the server starts.
+    pub fn tls(mut self, cert_file: &str, key_file: &str) -> Self {
+        self.config.tls_cert_file = Some(cert_file.to_string());
+        self.config.tls_key_file = Some(key_file.to_string());
+        self
+    }
+
+    // Validate the settings and return the finished config.
+    pub fn build(self) -> Result<ServerConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The message of the InvalidValue error a TOML config is rejected with.
+    fn invalid_value(contents: &str) -> String {
+        match ServerConfig::from_toml_str(contents) {
+            Err(ConfigError::InvalidValue(message)) => message,
+            other => panic!("expected an invalid value, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn empty_file_gives_the_defaults() {
+        let config = ServerConfig::from_toml_str("").unwrap();
+        assert_eq!(config.ip, "127.0.0.1");
+        assert_eq!(config.port, 7878);
+        assert!(config.tcp_enabled);
+        assert_eq!(config.max_message_size, 1024 * 1024);
+        assert_eq!(config.heartbeat_interval, None);
+        assert_eq!(config.rate_limit, None);
+        assert_eq!(config.account_rate_limit, Some(RateLimit { burst: 5, per_second: 0.2 }));
+        assert_eq!(config.rate_limit_strikes, 10);
+        assert_eq!(config.tls_cert_file, None);
+        assert_eq!(config.rate_limit_for(MessageType::LoginRequest), config.account_rate_limit);
+        assert_eq!(config.rate_limit_for(MessageType::ChatRequest), None);
+    }
+
+    #[test]
+    fn settings_override_the_defaults() {
+        let config = ServerConfig::from_toml_str(r#"
+            port = 9000
+            heartbeat_interval = 10
+            idle_timeout = 30
+
+            [message_rate_limits.ChatRequest]
+            burst = 2
+            per_second = 0.5
+        "#).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.heartbeat_interval, Some(10));
+        assert_eq!(config.idle_timeout, Some(30));
+        assert_eq!(config.rate_limit_for(MessageType::ChatRequest), Some(RateLimit { burst: 2, per_second: 0.5 }));
+        assert_eq!(config.ip, "127.0.0.1");
+    }
+
+    #[test]
+    fn zero_heartbeat_is_rejected() {
+        assert!(invalid_value("heartbeat_interval = 0").contains("heartbeat_interval"));
+    }
+
+    #[test]
+    fn idle_timeout_must_outlast_the_heartbeat() {
+        assert!(invalid_value("heartbeat_interval = 30\nidle_timeout = 30").contains("idle_timeout"));
+    }
+
+    #[test]
+    fn bad_rate_limits_are_rejected() {
+        assert!(invalid_value("[rate_limit]\nburst = 0\nper_second = 1").contains("rate_limit"));
+        assert!(invalid_value("[account_rate_limit]\nburst = 5\nper_second = 0").contains("account_rate_limit"));
+        assert!(invalid_value("[rate_limit]\nburst = 5\nper_second = -1").contains("rate_limit"));
+        assert!(invalid_value("[rate_limit]\nburst = 5\nper_second = inf").contains("rate_limit"));
+        assert!(invalid_value("[message_rate_limits.NotAMessage]\nburst = 1\nper_second = 1").contains("NotAMessage"));
+        assert!(invalid_value("rate_limit_strikes = 0").contains("rate_limit_strikes"));
+    }
+
+    #[test]
+    fn tls_cert_without_key_is_rejected() {
+        assert!(invalid_value(r#"tls_cert_file = "cert.pem""#).contains("tls_key_file"));
+        assert!(invalid_value(r#"tls_key_file = "key.pem""#).contains("tls_cert_file"));
+    }
+
+    #[test]
+    fn unknown_and_mistyped_settings_fail_to_parse() {
+        assert!(matches!(ServerConfig::from_toml_str("prot = 7878"), Err(ConfigError::ParseError(_))));
+        assert!(matches!(ServerConfig::from_toml_str(r#"port = "7878""#), Err(ConfigError::ParseError(_))));
+        assert!(matches!(ServerConfig::from_toml_str("[rate_limit]\nburst = 5"), Err(ConfigError::ParseError(_))));
+    }
+
+    #[test]
+    fn missing_file_is_a_read_error() {
+        assert!(matches!(ServerConfig::from_toml_file("/nonexistent/server.toml"), Err(ConfigError::ReadError(_))));
+    }
+}
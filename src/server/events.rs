@@ -0,0 +1,68 @@
+use crate::game_module::GameMove;
+use crate::shared_data::Lobby;
+
+/*
+    Hooks for applications embedding the server to react to what happens on it, e.g. for analytics, persistence or moderation.
+    Implement ServerEventHandler and register it with GameProtocolServer::add_event_handler. Every callback does nothing by default,
+    so only the ones that are needed have to be implemented.
+    Callbacks run on the thread that caused the event while the server state is locked. They should return quickly,
+    and must not call back into the server or they will deadlock. Hand work off to another thread if it might block.
+ */
+
+// Trait for event handlers. Must be thread safe since events happen on every client's thread.
+pub trait ServerEventHandler: Send {
+    fn on_client_connected(&self, _client_id: &str, _reconnected: bool) {} // A client was given a session. Reconnected if it resumed a session from a snapshot.
+    fn on_client_disconnected(&self, _client_id: &str) {} // A client's session ended, whether it disconnected itself or was dropped.
+    fn on_lobby_created(&self, _lobby: &Lobby) {} // A lobby was created, with its owner as its only player.
+    fn on_player_joined(&self, _lobby_id: &str, _client_id: &str) {} // A client joined an existing lobby.
+    fn on_player_left(&self, _lobby_id: &str, _client_id: &str) {} // A client left a lobby, disconnected, or lost its seat after not reconnecting.
+    fn on_game_started(&self, _lobby_id: &str, _game_type_id: &str, _player_ids: &[String]) {} // A game session started in a lobby.
+    fn on_move_applied(&self, _lobby_id: &str, _client_id: &str, _game_move: &dyn GameMove) {} // A valid move was applied to a lobby's game session.
+    fn on_game_ended(&self, _lobby_id: &str, _winner: Option<&str>) {} // A game session reached its end condition. No winner if it was a draw.
+}
+
+// Every handler registered on a server. Passes each event on to all of them in the order they were added.
+#[derive(Default)]
+pub struct EventHandlers {
+    handlers: Vec<Box<dyn ServerEventHandler>>
+}
+
+impl EventHandlers {
+    pub fn add(&mut self, handler: Box<dyn ServerEventHandler>) {
+        self.handlers.push(handler);
+    }
+}
+
+impl ServerEventHandler for EventHandlers {
+    fn on_client_connected(&self, client_id: &str, reconnected: bool) {
+        self.handlers.iter().for_each(|handler| handler.on_client_connected(client_id, reconnected));
+    }
+
+    fn on_client_disconnected(&self, client_id: &str) {
+        self.handlers.iter().for_each(|handler| handler.on_client_disconnected(client_id));
+    }
+
+    fn on_lobby_created(&self, lobby: &Lobby) {
+        self.handlers.iter().for_each(|handler| handler.on_lobby_created(lobby));
+    }
+
+    fn on_player_joined(&self, lobby_id: &str, client_id: &str) {
+        self.handlers.iter().for_each(|handler| handler.on_player_joined(lobby_id, client_id));
+    }
+
+    fn on_player_left(&self, lobby_id: &str, client_id: &str) {
+        self.handlers.iter().for_each(|handler| handler.on_player_left(lobby_id, client_id));
+    }
+
+    fn on_game_started(&self, lobby_id: &str, game_type_id: &str, player_ids: &[String]) {
+        self.handlers.iter().for_each(|handler| handler.on_game_started(lobby_id, game_type_id, player_ids));
+    }
+
+    fn on_move_applied(&self, lobby_id: &str, client_id: &str, game_move: &dyn GameMove) {
+        self.handlers.iter().for_each(|handler| handler.on_move_applied(lobby_id, client_id, game_move));
+    }
+
+    fn on_game_ended(&self, lobby_id: &str, winner: Option<&str>) {
+        self.handlers.iter().for_each(|handler| handler.on_game_ended(lobby_id, winner));
+    }
+}
@@ -0,0 +1,248 @@
+use tracing::error;
+use crate::enums::StatusCode;
+use crate::server::server_message_utils::{build_friend_list_response, build_lobby_invite, build_presence_update};
+use crate::server::{unix_time, Client, GameProtocolServerState, SocketSend};
+use crate::shared_data::{FriendInfo, FriendStatus, LobbyInvite, Presence, PresenceStatus};
+use crate::storage::FriendshipRecord;
+
+/*
+    Friends and presence for logged in players. Friend requests are sent by username, and friendships are kept in storage under
+    account IDs so they outlive connections. Once a request is accepted, each friend is pushed a PresenceUpdate whenever the other
+    connects, disconnects, or moves between being idle, in a lobby and in a game, and they can invite each other to the lobby they're in.
+    Each connected client keeps the account IDs of its accepted friends so presence can be pushed without going to storage.
+    Friend requests, accepts and removals are answered with the client's updated friend list, and the other player is sent theirs if they're online.
+ */
+
+// Send a friend request to the account with the given username. If that account already asked to be friends, the request is accepted instead.
+pub fn send_friend_request(state: &mut GameProtocolServerState, client_id: &str, username: &str) -> Result<Vec<FriendInfo>, StatusCode> {
+    let account_id = logged_in_account(state, client_id)?;
+    let other_id = match state.storage.find_account_by_username(username) {
+        Ok(Some(account)) if account.account_id != account_id => account.account_id,
+        Ok(_) => return Err(StatusCode::AccountNotFound),
+        Err(e) => {
+            error!(error = ?e, "Storage error looking up account");
+            return Err(StatusCode::UnexpectedError);
+        }
+    };
+
+    match friendship_between(state, &account_id, &other_id)? {
+        Some(friendship) if friendship.accepted || friendship.requester_id == account_id => return Err(StatusCode::AlreadyFriends),
+        Some(friendship) => accept(state, friendship)?,
+        None => {
+            let friendship = FriendshipRecord {
+                requester_id: account_id.clone(),
+                addressee_id: other_id.clone(),
+                accepted: false,
+                created_at: unix_time()
+            };
+            save_friendship(state, &friendship)?;
+        }
+    }
+    send_friend_list(state, &other_id);
+    friend_list(state, &account_id)
+}
+
+// Accept a friend request another account sent to the client's account.
+pub fn accept_friend_request(state: &mut GameProtocolServerState, client_id: &str, other_id: &str) -> Result<Vec<FriendInfo>, StatusCode> {
+    let account_id = logged_in_account(state, client_id)?;
+    match friendship_between(state, &account_id, other_id)? {
+        Some(friendship) if !friendship.accepted && friendship.addressee_id == account_id => accept(state, friendship)?,
+        _ => return Err(StatusCode::NotFriends)
+    }
+    send_friend_list(state, other_id);
+    friend_list(state, &account_id)
+}
+
+// Remove a friend. Also declines a request the other account sent, or withdraws one the client's account sent.
+pub fn remove_friend(state: &mut GameProtocolServerState, client_id: &str, other_id: &str) -> Result<Vec<FriendInfo>, StatusCode> {
+    let account_id = logged_in_account(state, client_id)?;
+    if friendship_between(state, &account_id, other_id)?.is_none() {
+        return Err(StatusCode::NotFriends);
+    }
+    if let Err(e) = state.storage.remove_friendship(&account_id, other_id) {
+        error!(error = ?e, "Storage error removing friendship");
+        return Err(StatusCode::UnexpectedError);
+    }
+    for client in state.clients.values_mut() {
+        match client.profile.account_id.as_deref() {
+            Some(id) if id == account_id => client.friend_ids.retain(|id| id != other_id),
+            Some(id) if id == other_id => client.friend_ids.retain(|id| *id != account_id),
+            _ => {}
+        }
+    }
+    send_friend_list(state, other_id);
+    friend_list(state, &account_id)
+}
+
+// Send a friend an invite to the lobby the client is in. The friend has to be online to receive it.
+pub fn invite_to_lobby(state: &GameProtocolServerState, client_id: &str, other_id: &str) -> Result<(), StatusCode> {
+    logged_in_account(state, client_id)?;
+    let client = state.clients.get(client_id).unwrap();
+    let lobby = match client.lobby_id.as_ref().and_then(|lobby_id| state.lobbies.get(lobby_id)) {
+        Some(lobby) => lobby,
+        None => return Err(StatusCode::NotInLobby)
+    };
+    if !client.friend_ids.iter().any(|id| id == other_id) {
+        return Err(StatusCode::NotFriends);
+    }
+    match client_for_account(state, other_id) {
+        Some(friend) => {
+            friend.socket.send_message(build_lobby_invite(friend.socket.codec(), LobbyInvite {
+                lobby_id: lobby.id.clone(),
+                game_type_id: lobby.game_metadata.get_game_type_id(),
+                from: client.profile.clone()
+            }));
+            Ok(())
+        }
+        None => Err(StatusCode::FriendOffline)
+    }
+}
+
+// Everyone an account is friends with or has a pending request with, including what each friend is doing.
+pub fn friend_list(state: &GameProtocolServerState, account_id: &str) -> Result<Vec<FriendInfo>, StatusCode> {
+    let friendships = match state.storage.load_friendships(account_id) {
+        Ok(friendships) => friendships,
+        Err(e) => {
+            error!(error = ?e, "Storage error loading friendships");
+            return Err(StatusCode::UnexpectedError);
+        }
+    };
+
+    let mut friends = vec![];
+    for friendship in friendships {
+        let other_id = friendship.other(account_id);
+        let online = client_for_account(state, other_id);
+
+        // Connected friends show their current display name. Otherwise it comes from their account.
+        let display_name = match online {
+            Some(client) => client.profile.display_name.clone(),
+            None => match state.storage.load_account(other_id) {
+                Ok(Some(account)) => account.display_name,
+                _ => continue
+            }
+        };
+        let status = if friendship.accepted {
+            FriendStatus::Friends
+        } else if friendship.requester_id == account_id {
+            FriendStatus::RequestSent
+        } else {
+            FriendStatus::RequestReceived
+        };
+        let presence = match online {
+            Some(client) if friendship.accepted => presence_of(state, client),
+            _ => Presence::offline()
+        };
+        friends.push(FriendInfo { account_id: other_id.to_string(), display_name, status, presence });
+    }
+    Ok(friends)
+}
+
+// Load the friends of a client that just logged in and tell them it is online.
+pub fn load_friends(state: &mut GameProtocolServerState, client_id: &str) {
+    let account_id = match state.clients.get(client_id).and_then(|client| client.profile.account_id.clone()) {
+        Some(account_id) => account_id,
+        None => return
+    };
+    let friend_ids = match state.storage.load_friendships(&account_id) {
+        Ok(friendships) => friendships.iter().filter(|f| f.accepted).map(|f| f.other(&account_id).to_string()).collect(),
+        Err(e) => {
+            error!(error = ?e, "Storage error loading friendships");
+            vec![]
+        }
+    };
+    state.clients.get_mut(client_id).unwrap().friend_ids = friend_ids;
+    update_presence(state, client_id);
+}
+
+// Push a client's current presence to its online friends. Called whenever the client enters or leaves a lobby or game.
+pub fn update_presence(state: &GameProtocolServerState, client_id: &str) {
+    if let Some(client) = state.clients.get(client_id) {
+        push_presence(state, client, presence_of(state, client));
+    }
+}
+
+// Tell a disconnecting client's online friends that it has gone offline.
+pub fn went_offline(state: &GameProtocolServerState, client_id: &str) {
+    if let Some(client) = state.clients.get(client_id) {
+        push_presence(state, client, Presence::offline());
+    }
+}
+
+fn push_presence(state: &GameProtocolServerState, client: &Client, presence: Presence) {
+    let account_id = match &client.profile.account_id {
+        Some(account_id) => account_id,
+        None => return
+    };
+    for friend_id in client.friend_ids.iter() {
+        if let Some(friend) = client_for_account(state, friend_id) {
+            friend.socket.send_message(build_presence_update(friend.socket.codec(), account_id, presence.clone()));
+        }
+    }
+}
+
+// What a connected client is doing, based on the lobby it is in and whether that lobby's game is running.
+fn presence_of(state: &GameProtocolServerState, client: &Client) -> Presence {
+    match client.lobby_id.as_ref().and_then(|lobby_id| state.lobbies.get(lobby_id)) {
+        Some(lobby) => Presence {
+            status: if lobby.game_started { PresenceStatus::InGame } else { PresenceStatus::InLobby },
+            lobby_id: Some(lobby.id.clone()),
+            game_type_id: Some(lobby.game_metadata.get_game_type_id())
+        },
+        None => Presence {
+            status: PresenceStatus::Online,
+            lobby_id: None,
+            game_type_id: None
+        }
+    }
+}
+
+// Mark a pending request as accepted, and if either friend is online, add the other to its friends and tell it where the other is.
+fn accept(state: &mut GameProtocolServerState, mut friendship: FriendshipRecord) -> Result<(), StatusCode> {
+    friendship.accepted = true;
+    save_friendship(state, &friendship)?;
+    for (account_id, other_id) in [(&friendship.requester_id, &friendship.addressee_id), (&friendship.addressee_id, &friendship.requester_id)] {
+        if let Some(client) = state.clients.values_mut().find(|client| client.profile.account_id.as_ref() == Some(account_id)) {
+            client.friend_ids.push(other_id.clone());
+            let client_id = client.id.clone();
+            update_presence(state, &client_id);
+        }
+    }
+    Ok(())
+}
+
+// Send an account its friend list if it is online.
+fn send_friend_list(state: &GameProtocolServerState, account_id: &str) {
+    if let Some(client) = client_for_account(state, account_id) {
+        if let Ok(friends) = friend_list(state, account_id) {
+            client.socket.send_message(build_friend_list_response(client.socket.codec(), StatusCode::Success, &friends));
+        }
+    }
+}
+
+fn save_friendship(state: &mut GameProtocolServerState, friendship: &FriendshipRecord) -> Result<(), StatusCode> {
+    state.storage.save_friendship(friendship).map_err(|e| {
+        error!(error = ?e, "Storage error saving friendship");
+        StatusCode::UnexpectedError
+    })
+}
+
+// The friendship or pending request between two accounts, whichever of them sent it.
+fn friendship_between(state: &GameProtocolServerState, account_id: &str, other_id: &str) -> Result<Option<FriendshipRecord>, StatusCode> {
+    match state.storage.load_friendships(account_id) {
+        Ok(friendships) => Ok(friendships.into_iter().find(|f| f.other(account_id) == other_id)),
+        Err(e) => {
+            error!(error = ?e, "Storage error loading friendships");
+            Err(StatusCode::UnexpectedError)
+        }
+    }
+}
+
+// Friends are tied to accounts, so guests can't have any.
+fn logged_in_account(state: &GameProtocolServerState, client_id: &str) -> Result<String, StatusCode> {
+    state.clients.get(client_id).unwrap().profile.account_id.clone().ok_or(StatusCode::NotLoggedIn)
+}
+
+// The connected client logged in to an account, if any.
+fn client_for_account<'a>(state: &'a GameProtocolServerState, account_id: &str) -> Option<&'a Client> {
+    state.clients.values().find(|client| client.profile.account_id.as_deref() == Some(account_id))
+}
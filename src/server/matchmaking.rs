@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+use crate::server::ratings::RatingStore;
+use crate::shared_data::Lobby;
+
+/*
+    Rating based matchmaking. Places a client into the open lobby whose players are closest to the client's skill.
+ */
+
+// Find the open lobby for a game type whose players' average rating is closest to the given rating.
+// Lobbies the client isn't admitted to, empty lobbies, and lobbies whose average is further away than the window are skipped.
+pub fn find_lobby_for_rating(lobbies: &HashMap<String, Lobby>, ratings: &RatingStore, game_type_id: &str, rating: f64, window: f64, admits: impl Fn(&Lobby) -> bool) -> Option<String> {
+    let mut best_match: Option<(String, f64)> = None;
+
+    for lobby in lobbies.values() {
+        if lobby.player_ids.is_empty() || lobby.game_metadata.get_game_type_id() != game_type_id || !admits(lobby) {
+            continue;
+        }
+
+        // Average rating of everyone already waiting in the lobby
+        let total: f64 = lobby.rating_ids().iter().map(|id| ratings.get(id, game_type_id).rating).sum();
+        let difference = (total / lobby.player_ids.len() as f64 - rating).abs();
+
+        // Keep the closest lobby that falls within the window
+        if difference <= window && best_match.as_ref().is_none_or(|(_, best)| difference < *best) {
+            best_match = Some((lobby.id.clone(), difference));
+        }
+    }
+    best_match.map(|(lobby_id, _)| lobby_id)
+}
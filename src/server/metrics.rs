@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+use crate::common_message_utils::{parse_message_type, parse_status_code};
+use crate::enums::{MessageType, ServerError};
+use crate::server::GameProtocolServerState;
+use crate::server::shutdown::ShutdownHandle;
+
+/*
+    Server health metrics in the Prometheus text format.
+    Counters are recorded as messages flow through the server. Gauges for clients, lobbies and running games
+    are read from the server state when metrics are scraped. When the config sets a metrics port, the server
+    serves them over HTTP at /metrics on that port.
+ */
+
+// Upper bounds in seconds of the handler latency histogram buckets
+const LATENCY_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1, 0.5];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()], // Number of observations at or below each bucket's upper bound
+    count: u64,
+    sum: f64
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+// Counters keyed by label values. BTreeMaps keep the output in a stable order.
+#[derive(Default)]
+struct Counters {
+    requests: BTreeMap<String, u64>, // Requests received by message type
+    responses: BTreeMap<(String, String), u64>, // Messages sent by status code and message type
+    protocol_errors: BTreeMap<String, u64>, // Requests that couldn't be parsed by ServerError variant
+    handler_latency: BTreeMap<String, Histogram> // Time taken to handle requests by message type
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    counters: Mutex<Counters>
+}
+
+impl Metrics {
+    pub fn record_request(&self, message_type: MessageType, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        *self.counters.lock().unwrap().requests.entry(format!("{:?}", message_type)).or_default() += 1;
+    }
+
+    // Count a message about to be sent. The status code and message type are read from its header.
+    pub fn record_response(&self, data: &[u8]) {
+        self.bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+        if data.len() < 4 {
+            return;
+        }
+        let (status_code, remainder) = parse_status_code(data);
+        let (message_type, _) = parse_message_type(remainder);
+        let key = (format!("{:?}", status_code), format!("{:?}", message_type));
+        *self.counters.lock().unwrap().responses.entry(key).or_default() += 1;
+    }
+
+    pub fn record_protocol_error(&self, e: ServerError) {
+        *self.counters.lock().unwrap().protocol_errors.entry(format!("{:?}", e)).or_default() += 1;
+    }
+
+    pub fn record_latency(&self, message_type: MessageType, elapsed: Duration) {
+        self.counters.lock().unwrap().handler_latency.entry(format!("{:?}", message_type)).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    // Start timing a request. The time is recorded when the returned timer is dropped, so every way out of a handler is covered.
+    pub fn time_handler(self: &Arc<Self>, message_type: MessageType) -> HandlerTimer {
+        HandlerTimer {
+            metrics: self.clone(),
+            message_type,
+            start: Instant::now()
+        }
+    }
+}
+
+pub struct HandlerTimer {
+    metrics: Arc<Metrics>,
+    message_type: MessageType,
+    start: Instant
+}
+
+impl Drop for HandlerTimer {
+    fn drop(&mut self) {
+        self.metrics.record_latency(self.message_type, self.start.elapsed());
+    }
+}
+
+// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+// Render every metric in the Prometheus text format, including gauges read from the current server state.
+pub fn render_metrics(state: &GameProtocolServerState) -> String {
+    let mut output = String::new();
+
+    write_header(&mut output, "game_protocol_connected_clients", "Clients with an active session.", "gauge");
+    let _ = writeln!(output, "game_protocol_connected_clients {}", state.clients.len());
+
+    write_header(&mut output, "game_protocol_lobbies", "Lobbies that currently exist.", "gauge");
+    let _ = writeln!(output, "game_protocol_lobbies {}", state.lobbies.len());
+
+    // Every supported game type is listed so game types without running games show up as 0
+    let mut running_games: BTreeMap<String, usize> = state.supported_games.keys().map(|id| (id.clone(), 0)).collect();
+    for game in state.games_in_progress.values() {
+        *running_games.entry(game.get_metadata().get_game_type_id()).or_default() += 1;
+    }
+    write_header(&mut output, "game_protocol_running_games", "Game sessions in progress by game type.", "gauge");
+    for (game_type_id, count) in running_games.iter() {
+        let _ = writeln!(output, "game_protocol_running_games{{game_type=\"{}\"}} {}", escape_label(game_type_id), count);
+    }
+
+    let metrics = &state.metrics;
+    write_header(&mut output, "game_protocol_bytes_received_total", "Bytes of client messages received.", "counter");
+    let _ = writeln!(output, "game_protocol_bytes_received_total {}", metrics.bytes_received.load(Ordering::Relaxed));
+    write_header(&mut output, "game_protocol_bytes_sent_total", "Bytes of messages sent to clients.", "counter");
+    let _ = writeln!(output, "game_protocol_bytes_sent_total {}", metrics.bytes_sent.load(Ordering::Relaxed));
+
+    let counters = metrics.counters.lock().unwrap();
+    write_header(&mut output, "game_protocol_requests_total", "Client requests received by message type.", "counter");
+    for (message_type, count) in counters.requests.iter() {
+        let _ = writeln!(output, "game_protocol_requests_total{{message_type=\"{}\"}} {}", message_type, count);
+    }
+
+    write_header(&mut output, "game_protocol_responses_total", "Messages sent to clients by status code and message type.", "counter");
+    for ((status_code, message_type), count) in counters.responses.iter() {
+        let _ = writeln!(output, "game_protocol_responses_total{{status_code=\"{}\",message_type=\"{}\"}} {}", status_code, message_type, count);
+    }
+
+    write_header(&mut output, "game_protocol_protocol_errors_total", "Client requests that could not be parsed by error.", "counter");
+    for (error, count) in counters.protocol_errors.iter() {
+        let _ = writeln!(output, "game_protocol_protocol_errors_total{{error=\"{}\"}} {}", error, count);
+    }
+
+    write_header(&mut output, "game_protocol_handler_duration_seconds", "Time taken to handle client requests by message type.", "histogram");
+    for (message_type, histogram) in counters.handler_latency.iter() {
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            let _ = writeln!(output, "game_protocol_handler_duration_seconds_bucket{{message_type=\"{}\",le=\"{}\"}} {}", message_type, bound, count);
+        }
+        let _ = writeln!(output, "game_protocol_handler_duration_seconds_bucket{{message_type=\"{}\",le=\"+Inf\"}} {}", message_type, histogram.count);
+        let _ = writeln!(output, "game_protocol_handler_duration_seconds_sum{{message_type=\"{}\"}} {}", message_type, histogram.sum);
+        let _ = writeln!(output, "game_protocol_handler_duration_seconds_count{{message_type=\"{}\"}} {}", message_type, histogram.count);
+    }
+    output
+}
+
+// Spawn a thread that serves metrics over HTTP on the listener until the server shuts down.
+// Requests are answered one at a time, which is plenty for a scraper polling every few seconds.
+pub fn serve_metrics(listener: TcpListener, state: Arc<Mutex<GameProtocolServerState>>, shutdown: ShutdownHandle) {
+    thread::spawn(move || {
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!(error = %e, "Metrics listener non-blocking error");
+            return;
+        }
+        while !shutdown.is_shutdown_requested() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = answer_request(stream, &state) {
+                        warn!(error = %e, "Metrics request error");
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => {
+                    warn!(error = %e, "Metrics incoming stream error");
+                }
+            }
+        }
+    });
+}
+
+// Read an HTTP request's head and answer GET /metrics with the rendered metrics. Anything else gets a 404.
+fn answer_request(mut stream: TcpStream, state: &Arc<Mutex<GameProtocolServerState>>) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // Only the request line matters, but read up to the end of the headers so the client isn't cut off mid-send
+    let mut head = vec![];
+    let mut chunk = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < 8192 {
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..size]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render_metrics(&state.lock().unwrap());
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes())
+}
@@ -1,17 +1,56 @@
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
+use std::net::{SocketAddr, TcpListener};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::thread;
+use std::fs;
+use std::io::{self, BufReader, ErrorKind};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, debug_span, error, field, info, info_span, warn};
 use uuid::Uuid;
-use crate::common_message_utils::parse_message_data;
-use crate::enums::{MessageType, StatusCode};
+use crate::codec::Codec;
+use crate::compression::Compression;
+use crate::common_message_utils::{compress_message, negotiate_protocol_version, parse_message_data, parse_message_type, parse_status_code, read_message, CLIENT_HEADER_SIZE, GAME_STATE_REQUEST_VERSION, SERVER_HEADER_SIZE};
+use crate::enums::{ConfigError, Feature, MessageType, ServerError, StatusCode, StorageError};
 use crate::game_module::{GameModule, GameMove};
-use crate::server::server_message_utils::{build_connect_response, build_game_state_response, build_lobby_info_response, build_lobby_list_response, build_missing_message_response, build_server_error_response, build_server_headers, build_supported_game_response, parse_client_message_header, parse_connect_request};
-use crate::shared_data::{CreateLobbyRequest, JoinLobbyRequest, Lobby, StartGameRequest};
+use crate::game_module::delta::StateChange;
+use crate::server::accounts::{answer_account_request, update_profile, AccountRequest};
+use crate::server::chat::send_chat_message;
+use crate::server::config::ServerConfig;
+use crate::server::admin::serve_admin;
+use crate::server::admission::{AdmissionContext, AdmissionPolicy, DefaultAdmissionPolicy};
+use crate::server::events::{EventHandlers, ServerEventHandler};
+use crate::server::friends::{accept_friend_request, friend_list, invite_to_lobby, remove_friend, send_friend_request, update_presence, went_offline};
+use crate::server::matchmaking::find_lobby_for_rating;
+use crate::server::metrics::{serve_metrics, Metrics};
+use crate::server::rate_limit::{RateLimiter, Strikes};
+use crate::server::ratings::{RatingStore, RatingSystem};
+use crate::server::shutdown::{ServerHandle, ShutdownHandle};
+use crate::server::snapshot::{restore_snapshot, save_snapshot, HeldSeat};
+use crate::server::server_message_utils::{build_connect_response, build_empty_response, build_friend_list_response, build_game_state_delta, build_game_state_response, build_leaderboard_response, build_lobby_info_response, build_lobby_list_response, build_missing_message_response, build_profile_response, build_rating_response, build_server_error_response, build_supported_game_response, build_tournament_info_response, build_tournament_list_response, build_unsolicited_message, parse_client_message_header, parse_connect_request};
+use crate::server::tournament::Tournament;
+use crate::storage::{BanRecord, GameRecord, MemoryStorage, Storage};
+use crate::transport::{bind_unix_socket, Connection, Listener};
+use crate::transport::loopback::{LoopbackConnector, LoopbackListener};
+use crate::transport::tls::{ServerTls, TlsListener};
+use crate::transport::websocket::WebSocketListener;
+use crate::shared_data::{ChatRequest, ConnectResponse, CreateLobbyRequest, CreateTournamentRequest, FriendAccountRequest, FriendRequest, GameStateDelta, JoinLobbyRequest, LeaderboardRequest, Lobby, LoginRequest, MatchmakingRequest, PlayerProfile, RatingRequest, RegisterRequest, StartGameRequest, TournamentFormat, TournamentInfo, TournamentRequest, TournamentStatus, UpdateProfileRequest};
 
 mod server_message_utils;
+mod accounts;
+mod friends;
+mod chat;
+mod rate_limit;
+mod matchmaking;
+mod tournament;
+mod snapshot;
+mod metrics;
+pub mod events;
+pub mod admission;
+pub mod admin;
+pub mod shutdown;
+pub mod ratings;
+pub mod config;
 
 /*
     Server component of the game protocol.
@@ -20,60 +59,198 @@ mod server_message_utils;
     appropriate to the request or the result of the processing.
  */
 
-// Give TcpStream its own send_message function as a wrapper around the socket's write function.
+// How long a new connection has to finish setting up, e.g. a TLS handshake, whether or not there is an idle timeout.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Give connections their own send_message function as a wrapper around the connection's write function.
 pub trait SocketSend {
     fn send_message(&self, data: Vec<u8>);
 }
 
-impl SocketSend for TcpStream {
+impl SocketSend for dyn Connection {
     fn send_message(&self, data: Vec<u8>) {
-        match self.clone().write(data.as_slice()) {
+        match self.write_all(data.as_slice()) {
             Ok(_) => {}
             Err(e) => {
-                println!("Client socket write error. {:?}", e);
+                warn!(error = ?e, "Client socket write error");
+            }
+        };
+    }
+}
+
+// A client's socket. Everything sent through it is counted in the server's metrics.
+pub struct ClientSocket {
+    connection: Box<dyn Connection>,
+    metrics: Arc<Metrics>,
+    codec: OnceLock<Codec>, // Codec picked for the client's message bodies when it connected. JSON until then.
+    compression: OnceLock<(Compression, usize)>, // Compression picked for the client's large message bodies when it connected, and the body size above which it is used
+    features: OnceLock<Vec<Feature>> // Features both the client and server support, agreed on when it connected. None until then.
+}
+
+impl ClientSocket {
+    fn new(connection: Box<dyn Connection>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            connection,
+            metrics,
+            codec: OnceLock::new(),
+            compression: OnceLock::new(),
+            features: OnceLock::new()
+        }
+    }
+
+    // Codec the client's message bodies are encoded with, both ways.
+    pub fn codec(&self) -> Codec {
+        self.codec.get().copied().unwrap_or_default()
+    }
+
+    // Compression the client's large message bodies are compressed with, both ways. None if the client didn't ask for any or compression is disabled.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression.get().map(|(compression, _)| *compression)
+    }
+
+    // Whether the client agreed to use a feature when it connected.
+    pub fn supports_feature(&self, feature: Feature) -> bool {
+        self.features.get().is_some_and(|features| features.contains(&feature))
+    }
+
+    // Send the error response for a request that couldn't be parsed, counting the error in the server's metrics.
+    fn send_server_error(&self, e: ServerError) {
+        self.metrics.record_protocol_error(e);
+        self.send_message(build_server_error_response(e));
+    }
+}
+
+impl Deref for ClientSocket {
+    type Target = dyn Connection;
+
+    fn deref(&self) -> &(dyn Connection + 'static) {
+        self.connection.as_ref()
+    }
+}
+
+impl SocketSend for ClientSocket {
+    fn send_message(&self, data: Vec<u8>) {
+        // Messages of features the client didn't agree on, e.g. presence updates to a client without friends, are never sent.
+        // Error responses are sent as ProtocolError messages, so they always get through.
+        if data.len() >= SERVER_HEADER_SIZE {
+            let (_, remainder) = parse_status_code(&data);
+            let (message_type, _) = parse_message_type(remainder);
+            if Feature::of_message_type(message_type).is_some_and(|feature| !self.supports_feature(feature)) {
+                return;
             }
+        }
+        let data = match self.compression.get() {
+            Some((compression, threshold)) => compress_message(data, SERVER_HEADER_SIZE, *compression, *threshold),
+            None => data
         };
+        self.metrics.record_response(&data);
+        self.connection.send_message(data);
     }
 }
 
 // Simple struct for data specific to each client. Data associated with each connected client.
 // This is only used on the server side.
 pub struct Client {
-    socket: Arc<TcpStream>, // Socket on which client has connected to the server
+    socket: Arc<ClientSocket>, // Socket on which client has connected to the server
     id: String, // Unique ID generated by the server to identify the client
     lobby_id: Option<String>, // If client is in a lobby, store the lobby ID
-    next_message_id: u32 // The next message ID the server expects from the client
+    profile: PlayerProfile, // What other players see of the client. A guest profile until the client logs in.
+    friend_ids: Vec<String>, // Account IDs of the client's accepted friends. Always empty for guests.
+    next_message_id: u32, // The next message ID the server expects from the client
+    reconnect_token: String // Secret the client has to send to resume its session after a restart. Saved in snapshots.
 }
 
 struct GameProtocolServerState {
+    config: ServerConfig, // Limits and timeouts the server enforces
+    config_path: Option<String>, // File the config was loaded from, so it can be reloaded through the admin channel
     clients: HashMap<String, Client>, // Hash map of clients, indexed by client UUID
     lobbies: HashMap<String, Lobby>, // Hash map of lobbies, indexed by lobby UUID
     supported_games: HashMap<String, Arc<dyn GameModule>>, // Hash map of supported game factory objects, indexed by game module ID
-    games_in_progress: HashMap<String, Box<dyn GameModule>> // Hash map of game sessions, indexed by lobby UUID
+    games_in_progress: HashMap<String, Box<dyn GameModule>>, // Hash map of game sessions, indexed by lobby UUID
+    game_records: HashMap<String, GameRecord>, // Record of each game session in progress, indexed by lobby UUID. Saved to storage when the game ends.
+    game_state_versions: HashMap<String, (u64, HashMap<String, serde_json::Value>)>, // Version of each game session's state and the state each player taking delta updates was last sent, indexed by lobby UUID and player ID
+    storage: Box<dyn Storage>, // Where accounts, finished games and ratings are persisted
+    bans: HashMap<String, BanRecord>, // Banned IP addresses and account IDs, indexed by ban target. Kept in storage too so they outlive a restart.
+    mutes: HashMap<String, BanRecord>, // Players that can't send chat messages, indexed by account ID or IP address. Only kept while the server runs.
+    account_rate_limits: HashMap<String, RateLimiter>, // Rate limits shared by every connection logged in to an account, indexed by account ID. Dropped when the account's last connection closes.
+    ratings: RatingStore, // Player ratings for each game type, updated whenever a game session ends
+    tournaments: HashMap<String, Tournament>, // Hash map of tournaments, indexed by tournament UUID
+    matchmaking_rating_window: f64, // Largest rating difference matchmaking will accept when placing a client in an existing lobby
+    held_seats: HashMap<String, HeldSeat>, // Seats of players from a restored snapshot that haven't reconnected yet, indexed by client ID
+    shutting_down: bool, // Set once the server has started shutting down. No new games are started.
+    metrics: Arc<Metrics>, // Counters for messages, errors and latency. Shared with every client's socket.
+    event_handlers: EventHandlers, // Handlers the embedding application registered to be told about server events
+    admission_policy: Box<dyn AdmissionPolicy> // Rules for creating and joining lobbies, starting games and making moves
 }
 
 pub struct GameProtocolServer {
     state: Arc<Mutex<GameProtocolServerState>>, // Store server state in a thread safe pointer and mutex since it will be accessed across threads handling individual clients
     listener: Option<TcpListener>, // Listener object on which server listens for new incoming connections
+    tcp_enabled: bool, // Whether the server binds a TCP listener. Turned off for servers only reached through other listeners, e.g. loopback.
+    tls: Option<ServerTls>, // Certificate and key clients connect over TLS with. Connections are plain TCP if None.
+    listeners: Vec<Box<dyn Listener>>, // Listeners added on top of the TCP listener, accepting connections over other transports
     ip: String, // IP address on which the server runs
-    port: String // Port number on which the server runs
+    port: String, // Port number on which the server runs
+    snapshot_path: Option<String>, // File lobbies and game sessions are snapshotted to. Snapshots are disabled if None.
+    snapshot_interval: Duration, // How often a snapshot is taken
+    reconnect_grace_period: Duration, // How long players of a restored lobby have to reconnect before their seats are given up
+    shutdown: ShutdownHandle, // Set from another thread to stop the server
+    client_threads: Vec<(Arc<ClientSocket>, JoinHandle<()>)> // Socket and listening thread of every connection that is still open
 }
 
 impl GameProtocolServer {
     pub fn new(ip: &str, port: &str) -> Self {
         Self {
             state: Arc::new(Mutex::new(GameProtocolServerState{
+                config: ServerConfig::default(),
+                config_path: None,
                 clients: HashMap::new(),
                 lobbies: HashMap::new(),
                 supported_games: HashMap::new(),
-                games_in_progress: HashMap::new()
+                games_in_progress: HashMap::new(),
+                game_records: HashMap::new(),
+                game_state_versions: HashMap::new(),
+                storage: Box::new(MemoryStorage::new()),
+                bans: HashMap::new(),
+                mutes: HashMap::new(),
+                account_rate_limits: HashMap::new(),
+                ratings: RatingStore::new(RatingSystem::default()),
+                tournaments: HashMap::new(),
+                matchmaking_rating_window: 200.0,
+                held_seats: HashMap::new(),
+                shutting_down: false,
+                metrics: Arc::new(Metrics::default()),
+                event_handlers: EventHandlers::default(),
+                admission_policy: Box::new(DefaultAdmissionPolicy)
             })),
             listener: None,
+            tcp_enabled: true,
+            tls: None,
+            listeners: vec![],
             ip: ip.to_string(),
-            port: port.to_string()
+            port: port.to_string(),
+            snapshot_path: None,
+            snapshot_interval: Duration::from_secs(30),
+            reconnect_grace_period: Duration::from_secs(120),
+            shutdown: ShutdownHandle::default(),
+            client_threads: vec![]
         }
     }
 
+    // Create a server from a config. The config is expected to have been validated when it was built or loaded.
+    pub fn with_config(config: ServerConfig) -> Self {
+        let server = Self::new(&config.ip, &config.port.to_string());
+        server.state.lock().unwrap().config = config;
+        server
+    }
+
+    // Create a server from a TOML config file. The file is remembered so the config can be reloaded through the admin channel.
+    pub fn with_config_file(path: &str) -> Result<Self, ConfigError> {
+        let server = Self::with_config(ServerConfig::from_toml_file(path)?);
+        server.state.lock().unwrap().config_path = Some(path.to_string());
+        Ok(server)
+    }
+
     // Register a game module by using generics. Generic must have a static lifetime and implement the GameModule trait.
     // That way we know that the game module will be compatible with protocol operations.
     pub fn register_game<T: 'static + GameModule>(&self) {
@@ -82,98 +259,599 @@ impl GameProtocolServer {
         state_lock.supported_games.insert(game.get_metadata().get_game_type_id(),  game);
     }
 
-    // Spin up the server and have it listen for incoming connection requests
+    // Set the backend the server persists data through. Defaults to MemoryStorage, which keeps nothing across restarts.
+    // Ratings and bans already in the storage are loaded straight away.
+    pub fn set_storage<T: 'static + Storage>(&self, storage: T) -> Result<(), StorageError> {
+        let ratings = storage.load_ratings()?;
+        let bans = storage.load_bans()?;
+        let mut state_lock = self.state.lock().unwrap();
+        state_lock.ratings.load(ratings);
+        state_lock.bans = bans.into_iter().map(|ban| (ban.target.clone(), ban)).collect();
+        state_lock.storage = Box::new(storage);
+        Ok(())
+    }
+
+    // Register a handler to be told about clients connecting, lobbies changing and games being played.
+    // Handlers are called in the order they were added. See ServerEventHandler for what they must not do.
+    pub fn add_event_handler<T: 'static + ServerEventHandler>(&self, handler: T) {
+        self.state.lock().unwrap().event_handlers.add(Box::new(handler));
+    }
+
+    // Set the rules for who may create and join lobbies, start games and make moves. Defaults to DefaultAdmissionPolicy.
+    pub fn set_admission_policy<T: 'static + AdmissionPolicy>(&self, policy: T) {
+        self.state.lock().unwrap().admission_policy = Box::new(policy);
+    }
+
+    // Choose the algorithm used to update player ratings after a game ends. Defaults to Elo.
+    pub fn set_rating_system(&self, system: RatingSystem) {
+        self.state.lock().unwrap().ratings.set_system(system);
+    }
+
+    // Set how far apart, in rating points, a client and the players of a lobby can be for matchmaking to pair them.
+    pub fn set_matchmaking_rating_window(&self, window: f64) {
+        self.state.lock().unwrap().matchmaking_rating_window = window;
+    }
+
+    // Create a tournament for a supported game that clients can register for. Returns the tournament ID, or None if the game isn't supported.
+    pub fn create_tournament(&self, name: &str, game_type_id: &str, format: TournamentFormat) -> Option<String> {
+        let mut state_lock = self.state.lock().unwrap();
+        if !game_offered(&state_lock, game_type_id) {
+            return None;
+        }
+        Some(create_tournament(state_lock.deref_mut(), name, game_type_id, format, None))
+    }
+
+    // Close registration for a tournament and start its first round. Lobbies and game sessions are created for every pairing.
+    pub fn start_tournament(&self, tournament_id: &str) -> Result<(), StatusCode> {
+        let mut state_lock = self.state.lock().unwrap();
+        start_tournament(state_lock.deref_mut(), tournament_id)
+    }
+
+    // Get a copy of every tournament's current data, including standings.
+    pub fn get_tournaments(&self) -> Vec<TournamentInfo> {
+        self.state.lock().unwrap().tournaments.values().map(|t| t.info.clone()).collect()
+    }
+
+    // Periodically snapshot lobbies and running game sessions to a file, and restore them from it when the server starts.
+    pub fn enable_snapshots(&mut self, path: &str, interval: Duration) {
+        self.snapshot_path = Some(path.to_string());
+        self.snapshot_interval = interval;
+    }
+
+    // Set how long players of lobbies restored from a snapshot have to reconnect before they lose their seat. Defaults to 2 minutes.
+    pub fn set_reconnect_grace_period(&mut self, grace_period: Duration) {
+        self.reconnect_grace_period = grace_period;
+    }
+
+    // Get a handle that can stop the server from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Listen on a listener that is already bound instead of binding to the server's IP address and port when starting.
+    pub fn set_listener(&mut self, listener: TcpListener) {
+        self.listener = Some(listener);
+    }
+
+    // Have clients connect over TLS, presenting the given certificate. Takes the place of any certificate files set in the config.
+    pub fn set_tls(&mut self, tls: ServerTls) {
+        self.tls = Some(tls);
+    }
+
+    // Also accept connections from another listener, e.g. one for a different transport. Clients from every listener share lobbies and games.
+    pub fn add_listener<T: 'static + Listener>(&mut self, listener: T) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    // Accept clients running in the same process without going through the network. Pass the connector's connections to GameProtocolClient::connect_with.
+    pub fn loopback_connector(&mut self) -> LoopbackConnector {
+        let (listener, connector) = LoopbackListener::new();
+        self.add_listener(listener);
+        connector
+    }
+
+    // Don't bind a TCP listener when starting, e.g. when every client connects through a loopback connector.
+    pub fn disable_tcp(&mut self) {
+        self.tcp_enabled = false;
+    }
+
+    // Address the server is listening on. None until the server has been bound, or if TCP is disabled. Useful to find the port picked when binding to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    // Spin up the server and have it listen for incoming connection requests.
+    // Blocks until the server is shut down through a ShutdownHandle.
     pub fn start(&mut self) {
-        println!("Starting server...");
-        // Binds a TCP listener to an IP address and port number, creating a socket address.
-        match TcpListener::bind(format!("{}:{}", self.ip, self.port)) {
-            Ok(listener) => {
-                self.listener = Some(listener);
-                println!("Server listening at {}:{}", self.ip, self.port);
+        info!("Starting server");
+        match self.bind() {
+            Ok(address) => {
+                info!(address = ?address, "Server listening");
                 self.start_server_loop();
-            },
+            }
             Err(e) => {
-                println!("TCP bind error: {}", e);
+                error!(error = %e, "Server start error");
+            }
+        }
+    }
+
+    // Bind the server and run it on a background thread instead of blocking the calling thread.
+    // The returned handle has the address the server is listening on and can shut it down.
+    pub fn spawn(mut self) -> io::Result<ServerHandle> {
+        let local_addr = self.bind()?;
+        info!(address = ?local_addr, "Server listening");
+        let shutdown = self.shutdown_handle();
+        let thread = thread::spawn(move || self.start_server_loop());
+        Ok(ServerHandle::new(local_addr, shutdown, thread))
+    }
+
+    // Validate the config, bind a TCP listener to the server's IP address and port unless one was already set or TCP is disabled,
+    // bind any Unix socket and WebSocket listeners the config sets, and start the metrics, snapshot and heartbeat threads. Returns the address the server is listening on, if it listens on TCP.
+    fn bind(&mut self) -> io::Result<Option<SocketAddr>> {
+        if let Err(e) = self.apply_config() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, e.to_string()));
+        }
+        if self.tls.is_none() {
+            self.tls = self.load_tls()?;
+        }
+        if !self.tcp_enabled || !self.state.lock().unwrap().config.tcp_enabled {
+            self.listener = None;
+        } else if self.listener.is_none() {
+            self.listener = Some(TcpListener::bind(format!("{}:{}", self.ip, self.port))?);
+        }
+        let local_addr = match &self.listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None
+        };
+        self.start_unix_socket()?;
+        self.start_websocket()?;
+        self.start_metrics()?;
+        self.start_admin()?;
+
+        if self.snapshot_path.is_some() {
+            self.start_snapshots();
+        }
+        self.start_heartbeats();
+        Ok(local_addr)
+    }
+
+    // Load the TLS certificate and key files set in the config, if any.
+    fn load_tls(&self) -> io::Result<Option<ServerTls>> {
+        let state_lock = self.state.lock().unwrap();
+        match (&state_lock.config.tls_cert_file, &state_lock.config.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => match ServerTls::from_pem_files(cert_file, key_file) {
+                Ok(tls) => Ok(Some(tls)),
+                Err(e) => Err(io::Error::new(ErrorKind::InvalidInput, e.to_string()))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    // Validate the config against the games that were registered. Games the config doesn't allow stay registered,
+    // so a config reload can allow them again, but no new lobbies or tournaments can be created for them.
+    fn apply_config(&self) -> Result<(), ConfigError> {
+        let state_lock = self.state.lock().unwrap();
+        state_lock.config.validate()?;
+        if let Some(allowed) = &state_lock.config.allowed_game_types {
+            if let Some(missing) = allowed.iter().find(|id| !state_lock.supported_games.contains_key(*id)) {
+                return Err(ConfigError::InvalidValue(format!("allowed game type \"{}\" has not been registered", missing)));
+            }
+        }
+        Ok(())
+    }
+
+    // If the config sets a heartbeat interval, spawn a thread that sends a heartbeat each interval to every client that agreed on heartbeats.
+    fn start_heartbeats(&self) {
+        let interval = match self.state.lock().unwrap().config.heartbeat_interval {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => return
+        };
+        let state_clone = self.state.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                // Only hold the lock to collect the sockets, so a slow client's write doesn't hold up every other thread
+                let state_lock = state_clone.lock().unwrap();
+                if state_lock.shutting_down {
+                    break;
+                }
+                let sockets: Vec<Arc<ClientSocket>> = state_lock.clients.values()
+                    .filter(|client| client.socket.supports_feature(Feature::Heartbeats))
+                    .map(|client| client.socket.clone())
+                    .collect();
+                drop(state_lock);
+
+                let heartbeat = build_empty_response(StatusCode::Success, MessageType::Heartbeat);
+                for socket in sockets {
+                    socket.send_message(heartbeat.clone());
+                }
             }
+        });
+    }
+
+    // If the config sets a Unix socket path, listen on it alongside the other listeners. The socket file is removed when the server shuts down.
+    fn start_unix_socket(&mut self) -> io::Result<()> {
+        let path = match self.state.lock().unwrap().config.unix_socket_path.clone() {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        self.listeners.push(bind_unix_socket(&path)?);
+        info!(%path, "Unix socket listening");
+        Ok(())
+    }
+
+    // If the config sets a WebSocket port, bind it on the server's IP address and accept WebSocket clients alongside the others.
+    fn start_websocket(&mut self) -> io::Result<()> {
+        let port = match self.state.lock().unwrap().config.websocket_port {
+            Some(port) => port,
+            None => return Ok(())
+        };
+        let listener = WebSocketListener::new(TcpListener::bind(format!("{}:{}", self.ip, port))?);
+        info!(address = %listener.local_addr()?, "WebSocket listening");
+        self.add_listener(listener);
+        Ok(())
+    }
+
+    // If the config sets a metrics port, bind it on the server's IP address and serve metrics over HTTP from a new thread.
+    fn start_metrics(&self) -> io::Result<()> {
+        let port = match self.state.lock().unwrap().config.metrics_port {
+            Some(port) => port,
+            None => return Ok(())
+        };
+        let listener = TcpListener::bind(format!("{}:{}", self.ip, port))?;
+        info!(address = %listener.local_addr()?, "Metrics endpoint listening");
+        serve_metrics(listener, self.state.clone(), self.shutdown.clone());
+        Ok(())
+    }
+
+    // If the config sets an admin port, bind it on the admin IP address and accept admin commands from a new thread.
+    // The admin channel uses the server's TLS certificate if it has one.
+    fn start_admin(&self) -> io::Result<()> {
+        let (ip, port) = {
+            let state_lock = self.state.lock().unwrap();
+            match state_lock.config.admin_port {
+                Some(port) => (state_lock.config.admin_ip.clone(), port),
+                None => return Ok(())
+            }
+        };
+        let listener = TcpListener::bind(format!("{}:{}", ip, port))?;
+        let address = listener.local_addr()?;
+        info!(%address, tls = self.tls.is_some(), "Admin channel listening");
+        if !address.ip().is_loopback() && self.tls.is_none() {
+            warn!(%address, "Admin channel is reachable from other machines without TLS. Admin tokens are sent in plain text.");
         }
+        let listener: Box<dyn Listener> = match self.tls.clone() {
+            Some(tls) => Box::new(TlsListener::new(listener, tls)),
+            None => Box::new(listener)
+        };
+        serve_admin(listener, self.state.clone(), self.shutdown.clone());
+        Ok(())
+    }
+
+    // Restore the last snapshot if there is one, then spawn a thread that takes a new snapshot every interval.
+    // The same thread gives up the seats of restored players whose grace period has ended.
+    fn start_snapshots(&self) {
+        let path = self.snapshot_path.clone().unwrap();
+        if fs::metadata(&path).is_ok() {
+            let mut state_lock = self.state.lock().unwrap();
+            match restore_snapshot(state_lock.deref_mut(), &path, self.reconnect_grace_period) {
+                Ok(restored) => info!(restored, %path, "Restored game sessions from snapshot"),
+                Err(e) => error!(error = %e, %path, "Snapshot restore error")
+            }
+        }
+
+        let state_clone = self.state.clone();
+        let interval = self.snapshot_interval;
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                let mut state_lock = state_clone.lock().unwrap();
+
+                // The final snapshot is taken during shutdown. Taking one after it would lose the lobbies of clients that were disconnected.
+                if state_lock.shutting_down {
+                    break;
+                }
+                expire_reconnections(state_lock.deref_mut());
+                if let Err(e) = save_snapshot(&state_lock, &path) {
+                    error!(error = %e, %path, "Snapshot save error");
+                }
+            }
+        });
     }
 
     // Listen for any incoming client connections. For each one, split off into a new thread.
-    // The main thread will listen for incoming client connections.
+    // The main thread will listen for incoming client connections until shutdown is requested.
     fn start_server_loop(&mut self) {
+        // The TCP listener, over TLS if it's set up, is checked first, then any listeners that were added.
+        let mut listeners: Vec<Box<dyn Listener>> = match (self.listener.take(), self.tls.clone()) {
+            (Some(listener), Some(tls)) => vec![Box::new(TlsListener::new(listener, tls))],
+            (Some(listener), None) => vec![Box::new(listener)],
+            (None, _) => vec![]
+        };
+        listeners.append(&mut self.listeners);
+        for listener in listeners.iter() {
+            if let Err(e) = listener.start() {
+                error!(error = %e, "Listener start error");
+                return;
+            }
+        }
+
         // Wait for incoming connection attempts.
-        for stream in self.listener.as_ref().unwrap().incoming() {
-            // Make sure getting the stream is successful.
-            match stream {
-                Ok(stream) => {
-                    println!("New connection: {}", stream.peer_addr().unwrap());
-                    self.listen_to_client(stream);
-                },
-                Err(e) => {
-                    println!("Incoming stream error: {}", e);
+        while !self.shutdown.is_shutdown_requested() {
+            let mut accepted = false;
+            for listener in listeners.iter() {
+                match listener.accept() {
+                    Ok(Some(connection)) => {
+                        let peer = connection.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+                        info!(%peer, "New connection");
+                        accepted = true;
+                        let client_thread = self.listen_to_client(connection);
+
+                        // Forget connections that have already closed
+                        self.client_threads.retain(|(_, handle)| !handle.is_finished());
+                        self.client_threads.push(client_thread);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!(error = %e, "Incoming stream error");
+                    }
                 }
             }
+            if !accepted {
+                thread::sleep(Duration::from_millis(50));
+            }
         }
+
+        // Stop accepting connections before clients are told the server is shutting down
+        drop(listeners);
+        self.shut_down();
+    }
+
+    // Stop accepting connections and tell every client the server is shutting down. Give running games until the drain timeout to finish,
+    // then take a final snapshot if snapshots are enabled. Finally disconnect everyone and wait for each client's thread to finish.
+    fn shut_down(&mut self) {
+        info!("Server shutting down");
+        let mut state_lock = self.state.lock().unwrap();
+        state_lock.shutting_down = true;
+        for client in state_lock.clients.values() {
+            client.socket.send_message(build_unsolicited_message(client.socket.codec(), StatusCode::ServerShuttingDown, "Server is shutting down."));
+        }
+        drop(state_lock);
+
+        // Wait for games that haven't reached their end condition yet
+        let deadline = Instant::now() + self.shutdown.get_drain_timeout();
+        while Instant::now() < deadline && self.state.lock().unwrap().games_in_progress.values().any(|game| !game.end_condition_met().0) {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        if let Some(path) = &self.snapshot_path {
+            if let Err(e) = save_snapshot(&self.state.lock().unwrap(), path) {
+                error!(error = %e, %path, "Snapshot save error");
+            }
+        }
+
+        // Closing each socket ends its client's listening loop
+        for (socket, _) in self.client_threads.iter() {
+            let _ = socket.shutdown();
+        }
+        for (_, handle) in self.client_threads.drain(..) {
+            if handle.join().is_err() {
+                error!("Client thread panicked during shutdown");
+            }
+        }
+        info!("Server shut down");
     }
 
     // Create a thread to continuously listen for requests coming from a client's socket.
-    fn listen_to_client(&self, stream: TcpStream) {
+    // Returns the socket and the thread so the server can close the connection and wait for the thread when shutting down.
+    fn listen_to_client(&self, connection: Box<dyn Connection>) -> (Arc<ClientSocket>, JoinHandle<()>) {
 
         // Clone server state pointer to use in the newly spawned thread.
         let state_clone = self.state.clone();
-        let client_socket = Arc::new(stream); // Wrap client socket in a thread safe pointer
-        thread::spawn(move|| {
+        let (config, metrics) = {
+            let state_lock = self.state.lock().unwrap();
+            (state_lock.config.clone(), state_lock.metrics.clone())
+        };
+
+        // A client that never finishes the handshake is dropped instead of holding its thread forever.
+        if let Err(e) = connection.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+            warn!(error = %e, "Client socket read timeout error");
+        }
+        let client_socket = Arc::new(ClientSocket::new(connection, metrics.clone())); // Wrap client socket in a thread safe pointer
+        let socket_clone = client_socket.clone();
+        let handle = thread::spawn(move|| {
+            // Everything logged for this connection is tagged with the client's address, and its ID once it has one.
+            let peer = client_socket.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+            let connection_span = info_span!("connection", %peer, client_id = field::Empty);
+            let _connection_guard = connection_span.enter();
+
+            // Nothing is read or sent until the connection is set up, e.g. a TLS client has finished its handshake.
+            if let Err(e) = client_socket.handshake() {
+                warn!(error = %e, "Connection handshake error");
+                if let Err(e) = client_socket.shutdown() {
+                    debug!(error = %e, "Socket with client shutdown error");
+                }
+                return;
+            }
+
+            // From here on reads time out once the client has been quiet for longer than the idle timeout, if there is one.
+            if let Err(e) = client_socket.set_read_timeout(config.idle_timeout.map(Duration::from_secs)) {
+                warn!(error = %e, "Client socket read timeout error");
+            }
+
             // Initialize client ID as an empty string, indicating that it does not have an active session yet.
             let mut client_id = "".to_string();
+            let mut rate_limiter = RateLimiter::default();
+            let mut strikes = Strikes::new(&config);
+            let mut last_rejected_id = None; // ID of the last message rejected before the state was locked, so the next one isn't taken as out of order
+            let mut reader = BufReader::with_capacity(config.read_buffer_size, client_socket.deref().deref());
             loop {
-                let mut buffer = [0; 4096];
+                let mut buffer = vec![];
 
-                // Read stream data into the buffer.
-                match client_socket.as_ref().read(&mut buffer) {
+                // Read the next message into the buffer.
+                match read_message(&mut reader, CLIENT_HEADER_SIZE, Some(config.max_message_size), client_socket.compression(), &mut buffer) {
                     Ok(size) => {
                         // If size of data is more than 0, then this is a message we are receiving.
                         // If size is 0, then socket is closed, so formally shut it down.
                         if size > 0 {
                             let (message_id, message_type, remainder) = parse_client_message_header(&buffer);
-                            println!("Request from client {}: {:?}", client_id, message_type);
+                            metrics.record_request(message_type, size);
+                            let _handler_timer = metrics.time_handler(message_type);
+                            let message_span = debug_span!("message", message_id, ?message_type, lobby_id = field::Empty);
+                            let _message_guard = message_span.enter();
+
+                            // Messages over the connection's rate limit are turned away before the server state is locked
+                            if !rate_limiter.allow(&config, message_type) {
+                                if !reject_rate_limited(&client_socket, &mut strikes) {
+                                    break;
+                                }
+                                last_rejected_id = Some(message_id);
+                                continue;
+                            }
 
                             // Set up variables pretty much each handler will need
                             let mut state_lock = state_clone.lock().unwrap();
                             let state_ref = state_lock.deref_mut();
+                            if let (Some(id), Some(client)) = (last_rejected_id.take(), state_ref.clients.get_mut(&client_id)) {
+                                client.next_message_id = id.wrapping_add(1);
+                            }
+                            if let Some(lobby_id) = state_ref.clients.get(&client_id).and_then(|client| client.lobby_id.as_deref()) {
+                                message_span.record("lobby_id", &lobby_id);
+                            }
+                            debug!("Request from client");
 
                             // If client is not authenticated by the server and stored as a connected client,
                             // then server will only accept ConnectRequests and send client an error otherwise.
                             let is_id_empty = client_id.is_empty();
                             if is_id_empty && matches!(message_type, MessageType::ConnectRequest) {
+                                // Turn away clients connecting from a banned address
+                                if is_banned(state_ref, &client_socket) {
+                                    info!("Banned client turned away");
+                                    client_socket.send_message(build_empty_response(StatusCode::Banned, MessageType::ConnectResponse));
+                                    break;
+                                }
+
                                 // If authentication is successful, add client to the server.
                                 let connect_message = parse_connect_request(remainder);
+
+                                // Speak the lower of the two protocol versions, and turn away clients too old for this server to talk to
+                                let protocol_version = match negotiate_protocol_version(connect_message.protocol_version) {
+                                    Some(protocol_version) => protocol_version,
+                                    None => {
+                                        info!(protocol_version = connect_message.protocol_version, "Client protocol version too old, turning client away");
+                                        client_socket.send_message(build_empty_response(StatusCode::IncompatibleProtocolVersion, MessageType::ConnectResponse));
+                                        break;
+                                    }
+                                };
                                 if connect_message.authenticate() {
-                                    // Create a new UUID for this client. Check for collisions.
-                                    let mut new_client_id = Uuid::new_v4().to_string();
-                                    let mut unique = false;
-                                    while !unique {
-                                        if state_ref.clients.contains_key(&new_client_id) {
-                                            new_client_id = Uuid::new_v4().to_string();
-                                        } else {
-                                            unique = true;
-                                        }
+                                    // A player from a restored snapshot can reclaim their previous ID and seat if their grace period hasn't ended.
+                                    // Client IDs are public, so it also takes the reconnect token the seat is held with.
+                                    let reconnecting = match (&connect_message.previous_client_id, &connect_message.reconnect_token) {
+                                        (Some(id), Some(token)) => state_ref.held_seats.get(id)
+                                            .filter(|seat| Instant::now() < seat.deadline)
+                                            .and_then(|seat| if secrets_match(&seat.reconnect_token, token) {
+                                                Some(id.clone())
+                                            } else {
+                                                warn!(previous_client_id = %id, "Reconnect with an invalid token");
+                                                None
+                                            }),
+                                        _ => None
+                                    };
+                                    if let Some(id) = &reconnecting {
+                                        state_ref.held_seats.remove(id);
+                                    }
+
+                                    // Turn away new clients once the server is full. Reconnecting players already have a seat.
+                                    if reconnecting.is_none() && state_ref.config.max_clients.is_some_and(|max| state_ref.clients.len() >= max) {
+                                        info!("Server full, turning client away");
+                                        client_socket.send_message(build_empty_response(StatusCode::ServerFull, MessageType::ConnectResponse));
+                                        break;
                                     }
+
+                                    // Otherwise create a new UUID for this client. Check for collisions.
+                                    let new_client_id = match &reconnecting {
+                                        Some(id) => id.clone(),
+                                        None => {
+                                            let mut new_client_id = Uuid::new_v4().to_string();
+                                            let mut unique = false;
+                                            while !unique {
+                                                if state_ref.clients.contains_key(&new_client_id) {
+                                                    new_client_id = Uuid::new_v4().to_string();
+                                                } else {
+                                                    unique = true;
+                                                }
+                                            }
+                                            new_client_id
+                                        }
+                                    };
                                     client_id = new_client_id.clone();
+                                    connection_span.record("client_id", &client_id.as_str());
+                                    info!(reconnected = reconnecting.is_some(), "Client connected");
+                                    state_ref.event_handlers.on_client_connected(&client_id, reconnecting.is_some());
+                                    let lobby_id = match &reconnecting {
+                                        Some(id) => state_ref.lobbies.values().find(|lobby| lobby.player_ids.contains(id)).map(|lobby| lobby.id.clone()),
+                                        None => None
+                                    };
+
+                                    // Every session starts as a guest, including a resumed one. The reconnect token only proves the player owns the seat,
+                                    // so a player that was logged in has to log in again to get their account back.
+                                    let profile = PlayerProfile::guest(&new_client_id);
+                                    if let Some(seat) = lobby_id.as_ref().and_then(|lobby_id| state_ref.lobbies.get_mut(lobby_id)).and_then(|lobby| lobby.players.iter_mut().find(|seat| seat.player_id == new_client_id)) {
+                                        *seat = profile.clone();
+                                    }
 
                                     // Create new client struct and add it to the hash map
                                     let new_client = Client {
                                         socket: client_socket.clone(),
                                         id: new_client_id.clone(),
-                                        lobby_id: None,
-                                        next_message_id: message_id + 1
+                                        lobby_id: lobby_id.clone(),
+                                        profile: profile.clone(),
+                                        friend_ids: vec![],
+                                        next_message_id: message_id + 1,
+                                        reconnect_token: new_secret()
                                     };
                                     state_ref.clients.insert(new_client_id.clone(), new_client);
-                                    client_socket.send_message(build_connect_response(StatusCode::Success, new_client_id));
+                                    // Use the first codec and compression the client asked for that the server knows, and every feature both support.
+                                    // The connect response itself is always JSON and never compressed.
+                                    let codec = connect_message.codecs.iter().find_map(|name| Codec::from_name(name)).unwrap_or_default();
+                                    let compression = state_ref.config.compression_threshold
+                                        .and_then(|threshold| connect_message.compression.iter().find_map(|name| Compression::from_name(name)).map(|compression| (compression, threshold)));
+                                    let features: Vec<Feature> = match &connect_message.features {
+                                        Some(names) => Feature::ALL.into_iter().filter(|feature| names.iter().any(|name| name == feature.name())).collect(),
+                                        None => Feature::UNVERSIONED.to_vec()
+                                    };
+                                    let _ = client_socket.codec.set(codec);
+                                    let _ = client_socket.features.set(features.clone());
+                                    client_socket.send_message(build_connect_response(StatusCode::Success, &ConnectResponse {
+                                        reconnect_token: state_ref.clients.get(&new_client_id).unwrap().reconnect_token.clone(),
+                                        client_id: new_client_id,
+                                        profile,
+                                        codec,
+                                        compression: compression.map(|(compression, _)| compression),
+                                        compression_threshold: compression.map(|(_, threshold)| threshold).unwrap_or_default(),
+                                        protocol_version: Some(protocol_version),
+                                        features: Some(features.iter().map(|feature| feature.name().to_string()).collect())
+                                    }));
+                                    if let Some(compression) = compression {
+                                        let _ = client_socket.compression.set(compression);
+                                    }
+
+                                    // Send a reconnected player their lobby and the game they were playing so they can continue.
+                                    // The rest of the lobby gets the lobby too, since the player's profile is back to a guest one.
+                                    if let Some(lobby_id) = lobby_id {
+                                        let lobby = state_ref.lobbies.get(&lobby_id).unwrap();
+                                        send_to_players(&state_ref.clients, &lobby.player_ids, |codec| build_lobby_info_response(codec, StatusCode::Success, lobby.clone()));
+
+                                        // Clients that know GameStateRequest ask for the game state themselves once they have resumed
+                                        if protocol_version < GAME_STATE_REQUEST_VERSION {
+                                            send_full_game_state(state_ref, &client_socket, &lobby_id, &client_id);
+                                        }
+                                    }
                                     continue;
                                 }
                             } else if is_id_empty {
                                 // Send client an error if it does not have an ID, indicating that it has not been added to the server as an active client.
-                                client_socket.send_message(build_server_headers(StatusCode::NoActiveSession, MessageType::ConnectResponse));
+                                client_socket.send_message(build_empty_response(StatusCode::NoActiveSession, MessageType::ConnectResponse));
                                 continue;
                             }
 
@@ -182,129 +860,114 @@ impl GameProtocolServer {
                                 let expected_id = state_ref.clients.get(&client_id).unwrap().next_message_id;
                                 // If the incoming message ID does not match the expected ID, then gather a list of missing IDs.
                                 if message_id != expected_id {
-                                    let mut missing = vec![];
+                                    // IDs wrap around after u32::MAX, so an ID from before the expected one looks like a gap of almost u32::MAX.
+                                    // Gaps that large can't come from lost messages, and listing them would take gigabytes, so the client is disconnected.
+                                    let gap = message_id.wrapping_sub(expected_id);
+                                    if gap > state_ref.config.max_missing_messages {
+                                        warn!(expected_id, gap, "Client skipped too many message IDs, disconnecting");
+                                        client_socket.send_message(build_unsolicited_message(client_socket.codec(), StatusCode::TooManyMissingMessages, "Disconnected for sending a message ID too far from the expected one."));
+                                        break;
+                                    }
 
-                                    if message_id > expected_id {
-                                        // Received message ID is farther ahead than what is expected. Request the messages between
-                                        let diff = message_id - expected_id;
-                                        for id in expected_id..=expected_id + diff {
-                                            missing.push(id);
-                                        }
-                                    } else {
-                                        // Received message ID is less than what is expected. Need to get IDs that wrap around u32::MAX
-                                        for id in expected_id..=u32::MAX {
-                                            missing.push(id)
-                                        }
+                                    // Each out of order message costs a strike, so a client can't keep the server busy answering them
+                                    if !take_strike(&client_socket, &mut strikes) {
+                                        break;
+                                    }
 
-                                        for id in 0..=message_id {
-                                            missing.push(id);
-                                        }
-                                    };
                                     // Send missing message response and continue back to the beginning of the loop, waiting for the missing messages to be received.
-                                    client_socket.send_message(build_missing_message_response(missing));
+                                    let missing = (0..=gap).map(|offset| expected_id.wrapping_add(offset)).collect();
+                                    client_socket.send_message(build_missing_message_response(client_socket.codec(), missing));
                                     continue;
                                 }
+
+                                // Logged in players also share limits across their connections
+                                if let Some(account_id) = state_ref.clients.get(&client_id).unwrap().profile.account_id.clone() {
+                                    if !state_ref.account_rate_limits.entry(account_id).or_default().allow(&state_ref.config, message_type) {
+                                        advance_message_id(state_ref.clients.get_mut(&client_id).unwrap());
+                                        if reject_rate_limited(&client_socket, &mut strikes) {
+                                            continue;
+                                        }
+                                        break;
+                                    }
+                                }
                             }
 
                             // If the client ID is not an empty string, indicating it has an active session, then we handle any type of message from the client.
+                            let mut account_request = None;
                             match message_type {
+                                // Requests of features the client didn't agree on when connecting are answered like unknown requests
+                                _ if Feature::of_message_type(message_type).is_some_and(|feature| !client_socket.supports_feature(feature)) => {
+                                    client_socket.send_message(build_empty_response(StatusCode::UnsupportedRequestType, MessageType::ProtocolError));
+                                }
                                 MessageType::DisconnectRequest => {
                                     // Break out of the listening loop and handle clean up at the bottom of this function.
-                                    let response = build_server_headers(StatusCode::Success, MessageType::DisconnectResponse);
+                                    let response = build_empty_response(StatusCode::Success, MessageType::DisconnectResponse);
                                     client_socket.send_message(response);
                                     break;
                                 }
                                 MessageType::LobbyListRequest => {
                                     // Simply collect the lobbies stored in the hash map and put it in a vector
-                                    let lobbies = state_ref.lobbies.clone().into_values().collect();
-                                    let response = build_lobby_list_response(StatusCode::Success, &lobbies);
+                                    let lobbies: Vec<Lobby> = state_ref.lobbies.clone().into_values().collect();
+                                    let response = build_lobby_list_response(client_socket.codec(), StatusCode::Success, &lobbies);
                                     client_socket.send_message(response);
                                 }
                                 MessageType::CreateLobbyRequest => {
-                                    match parse_message_data::<CreateLobbyRequest>(remainder) {
+                                    match parse_message_data::<CreateLobbyRequest>(client_socket.codec(), remainder) {
                                         Ok(req) => {
-                                            // Check if server supports the game. Otherwise send an error.
-                                            if state_ref.supported_games.contains_key(&req.game_type_id) {
-                                                let client = state_ref.clients.get_mut(&client_id).unwrap();
-
-                                                if client.lobby_id.is_none() {
-                                                    // Create a new UUID for this lobby. Check for collisions.
-                                                    let mut new_lobby_id = Uuid::new_v4().to_string();
-                                                    let mut unique = false;
-                                                    while !unique {
-                                                        if state_ref.lobbies.contains_key(&new_lobby_id) {
-                                                            new_lobby_id = Uuid::new_v4().to_string();
-                                                        } else {
-                                                            unique = true;
-                                                        }
-                                                    }
-
-                                                    // Create new lobby object
-                                                    let new_lobby = Lobby {
-                                                        owner: client.id.clone(),
-                                                        id: new_lobby_id.clone(),
-                                                        player_ids: vec![client.id.clone()],
-                                                        game_started: false,
-                                                        game_metadata: state_ref.supported_games.get(&req.game_type_id).unwrap().get_metadata().clone()
-                                                    };
-
-                                                    // Set client's lobby to the newly created one, add lobby to server, send lobby info to client
-                                                    client.lobby_id = Some(new_lobby_id.clone());
-                                                    state_ref.lobbies.insert(new_lobby_id, new_lobby.clone());
-                                                    client_socket.send_message(build_lobby_info_response(StatusCode::Success, new_lobby.clone()));
+                                            // Check if server offers the game. Otherwise send an error.
+                                            if game_offered(state_ref, &req.game_type_id) {
+                                                if state_ref.clients.get(&client_id).unwrap().lobby_id.is_some() {
+                                                    client_socket.send_message(build_empty_response(StatusCode::AlreadyInALobby, MessageType::ProtocolError));
+                                                } else if lobby_limit_reached(state_ref, &req.game_type_id) {
+                                                    client_socket.send_message(build_empty_response(StatusCode::LobbyLimitReached, MessageType::ProtocolError));
+                                                } else if let Err(status) = state_ref.admission_policy.check_create_lobby(&AdmissionContext::new(state_ref), &client_id, &req.game_type_id) {
+                                                    client_socket.send_message(build_empty_response(status, MessageType::ProtocolError));
                                                 } else {
-                                                    client_socket.send_message(build_server_headers(StatusCode::AlreadyInALobby, MessageType::ProtocolError));
+                                                    // Create the lobby and send its info to the client
+                                                    let new_lobby = create_lobby(state_ref, &client_id, &req.game_type_id);
+                                                    client_socket.send_message(build_lobby_info_response(client_socket.codec(), StatusCode::Success, new_lobby));
                                                 }
                                             } else {
-                                                client_socket.send_message(build_server_headers(StatusCode::UnsupportedGame, MessageType::ProtocolError));
+                                                client_socket.send_message(build_empty_response(StatusCode::UnsupportedGame, MessageType::ProtocolError));
                                             }
                                         }
                                         Err(e) => {
-                                            client_socket.send_message(build_server_error_response(e));
+                                            client_socket.send_server_error(e);
                                         }
                                     }
                                 }
                                 MessageType::SupportedGamesRequest => {
-                                    // Simply collect supported game IDs and send it to client.
-                                    let games = state_ref.supported_games.keys().cloned().collect();
-                                    let response = build_supported_game_response(StatusCode::Success, &games);
+                                    // Simply collect the IDs of games the server offers and send it to client.
+                                    let games: Vec<String> = state_ref.supported_games.keys().filter(|id| game_offered(state_ref, id)).cloned().collect();
+                                    let response = build_supported_game_response(client_socket.codec(), StatusCode::Success, &games);
                                     client_socket.send_message(response);
                                 }
                                 MessageType::JoinLobbyRequest => {
-                                    match parse_message_data::<JoinLobbyRequest>(remainder) {
+                                    match parse_message_data::<JoinLobbyRequest>(client_socket.codec(), remainder) {
                                         Ok(req) => {
-                                            let client = state_ref.clients.get_mut(&client_id).unwrap();
-
                                             // Make sure client isn't already in a lobby
-                                            if client.lobby_id.is_none() {
+                                            if state_ref.clients.get(&client_id).unwrap().lobby_id.is_none() {
                                                 if state_ref.lobbies.contains_key(&req.lobby_id) {
-                                                    let lobby = state_ref.lobbies.get_mut(&req.lobby_id).unwrap();
-
-                                                    // If lobby isn't full, add the client to the lobby, send other connected clients updated lobby info, and send client lobby info
-                                                    if !lobby.is_full() && !lobby.game_started {
-                                                        // Update lobby and client
-                                                        lobby.player_ids.push(client_id.clone());
-                                                        client.lobby_id = Some(req.lobby_id.clone());
-
-                                                        // Build response to send to all clients in lobby, including the newly added one.
-                                                        let response = build_lobby_info_response(StatusCode::Success, lobby.clone());
-                                                        for client_id in lobby.player_ids.iter() {
-                                                            state_ref.clients.get(client_id).unwrap().socket.send_message(response.clone());
+                                                    let lobby = state_ref.lobbies.get(&req.lobby_id).unwrap();
+
+                                                    // If the policy admits the client, add the client to the lobby, send other connected clients updated lobby info, and send client lobby info
+                                                    match state_ref.admission_policy.check_join_lobby(&AdmissionContext::new(state_ref), &client_id, lobby) {
+                                                        Ok(_) => {
+                                                            join_lobby(state_ref, &client_id, &req.lobby_id);
+                                                        }
+                                                        Err(status) => {
+                                                            client_socket.send_message(build_empty_response(status, MessageType::ProtocolError));
                                                         }
-                                                    } else if lobby.game_started {
-                                                        client_socket.send_message(build_server_headers(StatusCode::GameStarted, MessageType::ProtocolError));
-                                                    } else {
-                                                        client_socket.send_message(build_server_headers(StatusCode::LobbyFull, MessageType::ProtocolError));
                                                     }
                                                 } else {
-                                                    client_socket.send_message(build_server_headers(StatusCode::LobbyNotFound, MessageType::ProtocolError));
+                                                    client_socket.send_message(build_empty_response(StatusCode::LobbyNotFound, MessageType::ProtocolError));
                                                 }
                                             } else {
-                                                client_socket.send_message(build_server_headers(StatusCode::AlreadyInALobby, MessageType::ProtocolError));
+                                                client_socket.send_message(build_empty_response(StatusCode::AlreadyInALobby, MessageType::ProtocolError));
                                             }
                                         }
                                         Err(e) => {
-                                            client_socket.send_message(build_server_error_response(e));
+                                            client_socket.send_server_error(e);
                                         }
                                     }
                                 }
@@ -313,137 +976,131 @@ impl GameProtocolServer {
                                     let client = state_ref.clients.get(&client_id).unwrap();
                                     if let Some(lobby_id) = client.lobby_id.clone() {
                                         let lobby = state_ref.lobbies.get(&lobby_id).unwrap();
-                                        client_socket.send_message(build_lobby_info_response(StatusCode::Success, lobby.clone()));
+                                        client_socket.send_message(build_lobby_info_response(client_socket.codec(), StatusCode::Success, lobby.clone()));
                                     } else {
                                         // If not in a lobby, send a lobby error with NotInLobby
-                                        client_socket.send_message(build_server_headers(StatusCode::NotInLobby, MessageType::ProtocolError));
+                                        client_socket.send_message(build_empty_response(StatusCode::NotInLobby, MessageType::ProtocolError));
                                     }
                                 }
                                 MessageType::LeaveLobbyRequest => {
                                     // If the client is in a lobby, then leave it.
-                                    let client = state_ref.clients.get_mut(&client_id).unwrap();
-                                    if client.lobby_id.is_some() {
-                                        // Find the lobby the client is in
-                                        let lobby_id = client.lobby_id.as_ref().unwrap().clone();
-                                        let mut found_lobby = state_ref.lobbies.get_mut(&lobby_id).unwrap();
-
-                                        // Find the position in which the player is in the lobby and remove it
-                                        let client_position = found_lobby.player_ids.iter().position(|id|
-                                            id.eq(&client.id)
-                                        ).unwrap();
-                                        found_lobby.player_ids.remove(client_position);
-                                        client.lobby_id = None;
-
-                                        // If the lobby is empty, remove it from the server
-                                        if found_lobby.player_ids.len() == 0 {
-                                            state_ref.lobbies.remove(&lobby_id);
-                                        } else {
-                                            // If client is owner, transfer ownership to another client
-                                            if found_lobby.owner.eq(&client_id) {
-                                                found_lobby.owner = found_lobby.player_ids[0].clone();
-                                            }
-
-                                            // Otherwise send remaining clients an updated lobby state
-                                            let response = build_lobby_info_response(StatusCode::Success, found_lobby.clone());
-
-                                            // Send all clients an updated lobby state.
-                                            for id in found_lobby.player_ids.iter() {
-                                                state_ref.clients.get(id).unwrap().socket.send_message(response.clone());
-                                            }
-                                        }
+                                    if state_ref.clients.get(&client_id).unwrap().lobby_id.is_some() {
+                                        leave_lobby(state_ref, &client_id);
 
                                         // Send the client a LeaveLobbyResponse, confirming that the server has removed the client from the lobby
-                                        client_socket.send_message(build_server_headers(StatusCode::Success, MessageType::LeaveLobbyResponse));
+                                        client_socket.send_message(build_empty_response(StatusCode::Success, MessageType::LeaveLobbyResponse));
+
+                                        // Leaving in the middle of a tournament match forfeits it.
+                                        forfeit_tournament_matches(state_ref, &client_id);
                                     } else {
-                                        client_socket.send_message(build_server_headers(StatusCode::NotInLobby, MessageType::LeaveLobbyResponse));
+                                        client_socket.send_message(build_empty_response(StatusCode::NotInLobby, MessageType::LeaveLobbyResponse));
                                     }
                                 }
+                                MessageType::StartGameRequest if state_ref.shutting_down => {
+                                    client_socket.send_message(build_empty_response(StatusCode::ServerShuttingDown, MessageType::ProtocolError));
+                                }
                                 MessageType::StartGameRequest => {
-                                    match parse_message_data::<StartGameRequest>(remainder) {
+                                    match parse_message_data::<StartGameRequest>(client_socket.codec(), remainder) {
                                         Ok(req) => {
                                             // Check if client is in a lobby first.
                                             let client = state_ref.clients.get(&client_id).unwrap();
                                             if let Some(lobby_id) = &client.lobby_id {
-                                                let lobby = state_ref.lobbies.get_mut(lobby_id).unwrap();
-                                                let player_req_met =
-                                                    lobby.player_ids.len() >= lobby.game_metadata.min_required_players &&
-                                                        lobby.player_ids.len() <= lobby.game_metadata.max_players;
-
-                                                // Start game if player requirement is met, requested lobby is the one the client is in, and if the client is the owner of the lobby
-                                                if req.lobby_id.eq(lobby_id) && lobby.owner.eq(&client_id) && player_req_met {
-                                                    let mut new_game = state_ref.supported_games.get(&lobby.game_metadata.get_game_type_id()).unwrap().init_new();
-
-                                                    // Add client IDs to game session
-                                                    for id in lobby.player_ids.iter() {
-                                                        new_game.add_player(id.clone());
-                                                    }
-
-                                                    // Tie game session to lobby by using the lobby ID as a key for the game session hash map
-                                                    state_ref.games_in_progress.insert(lobby_id.clone(), new_game);
+                                                let lobby = state_ref.lobbies.get(lobby_id).unwrap();
 
-                                                    // Get the game state for the newly created game session and send it to the clients that are in the lobby
-                                                    for id in lobby.player_ids.iter() {
-                                                        let game = state_ref.games_in_progress.get(lobby_id).unwrap();
-                                                        let game_state = build_game_state_response(StatusCode::Success, game.get_game_state());
-                                                        state_ref.clients.get(id).unwrap().socket.send_message(game_state);
-                                                    }
-
-                                                    // Set lobby as the game is in progress
-                                                    lobby.game_started = true;
+                                                // Start game if the requested lobby is the one the client is in and the policy allows it.
+                                                // A running game is never restarted, and tournament matches are only started by the tournament runner.
+                                                if !req.lobby_id.eq(lobby_id) {
+                                                    client_socket.send_message(build_empty_response(StatusCode::GameStartCriteriaNotMet, MessageType::ProtocolError));
+                                                } else if lobby.game_started || state_ref.games_in_progress.contains_key(lobby_id) {
+                                                    client_socket.send_message(build_empty_response(StatusCode::GameStarted, MessageType::ProtocolError));
+                                                } else if lobby.tournament_id.is_some() {
+                                                    client_socket.send_message(build_empty_response(StatusCode::GameStartCriteriaNotMet, MessageType::ProtocolError));
+                                                } else if let Err(status) = state_ref.admission_policy.check_start_game(&AdmissionContext::new(state_ref), &client_id, lobby) {
+                                                    client_socket.send_message(build_empty_response(status, MessageType::ProtocolError));
                                                 } else {
-                                                    client_socket.send_message(build_server_headers(StatusCode::GameStartCriteriaNotMet, MessageType::ProtocolError));
+                                                    let lobby_id = lobby_id.clone();
+                                                    start_game(state_ref, &lobby_id);
                                                 }
                                             } else {
                                                 // If not in lobby, send error
-                                                client_socket.send_message(build_server_headers(StatusCode::NotInLobby, MessageType::ProtocolError));
+                                                client_socket.send_message(build_empty_response(StatusCode::NotInLobby, MessageType::ProtocolError));
                                             }
                                         }
                                         Err(e) => {
-                                            client_socket.send_message(build_server_error_response(e));
+                                            client_socket.send_server_error(e);
                                         }
                                     }
                                 }
                                 MessageType::MoveRequest => {
-                                    match parse_message_data::<Box<dyn GameMove>>(remainder) {
+                                    match parse_message_data::<Box<dyn GameMove>>(client_socket.codec(), remainder) {
                                         Ok(req) => {
                                             // If client wasn't in a lobby, it's definitely not in a game session.
                                             // Also lobby ID is used for the game session hash map too.
                                             let client = state_ref.clients.get(&client_id).unwrap();
                                             if let Some(lobby_id) = &client.lobby_id {
+                                                let admitted = state_ref.admission_policy.check_move(&AdmissionContext::new(state_ref), &client_id, state_ref.lobbies.get(lobby_id).unwrap(), req.as_ref());
+
                                                 // Make sure there is a game session associated with a lobby
                                                 if let Some(game) = state_ref.games_in_progress.get_mut(lobby_id) {
 
                                                     // Check some conditions to see if the game is over or not
                                                     let game_ended = game.end_condition_met().0;
-                                                    let is_move_valid = game.is_valid_move(&req);
-                                                    if is_move_valid && !game_ended {
-                                                        game.apply_move(&req);
+                                                    let is_move_valid = game.is_valid_move_by(&client_id, req.as_ref());
+                                                    if let Err(status) = admitted {
+                                                        client.socket.send_message(build_empty_response(status, MessageType::ProtocolError));
+                                                    } else if is_move_valid && !game_ended {
+                                                        game.apply_move(req.as_ref());
+                                                        state_ref.event_handlers.on_move_applied(lobby_id, &client_id, req.as_ref());
+
+                                                        // Keep every applied move so the finished game can be stored and replayed
+                                                        if let Some(record) = state_ref.game_records.get_mut(lobby_id) {
+                                                            match serde_json::to_string(&req) {
+                                                                Ok(serialized_move) => record.moves.push(serialized_move),
+                                                                Err(e) => error!(error = %e, "Move serialization error")
+                                                            }
+                                                        }
 
                                                         // After applying a move to the game state, send all participating clients the updated game state
-                                                        let game_state = build_game_state_response(StatusCode::Success, game.get_game_state());
-                                                        for id in state_ref.lobbies.get(lobby_id).unwrap().player_ids.iter() {
-                                                            state_ref.clients.get(id).unwrap().socket.send_message(game_state.clone());
+                                                        let (game_ended, winner) = game.end_condition_met();
+                                                        let lobby_id = lobby_id.clone();
+                                                        send_game_state(state_ref, &lobby_id);
+
+                                                        // If this move ended the game, record the result.
+                                                        // Moves are rejected once the game is over, so this only happens once per game session.
+                                                        if game_ended {
+                                                            end_game(state_ref, &lobby_id, winner);
                                                         }
                                                     } else if game_ended {
                                                         // If client tries to make a move but game is over, send a GameOver game_protocol error.
-                                                        client.socket.send_message(build_server_headers(StatusCode::GameOver, MessageType::ProtocolError));
-                                                    } else if is_move_valid {
+                                                        client.socket.send_message(build_empty_response(StatusCode::GameOver, MessageType::ProtocolError));
+                                                    } else {
                                                         // If client's move is invalid, then return an error.
-                                                        client.socket.send_message(build_server_headers(StatusCode::InvalidMove, MessageType::ProtocolError));
+                                                        client.socket.send_message(build_empty_response(StatusCode::InvalidMove, MessageType::ProtocolError));
                                                     }
                                                 } else {
-                                                    client_socket.send_message(build_server_headers(StatusCode::GameSessionNotFound, MessageType::ProtocolError));
+                                                    client_socket.send_message(build_empty_response(StatusCode::GameSessionNotFound, MessageType::ProtocolError));
                                                 }
                                             } else {
-                                                client_socket.send_message(build_server_headers(StatusCode::NotInLobby, MessageType::ProtocolError));
+                                                client_socket.send_message(build_empty_response(StatusCode::NotInLobby, MessageType::ProtocolError));
                                             }
                                         }
                                         Err(e) => {
-                                            client_socket.send_message(build_server_error_response(e));
+                                            client_socket.send_server_error(e);
                                         }
                                     }
 
                                 }
+                                MessageType::GameStateRequest => {
+                                    // Send the client the current state of its game session as it sees it, e.g. to resync after reconnecting
+                                    let client = state_ref.clients.get(&client_id).unwrap();
+                                    match client.lobby_id.clone() {
+                                        Some(lobby_id) if state_ref.games_in_progress.contains_key(&lobby_id) => {
+                                            send_full_game_state(state_ref, &client_socket, &lobby_id, &client_id);
+                                        }
+                                        Some(_) => client_socket.send_message(build_empty_response(StatusCode::GameSessionNotFound, MessageType::ProtocolError)),
+                                        None => client_socket.send_message(build_empty_response(StatusCode::NotInLobby, MessageType::ProtocolError))
+                                    }
+                                }
                                 MessageType::ReturnToLobbyRequest => {
                                     // After a game session has ended, return client to the lobby
                                     let client = state_ref.clients.get(&client_id).unwrap();
@@ -454,96 +1111,810 @@ impl GameProtocolServer {
                                             game.remove_player(client.id.clone());
                                             if game.get_player_num() == 0 {
                                                 state_ref.games_in_progress.remove(lobby_id);
+                                                state_ref.game_records.remove(lobby_id);
+                                                state_ref.game_state_versions.remove(lobby_id);
                                                 state_ref.lobbies.get_mut(lobby_id).unwrap().game_started = false;
+                                                for id in state_ref.lobbies.get(lobby_id).unwrap().player_ids.iter() {
+                                                    update_presence(state_ref, id);
+                                                }
                                             }
 
                                             // Send client the lobby info so they know leaving the game session and returning to the lobby was successful.
                                             let lobby = state_ref.lobbies.get(lobby_id).unwrap();
-                                            client.socket.send_message(build_lobby_info_response(StatusCode::Success, lobby.clone()));
+                                            client.socket.send_message(build_lobby_info_response(client.socket.codec(), StatusCode::Success, lobby.clone()));
                                         } else {
-                                            client_socket.send_message(build_server_headers(StatusCode::GameSessionNotFound, MessageType::ProtocolError));
+                                            client_socket.send_message(build_empty_response(StatusCode::GameSessionNotFound, MessageType::ProtocolError));
                                         }
                                     } else {
-                                        client_socket.send_message(build_server_headers(StatusCode::NotInLobby, MessageType::ProtocolError));
+                                        client_socket.send_message(build_empty_response(StatusCode::NotInLobby, MessageType::ProtocolError));
                                     }
                                 }
+                                MessageType::RatingRequest => {
+                                    match parse_message_data::<RatingRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            // Ratings only exist for games the server supports.
+                                            if state_ref.supported_games.contains_key(&req.game_type_id) {
+                                                // Default to the requesting client's own rating if no player is specified.
+                                                let player_id = req.player_id.unwrap_or_else(|| rating_id(state_ref, &client_id));
+                                                let rating = state_ref.ratings.get(&player_id, &req.game_type_id);
+                                                client_socket.send_message(build_rating_response(client_socket.codec(), StatusCode::Success, rating));
+                                            } else {
+                                                client_socket.send_message(build_empty_response(StatusCode::UnsupportedGame, MessageType::ProtocolError));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::LeaderboardRequest => {
+                                    match parse_message_data::<LeaderboardRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            if state_ref.supported_games.contains_key(&req.game_type_id) {
+                                                let ratings = state_ref.ratings.leaderboard(&req.game_type_id, req.limit);
+                                                client_socket.send_message(build_leaderboard_response(client_socket.codec(), StatusCode::Success, &req.game_type_id, &ratings));
+                                            } else {
+                                                client_socket.send_message(build_empty_response(StatusCode::UnsupportedGame, MessageType::ProtocolError));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::MatchmakingRequest => {
+                                    match parse_message_data::<MatchmakingRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            if !game_offered(state_ref, &req.game_type_id) {
+                                                client_socket.send_message(build_empty_response(StatusCode::UnsupportedGame, MessageType::ProtocolError));
+                                            } else if state_ref.clients.get(&client_id).unwrap().lobby_id.is_some() {
+                                                client_socket.send_message(build_empty_response(StatusCode::AlreadyInALobby, MessageType::ProtocolError));
+                                            } else {
+                                                // Put the client in the open lobby with the closest rating. If there isn't one close enough, create a new lobby for others to be matched into.
+                                                let rating = state_ref.ratings.get(&rating_id(state_ref, &client_id), &req.game_type_id).rating;
+                                                let context = AdmissionContext::new(state_ref);
+                                                let admits = |lobby: &Lobby| state_ref.admission_policy.check_join_lobby(&context, &client_id, lobby).is_ok();
+                                                match find_lobby_for_rating(&state_ref.lobbies, &state_ref.ratings, &req.game_type_id, rating, state_ref.matchmaking_rating_window, admits) {
+                                                    Some(lobby_id) => {
+                                                        join_lobby(state_ref, &client_id, &lobby_id);
+                                                    }
+                                                    None if lobby_limit_reached(state_ref, &req.game_type_id) => {
+                                                        client_socket.send_message(build_empty_response(StatusCode::LobbyLimitReached, MessageType::ProtocolError));
+                                                    }
+                                                    None => {
+                                                        if let Err(status) = state_ref.admission_policy.check_create_lobby(&AdmissionContext::new(state_ref), &client_id, &req.game_type_id) {
+                                                            client_socket.send_message(build_empty_response(status, MessageType::ProtocolError));
+                                                        } else {
+                                                            let new_lobby = create_lobby(state_ref, &client_id, &req.game_type_id);
+                                                            client_socket.send_message(build_lobby_info_response(client_socket.codec(), StatusCode::Success, new_lobby));
+                                                            client_socket.send_message(build_unsolicited_message(client_socket.codec(), StatusCode::Success, "No lobbies near your rating were open, so a new lobby was created. Waiting for players."));
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::TournamentListRequest => {
+                                    let tournaments: Vec<TournamentInfo> = state_ref.tournaments.values().map(|t| t.info.clone()).collect();
+                                    client_socket.send_message(build_tournament_list_response(client_socket.codec(), StatusCode::Success, &tournaments));
+                                }
+                                MessageType::CreateTournamentRequest => {
+                                    match parse_message_data::<CreateTournamentRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            // The requesting client becomes the organizer and is the only one allowed to start the tournament.
+                                            if game_offered(state_ref, &req.game_type_id) {
+                                                let tournament_id = create_tournament(state_ref, &req.name, &req.game_type_id, req.format, Some(client_id.clone()));
+                                                let info = state_ref.tournaments.get(&tournament_id).unwrap().info.clone();
+                                                client_socket.send_message(build_tournament_info_response(client_socket.codec(), StatusCode::Success, info));
+                                            } else {
+                                                client_socket.send_message(build_empty_response(StatusCode::UnsupportedGame, MessageType::ProtocolError));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::JoinTournamentRequest | MessageType::LeaveTournamentRequest => {
+                                    match parse_message_data::<TournamentRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            match state_ref.tournaments.get_mut(&req.tournament_id) {
+                                                Some(tournament) => {
+                                                    let result = if matches!(message_type, MessageType::JoinTournamentRequest) {
+                                                        tournament.add_participant(&client_id)
+                                                    } else {
+                                                        tournament.remove_participant(&client_id)
+                                                    };
+
+                                                    // Let everyone involved know who is registered. A client that left won't be sent the update, so send it to them directly.
+                                                    match result {
+                                                        Ok(_) => {
+                                                            send_tournament_info(state_ref, &req.tournament_id);
+                                                            if matches!(message_type, MessageType::LeaveTournamentRequest) {
+                                                                let info = state_ref.tournaments.get(&req.tournament_id).unwrap().info.clone();
+                                                                client_socket.send_message(build_tournament_info_response(client_socket.codec(), StatusCode::Success, info));
+                                                            }
+                                                        }
+                                                        Err(status) => {
+                                                            client_socket.send_message(build_empty_response(status, MessageType::ProtocolError));
+                                                        }
+                                                    }
+                                                }
+                                                None => {
+                                                    client_socket.send_message(build_empty_response(StatusCode::TournamentNotFound, MessageType::ProtocolError));
+                                                }
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::StartTournamentRequest => {
+                                    match parse_message_data::<TournamentRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            match state_ref.tournaments.get(&req.tournament_id) {
+                                                Some(tournament) => {
+                                                    // Only the client that created the tournament can start it.
+                                                    if tournament.info.organizer.as_deref() == Some(client_id.as_str()) {
+                                                        if let Err(status) = start_tournament(state_ref, &req.tournament_id) {
+                                                            client_socket.send_message(build_empty_response(status, MessageType::ProtocolError));
+                                                        }
+                                                    } else {
+                                                        client_socket.send_message(build_empty_response(StatusCode::NotTournamentOrganizer, MessageType::ProtocolError));
+                                                    }
+                                                }
+                                                None => {
+                                                    client_socket.send_message(build_empty_response(StatusCode::TournamentNotFound, MessageType::ProtocolError));
+                                                }
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::RegisterRequest => {
+                                    match parse_message_data::<RegisterRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            account_request = Some(AccountRequest::Register(req));
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::LoginRequest => {
+                                    match parse_message_data::<LoginRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            account_request = Some(AccountRequest::Login(req));
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::UpdateProfileRequest => {
+                                    match parse_message_data::<UpdateProfileRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            match update_profile(state_ref, &client_id, req) {
+                                                Ok(profile) => client_socket.send_message(build_profile_response(client_socket.codec(), StatusCode::Success, profile)),
+                                                Err(status) => client_socket.send_message(build_empty_response(status, MessageType::ProfileResponse))
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::FriendListRequest => {
+                                    let result = match &state_ref.clients.get(&client_id).unwrap().profile.account_id {
+                                        Some(account_id) => friend_list(state_ref, account_id),
+                                        None => Err(StatusCode::NotLoggedIn)
+                                    };
+                                    match result {
+                                        Ok(friends) => client_socket.send_message(build_friend_list_response(client_socket.codec(), StatusCode::Success, &friends)),
+                                        Err(status) => client_socket.send_message(build_empty_response(status, MessageType::FriendListResponse))
+                                    }
+                                }
+                                MessageType::FriendRequest => {
+                                    match parse_message_data::<FriendRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            match send_friend_request(state_ref, &client_id, &req.username) {
+                                                Ok(friends) => client_socket.send_message(build_friend_list_response(client_socket.codec(), StatusCode::Success, &friends)),
+                                                Err(status) => client_socket.send_message(build_empty_response(status, MessageType::FriendListResponse))
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::AcceptFriendRequest | MessageType::RemoveFriendRequest => {
+                                    match parse_message_data::<FriendAccountRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            let result = if matches!(message_type, MessageType::AcceptFriendRequest) {
+                                                accept_friend_request(state_ref, &client_id, &req.account_id)
+                                            } else {
+                                                remove_friend(state_ref, &client_id, &req.account_id)
+                                            };
+                                            match result {
+                                                Ok(friends) => client_socket.send_message(build_friend_list_response(client_socket.codec(), StatusCode::Success, &friends)),
+                                                Err(status) => client_socket.send_message(build_empty_response(status, MessageType::FriendListResponse))
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::InviteToLobbyRequest => {
+                                    // Answered with an empty InviteToLobby carrying the status, so the inviting client knows whether it was delivered.
+                                    match parse_message_data::<FriendAccountRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            let status = match invite_to_lobby(state_ref, &client_id, &req.account_id) {
+                                                Ok(_) => StatusCode::Success,
+                                                Err(status) => status
+                                            };
+                                            client_socket.send_message(build_empty_response(status, MessageType::InviteToLobby));
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::ChatRequest => {
+                                    // The sender gets its own message back as the answer. Errors are sent as an empty ChatMessage.
+                                    match parse_message_data::<ChatRequest>(client_socket.codec(), remainder) {
+                                        Ok(req) => {
+                                            if let Err(status) = send_chat_message(state_ref, &client_id, &req.message) {
+                                                client_socket.send_message(build_empty_response(status, MessageType::ChatMessage));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            client_socket.send_server_error(e);
+                                        }
+                                    }
+                                }
+                                MessageType::Heartbeat => {
+                                    // Nothing to do. Receiving the heartbeat already reset the client's idle timeout.
+                                }
                                 MessageType::Unsupported => {
                                     // This and default case send an UnsupportedRequestType error.
-                                    client_socket.send_message(build_server_headers(StatusCode::UnsupportedRequestType, MessageType::ProtocolError));
+                                    client_socket.send_message(build_empty_response(StatusCode::UnsupportedRequestType, MessageType::ProtocolError));
                                 }
                                 _ => {
-                                    client_socket.send_message(build_server_headers(StatusCode::UnsupportedRequestType, MessageType::ProtocolError));
+                                    client_socket.send_message(build_empty_response(StatusCode::UnsupportedRequestType, MessageType::ProtocolError));
                                 }
                             };
 
                             // Increment expected message ID
                             if !is_id_empty {
-                                let client = state_ref.clients.get_mut(&client_id).unwrap();
-                                if client.next_message_id == u32::MAX {
-                                    client.next_message_id = 0;
-                                } else {
-                                    client.next_message_id += 1;
-                                }
+                                advance_message_id(state_ref.clients.get_mut(&client_id).unwrap());
+                            }
+
+                            // Passwords are hashed and checked without holding the server state lock, so other clients aren't held up
+                            if let Some(request) = account_request {
+                                drop(state_lock);
+                                answer_account_request(&state_clone, &client_socket, &client_id, request);
                             }
                         } else {
                             // Shut down socket if there are any issues.
-                            println!("Shutting down...");
-                            match client_socket.shutdown(Shutdown::Both) {
+                            info!("Client closed the connection");
+                            match client_socket.shutdown() {
                                 Ok(_) => {}
                                 Err(e) => {
-                                    println!("Socket with client shutdown error. {:?}", e);
+                                    debug!(error = %e, "Socket with client shutdown error");
                                 }
                             };
                             break;
                         }
                     }
                     Err(e) => {
-                        println!("Read from client socket error: {}", e);
+                        // Where the next message starts is unknown after a failed read, so the client is disconnected.
+                        match e.kind() {
+                            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                                info!("Client idle for too long, disconnecting");
+                            }
+                            ErrorKind::InvalidData => {
+                                warn!(error = %e, "Client sent a message that is too large or malformed, disconnecting");
+                                client_socket.send_message(build_empty_response(StatusCode::MalformedBody, MessageType::ProtocolError));
+                            }
+                            _ => {
+                                warn!(error = %e, "Read from client socket error");
+                            }
+                        }
+                        if let Err(e) = client_socket.shutdown() {
+                            debug!(error = %e, "Socket with client shutdown error");
+                        }
+                        break;
                     }
                 }
 
             }
             // When the listening loop exits, do clean up.
+            // Close the connection if the loop didn't, e.g. after turning the client away, so the client isn't left waiting.
             // Remove client from any lobby it's in.
             // Remove client from client list on server.
+            // Forfeit any tournament match the client was playing. Done last so the client can't be paired again if this finishes a round.
+            // Forget the client's ratings if it played as a guest.
+            // When the server is shutting down, lobbies and tournaments are left as they are, matching the final snapshot.
+            let _ = client_socket.shutdown();
             if !client_id.is_empty() {
                 let mut state_lock = state_clone.lock().unwrap();
                 let state_ref = state_lock.deref_mut();
-                let clients = &mut state_ref.clients;
-                let lobbies = &mut state_ref.lobbies;
-
-                // Get client being handled in this thread and check if it is in a lobby.
-                let client = clients.get_mut(&client_id).unwrap();
-                if client.lobby_id.is_some() {
-                    let lobby_id = client.lobby_id.as_ref().unwrap().clone();
-                    // Get the lobby the client is in, find where the client is in the lobby player_ids list and remove it.
-                    let mut found_lobby = lobbies.get_mut(&lobby_id).unwrap();
-                    let client_position = found_lobby.player_ids.iter().position(|id|
-                        id.eq(&client_id)
-                    ).unwrap();
-                    found_lobby.player_ids.remove(client_position);
-                    client.lobby_id = None; // Set client lobby_id to None just to be safe even though client is removed later.
-
-                    // If client is owner, transfer ownership to another client
-                    if found_lobby.owner.eq(&client_id) {
-                        found_lobby.owner = found_lobby.player_ids[0].clone();
-                    }
+                went_offline(state_ref, &client_id);
+                if state_ref.shutting_down {
+                    remove_client(state_ref, &client_id);
+                } else {
+                    leave_lobby(state_ref, &client_id);
+                    remove_client(state_ref, &client_id);
+                    forfeit_tournament_matches(state_ref, &client_id);
+                }
 
-                    // If there is a game going on, send remaining clients back to the lobby and close the game session.
-                    if state_ref.games_in_progress.contains_key(&lobby_id) {
-                        for id in found_lobby.player_ids.iter() {
-                            let message = build_lobby_info_response(StatusCode::Success, found_lobby.clone());
-                            clients.get(id).unwrap().socket.send_message(message);
-                        }
-                    }
+                // A guest's ratings are under its client ID, which no one uses again. Done after any forfeit has been rated.
+                state_ref.ratings.remove_guest(&client_id);
+                state_ref.event_handlers.on_client_disconnected(&client_id);
+            }
+        });
+        (socket_clone, handle)
+    }
+}
 
-                    // If the lobby is empty, remove it from the server
-                    if found_lobby.player_ids.len() == 0 {
-                        lobbies.remove(&lobby_id);
-                    }
+// Remove a disconnected client from the server. Its account's shared rate limits are dropped once none of the account's connections are left.
+fn remove_client(state: &mut GameProtocolServerState, client_id: &str) {
+    if let Some(account_id) = state.clients.remove(client_id).and_then(|client| client.profile.account_id) {
+        if !state.clients.values().any(|client| client.profile.account_id.as_ref() == Some(&account_id)) {
+            state.account_rate_limits.remove(&account_id);
+        }
+    }
+}
+
+// Create a new lobby hosting the given game with the client as its owner and only member. Returns a copy of the new lobby.
+fn create_lobby(state: &mut GameProtocolServerState, client_id: &str, game_type_id: &str) -> Lobby {
+    // Create a new UUID for this lobby. Check for collisions.
+    let mut new_lobby_id = Uuid::new_v4().to_string();
+    while state.lobbies.contains_key(&new_lobby_id) {
+        new_lobby_id = Uuid::new_v4().to_string();
+    }
+
+    // Create new lobby object
+    let new_lobby = Lobby {
+        owner: client_id.to_string(),
+        id: new_lobby_id.clone(),
+        player_ids: vec![client_id.to_string()],
+        players: vec![state.clients.get(client_id).unwrap().profile.clone()],
+        game_started: false,
+        game_metadata: state.supported_games.get(game_type_id).unwrap().get_metadata().clone(),
+        tournament_id: None
+    };
+
+    // Set client's lobby to the newly created one and add lobby to server
+    state.clients.get_mut(client_id).unwrap().lobby_id = Some(new_lobby_id.clone());
+    state.lobbies.insert(new_lobby_id, new_lobby.clone());
+    state.event_handlers.on_lobby_created(&new_lobby);
+    update_presence(state, client_id);
+    new_lobby
+}
+
+// Check whether a client's address has a ban that hasn't expired.
+fn is_banned(state: &GameProtocolServerState, socket: &ClientSocket) -> bool {
+    match socket.peer_addr() {
+        Ok(address) => ban_active(&state.bans, &address.ip().to_string()),
+        Err(_) => false
+    }
+}
+
+// Check whether an account ID or IP address has a ban or mute that hasn't expired.
+fn ban_active(bans: &HashMap<String, BanRecord>, target: &str) -> bool {
+    bans.get(target).is_some_and(|ban| ban.expires_at.is_none_or(|expires_at| expires_at > unix_time()))
+}
+
+// Answer a message that went over a rate limit. Each one costs the client a strike, and a client that runs out is told why and disconnected.
+// Returns false if the client was disconnected.
+fn reject_rate_limited(socket: &ClientSocket, strikes: &mut Strikes) -> bool {
+    if take_strike(socket, strikes) {
+        socket.send_message(build_empty_response(StatusCode::RateLimited, MessageType::ProtocolError));
+        true
+    } else {
+        false
+    }
+}
+
+// Count a strike against the client, disconnecting it if it has none left. Returns false if the client was disconnected.
+fn take_strike(socket: &ClientSocket, strikes: &mut Strikes) -> bool {
+    if strikes.strike() {
+        return true;
+    }
+    warn!("Client went over rate limits too often, disconnecting");
+    socket.send_message(build_unsolicited_message(socket.codec(), StatusCode::RateLimited, "Disconnected for sending too many messages."));
+    if let Err(e) = socket.shutdown() {
+        debug!(error = %e, "Socket with client shutdown error");
+    }
+    false
+}
+
+// Move on to the next message ID the client is expected to send. IDs wrap around after u32::MAX.
+fn advance_message_id(client: &mut Client) {
+    client.next_message_id = client.next_message_id.wrapping_add(1);
+}
+
+// ID a client's ratings are kept under. Its account ID if it is logged in, otherwise its client ID.
+fn rating_id(state: &GameProtocolServerState, client_id: &str) -> String {
+    match state.clients.get(client_id) {
+        Some(client) => client.profile.rating_id().to_string(),
+        None => client_id.to_string()
+    }
+}
+
+// Check whether new lobbies and tournaments can be created for a game type: it must be registered and allowed by the config.
+// Lobbies and tournaments that already exist keep running when the config stops allowing their game type.
+fn game_offered(state: &GameProtocolServerState, game_type_id: &str) -> bool {
+    state.supported_games.contains_key(game_type_id)
+        && state.config.allowed_game_types.as_ref().is_none_or(|allowed| allowed.iter().any(|id| id == game_type_id))
+}
+
+// Check whether the config's lobby limits leave room for another lobby hosting a game type.
+fn lobby_limit_reached(state: &GameProtocolServerState, game_type_id: &str) -> bool {
+    let for_game_type = state.lobbies.values().filter(|lobby| lobby.game_metadata.get_game_type_id() == game_type_id).count();
+    state.config.max_lobbies.is_some_and(|max| state.lobbies.len() >= max)
+        || state.config.max_lobbies_per_game_type.is_some_and(|max| for_game_type >= max)
+}
+
+// Add a client to an existing lobby, then send the updated lobby info to every member, including the newly added one.
+// Callers are expected to have checked that the lobby exists and can be joined.
+fn join_lobby(state: &mut GameProtocolServerState, client_id: &str, lobby_id: &str) {
+    let lobby = state.lobbies.get_mut(lobby_id).unwrap();
+    let client = state.clients.get_mut(client_id).unwrap();
+    lobby.player_ids.push(client_id.to_string());
+    lobby.players.push(client.profile.clone());
+    client.lobby_id = Some(lobby_id.to_string());
+    state.event_handlers.on_player_joined(lobby_id, client_id);
+
+    send_to_players(&state.clients, &lobby.player_ids, |codec| build_lobby_info_response(codec, StatusCode::Success, lobby.clone()));
+    update_presence(state, client_id);
+}
+
+// Remove a client from the lobby it is in, if any.
+fn leave_lobby(state: &mut GameProtocolServerState, client_id: &str) {
+    if let Some(lobby_id) = state.clients.get_mut(client_id).unwrap().lobby_id.take() {
+        remove_from_lobby(state, client_id, &lobby_id);
+    }
+}
+
+// Remove a player from a lobby. If a game is running in the lobby, the remaining players can't continue it,
+// so the game session is closed. Remaining players are sent the updated lobby info, and empty lobbies are removed from the server.
+fn remove_from_lobby(state: &mut GameProtocolServerState, client_id: &str, lobby_id: &str) {
+    let lobby_id = lobby_id.to_string();
+
+    // Find the position in which the player is in the lobby and remove it
+    let found_lobby = state.lobbies.get_mut(&lobby_id).unwrap();
+    found_lobby.player_ids.retain(|id| id != client_id);
+    found_lobby.players.retain(|profile| profile.player_id != client_id);
+    state.event_handlers.on_player_left(&lobby_id, client_id);
+    let game_stopped = state.games_in_progress.remove(&lobby_id).is_some();
+    if game_stopped {
+        state.game_records.remove(&lobby_id);
+        state.game_state_versions.remove(&lobby_id);
+        found_lobby.game_started = false;
+    }
+    update_presence(state, client_id);
+    let found_lobby = state.lobbies.get_mut(&lobby_id).unwrap();
+
+    // If the lobby is empty, remove it from the server
+    if found_lobby.player_ids.is_empty() {
+        state.lobbies.remove(&lobby_id);
+        return;
+    }
+
+    // If client is owner, transfer ownership to another client
+    if found_lobby.owner.eq(client_id) {
+        found_lobby.owner = found_lobby.player_ids[0].clone();
+    }
+
+    // Send all remaining clients an updated lobby state.
+    send_to_players(&state.clients, &found_lobby.player_ids, |codec| build_lobby_info_response(codec, StatusCode::Success, found_lobby.clone()));
+    if game_stopped {
+        for id in found_lobby.player_ids.clone() {
+            update_presence(state, &id);
+        }
+    }
+}
+
+// Give up the seats of players restored from a snapshot that didn't reconnect before their grace period ended.
+fn expire_reconnections(state: &mut GameProtocolServerState) {
+    let now = Instant::now();
+    let expired: Vec<String> = state.held_seats.iter().filter(|(_, seat)| seat.deadline <= now).map(|(id, _)| id.clone()).collect();
+    for id in expired {
+        state.held_seats.remove(&id);
+        let lobby_id = state.lobbies.values().find(|lobby| lobby.player_ids.contains(&id)).map(|lobby| lobby.id.clone());
+        if let Some(lobby_id) = lobby_id {
+            remove_from_lobby(state, &id, &lobby_id);
+        }
+    }
+}
+
+// Send a message to each of the given players that is connected. Players of a restored lobby may not have reconnected yet.
+// The message is built once for each codec the players use.
+fn send_to_players(clients: &HashMap<String, Client>, player_ids: &[String], build: impl Fn(Codec) -> Vec<u8>) {
+    let mut built: Vec<(Codec, Vec<u8>)> = vec![];
+    for id in player_ids.iter() {
+        if let Some(client) = clients.get(id) {
+            let codec = client.socket.codec();
+            let data = match built.iter().find(|(built_codec, _)| *built_codec == codec) {
+                Some((_, data)) => data.clone(),
+                None => {
+                    let data = build(codec);
+                    built.push((codec, data.clone()));
+                    data
                 }
-                clients.remove(&client_id);
+            };
+            client.socket.send_message(data);
+        }
+    }
+}
+
+// Send the players of a lobby its game session's state after it changed, and move the state to its next version.
+// Each player is sent the state as they see it. Players that agreed on delta updates get what changed since they were last sent it, everyone else the full state.
+fn send_game_state(state: &mut GameProtocolServerState, lobby_id: &str) {
+    let (Some(game), Some(lobby)) = (state.games_in_progress.get(lobby_id), state.lobbies.get(lobby_id)) else { return };
+    let (base_version, mut sent_states) = match state.game_state_versions.remove(lobby_id) {
+        Some((version, sent_states)) => (Some(version), sent_states),
+        None => (None, HashMap::new())
+    };
+    let version = base_version.map_or(0, |version| version + 1);
+    for id in lobby.player_ids.iter() {
+        let Some(client) = state.clients.get(id) else { continue };
+        let player_state = game.get_player_game_state(id);
+        if !client.socket.supports_feature(Feature::GameStateDeltas) {
+            client.socket.send_message(build_game_state_response(client.socket.codec(), StatusCode::Success, player_state.as_ref()));
+            continue;
+        }
+        let current = match serde_json::to_value(player_state.as_ref()) {
+            Ok(current) => current,
+            Err(e) => {
+                error!(error = %e, %lobby_id, "Game state serialization error");
+                continue;
             }
-        });
+        };
+
+        // Players that haven't been sent this game's state yet, e.g. after a snapshot was restored, get all of it
+        let delta = match sent_states.get(id) {
+            Some(previous) => GameStateDelta { base_version, version, changes: game.diff_game_state(previous, &current) },
+            None => GameStateDelta { base_version: None, version, changes: vec![StateChange::Set { path: String::new(), value: current.clone() }] }
+        };
+        client.socket.send_message(build_game_state_delta(client.socket.codec(), StatusCode::Success, &delta));
+        sent_states.insert(id.clone(), current);
     }
-}
\ No newline at end of file
+    state.game_state_versions.insert(lobby_id.to_string(), (version, sent_states));
+}
+
+// Send a player the full state of a lobby's game session as they see it, e.g. when they ask for it after reconnecting or noticing their copy is out of sync.
+// Players that agreed on delta updates get it as a delta without a base version, so they also learn which version it is.
+fn send_full_game_state(state: &mut GameProtocolServerState, socket: &ClientSocket, lobby_id: &str, player_id: &str) {
+    let Some(game) = state.games_in_progress.get(lobby_id) else { return };
+    let player_state = game.get_player_game_state(player_id);
+    if !socket.supports_feature(Feature::GameStateDeltas) {
+        socket.send_message(build_game_state_response(socket.codec(), StatusCode::Success, player_state.as_ref()));
+        return;
+    }
+    let current = match serde_json::to_value(player_state.as_ref()) {
+        Ok(current) => current,
+        Err(e) => {
+            error!(error = %e, %lobby_id, "Game state serialization error");
+            return;
+        }
+    };
+
+    // Game sessions restored from a snapshot have no version yet, so they start again at 0
+    let (version, sent_states) = state.game_state_versions.entry(lobby_id.to_string()).or_insert_with(|| (0, HashMap::new()));
+    sent_states.insert(player_id.to_string(), current.clone());
+    socket.send_message(build_game_state_delta(socket.codec(), StatusCode::Success, &GameStateDelta {
+        base_version: None,
+        version: *version,
+        changes: vec![StateChange::Set { path: String::new(), value: current }]
+    }));
+}
+
+// Create a game session for the game a lobby hosts, add the lobby's players to it, and send them the initial game state.
+fn start_game(state: &mut GameProtocolServerState, lobby_id: &str) {
+    let lobby = state.lobbies.get_mut(lobby_id).unwrap();
+    let mut new_game = state.supported_games.get(&lobby.game_metadata.get_game_type_id()).unwrap().init_new();
+
+    // Add client IDs to game session
+    for id in lobby.player_ids.iter() {
+        new_game.add_player(id.clone());
+    }
+
+    // Start a record of the game session so it can be stored once it ends. Players are recorded the way they're rated, so
+    // an account's games can be found after its client ID has changed.
+    state.game_records.insert(lobby_id.to_string(), GameRecord {
+        game_id: Uuid::new_v4().to_string(),
+        game_type_id: lobby.game_metadata.get_game_type_id(),
+        player_ids: lobby.rating_ids(),
+        winner: None,
+        moves: vec![],
+        final_state: String::new(),
+        started_at: unix_time(),
+        ended_at: 0
+    });
+
+    // Tie game session to lobby by using the lobby ID as a key for the game session hash map. Set lobby as the game is in progress.
+    state.games_in_progress.insert(lobby_id.to_string(), new_game);
+    lobby.game_started = true;
+
+    // Send the game state for the newly created game session to the clients that are in the lobby. It starts at version 0.
+    state.game_state_versions.remove(lobby_id);
+    send_game_state(state, lobby_id);
+    let lobby = state.lobbies.get(lobby_id).unwrap();
+    info!(%lobby_id, game_type_id = %lobby.game_metadata.get_game_type_id(), "Game started");
+    state.event_handlers.on_game_started(lobby_id, &lobby.game_metadata.get_game_type_id(), &lobby.player_ids);
+    for id in lobby.player_ids.clone() {
+        update_presence(state, &id);
+    }
+}
+
+// Record the result of a game session that has reached its end condition.
+// Stores the game record, updates and stores the ratings of everyone who played, sends each of them their new rating,
+// and advances the tournament the game belongs to if any.
+fn end_game(state: &mut GameProtocolServerState, lobby_id: &str, winner: Option<String>) {
+    info!(%lobby_id, winner = winner.as_deref().unwrap_or(""), "Game ended");
+    state.event_handlers.on_game_ended(lobby_id, winner.as_deref());
+
+    // Logged in players are rated by account, guests by client ID
+    let lobby = state.lobbies.get(lobby_id).unwrap();
+    let game_type_id = lobby.game_metadata.get_game_type_id();
+    let rating_ids = lobby.rating_ids();
+    let rated_winner = winner.as_ref().and_then(|winner| lobby.player_ids.iter().position(|id| id == winner)).map(|i| rating_ids[i].clone());
+
+    if let Some(mut record) = state.game_records.remove(lobby_id) {
+        record.winner = rated_winner.clone();
+        record.ended_at = unix_time();
+        record.final_state = match state.games_in_progress.get(lobby_id).map(|game| serde_json::to_string(game.get_game_state())) {
+            Some(Ok(final_state)) => final_state,
+            _ => String::new()
+        };
+        if let Err(e) = state.storage.save_game_record(&record) {
+            error!(error = ?e, game_id = %record.game_id, "Storage error saving game record");
+        }
+    }
+
+    // Guests' ratings only last as long as the server runs, so they're neither stored nor put on the leaderboard
+    let lobby = state.lobbies.get(lobby_id).unwrap();
+    state.ratings.record_result(&game_type_id, &rating_ids, rated_winner.as_deref());
+    for (id, rating_id) in lobby.player_ids.iter().zip(rating_ids.iter()) {
+        let rating = state.ratings.get(rating_id, &game_type_id);
+        if lobby.get_player(id).is_none_or(|profile| profile.account_id.is_none()) {
+            state.ratings.add_guest(rating_id);
+        } else if let Err(e) = state.storage.save_rating(&rating) {
+            error!(error = ?e, player_id = %rating_id, "Storage error saving rating");
+        }
+        if let Some(client) = state.clients.get(id) {
+            client.socket.send_message(build_rating_response(client.socket.codec(), StatusCode::Success, rating));
+        }
+    }
+
+    if let Some(tournament_id) = lobby.tournament_id.clone() {
+        if let Some(tournament) = state.tournaments.get_mut(&tournament_id) {
+            if let Some(match_index) = tournament.match_in_lobby(lobby_id) {
+                tournament.record_result(match_index, winner);
+                run_tournament_matches(state, &tournament_id);
+            }
+        }
+    }
+}
+
+// Create a tournament and return its ID.
+fn create_tournament(state: &mut GameProtocolServerState, name: &str, game_type_id: &str, format: TournamentFormat, organizer: Option<String>) -> String {
+    let mut tournament_id = Uuid::new_v4().to_string();
+    while state.tournaments.contains_key(&tournament_id) {
+        tournament_id = Uuid::new_v4().to_string();
+    }
+    let tournament = Tournament::new(tournament_id.clone(), name.to_string(), game_type_id.to_string(), format, organizer);
+    state.tournaments.insert(tournament_id.clone(), tournament);
+    tournament_id
+}
+
+// Close registration, seed participants by rating, and start the first round's matches.
+fn start_tournament(state: &mut GameProtocolServerState, tournament_id: &str) -> Result<(), StatusCode> {
+    let tournament = match state.tournaments.get(tournament_id) {
+        Some(tournament) => tournament,
+        None => return Err(StatusCode::TournamentNotFound)
+    };
+
+    // Highest rated participant is the top seed. Ties keep registration order.
+    let game_type_id = tournament.info.game_type_id.clone();
+    let mut seeded = tournament.info.participant_ids.clone();
+    let rating = |id: &str| state.ratings.get(&rating_id(state, id), &game_type_id).rating;
+    seeded.sort_by(|a, b| rating(b).total_cmp(&rating(a)));
+
+    state.tournaments.get_mut(tournament_id).unwrap().start(seeded)?;
+    run_tournament_matches(state, tournament_id);
+    Ok(())
+}
+
+// Start every match of the tournament's current round that isn't being played yet, then publish the tournament to its participants.
+// Each pair of players is moved out of whatever lobby they are in, into a new tournament lobby with a running game session.
+// Players that are no longer connected forfeit. If neither player is connected, the higher seed advances.
+fn run_tournament_matches(state: &mut GameProtocolServerState, tournament_id: &str) {
+    loop {
+        let tournament = state.tournaments.get(tournament_id).unwrap();
+        let game_type_id = tournament.info.game_type_id.clone();
+        let pending: Vec<(usize, Vec<String>)> = tournament.info.matches.iter().enumerate()
+            .filter(|(_, m)| !m.finished && m.lobby_id.is_none())
+            .map(|(i, m)| (i, m.player_ids.clone()))
+            .collect();
+
+        // Forfeits can finish a round and pair the next one, in which case the new round's matches need starting too.
+        let mut round_finished = false;
+        for (match_index, player_ids) in pending {
+            if player_ids.iter().any(|id| !state.clients.contains_key(id)) {
+                let winner = player_ids.iter().find(|id| state.clients.contains_key(*id)).unwrap_or(&player_ids[0]).clone();
+                if state.tournaments.get_mut(tournament_id).unwrap().record_result(match_index, Some(winner)) {
+                    round_finished = true;
+                    break;
+                }
+                continue;
+            }
+
+            for id in player_ids.iter() {
+                leave_lobby(state, id);
+            }
+            let lobby_id = create_lobby(state, &player_ids[0], &game_type_id).id;
+            state.lobbies.get_mut(&lobby_id).unwrap().tournament_id = Some(tournament_id.to_string());
+            for id in player_ids.iter().skip(1) {
+                join_lobby(state, id, &lobby_id);
+            }
+            start_game(state, &lobby_id);
+            state.tournaments.get_mut(tournament_id).unwrap().info.matches[match_index].lobby_id = Some(lobby_id);
+        }
+
+        if !round_finished || !matches!(state.tournaments.get(tournament_id).unwrap().info.status, TournamentStatus::Running) {
+            break;
+        }
+    }
+    send_tournament_info(state, tournament_id);
+}
+
+// A client that leaves or disconnects in the middle of a tournament match loses it.
+fn forfeit_tournament_matches(state: &mut GameProtocolServerState, client_id: &str) {
+    let tournament_ids: Vec<String> = state.tournaments.keys().cloned().collect();
+    for tournament_id in tournament_ids {
+        let tournament = state.tournaments.get_mut(&tournament_id).unwrap();
+        if let Some(match_index) = tournament.match_for_player(client_id) {
+            let winner = tournament.info.matches[match_index].player_ids.iter().find(|id| *id != client_id).cloned();
+            tournament.record_result(match_index, winner);
+            run_tournament_matches(state, &tournament_id);
+        }
+    }
+}
+
+// Send the current tournament data, including standings, to every connected participant and to the organizer.
+fn send_tournament_info(state: &GameProtocolServerState, tournament_id: &str) {
+    let info = state.tournaments.get(tournament_id).unwrap().info.clone();
+    for id in info.participant_ids.iter().chain(info.organizer.iter()) {
+        if let Some(client) = state.clients.get(id) {
+            client.socket.send_message(build_tournament_info_response(client.socket.codec(), StatusCode::Success, info.clone()));
+        }
+    }
+}
+
+// Current time in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Random secret, hex encoded, e.g. for reconnect tokens. Two v4 UUIDs give 244 random bits.
+fn new_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+// Compare secrets without stopping at the first difference, so response times don't give away how much of a guess was right.
+// An empty expected secret never matches.
+fn secrets_match(expected: &str, given: &str) -> bool {
+    !expected.is_empty() && expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use crate::enums::MessageType;
+use crate::server::config::{RateLimit, ServerConfig};
+
+/*
+    Token bucket rate limiting for client messages. Each type of message a client sends takes a token from its own bucket,
+    and messages that find their bucket empty are rejected. Each connection has its own buckets, checked before the server state is locked
+    so a flooding client can't hold up everyone else, and logged in players also share buckets across their connections while any of them
+    is open, so opening more connections doesn't get around them. Rejected messages cost the client a strike, and a client that runs out
+    of strikes is disconnected.
+ */
+
+const STRIKE_WINDOW_SECS: f64 = 60.0;
+
+pub struct TokenBucket {
+    tokens: f64, // Tokens left. Fractions build up between messages.
+    last_refill: Instant // When tokens were last added
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            last_refill: Instant::now()
+        }
+    }
+
+    // Add the tokens that came back since the last call, then take one if there is one. The limit is passed in so config reloads apply straight away.
+    pub fn take(&mut self, limit: RateLimit) -> bool {
+        let now = Instant::now();
+        let refilled = self.tokens + now.duration_since(self.last_refill).as_secs_f64() * limit.per_second;
+        self.tokens = refilled.min(limit.burst as f64);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Token buckets for each type of message, created the first time a limited message type is sent.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<MessageType, TokenBucket>
+}
+
+impl RateLimiter {
+    // Check whether a message is within its rate limit, taking a token if it is. Messages without a limit are always allowed.
+    pub fn allow(&mut self, config: &ServerConfig, message_type: MessageType) -> bool {
+        match config.rate_limit_for(message_type) {
+            Some(limit) => self.buckets.entry(message_type).or_insert_with(|| TokenBucket::new(limit)).take(limit),
+            None => true
+        }
+    }
+}
+
+// Strikes a client has left before being disconnected for going over rate limits. They come back over a minute.
+pub struct Strikes {
+    bucket: TokenBucket,
+    limit: RateLimit
+}
+
+impl Strikes {
+    pub fn new(config: &ServerConfig) -> Self {
+        let limit = RateLimit {
+            burst: config.rate_limit_strikes,
+            per_second: config.rate_limit_strikes as f64 / STRIKE_WINDOW_SECS
+        };
+        Self {
+            bucket: TokenBucket::new(limit),
+            limit
+        }
+    }
+
+    // Count a rejected message. Returns false once the client has no strikes left.
+    pub fn strike(&mut self) -> bool {
+        self.bucket.take(self.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { burst: 3, per_second: 2.0 };
+
+    // Move a bucket's last refill back in time, as if that long had passed since it was last used.
+    fn age(bucket: &mut TokenBucket, seconds: f64) {
+        bucket.last_refill -= Duration::from_secs_f64(seconds);
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_rejects() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        assert!((0..LIMIT.burst).all(|_| bucket.take(LIMIT)));
+        assert!(!bucket.take(LIMIT));
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        while bucket.take(LIMIT) {}
+
+        // Half a second at 2 per second brings back one token
+        age(&mut bucket, 0.5);
+        assert!(bucket.take(LIMIT));
+        assert!(!bucket.take(LIMIT));
+
+        // A long wait never gives back more than the burst
+        age(&mut bucket, 60.0);
+        assert!((0..LIMIT.burst).all(|_| bucket.take(LIMIT)));
+        assert!(!bucket.take(LIMIT));
+    }
+
+    #[test]
+    fn limiter_keeps_a_bucket_per_message_type() {
+        let config = ServerConfig::builder().message_rate_limit("ChatRequest", 1, 0.1).build().unwrap();
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.allow(&config, MessageType::ChatRequest));
+        assert!(!limiter.allow(&config, MessageType::ChatRequest));
+        assert!(limiter.allow(&config, MessageType::LobbyListRequest));
+    }
+
+    #[test]
+    fn strikes_run_out_and_come_back_over_the_window() {
+        let config = ServerConfig::builder().rate_limit_strikes(2).build().unwrap();
+        let mut strikes = Strikes::new(&config);
+        assert!(strikes.strike());
+        assert!(strikes.strike());
+        assert!(!strikes.strike());
+
+        // Every strike is back a full window later
+        age(&mut strikes.bucket, STRIKE_WINDOW_SECS);
+        assert!(strikes.strike());
+        assert!(strikes.strike());
+        assert!(!strikes.strike());
+    }
+}
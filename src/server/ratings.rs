@@ -0,0 +1,308 @@
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use crate::shared_data::PlayerRating;
+
+/*
+    Player skill ratings, kept per player and per game type ID.
+    Ratings are updated once a game session reaches its end condition using either Elo or Glicko-2.
+    Every finished game is treated as a set of head to head results between each pair of players:
+    the winner beats everyone else, and any pair without a winner between them is a draw.
+ */
+
+// Glicko-2 works on its own internal scale. This converts between it and the familiar Elo-like scale.
+const GLICKO2_SCALE: f64 = 173.7178;
+
+// Convergence tolerance used when solving for the new volatility in Glicko-2.
+const GLICKO2_EPSILON: f64 = 0.000001;
+
+// Which algorithm the server uses to update ratings after a game.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RatingSystem {
+    Elo { k_factor: f64 }, // How many points a single game can move a rating.
+    Glicko2 { tau: f64 } // Constrains how much volatility can change per game. Sensible values are between 0.3 and 1.2.
+}
+
+impl Default for RatingSystem {
+    fn default() -> Self {
+        RatingSystem::Elo { k_factor: 32.0 }
+    }
+}
+
+// Stores every player's rating, indexed by game type ID and then by player ID.
+pub struct RatingStore {
+    system: RatingSystem,
+    ratings: HashMap<String, HashMap<String, PlayerRating>>,
+    guests: HashSet<String> // Rating IDs of guests. They're rated while connected but kept off the leaderboard, and forgotten when they disconnect.
+}
+
+impl RatingStore {
+    pub fn new(system: RatingSystem) -> Self {
+        Self {
+            system,
+            ratings: HashMap::new(),
+            guests: HashSet::new()
+        }
+    }
+
+    pub fn set_system(&mut self, system: RatingSystem) {
+        self.system = system;
+    }
+
+    // Replace stored ratings with previously saved ones, such as those loaded from storage.
+    pub fn load(&mut self, ratings: Vec<PlayerRating>) {
+        for rating in ratings {
+            self.ratings.entry(rating.game_type_id.clone()).or_default().insert(rating.player_id.clone(), rating);
+        }
+    }
+
+    // Get a player's rating for a game type. Players that have not finished a game yet get the starting rating.
+    pub fn get(&self, player_id: &str, game_type_id: &str) -> PlayerRating {
+        self.ratings.get(game_type_id)
+            .and_then(|players| players.get(player_id))
+            .cloned()
+            .unwrap_or_else(|| PlayerRating::new(player_id, game_type_id))
+    }
+
+    // Mark a rating ID as belonging to a guest rather than an account.
+    pub fn add_guest(&mut self, player_id: &str) {
+        self.guests.insert(player_id.to_string());
+    }
+
+    // Forget a guest's ratings for every game type. Does nothing for players that aren't guests.
+    pub fn remove_guest(&mut self, player_id: &str) {
+        if self.guests.remove(player_id) {
+            for players in self.ratings.values_mut() {
+                players.remove(player_id);
+            }
+        }
+    }
+
+    // Highest rated players with an account for a game type, best first.
+    pub fn leaderboard(&self, game_type_id: &str, limit: usize) -> Vec<PlayerRating> {
+        let mut ratings: Vec<PlayerRating> = match self.ratings.get(game_type_id) {
+            Some(players) => players.values().filter(|rating| !self.guests.contains(&rating.player_id)).cloned().collect(),
+            None => vec![]
+        };
+        ratings.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        ratings.truncate(limit);
+        ratings
+    }
+
+    // Update the ratings of everyone that took part in a finished game. Winner is None if the game was a draw.
+    pub fn record_result(&mut self, game_type_id: &str, player_ids: &[String], winner: Option<&str>) {
+        // A single player game says nothing about skill.
+        if player_ids.len() < 2 {
+            return;
+        }
+
+        // Work from the ratings everyone had before the game so the order players are processed in doesn't matter.
+        let before: Vec<PlayerRating> = player_ids.iter().map(|id| self.get(id, game_type_id)).collect();
+        let mut after = vec![];
+        for (i, player) in before.iter().enumerate() {
+            // Collect the opponent and score of each head to head result for this player.
+            let mut results = vec![];
+            for (j, opponent) in before.iter().enumerate() {
+                if i != j {
+                    results.push((opponent, head_to_head_score(&player.player_id, &opponent.player_id, winner)));
+                }
+            }
+
+            let mut updated = match self.system {
+                RatingSystem::Elo { k_factor } => update_elo(player, &results, k_factor),
+                RatingSystem::Glicko2 { tau } => update_glicko2(player, &results, tau)
+            };
+            updated.games_played += 1;
+            after.push(updated);
+        }
+
+        let players = self.ratings.entry(game_type_id.to_string()).or_default();
+        for rating in after {
+            players.insert(rating.player_id.clone(), rating);
+        }
+    }
+}
+
+// Score of a player against a single opponent: 1 for a win, 0 for a loss, 0.5 if neither of them won.
+fn head_to_head_score(player_id: &str, opponent_id: &str, winner: Option<&str>) -> f64 {
+    match winner {
+        Some(winner) if winner == player_id => 1.0,
+        Some(winner) if winner == opponent_id => 0.0,
+        _ => 0.5
+    }
+}
+
+// Classic Elo. With more than two players the K factor is split across each head to head result.
+fn update_elo(player: &PlayerRating, results: &[(&PlayerRating, f64)], k_factor: f64) -> PlayerRating {
+    let k = k_factor / results.len() as f64;
+    let mut change = 0.0;
+    for (opponent, score) in results.iter() {
+        let expected = 1.0 / (1.0 + 10_f64.powf((opponent.rating - player.rating) / 400.0));
+        change += k * (score - expected);
+    }
+
+    let mut updated = player.clone();
+    updated.rating += change;
+    updated
+}
+
+// Glicko-2 as described by Mark Glickman, treating each game as a single rating period.
+fn update_glicko2(player: &PlayerRating, results: &[(&PlayerRating, f64)], tau: f64) -> PlayerRating {
+    // Convert to the Glicko-2 scale.
+    let mu = (player.rating - 1500.0) / GLICKO2_SCALE;
+    let phi = player.deviation / GLICKO2_SCALE;
+    let sigma = player.volatility;
+
+    // Estimated variance of the rating based only on game outcomes, and the estimated improvement in rating.
+    let mut variance_inverse = 0.0;
+    let mut improvement_sum = 0.0;
+    for (opponent, score) in results.iter() {
+        let opponent_mu = (opponent.rating - 1500.0) / GLICKO2_SCALE;
+        let opponent_phi = opponent.deviation / GLICKO2_SCALE;
+        let g = 1.0 / (1.0 + 3.0 * opponent_phi.powi(2) / PI.powi(2)).sqrt();
+        let expected = 1.0 / (1.0 + (-g * (mu - opponent_mu)).exp());
+        variance_inverse += g.powi(2) * expected * (1.0 - expected);
+        improvement_sum += g * (score - expected);
+    }
+    let v = 1.0 / variance_inverse;
+    let delta = v * improvement_sum;
+
+    // Solve for the new volatility using the Illinois algorithm.
+    let a = sigma.powi(2).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        (ex * (delta.powi(2) - phi.powi(2) - v - ex)) / (2.0 * (phi.powi(2) + v + ex).powi(2)) - (x - a) / tau.powi(2)
+    };
+    let mut bound_a = a;
+    let mut bound_b = if delta.powi(2) > phi.powi(2) + v {
+        (delta.powi(2) - phi.powi(2) - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * tau) < 0.0 {
+            k += 1.0;
+        }
+        a - k * tau
+    };
+    let mut f_bound_a = f(bound_a);
+    let mut f_bound_b = f(bound_b);
+    while (bound_b - bound_a).abs() > GLICKO2_EPSILON {
+        let c = bound_a + (bound_a - bound_b) * f_bound_a / (f_bound_b - f_bound_a);
+        let f_c = f(c);
+        if f_c * f_bound_b <= 0.0 {
+            bound_a = bound_b;
+            f_bound_a = f_bound_b;
+        } else {
+            f_bound_a /= 2.0;
+        }
+        bound_b = c;
+        f_bound_b = f_c;
+    }
+    let new_sigma = (bound_a / 2.0).exp();
+
+    // Update deviation and rating, then convert back to the original scale.
+    let pre_period_phi = (phi.powi(2) + new_sigma.powi(2)).sqrt();
+    let new_phi = 1.0 / (1.0 / pre_period_phi.powi(2) + 1.0 / v).sqrt();
+    let new_mu = mu + new_phi.powi(2) * improvement_sum;
+
+    let mut updated = player.clone();
+    updated.rating = new_mu * GLICKO2_SCALE + 1500.0;
+    updated.deviation = new_phi * GLICKO2_SCALE;
+    updated.volatility = new_sigma;
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(player_id: &str, rating: f64, deviation: f64) -> PlayerRating {
+        let mut player = PlayerRating::new(player_id, "game");
+        player.rating = rating;
+        player.deviation = deviation;
+        player
+    }
+
+    fn players(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "expected {} to be within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn elo_expected_score() {
+        // Evenly matched players expect half a point each, so the winner gains half the K factor.
+        let mut store = RatingStore::new(RatingSystem::Elo { k_factor: 32.0 });
+        store.record_result("game", &players(&["a", "b"]), Some("a"));
+        assert_close(store.get("a", "game").rating, 1516.0, 1e-9);
+        assert_close(store.get("b", "game").rating, 1484.0, 1e-9);
+
+        // 400 points below an opponent the expected score is 1 / 11, so an upset win is worth almost the whole K factor.
+        let underdog = rating("a", 1500.0, 350.0);
+        let favourite = rating("b", 1900.0, 350.0);
+        let updated = update_elo(&underdog, &[(&favourite, 1.0)], 32.0);
+        assert_close(updated.rating, 1500.0 + 32.0 * (1.0 - 1.0 / 11.0), 1e-9);
+        let updated = update_elo(&favourite, &[(&underdog, 0.0)], 32.0);
+        assert_close(updated.rating, 1900.0 - 32.0 * (1.0 - 1.0 / 11.0), 1e-9);
+    }
+
+    #[test]
+    fn elo_splits_k_factor_across_opponents() {
+        // With three players each head to head result moves a rating by at most K / 2.
+        let mut store = RatingStore::new(RatingSystem::Elo { k_factor: 32.0 });
+        store.record_result("game", &players(&["a", "b", "c"]), Some("a"));
+        assert_close(store.get("a", "game").rating, 1516.0, 1e-9);
+        assert_close(store.get("b", "game").rating, 1492.0, 1e-9);
+        assert_close(store.get("c", "game").rating, 1492.0, 1e-9);
+
+        // A draw between evenly matched players changes nothing, but still counts as a game played.
+        let mut store = RatingStore::new(RatingSystem::Elo { k_factor: 32.0 });
+        store.record_result("game", &players(&["a", "b"]), None);
+        assert_close(store.get("a", "game").rating, 1500.0, 1e-9);
+        assert_eq!(store.get("a", "game").games_played, 1);
+    }
+
+    #[test]
+    fn glicko2_matches_worked_example() {
+        // The example from Glickman's "Example of the Glicko-2 system": a 1500 player with deviation 200 and volatility 0.06
+        // beats a 1400 player and loses to 1550 and 1700 players in one rating period, with tau = 0.5.
+        let player = rating("a", 1500.0, 200.0);
+        let opponents = [rating("b", 1400.0, 30.0), rating("c", 1550.0, 100.0), rating("d", 1700.0, 300.0)];
+        let results = [(&opponents[0], 1.0), (&opponents[1], 0.0), (&opponents[2], 0.0)];
+        let updated = update_glicko2(&player, &results, 0.5);
+        assert_close(updated.rating, 1464.06, 0.01);
+        assert_close(updated.deviation, 151.52, 0.01);
+        assert_close(updated.volatility, 0.05999, 0.00001);
+    }
+
+    #[test]
+    fn leaderboard_is_sorted_and_limited() {
+        let mut store = RatingStore::new(RatingSystem::default());
+        store.load(vec![rating("a", 1400.0, 350.0), rating("b", 1600.0, 350.0), rating("c", 1500.0, 350.0)]);
+        let leaderboard = store.leaderboard("game", 2);
+        let ids: Vec<&str> = leaderboard.iter().map(|rating| rating.player_id.as_str()).collect();
+        assert_eq!(ids, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn leaderboard_leaves_out_guests() {
+        let mut store = RatingStore::new(RatingSystem::default());
+        store.load(vec![rating("a", 1400.0, 350.0), rating("b", 1600.0, 350.0), rating("c", 1500.0, 350.0)]);
+        store.add_guest("b");
+        let leaderboard = store.leaderboard("game", 2);
+        let ids: Vec<&str> = leaderboard.iter().map(|rating| rating.player_id.as_str()).collect();
+        assert_eq!(ids, vec!["c", "a"]);
+    }
+
+    #[test]
+    fn removing_a_guest_forgets_its_ratings() {
+        let mut store = RatingStore::new(RatingSystem::default());
+        store.load(vec![rating("a", 1400.0, 350.0), rating("b", 1600.0, 350.0)]);
+        store.add_guest("b");
+        store.remove_guest("a");
+        store.remove_guest("b");
+        assert_eq!(store.get("a", "game").rating, 1400.0);
+        assert_eq!(store.get("b", "game").rating, PlayerRating::new("b", "game").rating);
+        assert!(store.guests.is_empty());
+    }
+}
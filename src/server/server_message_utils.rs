@@ -1,9 +1,10 @@
 use std::mem::size_of;
-use serde_json;
-use crate::common_message_utils::{build_message_body, parse_message_type};
+use tracing::warn;
+use crate::codec::Codec;
+use crate::common_message_utils::{build_message_body, parse_message_data, parse_message_type};
 use crate::enums::{ServerError, MessageType, StatusCode};
 use crate::game_module::{GameState};
-use crate::shared_data::{ConnectRequest, ConnectResponse, Lobby, LobbyInfoResponse, LobbyListResponse, MissingMessageResponse, NoAuth, SupportedGamesResponse, UnsolicitedMessage};
+use crate::shared_data::{ChatMessage, ConnectRequest, ConnectRequestBody, ConnectResponse, FriendInfo, FriendListResponse, GameStateDelta, LeaderboardResponse, Lobby, LobbyInfoResponse, LobbyInvite, LobbyListResponse, MissingMessageResponse, NoAuth, PlayerProfile, PlayerRating, Presence, PresenceUpdate, ProfileResponse, RatingResponse, SupportedGamesResponse, TournamentInfo, TournamentInfoResponse, TournamentListResponse, UnsolicitedMessage};
 
 /*
     Full of helper functions to parse client requests and build server responses.
@@ -24,8 +25,11 @@ pub fn parse_client_message_header(raw_message: &[u8]) -> (u32, MessageType, &[u
     (message_id, message_type, remainder)
 }
 
-pub fn parse_connect_request(_: &[u8]) -> ConnectRequest<NoAuth> {
-    ConnectRequest::new(NoAuth {})
+// A ConnectRequest body has the client's protocol version and what it supports, and the client ID it wants to resume when reconnecting.
+// Clients from before versions were exchanged only send one when reconnecting. Connect requests are always JSON.
+pub fn parse_connect_request(data: &[u8]) -> ConnectRequest<NoAuth> {
+    let body = parse_message_data::<ConnectRequestBody>(Codec::Json, data).unwrap_or_default();
+    ConnectRequest::new(NoAuth {}, body)
 }
 
 // Build the headers for server message: status code and message type.
@@ -36,75 +40,161 @@ pub fn build_server_headers(status_code: StatusCode, message_type: MessageType)
     byte_vec
 }
 
-pub fn build_missing_message_response(missing_message_ids: Vec<u32>) -> Vec<u8> {
+// Response that only has headers. Still sends a body size of 0 so the client knows where the message ends.
+pub fn build_empty_response(status_code: StatusCode, message_type: MessageType) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, message_type);
+    byte_vec.extend_from_slice(&build_message_body(None));
+    byte_vec
+}
+
+pub fn build_missing_message_response(codec: Codec, missing_message_ids: Vec<u32>) -> Vec<u8> {
     let mut byte_vec = build_server_headers(StatusCode::MessageSequenceError, MessageType::MissingMessageResponse);
     let missing_messages = MissingMessageResponse { missing_message_ids };
-    let serialized_response = serde_json::to_string(&missing_messages).unwrap();
+    let serialized_response = codec.encode(&missing_messages);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized_response)));
     byte_vec
 }
 
-pub fn build_unsolicited_message(status_code: StatusCode, message: &str) -> Vec<u8> {
+pub fn build_unsolicited_message(codec: Codec, status_code: StatusCode, message: &str) -> Vec<u8> {
     let mut byte_vec = build_server_headers(status_code, MessageType::UnsolicitedMessage);
     let unsolicited_message = UnsolicitedMessage { message: message.to_string() };
-    let serialized = serde_json::to_string(&unsolicited_message).unwrap();
+    let serialized = codec.encode(&unsolicited_message);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
     byte_vec
 }
 
-pub fn build_connect_response(status_code: StatusCode, client_id: String) -> Vec<u8> {
+// Always JSON and never compressed. Tells the client the protocol version, codec, compression and features every later message uses.
+pub fn build_connect_response(status_code: StatusCode, connect_response: &ConnectResponse) -> Vec<u8> {
     let mut byte_vec = build_server_headers(status_code, MessageType::ConnectResponse);
-    let connect_response = ConnectResponse { client_id };
-    let serialized_response = serde_json::to_string(&connect_response).unwrap();
+    let serialized_response = Codec::Json.encode(connect_response);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized_response)));
     byte_vec
 }
 
-pub fn build_lobby_list_response(status_code: StatusCode, lobbies: &Vec<Lobby>) -> Vec<u8> {
+pub fn build_lobby_list_response(codec: Codec, status_code: StatusCode, lobbies: &[Lobby]) -> Vec<u8> {
     let mut byte_vec = build_server_headers(status_code, MessageType::LobbyListResponse);
     let lobby_list = LobbyListResponse { lobbies: lobbies.to_vec() };
-    let serialized_lobbies = serde_json::to_string(&lobby_list).unwrap();
+    let serialized_lobbies = codec.encode(&lobby_list);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized_lobbies)));
     byte_vec
 }
 
-pub fn build_supported_game_response(status_code: StatusCode, games: &Vec<String>) -> Vec<u8> {
+pub fn build_supported_game_response(codec: Codec, status_code: StatusCode, games: &[String]) -> Vec<u8> {
     let mut byte_vec = build_server_headers(status_code, MessageType::SupportedGamesResponse);
-    let supported_game_response = SupportedGamesResponse { games: games.clone() };
-    let serialized_games = serde_json::to_string(&supported_game_response).unwrap();
+    let supported_game_response = SupportedGamesResponse { games: games.to_vec() };
+    let serialized_games = codec.encode(&supported_game_response);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized_games)));
     byte_vec
 }
 
-pub fn build_lobby_info_response(status_code: StatusCode, lobby: Lobby) -> Vec<u8> {
+pub fn build_lobby_info_response(codec: Codec, status_code: StatusCode, lobby: Lobby) -> Vec<u8> {
     let mut byte_vec = build_server_headers(status_code, MessageType::LobbyInfoResponse);
     let lobby_response = LobbyInfoResponse { lobby: lobby.clone() };
-    let serialized_lobby = serde_json::to_string(&lobby_response).unwrap();
+    let serialized_lobby = codec.encode(&lobby_response);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized_lobby)));
     byte_vec
 }
 
-pub fn build_game_state_response(status_code: StatusCode, game_state: &dyn GameState) -> Vec<u8> {
+pub fn build_game_state_response(codec: Codec, status_code: StatusCode, game_state: &dyn GameState) -> Vec<u8> {
     let mut byte_vec = build_server_headers(status_code, MessageType::GameStateResponse);
-    let serialized = serde_json::to_string(game_state).unwrap();
+    let serialized = codec.encode(game_state);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_game_state_delta(codec: Codec, status_code: StatusCode, delta: &GameStateDelta) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, MessageType::GameStateDelta);
+    let serialized = codec.encode(delta);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_rating_response(codec: Codec, status_code: StatusCode, rating: PlayerRating) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, MessageType::RatingResponse);
+    let rating_response = RatingResponse { rating };
+    let serialized = codec.encode(&rating_response);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_leaderboard_response(codec: Codec, status_code: StatusCode, game_type_id: &str, ratings: &[PlayerRating]) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, MessageType::LeaderboardResponse);
+    let leaderboard_response = LeaderboardResponse { game_type_id: game_type_id.to_string(), ratings: ratings.to_vec() };
+    let serialized = codec.encode(&leaderboard_response);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_tournament_list_response(codec: Codec, status_code: StatusCode, tournaments: &[TournamentInfo]) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, MessageType::TournamentListResponse);
+    let tournament_list = TournamentListResponse { tournaments: tournaments.to_vec() };
+    let serialized = codec.encode(&tournament_list);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_tournament_info_response(codec: Codec, status_code: StatusCode, tournament: TournamentInfo) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, MessageType::TournamentInfoResponse);
+    let tournament_response = TournamentInfoResponse { tournament };
+    let serialized = codec.encode(&tournament_response);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_profile_response(codec: Codec, status_code: StatusCode, profile: PlayerProfile) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, MessageType::ProfileResponse);
+    let profile_response = ProfileResponse { profile };
+    let serialized = codec.encode(&profile_response);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_friend_list_response(codec: Codec, status_code: StatusCode, friends: &[FriendInfo]) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(status_code, MessageType::FriendListResponse);
+    let friend_list = FriendListResponse { friends: friends.to_vec() };
+    let serialized = codec.encode(&friend_list);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_presence_update(codec: Codec, account_id: &str, presence: Presence) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(StatusCode::Success, MessageType::PresenceUpdate);
+    let presence_update = PresenceUpdate { account_id: account_id.to_string(), presence };
+    let serialized = codec.encode(&presence_update);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_lobby_invite(codec: Codec, invite: LobbyInvite) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(StatusCode::Success, MessageType::InviteToLobby);
+    let serialized = codec.encode(&invite);
+    byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
+    byte_vec
+}
+
+pub fn build_chat_message(codec: Codec, message: &ChatMessage) -> Vec<u8> {
+    let mut byte_vec = build_server_headers(StatusCode::Success, MessageType::ChatMessage);
+    let serialized = codec.encode(message);
     byte_vec.extend_from_slice(&build_message_body(Some(serialized)));
     byte_vec
 }
 
 // Based on the error enum received, build an error response to send to the client.
+// The error is logged too, inside the span of the request that caused it.
 pub fn build_server_error_response(e: ServerError) -> Vec<u8> {
+    warn!(error = ?e, "Could not parse client message");
      match e {
          ServerError::ChecksumError => {
-            build_server_headers(StatusCode::DataIntegrityError, MessageType::ProtocolError)
+            build_empty_response(StatusCode::DataIntegrityError, MessageType::ProtocolError)
         }
          ServerError::BodySizeError => {
-            build_server_headers(StatusCode::DataIntegrityError, MessageType::ProtocolError)
+            build_empty_response(StatusCode::DataIntegrityError, MessageType::ProtocolError)
         }
          ServerError::BytesToStringError => {
-             build_server_headers(StatusCode::DataParseError, MessageType::ProtocolError)
+             build_empty_response(StatusCode::DataParseError, MessageType::ProtocolError)
         }
          ServerError::DeserializeError => {
-             build_server_headers(StatusCode::MalformedBody, MessageType::ProtocolError)
+             build_empty_response(StatusCode::MalformedBody, MessageType::ProtocolError)
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::error;
+
+/*
+    Handles for stopping a running server from another thread.
+    Get a ShutdownHandle from GameProtocolServer::shutdown_handle before calling start. Handles can be cloned and sent between threads.
+    GameProtocolServer::spawn returns a ServerHandle, which owns the thread the server runs on.
+ */
+
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>, // Set once shutdown has been requested
+    drain_timeout: Arc<Mutex<Duration>> // How long running games get to finish before clients are disconnected
+}
+
+impl ShutdownHandle {
+    // Ask the server to shut down. It stops accepting connections and tells clients it is shutting down,
+    // then gives running games up to the drain timeout to finish. Pass Duration::ZERO to disconnect everyone straight away.
+    // The server's start function returns once every client thread has finished.
+    pub fn shutdown(&self, drain_timeout: Duration) {
+        *self.drain_timeout.lock().unwrap() = drain_timeout;
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn get_drain_timeout(&self) -> Duration {
+        *self.drain_timeout.lock().unwrap()
+    }
+}
+
+// Handle for a server running on a background thread.
+pub struct ServerHandle {
+    local_addr: Option<SocketAddr>, // Address the server is listening on. None if it doesn't listen on TCP.
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<()> // Thread the server's connection loop runs on
+}
+
+impl ServerHandle {
+    pub fn new(local_addr: Option<SocketAddr>, shutdown: ShutdownHandle, thread: JoinHandle<()>) -> Self {
+        Self {
+            local_addr,
+            shutdown,
+            thread
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    // Get a handle that can request shutdown without owning the server's thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Shut the server down and wait for it to finish. See ShutdownHandle::shutdown for what happens while shutting down.
+    pub fn shutdown(self, drain_timeout: Duration) {
+        self.shutdown.shutdown(drain_timeout);
+        if self.thread.join().is_err() {
+            error!("Server thread panicked during shutdown");
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use crate::game_module::GameState;
+use crate::server::GameProtocolServerState;
+use crate::shared_data::Lobby;
+use crate::storage::GameRecord;
+
+/*
+    Snapshots of the in-progress parts of server state: lobbies and the game sessions running in them.
+    The server writes a snapshot to disk periodically and restores the most recent one when it starts,
+    so games survive a restart. Players of restored lobbies keep their seats for a grace period,
+    during which they can reconnect with their previous client ID and continue where they left off.
+    Client IDs are visible to other players, so resuming a seat also takes the secret reconnect token the player was given when they connected.
+    Tournaments aren't snapshotted, so the lobbies of their matches aren't restored either. A restart ends every tournament.
+ */
+
+#[derive(Serialize, Deserialize)]
+struct ServerSnapshot {
+    lobbies: Vec<Lobby>,
+    games: Vec<GameSessionSnapshot>,
+    #[serde(default)]
+    reconnect_tokens: HashMap<String, String> // Reconnect token of each seated player, indexed by client ID
+}
+
+// A seat in a restored lobby, held for a player until they reconnect or the grace period ends.
+pub struct HeldSeat {
+    pub reconnect_token: String, // Must match the token the player reconnects with. Empty if the snapshot didn't have one, in which case the seat can't be resumed.
+    pub deadline: Instant
+}
+
+#[derive(Serialize, Deserialize)]
+struct GameSessionSnapshot {
+    lobby_id: String, // Lobby the game session is running in
+    game_type_id: String,
+    player_ids: Vec<String>, // Client IDs of the players seated in the game session, in the order they were added
+    game_state: Box<dyn GameState>,
+    record: Option<GameRecord> // Record of the game so far. None if the game has already ended and been stored.
+}
+
+// Write the current lobbies and game sessions to a file.
+// The snapshot is written to a temporary file first and then moved into place, so a crash mid-write never leaves a corrupt snapshot.
+pub fn save_snapshot(state: &GameProtocolServerState, path: &str) -> io::Result<()> {
+    let mut games = vec![];
+    for (lobby_id, game) in state.games_in_progress.iter() {
+        // Game sessions seat players by client ID. The record identifies them by rating ID, so it can't be used to seat them again.
+        games.push(GameSessionSnapshot {
+            lobby_id: lobby_id.clone(),
+            game_type_id: game.get_metadata().get_game_type_id(),
+            player_ids: state.lobbies.get(lobby_id).map(|lobby| lobby.player_ids.clone()).unwrap_or_default(),
+            game_state: game.get_game_state().clone(),
+            record: state.game_records.get(lobby_id).cloned()
+        });
+    }
+
+    // Players that haven't reconnected since the last restore keep the token their seat is held with
+    let mut reconnect_tokens = HashMap::new();
+    for id in state.lobbies.values().flat_map(|lobby| lobby.player_ids.iter()) {
+        let token = match state.clients.get(id) {
+            Some(client) => Some(&client.reconnect_token),
+            None => state.held_seats.get(id).map(|seat| &seat.reconnect_token)
+        };
+        if let Some(token) = token {
+            reconnect_tokens.insert(id.clone(), token.clone());
+        }
+    }
+
+    let snapshot = ServerSnapshot {
+        lobbies: state.lobbies.values().cloned().collect(),
+        games,
+        reconnect_tokens
+    };
+    let json = serde_json::to_string(&snapshot).map_err(io::Error::other)?;
+    let temp_path = format!("{}.tmp", path);
+    fs::write(&temp_path, json)?;
+    fs::rename(&temp_path, path)
+}
+
+// Load lobbies and game sessions from a snapshot file. Game sessions are rebuilt by creating a new instance of the game module,
+// seating the players and setting the saved game state. Every player in a restored lobby has their seat held until the grace period ends.
+// Snapshots from before reconnect tokens were saved still restore, but their players can't resume their seats.
+// Game sessions for games the server no longer supports are dropped, as are tournament match lobbies and their game sessions, since the
+// tournaments they belong to aren't restored. Returns the number of game sessions restored.
+pub fn restore_snapshot(state: &mut GameProtocolServerState, path: &str, grace_period: Duration) -> io::Result<usize> {
+    let json = fs::read_to_string(path)?;
+    let snapshot: ServerSnapshot = serde_json::from_str(&json).map_err(io::Error::other)?;
+
+    let deadline = Instant::now() + grace_period;
+    for mut lobby in snapshot.lobbies {
+        if lobby.tournament_id.is_some() || !state.supported_games.contains_key(&lobby.game_metadata.get_game_type_id()) {
+            continue;
+        }
+        for id in lobby.player_ids.iter() {
+            let reconnect_token = snapshot.reconnect_tokens.get(id).cloned().unwrap_or_default();
+            state.held_seats.insert(id.clone(), HeldSeat { reconnect_token, deadline });
+        }
+
+        // Only lobbies that get their game session back are still playing
+        lobby.game_started = false;
+        state.lobbies.insert(lobby.id.clone(), lobby);
+    }
+
+    let mut restored = 0;
+    for game in snapshot.games {
+        let (factory, lobby) = match (state.supported_games.get(&game.game_type_id), state.lobbies.get_mut(&game.lobby_id)) {
+            (Some(factory), Some(lobby)) => (factory, lobby),
+            _ => continue
+        };
+        let mut session = factory.init_new();
+        for id in game.player_ids.iter() {
+            session.add_player(id.clone());
+        }
+        session.set_game_state(game.game_state);
+
+        lobby.game_started = true;
+        state.games_in_progress.insert(game.lobby_id.clone(), session);
+        if let Some(record) = game.record {
+            state.game_records.insert(game.lobby_id, record);
+        }
+        restored += 1;
+    }
+    Ok(restored)
+}
+
@@ -0,0 +1,447 @@
+use std::collections::HashSet;
+use crate::enums::StatusCode;
+use crate::shared_data::{TournamentFormat, TournamentInfo, TournamentMatch, TournamentStanding, TournamentStatus};
+
+/*
+    Tournament bookkeeping: registration, pairing participants each round according to the format,
+    recording match results and keeping standings.
+    This module only decides who plays who. The server creates the lobbies and game sessions for each pairing.
+ */
+
+pub struct Tournament {
+    pub info: TournamentInfo, // Data published to participants
+    schedule: Vec<Vec<Vec<String>>>, // Round robin pairings for every round, computed when the tournament starts
+    played: HashSet<(String, String)>, // Pairs of players that have already been matched, used to avoid Swiss rematches
+}
+
+impl Tournament {
+    pub fn new(id: String, name: String, game_type_id: String, format: TournamentFormat, organizer: Option<String>) -> Self {
+        Self {
+            info: TournamentInfo {
+                id,
+                name,
+                organizer,
+                game_type_id,
+                format,
+                status: TournamentStatus::Registering,
+                participant_ids: vec![],
+                round: 0,
+                matches: vec![],
+                standings: vec![],
+                winner: None
+            },
+            schedule: vec![],
+            played: HashSet::new()
+        }
+    }
+
+    // Register a participant. Only allowed before the tournament starts.
+    pub fn add_participant(&mut self, player_id: &str) -> Result<(), StatusCode> {
+        if !matches!(self.info.status, TournamentStatus::Registering) {
+            return Err(StatusCode::TournamentRegistrationClosed);
+        }
+        if self.info.participant_ids.iter().any(|id| id == player_id) {
+            return Err(StatusCode::AlreadyInTournament);
+        }
+        self.info.participant_ids.push(player_id.to_string());
+        Ok(())
+    }
+
+    // Withdraw a participant. Only allowed before the tournament starts.
+    pub fn remove_participant(&mut self, player_id: &str) -> Result<(), StatusCode> {
+        if !matches!(self.info.status, TournamentStatus::Registering) {
+            return Err(StatusCode::TournamentRegistrationClosed);
+        }
+        match self.info.participant_ids.iter().position(|id| id == player_id) {
+            Some(position) => {
+                self.info.participant_ids.remove(position);
+                Ok(())
+            }
+            None => Err(StatusCode::NotInTournament)
+        }
+    }
+
+    // Close registration and pair the first round. Participants are expected to be in seed order, best first.
+    pub fn start(&mut self, seeded_participants: Vec<String>) -> Result<(), StatusCode> {
+        if !matches!(self.info.status, TournamentStatus::Registering) {
+            return Err(StatusCode::TournamentRegistrationClosed);
+        }
+        if seeded_participants.len() < 2 {
+            return Err(StatusCode::GameStartCriteriaNotMet);
+        }
+
+        self.info.participant_ids = seeded_participants;
+        self.info.standings = self.info.participant_ids.iter().map(|id| TournamentStanding {
+            player_id: id.clone(),
+            points: 0.0,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            byes: 0
+        }).collect();
+        self.info.status = TournamentStatus::Running;
+
+        // Swiss with 0 rounds plays enough rounds for one player to be able to win every game.
+        if let TournamentFormat::Swiss { rounds } = self.info.format {
+            if rounds == 0 {
+                let needed = (self.info.participant_ids.len() as f64).log2().ceil() as usize;
+                self.info.format = TournamentFormat::Swiss { rounds: needed.max(1) };
+            }
+        }
+        if matches!(self.info.format, TournamentFormat::RoundRobin) {
+            self.schedule = round_robin_schedule(&self.info.participant_ids);
+        }
+
+        self.next_round();
+        Ok(())
+    }
+
+    // Find the unfinished match being played in a lobby.
+    pub fn match_in_lobby(&self, lobby_id: &str) -> Option<usize> {
+        self.info.matches.iter().position(|m| !m.finished && m.lobby_id.as_deref() == Some(lobby_id))
+    }
+
+    // Find the unfinished match a player is part of in the current round.
+    pub fn match_for_player(&self, player_id: &str) -> Option<usize> {
+        self.info.matches.iter().position(|m| !m.finished && m.player_ids.iter().any(|id| id == player_id))
+    }
+
+    // Record the result of a match in the current round. Winner is None for a draw.
+    // Elimination matches can't end in a draw, so the match is reset to be replayed instead.
+    // Returns true if this finished the round, in which case the next round has been paired or the tournament has finished.
+    pub fn record_result(&mut self, match_index: usize, winner: Option<String>) -> bool {
+        let is_elimination = matches!(self.info.format, TournamentFormat::SingleElimination | TournamentFormat::DoubleElimination);
+        let tournament_match = &mut self.info.matches[match_index];
+        if tournament_match.finished {
+            return false;
+        }
+        if winner.is_none() && is_elimination {
+            tournament_match.lobby_id = None;
+            return false;
+        }
+
+        tournament_match.finished = true;
+        tournament_match.winner = winner.clone();
+        let player_ids = tournament_match.player_ids.clone();
+
+        // Update the record of everyone in the match
+        for id in player_ids.iter() {
+            let standing = self.info.standings.iter_mut().find(|s| &s.player_id == id).unwrap();
+            match &winner {
+                Some(winner) if winner == id => {
+                    standing.wins += 1;
+                    standing.points += 1.0;
+                }
+                Some(_) => {
+                    standing.losses += 1;
+                }
+                None => {
+                    standing.draws += 1;
+                    standing.points += 0.5;
+                }
+            }
+        }
+        self.sort_standings();
+
+        if self.info.matches.iter().all(|m| m.finished) {
+            self.next_round();
+            return true;
+        }
+        false
+    }
+
+    // Pair the next round or finish the tournament if there are no more rounds to play.
+    fn next_round(&mut self) {
+        let round = self.info.round + 1;
+        let pairings = match self.info.format {
+            TournamentFormat::RoundRobin => self.schedule.get(round - 1).cloned().unwrap_or_default(),
+            TournamentFormat::Swiss { rounds } => {
+                if round > rounds {
+                    vec![]
+                } else {
+                    self.swiss_pairings()
+                }
+            }
+            TournamentFormat::SingleElimination => self.elimination_pairings(1),
+            TournamentFormat::DoubleElimination => self.elimination_pairings(2)
+        };
+
+        // Nothing left to play, so the tournament is over.
+        if pairings.iter().all(|players| players.len() < 2) {
+            self.finish();
+            return;
+        }
+
+        self.info.round = round;
+        self.info.matches = vec![];
+        for players in pairings {
+            if players.len() == 2 {
+                self.played.insert((players[0].clone(), players[1].clone()));
+                self.played.insert((players[1].clone(), players[0].clone()));
+            }
+            self.info.matches.push(TournamentMatch {
+                round,
+                player_ids: players,
+                lobby_id: None,
+                finished: false,
+                winner: None
+            });
+        }
+
+        // Byes are finished straight away
+        for tournament_match in self.info.matches.iter_mut() {
+            if tournament_match.player_ids.len() == 1 {
+                tournament_match.finished = true;
+                tournament_match.winner = Some(tournament_match.player_ids[0].clone());
+                let standing = self.info.standings.iter_mut().find(|s| s.player_id == tournament_match.player_ids[0]).unwrap();
+                standing.byes += 1;
+                standing.points += 1.0;
+            }
+        }
+        self.sort_standings();
+    }
+
+    fn finish(&mut self) {
+        self.info.status = TournamentStatus::Finished;
+        self.info.matches = vec![];
+        self.info.winner = match self.info.format {
+            // In elimination formats the winner is the last player standing
+            TournamentFormat::SingleElimination => self.remaining_players(1).first().cloned(),
+            TournamentFormat::DoubleElimination => self.remaining_players(2).first().cloned(),
+            _ => self.info.standings.first().map(|s| s.player_id.clone())
+        };
+    }
+
+    // Pair players with similar scores, avoiding rematches where possible.
+    // If there is an odd number of players, the lowest ranked player that has not had a bye yet gets one.
+    fn swiss_pairings(&self) -> Vec<Vec<String>> {
+        let mut ranked: Vec<String> = self.info.standings.iter().map(|s| s.player_id.clone()).collect();
+        let mut pairings = vec![];
+
+        if ranked.len() % 2 == 1 {
+            let bye_position = ranked.iter().rposition(|id| self.standing(id).byes == 0).unwrap_or(ranked.len() - 1);
+            pairings.push(vec![ranked.remove(bye_position)]);
+        }
+
+        // Only allow rematches if there is no way to pair everyone without one.
+        if let Some(rematch_free) = self.pairings_without_rematches(&ranked) {
+            pairings.extend(rematch_free);
+            return pairings;
+        }
+        while !ranked.is_empty() {
+            let player = ranked.remove(0);
+            let opponent_position = ranked.iter()
+                .position(|id| !self.played.contains(&(player.clone(), id.clone())))
+                .unwrap_or(0);
+            let opponent = ranked.remove(opponent_position);
+            pairings.push(vec![player, opponent]);
+        }
+        pairings
+    }
+
+    // Pair each player, best ranked first, with the best ranked opponent they haven't played,
+    // backtracking when that leaves players further down unable to be paired. None if every pairing has a rematch.
+    fn pairings_without_rematches(&self, ranked: &[String]) -> Option<Vec<Vec<String>>> {
+        let Some((player, others)) = ranked.split_first() else { return Some(vec![]) };
+        for (i, opponent) in others.iter().enumerate() {
+            if self.played.contains(&(player.clone(), opponent.clone())) {
+                continue;
+            }
+            let rest: Vec<String> = others.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, id)| id.clone()).collect();
+            if let Some(mut pairings) = self.pairings_without_rematches(&rest) {
+                pairings.insert(0, vec![player.clone(), opponent.clone()]);
+                return Some(pairings);
+            }
+        }
+        None
+    }
+
+    // Pair the players that have fewer losses than the elimination limit. Top seeds are paired against bottom seeds.
+    // For double elimination, players without a loss and players with one loss are paired separately,
+    // until one player is left in each group, who then meet in the final.
+    fn elimination_pairings(&self, max_losses: u32) -> Vec<Vec<String>> {
+        let remaining = self.remaining_players(max_losses);
+        if remaining.len() < 2 {
+            return vec![];
+        }
+
+        let mut pairings = vec![];
+        let groups: Vec<Vec<String>> = (0..max_losses)
+            .map(|losses| remaining.iter().filter(|id| self.standing(id).losses == losses).cloned().collect())
+            .filter(|group: &Vec<String>| !group.is_empty())
+            .collect();
+        if groups.iter().all(|group| group.len() == 1) {
+            pairings.push(remaining);
+            return pairings;
+        }
+
+        for mut group in groups {
+            // Top seed gets the bye if the group has an odd number of players
+            if group.len() % 2 == 1 {
+                pairings.push(vec![group.remove(0)]);
+            }
+            let half = group.len() / 2;
+            for i in 0..half {
+                pairings.push(vec![group[i].clone(), group[group.len() - 1 - i].clone()]);
+            }
+        }
+        pairings
+    }
+
+    // Players with fewer losses than the limit, in seed order.
+    fn remaining_players(&self, max_losses: u32) -> Vec<String> {
+        self.info.participant_ids.iter().filter(|id| self.standing(id).losses < max_losses).cloned().collect()
+    }
+
+    fn standing(&self, player_id: &str) -> &TournamentStanding {
+        self.info.standings.iter().find(|s| s.player_id == player_id).unwrap()
+    }
+
+    // Best first: most points, then most wins. Ties keep seed order.
+    fn sort_standings(&mut self) {
+        self.info.standings.sort_by(|a, b| b.points.total_cmp(&a.points).then(b.wins.cmp(&a.wins)));
+    }
+}
+
+// Build a round robin schedule using the circle method. With an odd number of players, one player sits out each round with a bye.
+fn round_robin_schedule(participant_ids: &[String]) -> Vec<Vec<Vec<String>>> {
+    let mut players: Vec<Option<String>> = participant_ids.iter().cloned().map(Some).collect();
+    if players.len() % 2 == 1 {
+        players.push(None);
+    }
+
+    let count = players.len();
+    let mut schedule = vec![];
+    for _ in 0..count - 1 {
+        let mut round = vec![];
+        for i in 0..count / 2 {
+            let pairing: Vec<String> = [&players[i], &players[count - 1 - i]].iter().filter_map(|p| (*p).clone()).collect();
+            round.push(pairing);
+        }
+        schedule.push(round);
+
+        // Keep the first player fixed and rotate everyone else
+        let last = players.pop().unwrap();
+        players.insert(1, last);
+    }
+    schedule
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use super::*;
+    use crate::shared_data::TournamentMatch;
+
+    fn ids(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("p{}", i)).collect()
+    }
+
+    fn pair(a: &str, b: &str) -> (String, String) {
+        if a < b { (a.to_string(), b.to_string()) } else { (b.to_string(), a.to_string()) }
+    }
+
+    // Play a tournament to the end, letting choose_winner decide each match. Returns the matches of every round in order.
+    fn play(tournament: &mut Tournament, choose_winner: impl Fn(&Tournament, &TournamentMatch) -> String) -> Vec<Vec<TournamentMatch>> {
+        let mut rounds = vec![];
+        while matches!(tournament.info.status, TournamentStatus::Running) {
+            rounds.push(tournament.info.matches.clone());
+            let unfinished: Vec<usize> = (0..tournament.info.matches.len()).filter(|i| !tournament.info.matches[*i].finished).collect();
+            for i in unfinished {
+                let winner = choose_winner(tournament, &tournament.info.matches[i]);
+                tournament.record_result(i, Some(winner));
+            }
+        }
+        rounds
+    }
+
+    #[test]
+    fn round_robin_schedule_pairs_everyone_once() {
+        for count in 2..=9 {
+            let players = ids(count);
+            let schedule = round_robin_schedule(&players);
+            let mut pairs: HashMap<(String, String), usize> = HashMap::new();
+            for round in schedule.iter() {
+                // Nobody plays twice in a round
+                let mut seen = HashSet::new();
+                for pairing in round.iter() {
+                    for id in pairing.iter() {
+                        assert!(seen.insert(id.clone()), "{} plays twice in a round of {} players", id, count);
+                    }
+                    if pairing.len() == 2 {
+                        *pairs.entry(pair(&pairing[0], &pairing[1])).or_default() += 1;
+                    }
+                }
+            }
+
+            assert_eq!(pairs.len(), count * (count - 1) / 2, "not every pair plays with {} players", count);
+            assert!(pairs.values().all(|times| *times == 1), "a pair plays twice with {} players", count);
+        }
+    }
+
+    #[test]
+    fn swiss_avoids_rematches_and_gives_each_bye_once() {
+        let mut tournament = Tournament::new("t".to_string(), "Swiss".to_string(), "game".to_string(), TournamentFormat::Swiss { rounds: 3 }, None);
+        tournament.start(ids(5)).unwrap();
+
+        // The higher seed always wins
+        let rounds = play(&mut tournament, |_, m| m.player_ids.iter().min().unwrap().clone());
+        assert_eq!(rounds.len(), 3);
+
+        let mut pairs = HashSet::new();
+        let mut byes = HashSet::new();
+        for round in rounds.iter() {
+            for m in round.iter() {
+                match m.player_ids.as_slice() {
+                    [a, b] => assert!(pairs.insert(pair(a, b)), "{} and {} were paired again", a, b),
+                    [player] => assert!(byes.insert(player.clone()), "{} got a second bye", player),
+                    _ => panic!("match with {} players", m.player_ids.len())
+                }
+            }
+        }
+        assert_eq!(byes.len(), 3);
+        assert!(tournament.info.standings.iter().all(|s| s.byes <= 1));
+        assert!(matches!(tournament.info.status, TournamentStatus::Finished));
+    }
+
+    #[test]
+    fn double_elimination_ends_in_grand_final() {
+        let mut tournament = Tournament::new("t".to_string(), "Double".to_string(), "game".to_string(), TournamentFormat::DoubleElimination, None);
+        tournament.start(ids(4)).unwrap();
+
+        // The higher seed always wins, so p0 comes through the winners' bracket and p1 through the losers' bracket.
+        let rounds = play(&mut tournament, |_, m| m.player_ids.iter().min().unwrap().clone());
+        let grand_final = rounds.last().unwrap();
+        assert_eq!(grand_final.len(), 1);
+        assert_eq!(grand_final[0].player_ids, vec!["p0".to_string(), "p1".to_string()]);
+        assert_eq!(tournament.info.winner.as_deref(), Some("p0"));
+    }
+
+    #[test]
+    fn double_elimination_grand_final_is_replayed_if_the_unbeaten_player_loses() {
+        let mut tournament = Tournament::new("t".to_string(), "Double".to_string(), "game".to_string(), TournamentFormat::DoubleElimination, None);
+        tournament.start(ids(4)).unwrap();
+
+        // p0 beats p1 in the winners' bracket, p1 comes back through the losers' bracket and then beats p0 in the grand final.
+        // Both players now have one loss, so they play once more.
+        let meetings = Cell::new(0);
+        let rounds = play(&mut tournament, |_, m| {
+            if m.player_ids.iter().any(|id| id == "p0") && m.player_ids.iter().any(|id| id == "p1") {
+                meetings.set(meetings.get() + 1);
+                if meetings.get() > 1 {
+                    return "p1".to_string();
+                }
+            }
+            m.player_ids.iter().min().unwrap().clone()
+        });
+
+        assert_eq!(meetings.get(), 3);
+        for round in rounds.iter().rev().take(2) {
+            assert_eq!(round.len(), 1);
+            assert_eq!(round[0].player_ids, vec!["p0".to_string(), "p1".to_string()]);
+        }
+        assert_eq!(tournament.info.winner.as_deref(), Some("p1"));
+        assert_eq!(tournament.standing("p0").losses, 2);
+    }
+}
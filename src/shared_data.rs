@@ -1,5 +1,9 @@
+use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
+use crate::codec::Codec;
+use crate::compression::Compression;
 use crate::game_module::{GameMetadata};
+use crate::game_module::delta::StateChange;
 
 /*
     Contains data structures that are shared between client and server
@@ -12,17 +16,186 @@ pub struct Lobby {
     pub id: String,
     pub owner: String,
     pub player_ids: Vec<String>,
+    #[serde(default)]
+    pub players: Vec<PlayerProfile>, // Profile of each player, in the same order as player_ids
     pub game_started: bool,
     pub game_metadata: GameMetadata,
+    pub tournament_id: Option<String>, // Set if the lobby was created by the server for a tournament match
 }
 
 // Functions for Lobby objects
 impl Lobby {
-    // Determine if lobby is full
+    // Determine if lobby is full. Tournament lobbies are reserved for the paired participants, so they always count as full.
     pub fn is_full(&self) -> bool {
         let connected_clients = self.player_ids.len();
-        connected_clients >= self.game_metadata.max_players
+        self.tournament_id.is_some() || connected_clients >= self.game_metadata.max_players
     }
+
+    // Get the profile of a player in the lobby.
+    pub fn get_player(&self, player_id: &str) -> Option<&PlayerProfile> {
+        self.players.iter().find(|profile| profile.player_id == player_id)
+    }
+
+    // Display name of a player in the lobby. Falls back to the player ID if the lobby has no profile for them.
+    pub fn get_display_name(&self, player_id: &str) -> String {
+        self.get_player(player_id).map(|profile| profile.display_name.clone()).unwrap_or_else(|| player_id.to_string())
+    }
+
+    // ID each player's ratings are kept under, in the same order as player_ids.
+    pub fn rating_ids(&self) -> Vec<String> {
+        self.player_ids.iter().map(|id| self.get_player(id).map(|profile| profile.rating_id().to_string()).unwrap_or_else(|| id.clone())).collect()
+    }
+}
+
+// What other players can see about a player. Clients that haven't logged in to an account play as guests.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerProfile {
+    pub player_id: String, // Client ID of the player's connection. Identifies the player in lobbies and game sessions.
+    pub account_id: Option<String>, // Stable ID of the account the player is logged in to. None for guests.
+    pub display_name: String,
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>, // Anything else an application wants to show about a player, e.g. a country or title
+}
+
+impl PlayerProfile {
+    // Profile for a client that hasn't logged in. The display name is made from the start of its client ID.
+    pub fn guest(player_id: &str) -> Self {
+        Self {
+            player_id: player_id.to_string(),
+            account_id: None,
+            display_name: format!("Guest {}", player_id.chars().take(8).collect::<String>()),
+            avatar_url: None,
+            metadata: HashMap::new()
+        }
+    }
+
+    // ID the player's ratings are kept under. Logged in players are rated by account so their rating carries over between connections.
+    pub fn rating_id(&self) -> &str {
+        self.account_id.as_deref().unwrap_or(&self.player_id)
+    }
+}
+
+// What a player is doing, as far as their friends can see. Follows the client through its protocol states.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceStatus {
+    Offline,
+    Online, // Connected but not in a lobby
+    InLobby,
+    InGame
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    pub lobby_id: Option<String>, // Lobby the player is in, so friends can join them
+    pub game_type_id: Option<String>, // Game the lobby hosts
+}
+
+impl Presence {
+    pub fn offline() -> Self {
+        Self {
+            status: PresenceStatus::Offline,
+            lobby_id: None,
+            game_type_id: None
+        }
+    }
+}
+
+// Where a friendship stands from the point of view of the player looking at it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FriendStatus {
+    Friends,
+    RequestSent, // Waiting for the other player to accept
+    RequestReceived // Waiting for this player to accept
+}
+
+// An entry in a player's friend list. Presence is only shared once a request is accepted, so pending entries always show as offline.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FriendInfo {
+    pub account_id: String,
+    pub display_name: String,
+    pub status: FriendStatus,
+    pub presence: Presence,
+}
+
+// A player's skill rating for a single game type. Stored on the server and sent to clients on request.
+// Deviation and volatility are only meaningful when the server uses Glicko-2.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerRating {
+    pub player_id: String,
+    pub game_type_id: String,
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+    pub games_played: u32,
+}
+
+impl PlayerRating {
+    // Starting rating for a player that has not finished a game of this type yet.
+    pub fn new(player_id: &str, game_type_id: &str) -> Self {
+        Self {
+            player_id: player_id.to_string(),
+            game_type_id: game_type_id.to_string(),
+            rating: 1500.0,
+            deviation: 350.0,
+            volatility: 0.06,
+            games_played: 0
+        }
+    }
+}
+
+// How participants of a tournament are paired up each round.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TournamentFormat {
+    RoundRobin, // Everyone plays everyone once
+    Swiss { rounds: usize }, // Players with similar scores are paired for a fixed number of rounds. 0 picks enough rounds to find a clear winner.
+    SingleElimination, // Players are knocked out after their first loss
+    DoubleElimination // Players are knocked out after their second loss
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TournamentStatus {
+    Registering,
+    Running,
+    Finished
+}
+
+// A single pairing within a tournament round. Has one player if it is a bye.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentMatch {
+    pub round: usize,
+    pub player_ids: Vec<String>,
+    pub lobby_id: Option<String>, // Lobby the match is being played in once it has started
+    pub finished: bool,
+    pub winner: Option<String>, // None after the match is finished means it was a draw
+}
+
+// A participant's record in a tournament.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentStanding {
+    pub player_id: String,
+    pub points: f64, // 1 for a win or bye, 0.5 for a draw
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    pub byes: u32,
+}
+
+// Tournament data published to clients
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentInfo {
+    pub id: String,
+    pub name: String,
+    pub organizer: Option<String>, // Client that created the tournament. None if it was created by the server application.
+    pub game_type_id: String,
+    pub format: TournamentFormat,
+    pub status: TournamentStatus,
+    pub participant_ids: Vec<String>,
+    pub round: usize, // Current round, starting at 1. 0 while registering.
+    pub matches: Vec<TournamentMatch>, // Matches of the current round
+    pub standings: Vec<TournamentStanding>, // Sorted best first
+    pub winner: Option<String>,
 }
 
 // To extend ConnectRequest to use authentication data, then create a struct the implements this trait.
@@ -42,13 +215,25 @@ impl ConnectRequestAuth for NoAuth {
 // Represents data for connecting to the server
 pub struct ConnectRequest<T> where T: ConnectRequestAuth {
     auth_data: T,
+    pub previous_client_id: Option<String>, // Set when a client is reconnecting to resume a session restored from a snapshot
+    pub reconnect_token: Option<String>, // Secret from the ConnectResponse of the session being resumed, proving the client owns it
+    pub codecs: Vec<String>, // Names of the codecs the client can use for message bodies, most preferred first
+    pub compression: Vec<String>, // Names of the compressions the client can use for large message bodies, most preferred first
+    pub protocol_version: Option<u32>, // Newest protocol version the client speaks. None for clients from before versions were exchanged.
+    pub features: Option<Vec<String>>, // Names of the features the client supports. None for clients from before features were exchanged, which support all of them.
 }
 
 // Generic impl for ConnectRequest to use authenticate function from any custom auth struct
 impl<T> ConnectRequest<T> where T: ConnectRequestAuth {
-    pub fn new(auth_data: T) -> Self {
+    pub fn new(auth_data: T, body: ConnectRequestBody) -> Self {
         Self {
-            auth_data
+            auth_data,
+            previous_client_id: body.previous_client_id,
+            reconnect_token: body.reconnect_token,
+            codecs: body.codecs,
+            compression: body.compression,
+            protocol_version: body.protocol_version,
+            features: body.features
         }
     }
 
@@ -81,9 +266,41 @@ pub struct LobbyInfoResponse {
     pub lobby: Lobby,
 }
 
+// Body of a connect request. Always JSON, since the codec isn't picked until the server answers.
+// Clients from before versions were exchanged only send one when reconnecting, so every field is optional.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ConnectRequestBody {
+    #[serde(default)]
+    pub previous_client_id: Option<String>, // Client ID to resume the session of when reconnecting
+    #[serde(default)]
+    pub reconnect_token: Option<String>, // Reconnect token the server gave that session. Resuming fails without it.
+    #[serde(default)]
+    pub codecs: Vec<String>, // Codecs the client can use, most preferred first. Codecs the server doesn't know are skipped. JSON if none are known.
+    #[serde(default)]
+    pub compression: Vec<String>, // Compressions the client can use, most preferred first. Bodies aren't compressed if the server knows none of them.
+    #[serde(default)]
+    pub protocol_version: Option<u32>, // Newest protocol version the client speaks
+    #[serde(default)]
+    pub features: Option<Vec<String>>, // Features the client supports. Features the server doesn't know are skipped.
+}
+
+// Always JSON, like the connect request. Every message after it uses the codec the server picked.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ConnectResponse {
     pub client_id: String,
+    pub profile: PlayerProfile, // Guest profile the client starts with, even if it resumed a session. Log in again to get an account back.
+    #[serde(default)]
+    pub codec: Codec, // Codec message bodies are encoded with from now on
+    #[serde(default)]
+    pub compression: Option<Compression>, // Compression used for large bodies from now on, both ways. None if bodies are never compressed.
+    #[serde(default)]
+    pub compression_threshold: usize, // Bodies larger than this many bytes are compressed
+    #[serde(default)]
+    pub protocol_version: Option<u32>, // Protocol version used from now on, the lower of the client's and server's. None from servers from before versions were exchanged.
+    #[serde(default)]
+    pub features: Option<Vec<String>>, // Features both the client and server support. None from servers from before features were exchanged, which support all of them.
+    #[serde(default)]
+    pub reconnect_token: String, // Secret to send along with the client ID when reconnecting to resume this session. Unlike the client ID, never shown to other players.
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -106,4 +323,130 @@ pub struct SupportedGamesResponse {
     pub games: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RatingRequest {
+    pub game_type_id: String,
+    pub player_id: Option<String>, // If None, the server returns the rating of the requesting client
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RatingResponse {
+    pub rating: PlayerRating,
+}
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LeaderboardRequest {
+    pub game_type_id: String,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LeaderboardResponse {
+    pub game_type_id: String,
+    pub ratings: Vec<PlayerRating>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MatchmakingRequest {
+    pub game_type_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TournamentListResponse {
+    pub tournaments: Vec<TournamentInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CreateTournamentRequest {
+    pub name: String,
+    pub game_type_id: String,
+    pub format: TournamentFormat,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TournamentRequest {
+    pub tournament_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TournamentInfoResponse {
+    pub tournament: TournamentInfo,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub password: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+// Fields left as None keep their current value.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProfileResponse {
+    pub profile: PlayerProfile,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FriendRequest {
+    pub username: String,
+}
+
+// Used for accepting and removing friends, and inviting them to a lobby, since those only need the friend's account ID.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FriendAccountRequest {
+    pub account_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FriendListResponse {
+    pub friends: Vec<FriendInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PresenceUpdate {
+    pub account_id: String,
+    pub presence: Presence,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LobbyInvite {
+    pub lobby_id: String,
+    pub game_type_id: String,
+    pub from: PlayerProfile, // Friend that sent the invite
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatRequest {
+    pub message: String,
+}
+
+// A chat message sent to everyone in a lobby.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub player_id: String,
+    pub display_name: String, // Sender's display name when the message was sent
+    pub message: String,
+    pub sent_at: u64, // Seconds since the Unix epoch
+}
+
+// Changes to a game session's state, sent instead of the full state to players that agreed on delta updates.
+// A delta without a base version carries the full state, e.g. when a game starts or a player resyncs.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GameStateDelta {
+    pub base_version: Option<u64>, // Version of the state the changes apply to
+    pub version: u64, // Version of the state once the changes are applied
+    pub changes: Vec<StateChange>,
+}
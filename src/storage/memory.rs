@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use crate::enums::StorageError;
+use crate::shared_data::PlayerRating;
+use crate::storage::{AccountRecord, BanRecord, FriendshipRecord, GameRecord, Storage};
+
+/*
+    Storage that only lives as long as the server does. Used by default.
+ */
+
+pub struct MemoryStorage {
+    accounts: HashMap<String, AccountRecord>, // Indexed by account ID
+    friendships: HashMap<(String, String), FriendshipRecord>, // Indexed by (requester ID, addressee ID)
+    game_records: Vec<GameRecord>, // In the order games ended
+    ratings: HashMap<(String, String), PlayerRating>, // Indexed by (player ID, game type ID)
+    bans: HashMap<String, BanRecord> // Indexed by ban target
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self {
+            accounts: HashMap::new(),
+            friendships: HashMap::new(),
+            game_records: vec![],
+            ratings: HashMap::new(),
+            bans: HashMap::new()
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn save_account(&mut self, account: &AccountRecord) -> Result<(), StorageError> {
+        self.accounts.insert(account.account_id.clone(), account.clone());
+        Ok(())
+    }
+
+    fn load_account(&self, account_id: &str) -> Result<Option<AccountRecord>, StorageError> {
+        Ok(self.accounts.get(account_id).cloned())
+    }
+
+    fn find_account_by_username(&self, username: &str) -> Result<Option<AccountRecord>, StorageError> {
+        Ok(self.accounts.values().find(|a| a.username == username).cloned())
+    }
+
+    fn save_friendship(&mut self, friendship: &FriendshipRecord) -> Result<(), StorageError> {
+        self.friendships.insert((friendship.requester_id.clone(), friendship.addressee_id.clone()), friendship.clone());
+        Ok(())
+    }
+
+    fn remove_friendship(&mut self, account_id: &str, other_account_id: &str) -> Result<(), StorageError> {
+        self.friendships.remove(&(account_id.to_string(), other_account_id.to_string()));
+        self.friendships.remove(&(other_account_id.to_string(), account_id.to_string()));
+        Ok(())
+    }
+
+    fn load_friendships(&self, account_id: &str) -> Result<Vec<FriendshipRecord>, StorageError> {
+        Ok(self.friendships.values().filter(|f| f.requester_id == account_id || f.addressee_id == account_id).cloned().collect())
+    }
+
+    fn save_game_record(&mut self, record: &GameRecord) -> Result<(), StorageError> {
+        self.game_records.push(record.clone());
+        Ok(())
+    }
+
+    fn load_game_records(&self, player_id: Option<&str>, limit: usize) -> Result<Vec<GameRecord>, StorageError> {
+        Ok(self.game_records.iter().rev()
+            .filter(|r| player_id.is_none_or(|id| r.player_ids.iter().any(|p| p == id)))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn save_rating(&mut self, rating: &PlayerRating) -> Result<(), StorageError> {
+        self.ratings.insert((rating.player_id.clone(), rating.game_type_id.clone()), rating.clone());
+        Ok(())
+    }
+
+    fn load_ratings(&self) -> Result<Vec<PlayerRating>, StorageError> {
+        Ok(self.ratings.values().cloned().collect())
+    }
+
+    fn save_ban(&mut self, ban: &BanRecord) -> Result<(), StorageError> {
+        self.bans.insert(ban.target.clone(), ban.clone());
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, target: &str) -> Result<(), StorageError> {
+        self.bans.remove(target);
+        Ok(())
+    }
+
+    fn load_bans(&self) -> Result<Vec<BanRecord>, StorageError> {
+        Ok(self.bans.values().cloned().collect())
+    }
+}
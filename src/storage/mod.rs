@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use crate::enums::StorageError;
+use crate::game_module::{GameModule, GameMove};
+use crate::shared_data::PlayerRating;
+
+pub use memory::MemoryStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/*
+    Persistent storage for server data that should outlive a server restart: accounts, friendships, completed games, ratings and bans.
+    The server writes through a Storage implementation as things happen, and loads what it needs back when the storage is set.
+    MemoryStorage is the default and keeps nothing across restarts. SqliteStorage (enable the "sqlite" feature) stores everything in an embedded database.
+    Implement the Storage trait to use any other backend.
+ */
+
+// Trait for storage backends. Must be thread safe since the server state is shared between client threads.
+pub trait Storage: Send {
+    fn save_account(&mut self, account: &AccountRecord) -> Result<(), StorageError>; // Insert or replace an account, keyed by account ID.
+    fn load_account(&self, account_id: &str) -> Result<Option<AccountRecord>, StorageError>; // Get an account by its ID.
+    fn find_account_by_username(&self, username: &str) -> Result<Option<AccountRecord>, StorageError>; // Get an account by its unique username.
+    fn save_friendship(&mut self, friendship: &FriendshipRecord) -> Result<(), StorageError>; // Insert or replace a friendship, keyed by requester and addressee.
+    fn remove_friendship(&mut self, account_id: &str, other_account_id: &str) -> Result<(), StorageError>; // Remove the friendship between two accounts, whichever of them sent the request.
+    fn load_friendships(&self, account_id: &str) -> Result<Vec<FriendshipRecord>, StorageError>; // Every friendship and pending request an account is part of.
+    fn save_game_record(&mut self, record: &GameRecord) -> Result<(), StorageError>; // Store a completed game.
+    fn load_game_records(&self, player_id: Option<&str>, limit: usize) -> Result<Vec<GameRecord>, StorageError>; // Most recent completed games first, optionally only those a player took part in.
+    fn save_rating(&mut self, rating: &PlayerRating) -> Result<(), StorageError>; // Insert or replace a rating, keyed by player ID and game type ID.
+    fn load_ratings(&self) -> Result<Vec<PlayerRating>, StorageError>; // Get every stored rating.
+    fn save_ban(&mut self, ban: &BanRecord) -> Result<(), StorageError>; // Insert or replace a ban, keyed by its target.
+    fn remove_ban(&mut self, target: &str) -> Result<(), StorageError>; // Lift the ban on a target.
+    fn load_bans(&self) -> Result<Vec<BanRecord>, StorageError>; // Get every stored ban.
+}
+
+// A registered player account.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountRecord {
+    pub account_id: String,
+    pub username: String,
+    pub password_hash: String,
+    pub display_name: String,
+    pub created_at: u64, // Seconds since the Unix epoch
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>, // Extra profile fields shown to other players
+}
+
+// A friendship between two accounts, or a request for one that hasn't been accepted yet.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FriendshipRecord {
+    pub requester_id: String, // Account that sent the friend request
+    pub addressee_id: String, // Account the request was sent to
+    pub accepted: bool,
+    pub created_at: u64, // Seconds since the Unix epoch
+}
+
+impl FriendshipRecord {
+    // The account on the other side of the friendship from the given one.
+    pub fn other(&self, account_id: &str) -> &str {
+        if self.requester_id == account_id { &self.addressee_id } else { &self.requester_id }
+    }
+}
+
+// A completed game session. Moves are stored in the order they were applied so the game can be replayed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameRecord {
+    pub game_id: String,
+    pub game_type_id: String,
+    pub player_ids: Vec<String>, // Account ID of each logged in player and client ID of each guest, in the order they were added to the game session
+    pub winner: Option<String>, // Identified the same way as the players
+    pub moves: Vec<String>, // Each move serialized as JSON
+    pub final_state: String, // Game state when the end condition was met, serialized as JSON
+    pub started_at: u64, // Seconds since the Unix epoch
+    pub ended_at: u64,
+}
+
+impl GameRecord {
+    // Rebuild the game by applying every recorded move to a new instance of the game module.
+    // Pass the registered module for the record's game type to use as a factory.
+    pub fn replay(&self, factory: &dyn GameModule) -> Result<Box<dyn GameModule>, StorageError> {
+        let mut game = factory.init_new();
+        for id in self.player_ids.iter() {
+            game.add_player(id.clone());
+        }
+        for serialized_move in self.moves.iter() {
+            match serde_json::from_str::<Box<dyn GameMove>>(serialized_move) {
+                Ok(game_move) => game.apply_move(game_move.as_ref()),
+                Err(e) => return Err(StorageError::SerializeError(e.to_string()))
+            }
+        }
+        Ok(game)
+    }
+}
+
+// A ban on an account ID or IP address.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BanRecord {
+    pub target: String, // Account ID or IP address
+    pub reason: String,
+    pub expires_at: Option<u64>, // Seconds since the Unix epoch. None if the ban is permanent.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game_record(game_id: &str, player_ids: &[&str], winner: Option<&str>, ended_at: u64) -> GameRecord {
+        GameRecord {
+            game_id: game_id.to_string(),
+            game_type_id: "game".to_string(),
+            player_ids: player_ids.iter().map(|id| id.to_string()).collect(),
+            winner: winner.map(|id| id.to_string()),
+            moves: vec!["{\"Move\":{\"index\":4}}".to_string(), "{\"Move\":{\"index\":0}}".to_string()],
+            final_state: "{\"State\":{\"board\":[1,0,0,0,2]}}".to_string(),
+            started_at: ended_at - 60,
+            ended_at
+        }
+    }
+
+    // Save game records and ratings, then check they come back the same, newest games first.
+    fn round_trip(storage: &mut dyn Storage) {
+        let first = game_record("first", &["a", "b"], Some("a"), 1000);
+        let second = game_record("second", &["b", "c"], None, 2000);
+        storage.save_game_record(&first).unwrap();
+        storage.save_game_record(&second).unwrap();
+
+        let records = storage.load_game_records(None, 10).unwrap();
+        let ids: Vec<&str> = records.iter().map(|r| r.game_id.as_str()).collect();
+        assert_eq!(ids, vec!["second", "first"]);
+        let loaded = &records[1];
+        assert_eq!(loaded.game_type_id, first.game_type_id);
+        assert_eq!(loaded.player_ids, first.player_ids);
+        assert_eq!(loaded.winner, first.winner);
+        assert_eq!(loaded.moves, first.moves);
+        assert_eq!(loaded.final_state, first.final_state);
+        assert_eq!((loaded.started_at, loaded.ended_at), (first.started_at, first.ended_at));
+        assert_eq!(records[0].winner, None);
+
+        // Filtering by player and limiting
+        let records = storage.load_game_records(Some("a"), 10).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].game_id, "first");
+        assert_eq!(storage.load_game_records(Some("b"), 1).unwrap()[0].game_id, "second");
+
+        // Saving a rating again replaces it
+        let mut rating = PlayerRating::new("a", "game");
+        storage.save_rating(&rating).unwrap();
+        rating.rating = 1516.5;
+        rating.deviation = 290.25;
+        rating.volatility = 0.0599;
+        rating.games_played = 1;
+        storage.save_rating(&rating).unwrap();
+
+        let ratings = storage.load_ratings().unwrap();
+        assert_eq!(ratings.len(), 1);
+        assert_eq!(ratings[0].player_id, "a");
+        assert_eq!(ratings[0].game_type_id, "game");
+        assert_eq!(ratings[0].rating, rating.rating);
+        assert_eq!(ratings[0].deviation, rating.deviation);
+        assert_eq!(ratings[0].volatility, rating.volatility);
+        assert_eq!(ratings[0].games_played, 1);
+    }
+
+    #[test]
+    fn memory_storage_round_trip() {
+        round_trip(&mut MemoryStorage::new());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_storage_round_trip() {
+        round_trip(&mut SqliteStorage::open_in_memory().unwrap());
+    }
+}
@@ -0,0 +1,340 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use rusqlite::types::Type;
+use crate::enums::StorageError;
+use crate::shared_data::PlayerRating;
+use crate::storage::{AccountRecord, BanRecord, FriendshipRecord, GameRecord, Storage};
+
+/*
+    Storage backed by an embedded SQLite database. Only available with the "sqlite" feature.
+    The schema is created and upgraded by running migrations in order when the database is opened.
+    The number of migrations already applied is tracked with SQLite's user_version pragma.
+ */
+
+// Each entry upgrades the schema by one version. Only ever append to this list so existing databases can be upgraded.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE accounts (
+        account_id TEXT PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        display_name TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE game_records (
+        game_id TEXT PRIMARY KEY,
+        game_type_id TEXT NOT NULL,
+        player_ids TEXT NOT NULL,
+        winner TEXT,
+        moves TEXT NOT NULL,
+        final_state TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER NOT NULL
+    );
+    CREATE TABLE game_players (
+        game_id TEXT NOT NULL REFERENCES game_records(game_id),
+        player_id TEXT NOT NULL
+    );
+    CREATE INDEX game_players_player_id ON game_players(player_id);
+    CREATE TABLE ratings (
+        player_id TEXT NOT NULL,
+        game_type_id TEXT NOT NULL,
+        rating REAL NOT NULL,
+        deviation REAL NOT NULL,
+        volatility REAL NOT NULL,
+        games_played INTEGER NOT NULL,
+        PRIMARY KEY (player_id, game_type_id)
+    );
+    CREATE TABLE bans (
+        target TEXT PRIMARY KEY,
+        reason TEXT NOT NULL,
+        expires_at INTEGER
+    );",
+    "ALTER TABLE accounts ADD COLUMN avatar_url TEXT;
+    ALTER TABLE accounts ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';",
+    "CREATE TABLE friendships (
+        requester_id TEXT NOT NULL,
+        addressee_id TEXT NOT NULL,
+        accepted INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (requester_id, addressee_id)
+    );
+    CREATE INDEX friendships_addressee_id ON friendships(addressee_id);"
+];
+
+pub struct SqliteStorage {
+    connection: Connection
+}
+
+impl SqliteStorage {
+    // Open or create a database file and bring its schema up to date.
+    pub fn open(path: &str) -> Result<Self, StorageError> {
+        let connection = Connection::open(path).map_err(|e| StorageError::OpenError(e.to_string()))?;
+        Self::from_connection(connection)
+    }
+
+    // Open a database that only exists in memory. Mostly useful for testing.
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        let connection = Connection::open_in_memory().map_err(|e| StorageError::OpenError(e.to_string()))?;
+        Self::from_connection(connection)
+    }
+
+    fn from_connection(mut connection: Connection) -> Result<Self, StorageError> {
+        migrate(&mut connection)?;
+        Ok(Self { connection })
+    }
+}
+
+// Run every migration the database hasn't had yet. Each one runs in its own transaction along with the version bump.
+fn migrate(connection: &mut Connection) -> Result<(), StorageError> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(|e| StorageError::MigrationError(e.to_string()))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction().map_err(|e| StorageError::MigrationError(e.to_string()))?;
+        transaction.execute_batch(migration).map_err(|e| StorageError::MigrationError(e.to_string()))?;
+        transaction.pragma_update(None, "user_version", i + 1).map_err(|e| StorageError::MigrationError(e.to_string()))?;
+        transaction.commit().map_err(|e| StorageError::MigrationError(e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn query_error(e: rusqlite::Error) -> StorageError {
+    StorageError::QueryError(e.to_string())
+}
+
+fn serialize_error(e: serde_json::Error) -> StorageError {
+    StorageError::SerializeError(e.to_string())
+}
+
+// Metadata is stored as a JSON object
+fn account_from_row(row: &Row) -> rusqlite::Result<AccountRecord> {
+    let metadata = row.get::<_, String>(6)?;
+    Ok(AccountRecord {
+        account_id: row.get(0)?,
+        username: row.get(1)?,
+        password_hash: row.get(2)?,
+        display_name: row.get(3)?,
+        created_at: row.get::<_, i64>(4)? as u64,
+        avatar_url: row.get(5)?,
+        metadata: serde_json::from_str(&metadata).map_err(|e| rusqlite::Error::FromSqlConversionFailure(6, Type::Text, Box::new(e)))?
+    })
+}
+
+impl Storage for SqliteStorage {
+    fn save_account(&mut self, account: &AccountRecord) -> Result<(), StorageError> {
+        let metadata = serde_json::to_string(&account.metadata).map_err(serialize_error)?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO accounts (account_id, username, password_hash, display_name, created_at, avatar_url, metadata) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![account.account_id, account.username, account.password_hash, account.display_name, account.created_at as i64, account.avatar_url, metadata]
+        ).map_err(query_error)?;
+        Ok(())
+    }
+
+    fn load_account(&self, account_id: &str) -> Result<Option<AccountRecord>, StorageError> {
+        self.connection.query_row(
+            "SELECT account_id, username, password_hash, display_name, created_at, avatar_url, metadata FROM accounts WHERE account_id = ?1",
+            params![account_id],
+            account_from_row
+        ).optional().map_err(query_error)
+    }
+
+    fn find_account_by_username(&self, username: &str) -> Result<Option<AccountRecord>, StorageError> {
+        self.connection.query_row(
+            "SELECT account_id, username, password_hash, display_name, created_at, avatar_url, metadata FROM accounts WHERE username = ?1",
+            params![username],
+            account_from_row
+        ).optional().map_err(query_error)
+    }
+
+    fn save_friendship(&mut self, friendship: &FriendshipRecord) -> Result<(), StorageError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO friendships (requester_id, addressee_id, accepted, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![friendship.requester_id, friendship.addressee_id, friendship.accepted, friendship.created_at as i64]
+        ).map_err(query_error)?;
+        Ok(())
+    }
+
+    fn remove_friendship(&mut self, account_id: &str, other_account_id: &str) -> Result<(), StorageError> {
+        self.connection.execute(
+            "DELETE FROM friendships WHERE (requester_id = ?1 AND addressee_id = ?2) OR (requester_id = ?2 AND addressee_id = ?1)",
+            params![account_id, other_account_id]
+        ).map_err(query_error)?;
+        Ok(())
+    }
+
+    fn load_friendships(&self, account_id: &str) -> Result<Vec<FriendshipRecord>, StorageError> {
+        let mut statement = self.connection.prepare(
+            "SELECT requester_id, addressee_id, accepted, created_at FROM friendships WHERE requester_id = ?1 OR addressee_id = ?1"
+        ).map_err(query_error)?;
+        let rows = statement.query_map(params![account_id], |row| {
+            Ok(FriendshipRecord {
+                requester_id: row.get(0)?,
+                addressee_id: row.get(1)?,
+                accepted: row.get(2)?,
+                created_at: row.get::<_, i64>(3)? as u64
+            })
+        }).map_err(query_error)?;
+        rows.collect::<Result<Vec<FriendshipRecord>, _>>().map_err(query_error)
+    }
+
+    fn save_game_record(&mut self, record: &GameRecord) -> Result<(), StorageError> {
+        let player_ids = serde_json::to_string(&record.player_ids).map_err(serialize_error)?;
+        let moves = serde_json::to_string(&record.moves).map_err(serialize_error)?;
+
+        // Store the record and which players took part in the same transaction
+        let transaction = self.connection.transaction().map_err(query_error)?;
+        transaction.execute(
+            "INSERT INTO game_records (game_id, game_type_id, player_ids, winner, moves, final_state, started_at, ended_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![record.game_id, record.game_type_id, player_ids, record.winner, moves, record.final_state, record.started_at as i64, record.ended_at as i64]
+        ).map_err(query_error)?;
+        for player_id in record.player_ids.iter() {
+            transaction.execute(
+                "INSERT INTO game_players (game_id, player_id) VALUES (?1, ?2)",
+                params![record.game_id, player_id]
+            ).map_err(query_error)?;
+        }
+        transaction.commit().map_err(query_error)
+    }
+
+    fn load_game_records(&self, player_id: Option<&str>, limit: usize) -> Result<Vec<GameRecord>, StorageError> {
+        let mut statement = self.connection.prepare(
+            "SELECT game_id, game_type_id, player_ids, winner, moves, final_state, started_at, ended_at FROM game_records
+            WHERE ?1 IS NULL OR game_id IN (SELECT game_id FROM game_players WHERE player_id = ?1)
+            ORDER BY ended_at DESC, rowid DESC LIMIT ?2"
+        ).map_err(query_error)?;
+        let rows = statement.query_map(params![player_id, limit as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, i64>(6)?,
+                row.get::<_, i64>(7)?
+            ))
+        }).map_err(query_error)?;
+
+        // Player IDs and moves are stored as JSON arrays
+        let mut records = vec![];
+        for row in rows {
+            let (game_id, game_type_id, player_ids, winner, moves, final_state, started_at, ended_at) = row.map_err(query_error)?;
+            records.push(GameRecord {
+                game_id,
+                game_type_id,
+                player_ids: serde_json::from_str(&player_ids).map_err(serialize_error)?,
+                winner,
+                moves: serde_json::from_str(&moves).map_err(serialize_error)?,
+                final_state,
+                started_at: started_at as u64,
+                ended_at: ended_at as u64
+            });
+        }
+        Ok(records)
+    }
+
+    fn save_rating(&mut self, rating: &PlayerRating) -> Result<(), StorageError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO ratings (player_id, game_type_id, rating, deviation, volatility, games_played) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![rating.player_id, rating.game_type_id, rating.rating, rating.deviation, rating.volatility, rating.games_played]
+        ).map_err(query_error)?;
+        Ok(())
+    }
+
+    fn load_ratings(&self) -> Result<Vec<PlayerRating>, StorageError> {
+        let mut statement = self.connection.prepare(
+            "SELECT player_id, game_type_id, rating, deviation, volatility, games_played FROM ratings"
+        ).map_err(query_error)?;
+        let rows = statement.query_map([], |row| {
+            Ok(PlayerRating {
+                player_id: row.get(0)?,
+                game_type_id: row.get(1)?,
+                rating: row.get(2)?,
+                deviation: row.get(3)?,
+                volatility: row.get(4)?,
+                games_played: row.get(5)?
+            })
+        }).map_err(query_error)?;
+        rows.collect::<Result<Vec<PlayerRating>, _>>().map_err(query_error)
+    }
+
+    fn save_ban(&mut self, ban: &BanRecord) -> Result<(), StorageError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO bans (target, reason, expires_at) VALUES (?1, ?2, ?3)",
+            params![ban.target, ban.reason, ban.expires_at.map(|t| t as i64)]
+        ).map_err(query_error)?;
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, target: &str) -> Result<(), StorageError> {
+        self.connection.execute("DELETE FROM bans WHERE target = ?1", params![target]).map_err(query_error)?;
+        Ok(())
+    }
+
+    fn load_bans(&self) -> Result<Vec<BanRecord>, StorageError> {
+        let mut statement = self.connection.prepare("SELECT target, reason, expires_at FROM bans").map_err(query_error)?;
+        let rows = statement.query_map([], |row| {
+            Ok(BanRecord {
+                target: row.get(0)?,
+                reason: row.get(1)?,
+                expires_at: row.get::<_, Option<i64>>(2)?.map(|t| t as u64)
+            })
+        }).map_err(query_error)?;
+        rows.collect::<Result<Vec<BanRecord>, _>>().map_err(query_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Databases created by earlier versions are left at every point of the migration chain. Each of them must upgrade to the latest schema.
+    #[test]
+    fn migrates_from_every_version() {
+        for version in 0..=MIGRATIONS.len() {
+            let connection = Connection::open_in_memory().unwrap();
+            for migration in MIGRATIONS.iter().take(version) {
+                connection.execute_batch(migration).unwrap();
+            }
+            connection.pragma_update(None, "user_version", version).unwrap();
+
+            // An account created before profiles existed, using only the columns of the first schema
+            if version >= 1 {
+                connection.execute(
+                    "INSERT INTO accounts (account_id, username, password_hash, display_name, created_at) VALUES ('a', 'alice', 'hash', 'Alice', 1)",
+                    []
+                ).unwrap();
+            }
+
+            let mut storage = SqliteStorage::from_connection(connection).unwrap_or_else(|e| panic!("migrating from version {} failed: {:?}", version, e));
+            let migrated: usize = storage.connection.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+            assert_eq!(migrated, MIGRATIONS.len(), "migrating from version {}", version);
+
+            if version >= 1 {
+                let account = storage.load_account("a").unwrap().unwrap();
+                assert_eq!(account.username, "alice");
+                assert_eq!(account.avatar_url, None);
+                assert!(account.metadata.is_empty());
+            }
+
+            // Every table of the latest schema is usable
+            storage.save_friendship(&FriendshipRecord {
+                requester_id: "a".to_string(),
+                addressee_id: "b".to_string(),
+                accepted: false,
+                created_at: 2
+            }).unwrap();
+            assert_eq!(storage.load_friendships("b").unwrap().len(), 1);
+            storage.save_rating(&PlayerRating::new("a", "game")).unwrap();
+            assert_eq!(storage.load_ratings().unwrap().len(), 1);
+        }
+    }
+
+    // Opening an up to date database again runs nothing.
+    #[test]
+    fn migrating_twice_is_a_no_op() {
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        storage.save_rating(&PlayerRating::new("a", "game")).unwrap();
+        migrate(&mut storage.connection).unwrap();
+        assert_eq!(storage.load_ratings().unwrap().len(), 1);
+    }
+}
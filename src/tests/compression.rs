@@ -0,0 +1,53 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use flate2::write::DeflateEncoder;
+use game_protocol::{GameProtocolServer, ServerConfig, StatusCode};
+
+// Wire numbers of message types used here
+const PROTOCOL_ERROR: u16 = 1;
+const CONNECT_REQUEST: u16 = 2;
+const LOBBY_LIST_REQUEST: u16 = 8;
+
+// A client message with the given body size field, checksum and body.
+fn client_message(message_id: u32, message_type: u16, size: u32, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![];
+    message.extend_from_slice(&message_id.to_be_bytes());
+    message.extend_from_slice(&message_type.to_be_bytes());
+    message.extend_from_slice(&size.to_be_bytes());
+    message.extend_from_slice(&crc32fast::hash(body).to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+#[test]
+fn body_inflating_past_the_limit_gets_an_error_status() {
+    let config = ServerConfig::builder().bind("127.0.0.1", 0).max_message_size(4096).compression_threshold(64).build().unwrap();
+    let handle = GameProtocolServer::with_config(config).spawn().unwrap();
+    let mut stream = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // Agree on Deflate, then skip past the connect response
+    let body = b"{\"protocol_version\":3,\"compression\":[\"Deflate\"]}";
+    stream.write_all(&client_message(0, CONNECT_REQUEST, body.len() as u32, body)).unwrap();
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).unwrap();
+    assert_eq!(u16::from_be_bytes([header[0], header[1]]), StatusCode::Success as u16);
+    let mut rest = vec![0u8; u32::from_be_bytes(header[4..].try_into().unwrap()) as usize + 4];
+    stream.read_exact(&mut rest).unwrap();
+
+    // A small compressed body that inflates to a megabyte, far past the 4 KB limit
+    let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(&vec![0u8; 1024 * 1024]).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert!(compressed.len() < 4096);
+    stream.write_all(&client_message(1, LOBBY_LIST_REQUEST, compressed.len() as u32 | 1 << 31, &compressed)).unwrap();
+
+    // The server answers with an error status and closes the connection
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    handle.shutdown(Duration::ZERO);
+    assert!(response.len() >= 4);
+    assert_eq!(u16::from_be_bytes([response[0], response[1]]), StatusCode::MalformedBody as u16);
+    assert_eq!(u16::from_be_bytes([response[2], response[3]]), PROTOCOL_ERROR);
+}
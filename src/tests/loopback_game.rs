@@ -0,0 +1,168 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use game_protocol::{Codec, Compression, Feature, GameProtocolClient, GameProtocolServer, LoopbackConnector, ProtocolState, ServerConfig, ServerHandle};
+use tic_tac_toe::{CellElement, TicTacToe, TicTacToeMove, TicTacToeState};
+
+/*
+    Clients and a server in the same process, connected through loopback connections, playing tic-tac-toe from start to finish
+    with each codec, compression, and delta or full state updates.
+ */
+
+const GAME_TYPE_ID: &str = "Tic-tac-toe v1.0";
+
+// X takes the top row while O plays the middle row
+const X_WINS: [(usize, usize); 5] = [(0, 0), (1, 0), (0, 1), (1, 1), (0, 2)];
+
+fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn start_server(config: ServerConfig) -> (ServerHandle, LoopbackConnector) {
+    let mut server = GameProtocolServer::with_config(config);
+    server.register_game::<TicTacToe>();
+    server.disable_tcp();
+    let connector = server.loopback_connector();
+    (server.spawn().unwrap(), connector)
+}
+
+fn connect(connector: &LoopbackConnector, setup: impl FnOnce(&mut GameProtocolClient)) -> GameProtocolClient {
+    let mut client = GameProtocolClient::new();
+    client.register_game::<TicTacToe>();
+    setup(&mut client);
+    client.connect_with(connector.connect().unwrap());
+    wait_for("connect", || client.get_protocol_state() == ProtocolState::Idle);
+    client.async_listen();
+    client
+}
+
+// Put both clients in a lobby and start a game of tic-tac-toe.
+fn start_game(host: &GameProtocolClient, guest: &GameProtocolClient) {
+    host.create_lobby(GAME_TYPE_ID);
+    wait_for("lobby", || host.get_current_lobby().is_some());
+    guest.join_lobby(&host.get_current_lobby().unwrap().id);
+    wait_for("join", || host.get_current_lobby().is_some_and(|lobby| lobby.player_ids.len() == 2));
+    host.start_game();
+    wait_for("game start", || host.get_protocol_state() == ProtocolState::GameRunning && guest.get_protocol_state() == ProtocolState::GameRunning);
+}
+
+fn board(client: &GameProtocolClient) -> Vec<Vec<CellElement>> {
+    let state = client.get_game_state().unwrap();
+    state.as_any().downcast_ref::<TicTacToeState>().unwrap().board.clone()
+}
+
+// Make a move for whichever player's turn it is and wait until both clients see it.
+fn play(a: &GameProtocolClient, b: &GameProtocolClient, (row, column): (usize, usize)) {
+    let state = a.get_game_state().unwrap();
+    let state = state.as_any().downcast_ref::<TicTacToeState>().unwrap();
+    let player_id = if matches!(state.this_turn, CellElement::X) { &state.x_player_id } else { &state.o_player_id };
+    let player = if *player_id == a.get_client_id() { a } else { b };
+    player.make_move(&TicTacToeMove { board_index: (row, column), symbol: state.this_turn });
+    wait_for("move", || board(a)[row][column] == state.this_turn && board(b)[row][column] == state.this_turn);
+}
+
+// Play X_WINS and check both clients agree X won.
+fn play_to_completion(a: &GameProtocolClient, b: &GameProtocolClient) {
+    let state = a.get_game_state().unwrap();
+    let x_player_id = state.as_any().downcast_ref::<TicTacToeState>().unwrap().x_player_id.clone();
+    for position in X_WINS {
+        assert_eq!(a.get_game_end_result(), Some((false, None)));
+        play(a, b, position);
+    }
+    assert_eq!(a.get_game_end_result(), Some((true, Some(x_player_id.clone()))));
+    assert_eq!(b.get_game_end_result(), Some((true, Some(x_player_id))));
+}
+
+#[test]
+fn plays_tic_tac_toe_with_delta_updates() {
+    let (server, connector) = start_server(ServerConfig::default());
+    let a = connect(&connector, |_| {});
+    let b = connect(&connector, |_| {});
+    assert!(a.get_features().contains(&Feature::GameStateDeltas));
+    start_game(&a, &b);
+
+    let start_version = a.get_game_state_version().unwrap();
+    play_to_completion(&a, &b);
+    assert_eq!(a.get_game_state_version(), Some(start_version + X_WINS.len() as u64));
+    assert_eq!(b.get_game_state_version(), a.get_game_state_version());
+    server.shutdown(Duration::ZERO);
+}
+
+#[test]
+fn plays_tic_tac_toe_with_full_state_updates() {
+    let (server, connector) = start_server(ServerConfig::default());
+    let without_deltas: Vec<Feature> = Feature::ALL.into_iter().filter(|feature| *feature != Feature::GameStateDeltas).collect();
+    let a = connect(&connector, |client| client.set_features(&without_deltas));
+    let b = connect(&connector, |_| {});
+    assert!(!a.get_features().contains(&Feature::GameStateDeltas));
+    start_game(&a, &b);
+
+    play_to_completion(&a, &b);
+    assert_eq!(a.get_game_state_version(), None);
+    assert!(b.get_game_state_version().is_some());
+    server.shutdown(Duration::ZERO);
+}
+
+#[test]
+fn plays_tic_tac_toe_with_message_pack_and_compression() {
+    // Small enough that game states and lobby info are compressed
+    let config = ServerConfig::builder().compression_threshold(32).build().unwrap();
+    let (server, connector) = start_server(config);
+    let a = connect(&connector, |client| {
+        client.set_codec(Codec::MessagePack);
+        client.set_compression(Some(Compression::Deflate));
+    });
+    let b = connect(&connector, |client| client.set_compression(Some(Compression::Deflate)));
+    assert_eq!(a.get_codec(), Codec::MessagePack);
+    assert_eq!(a.get_compression(), Some(Compression::Deflate));
+    assert_eq!(b.get_codec(), Codec::Json);
+    assert_eq!(b.get_compression(), Some(Compression::Deflate));
+    start_game(&a, &b);
+
+    play_to_completion(&a, &b);
+    server.shutdown(Duration::ZERO);
+}
+
+#[test]
+fn game_state_request_resyncs_the_game() {
+    let (server, connector) = start_server(ServerConfig::default());
+    let a = connect(&connector, |_| {});
+    let b = connect(&connector, |_| {});
+    start_game(&a, &b);
+    play(&a, &b, X_WINS[0]);
+    play(&a, &b, X_WINS[1]);
+
+    // The full state comes back at the same version, and later deltas apply on top of it
+    let version = a.get_game_state_version();
+    a.request_game_state();
+    wait_for("game state", || a.get_protocol_state() == ProtocolState::GameRunning);
+    assert_eq!(a.get_game_state_version(), version);
+    assert_eq!(board(&a), board(&b));
+    for position in &X_WINS[2..] {
+        play(&a, &b, *position);
+    }
+    assert!(a.get_game_end_result().unwrap().0);
+    server.shutdown(Duration::ZERO);
+}
+
+#[test]
+fn moves_for_the_other_player_are_rejected() {
+    let (server, connector) = start_server(ServerConfig::default());
+    let a = connect(&connector, |_| {});
+    let b = connect(&connector, |_| {});
+    start_game(&a, &b);
+
+    // The O player tries to place an X on X's turn. Whenever the server gets to it, X isn't O's symbol.
+    let state = a.get_game_state().unwrap();
+    let x_player_id = state.as_any().downcast_ref::<TicTacToeState>().unwrap().x_player_id.clone();
+    let o_player = if x_player_id == a.get_client_id() { &b } else { &a };
+    o_player.make_move(&TicTacToeMove { board_index: (2, 2), symbol: CellElement::X });
+    play(&a, &b, X_WINS[0]);
+    play(&a, &b, X_WINS[1]);
+    assert_eq!(board(&a)[2][2], CellElement::None);
+    assert_eq!(board(&b)[2][2], CellElement::None);
+    server.shutdown(Duration::ZERO);
+}
@@ -0,0 +1,65 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use game_protocol::{GameProtocolServer, StatusCode, MIN_PROTOCOL_VERSION};
+
+// Wire number of MessageType::ConnectRequest and ConnectResponse
+const CONNECT_REQUEST: u16 = 2;
+const CONNECT_RESPONSE: u16 = 3;
+
+// Send a raw connect request with the given JSON body, like a client built against an older version of the crate would,
+// and return the status code and message type of the server's response.
+fn connect_raw(body: &str) -> (u16, u16) {
+    let handle = GameProtocolServer::new("127.0.0.1", "0").spawn().unwrap();
+    let mut stream = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut request = vec![];
+    request.extend_from_slice(&0u32.to_be_bytes());
+    request.extend_from_slice(&CONNECT_REQUEST.to_be_bytes());
+    request.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    request.extend_from_slice(&crc32fast::hash(body.as_bytes()).to_be_bytes());
+    request.extend_from_slice(body.as_bytes());
+    stream.write_all(&request).unwrap();
+
+    let mut headers = [0u8; 4];
+    stream.read_exact(&mut headers).unwrap();
+    handle.shutdown(Duration::ZERO);
+    (u16::from_be_bytes([headers[0], headers[1]]), u16::from_be_bytes([headers[2], headers[3]]))
+}
+
+#[test]
+fn client_older_than_the_minimum_is_rejected() {
+    let body = format!("{{\"protocol_version\":{}}}", MIN_PROTOCOL_VERSION - 1);
+    assert_eq!(connect_raw(&body), (StatusCode::IncompatibleProtocolVersion as u16, CONNECT_RESPONSE));
+}
+
+#[test]
+fn unversioned_client_is_rejected() {
+    assert_eq!(connect_raw("{}"), (StatusCode::IncompatibleProtocolVersion as u16, CONNECT_RESPONSE));
+}
+
+#[test]
+fn baseline_client_is_rejected_cleanly() {
+    let handle = GameProtocolServer::new("127.0.0.1", "0").spawn().unwrap();
+    let mut stream = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // Connect request exactly as the original client built it: message ID 0, message type, and an empty body size
+    stream.write_all(&[0, 0, 0, 0, 0, 2, 0, 0, 0, 0]).unwrap();
+
+    // The version error is the whole response, and the server closes the connection after it
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    handle.shutdown(Duration::ZERO);
+    let mut expected = vec![];
+    expected.extend_from_slice(&(StatusCode::IncompatibleProtocolVersion as u16).to_be_bytes());
+    expected.extend_from_slice(&CONNECT_RESPONSE.to_be_bytes());
+    expected.extend_from_slice(&0u32.to_be_bytes());
+    assert_eq!(response, expected);
+}
+
+#[test]
+fn client_newer_than_the_server_is_accepted() {
+    assert_eq!(connect_raw("{\"protocol_version\":4294967295}"), (StatusCode::Success as u16, CONNECT_RESPONSE));
+}
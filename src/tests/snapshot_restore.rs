@@ -0,0 +1,132 @@
+use std::env;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use game_protocol::{GameProtocolClient, GameProtocolServer, ProtocolState, ServerHandle, TournamentFormat};
+use tic_tac_toe::{CellElement, TicTacToe, TicTacToeMove, TicTacToeState};
+
+/*
+    A game of tic-tac-toe that survives a server restart through a snapshot, with its players reconnecting to their seats.
+ */
+
+const GAME_TYPE_ID: &str = "Tic-tac-toe v1.0";
+
+fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Unique file to snapshot to for each test.
+fn snapshot_path(test: &str) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let path = env::temp_dir().join(format!("game_protocol_{}_{}_{}.json", test, std::process::id(), nanos));
+    path.to_str().unwrap().to_string()
+}
+
+fn start_server(snapshot_path: &str) -> (ServerHandle, String) {
+    let mut server = GameProtocolServer::new("127.0.0.1", "0");
+    server.register_game::<TicTacToe>();
+    server.enable_snapshots(snapshot_path, Duration::from_secs(60));
+    let handle = server.spawn().unwrap();
+    let port = handle.local_addr().unwrap().port().to_string();
+    (handle, port)
+}
+
+fn connect(port: &str) -> GameProtocolClient {
+    let mut client = GameProtocolClient::new();
+    client.register_game::<TicTacToe>();
+    client.connect("127.0.0.1", port);
+    wait_for("connect", || client.get_protocol_state() == ProtocolState::Idle);
+    client.async_listen();
+    client
+}
+
+fn board(client: &GameProtocolClient) -> Vec<Vec<CellElement>> {
+    let state = client.get_game_state().unwrap();
+    state.as_any().downcast_ref::<TicTacToeState>().unwrap().board.clone()
+}
+
+// Make a move and wait until both clients see it.
+fn play(player: &GameProtocolClient, other: &GameProtocolClient, (row, column): (usize, usize), symbol: CellElement) {
+    player.make_move(&TicTacToeMove { board_index: (row, column), symbol });
+    wait_for("move", || board(player)[row][column] == symbol && board(other)[row][column] == symbol);
+}
+
+#[test]
+fn logged_in_player_keeps_playing_after_a_restore() {
+    let path = snapshot_path("logged_in_restore");
+
+    // The host logs in before creating the lobby, so it's seated first and plays X
+    let (server, port) = start_server(&path);
+    let mut host = connect(&port);
+    let mut guest = connect(&port);
+    host.register("snapshot_host", "correct horse battery", "Host");
+    wait_for("login", || host.get_profile().is_some_and(|profile| profile.account_id.is_some()));
+    host.create_lobby(GAME_TYPE_ID);
+    wait_for("lobby", || host.get_current_lobby().is_some());
+    guest.join_lobby(&host.get_current_lobby().unwrap().id);
+    wait_for("join", || host.get_current_lobby().is_some_and(|lobby| lobby.player_ids.len() == 2));
+    host.start_game();
+    wait_for("game start", || host.get_protocol_state() == ProtocolState::GameRunning && guest.get_protocol_state() == ProtocolState::GameRunning);
+    play(&host, &guest, (0, 0), CellElement::X);
+
+    // Shutting down takes the final snapshot, which the next server restores
+    server.shutdown(Duration::ZERO);
+    wait_for("disconnect", || host.get_protocol_state() == ProtocolState::Closed && guest.get_protocol_state() == ProtocolState::Closed);
+    let (server, port) = start_server(&path);
+    for client in [&mut host, &mut guest] {
+        client.reconnect("127.0.0.1", &port);
+        wait_for("reconnect", || client.get_protocol_state() != ProtocolState::Authenticating);
+        client.async_listen();
+    }
+    wait_for("resumed game", || host.get_protocol_state() == ProtocolState::GameRunning && guest.get_protocol_state() == ProtocolState::GameRunning);
+    assert_eq!(board(&host)[0][0], CellElement::X);
+
+    // The host takes the top row, then both players leave the finished game, which frees the lobby for the next one
+    play(&guest, &host, (1, 1), CellElement::O);
+    play(&host, &guest, (0, 1), CellElement::X);
+    play(&guest, &host, (1, 0), CellElement::O);
+    play(&host, &guest, (0, 2), CellElement::X);
+    assert_eq!(host.get_game_end_result(), Some((true, Some(host.get_client_id()))));
+    for client in [&host, &guest] {
+        client.return_to_lobby();
+        wait_for("return to lobby", || client.get_protocol_state() == ProtocolState::InLobby);
+    }
+    host.refresh_current_lobby();
+    wait_for("game session removed", || host.get_current_lobby().is_some_and(|lobby| !lobby.game_started));
+    server.shutdown(Duration::ZERO);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn tournament_matches_end_with_a_restart() {
+    let path = snapshot_path("tournament_restore");
+    let (handle, port) = start_server(&path);
+    let mut a = connect(&port);
+    let mut b = connect(&port);
+    a.create_tournament("Cup", GAME_TYPE_ID, TournamentFormat::SingleElimination);
+    wait_for("tournament", || a.get_current_tournament().is_some());
+    let tournament_id = a.get_current_tournament().unwrap().id;
+    for client in [&a, &b] {
+        client.join_tournament(&tournament_id);
+        wait_for("registration", || client.get_current_tournament().is_some_and(|tournament| tournament.participant_ids.contains(&client.get_client_id())));
+    }
+    a.start_tournament(&tournament_id);
+    wait_for("match start", || a.get_protocol_state() == ProtocolState::GameRunning && b.get_protocol_state() == ProtocolState::GameRunning);
+
+    // The match's lobby isn't restored without its tournament, so its players start new sessions instead of resuming their seats
+    handle.shutdown(Duration::ZERO);
+    wait_for("disconnect", || a.get_protocol_state() == ProtocolState::Closed && b.get_protocol_state() == ProtocolState::Closed);
+    let (handle, port) = start_server(&path);
+    for client in [&mut a, &mut b] {
+        let previous_id = client.get_client_id();
+        client.reconnect("127.0.0.1", &port);
+        wait_for("reconnect", || client.get_protocol_state() == ProtocolState::Idle);
+        assert_ne!(client.get_client_id(), previous_id);
+    }
+    handle.shutdown(Duration::ZERO);
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use crate::transport::{Connection, Listener};
+
+/*
+    In-memory connections between a client and a server running in the same process, so no port needs to be bound.
+    Useful for integration tests of game modules, and for offline or hot-seat play with the same client code.
+    The server side is a LoopbackListener. Each call to LoopbackConnector::connect hands it the other end of a new connection.
+ */
+
+// Bytes sent one way through a loopback connection. Readers wait on the condvar for bytes to arrive or the pipe to close.
+#[derive(Default)]
+struct Pipe {
+    buffer: Mutex<PipeBuffer>,
+    ready: Condvar
+}
+
+#[derive(Default)]
+struct PipeBuffer {
+    bytes: VecDeque<u8>, // Bytes written but not read yet
+    closed: bool // Set once either end shuts down. Bytes already written can still be read.
+}
+
+impl Pipe {
+    fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut buffer = self.buffer.lock().unwrap();
+        while buffer.bytes.is_empty() && !buffer.closed {
+            buffer = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(ErrorKind::TimedOut, "Loopback read timed out"));
+                    }
+                    self.ready.wait_timeout(buffer, deadline - now).unwrap().0
+                }
+                None => self.ready.wait(buffer).unwrap()
+            };
+        }
+
+        // Returns 0 once the pipe is closed and everything in it has been read
+        let size = buf.len().min(buffer.bytes.len());
+        for (byte, value) in buf.iter_mut().zip(buffer.bytes.drain(..size)) {
+            *byte = value;
+        }
+        Ok(size)
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Loopback connection is closed"));
+        }
+        buffer.bytes.extend(data);
+        self.ready.notify_all();
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+// One end of an in-memory connection. Dropping it closes the connection, like closing a socket.
+pub struct LoopbackConnection {
+    incoming: Arc<Pipe>, // Bytes the other end sent
+    outgoing: Arc<Pipe>, // Bytes this end sends
+    read_timeout: Mutex<Option<Duration>>
+}
+
+impl LoopbackConnection {
+    // Create both ends of a connection.
+    pub fn pair() -> (Self, Self) {
+        let (first, second) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        (Self::new(first.clone(), second.clone()), Self::new(second, first))
+    }
+
+    fn new(incoming: Arc<Pipe>, outgoing: Arc<Pipe>) -> Self {
+        Self {
+            incoming,
+            outgoing,
+            read_timeout: Mutex::new(None)
+        }
+    }
+}
+
+impl Connection for LoopbackConnection {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        self.incoming.read(buf, timeout)
+    }
+
+    fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        self.outgoing.write(data)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.incoming.close();
+        self.outgoing.close();
+        Ok(())
+    }
+
+    // Loopback connections have no address, so guests on them can't be banned or muted by IP address.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(ErrorKind::Unsupported, "Loopback connections have no address"))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+impl Drop for LoopbackConnection {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+// Accepts the server ends of connections opened through its LoopbackConnectors.
+pub struct LoopbackListener {
+    incoming: Receiver<LoopbackConnection>
+}
+
+impl LoopbackListener {
+    // Create a listener and a connector for clients to connect to it with.
+    pub fn new() -> (Self, LoopbackConnector) {
+        let (sender, incoming) = mpsc::channel();
+        (Self { incoming }, LoopbackConnector { sender })
+    }
+}
+
+impl Listener for LoopbackListener {
+    fn accept(&self) -> io::Result<Option<Box<dyn Connection>>> {
+        match self.incoming.try_recv() {
+            Ok(connection) => Ok(Some(Box::new(connection))),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => Ok(None)
+        }
+    }
+}
+
+// Opens connections to a LoopbackListener. Can be cloned and shared between clients.
+#[derive(Clone)]
+pub struct LoopbackConnector {
+    sender: Sender<LoopbackConnection>
+}
+
+impl LoopbackConnector {
+    // Open a new connection and hand its other end to the listener. Pass the result to GameProtocolClient::connect_with.
+    // Fails once the listener is gone, e.g. because the server shut down.
+    pub fn connect(&self) -> io::Result<LoopbackConnection> {
+        let (client_end, server_end) = LoopbackConnection::pair();
+        match self.sender.send(server_end) {
+            Ok(_) => Ok(client_end),
+            Err(_) => Err(io::Error::new(ErrorKind::ConnectionRefused, "Loopback listener has stopped"))
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+pub mod tcp;
+pub mod tls;
+pub mod loopback;
+pub mod websocket;
+#[cfg(unix)]
+pub mod unix;
+
+/*
+    What clients and servers send messages over. The protocol only needs a connection it can read bytes from and write bytes to,
+    so anything implementing Connection can carry it, whether that's a TCP socket, TLS, WebSocket, a Unix domain socket, an in-memory loopback, or a test double.
+    Servers accept connections through Listeners. The server's own TCP listener is one, and more can be added with add_listener.
+ */
+
+// One open connection between a client and a server. One thread reads from it while others write to it, so every method takes &self.
+pub trait Connection: Send + Sync {
+    // Read whatever bytes have arrived into the buffer, waiting until there is at least one. Returns 0 once the connection is closed.
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    // Send all of the data. Several threads can send at once, so the data of one call must never be mixed with another's.
+    fn write_all(&self, data: &[u8]) -> io::Result<()>;
+
+    // Close the connection in both directions. A thread waiting in read must be woken up and see it closed.
+    fn shutdown(&self) -> io::Result<()>;
+
+    // Address of the other end. Bans and mutes of guests are by IP address, so connections without one should return an error.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    // Make reads give up with a WouldBlock or TimedOut error after waiting this long. None waits forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // Finish setting up the connection, e.g. a TLS handshake, before any message is sent or read.
+    // Runs on the thread that will listen to the connection. Nothing to do by default.
+    fn handshake(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Where a server accepts new connections from.
+pub trait Listener: Send {
+    // Called once before the server starts accepting connections.
+    fn start(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Accept a connection if one is waiting. Must not block, so return None when there is nothing to accept.
+    // The server checks every listener in turn, and waits a little before checking again when none had a connection.
+    fn accept(&self) -> io::Result<Option<Box<dyn Connection>>>;
+}
+
+// Connect to a server's Unix domain socket at the path. Only supported on Unix platforms.
+pub(crate) fn connect_unix_socket(path: &str) -> io::Result<Box<dyn Connection>> {
+    #[cfg(unix)]
+    {
+        Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+    }
+    #[cfg(not(unix))]
+    {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Can't connect to {}, Unix domain sockets are only supported on Unix platforms", path)))
+    }
+}
+
+// Listen on a Unix domain socket at the path. Only supported on Unix platforms.
+pub(crate) fn bind_unix_socket(path: &str) -> io::Result<Box<dyn Listener>> {
+    #[cfg(unix)]
+    {
+        Ok(Box::new(unix::UnixSocketListener::bind(path)?))
+    }
+    #[cfg(not(unix))]
+    {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Can't listen on {}, Unix domain sockets are only supported on Unix platforms", path)))
+    }
+}
+
+// Lets a connection be read through a BufReader and the message reading functions.
+impl Read for &dyn Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Connection::read(*self, buf)
+    }
+}
+
+impl Write for &dyn Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+use crate::transport::{Connection, Listener};
+
+/*
+    Plain TCP, the transport clients and servers use unless told otherwise.
+ */
+
+impl Connection for TcpStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut &*self, data)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Listener for TcpListener {
+    // The listener doesn't block so the server can check its other listeners and notice a shutdown request between connection attempts.
+    fn start(&self) -> io::Result<()> {
+        self.set_nonblocking(true)
+    }
+
+    fn accept(&self) -> io::Result<Option<Box<dyn Connection>>> {
+        match TcpListener::accept(self) {
+            Ok((stream, _)) => {
+                // Some platforms give accepted sockets the listener's non-blocking mode. Client threads expect blocking reads.
+                stream.set_nonblocking(false)?;
+                Ok(Some(Box::new(stream)))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
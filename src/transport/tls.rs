@@ -0,0 +1,287 @@
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig, ServerConnection, SignatureScheme};
+use crate::enums::TlsError;
+use crate::transport::{Connection, Listener};
+
+/*
+    TLS for connections between clients and servers, using rustls.
+    The server is given a certificate and private key, either as PEM files or PEM already in memory.
+    The client either pins the certificate authority (or self-signed certificate) it trusts, or, for local development only,
+    accepts whatever certificate the server presents.
+    Once the handshake is done, messages are sent and read exactly as they are over plain TCP.
+ */
+
+// Certificate and key a server presents to clients connecting over TLS.
+#[derive(Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>
+}
+
+impl ServerTls {
+    // Load a PEM certificate chain and private key from files.
+    pub fn from_pem_files(cert_path: &str, key_path: &str) -> Result<Self, TlsError> {
+        let cert_pem = fs::read(cert_path).map_err(|e| TlsError::ReadError(format!("{}: {}", cert_path, e)))?;
+        let key_pem = fs::read(key_path).map_err(|e| TlsError::ReadError(format!("{}: {}", key_path, e)))?;
+        Self::from_pem(&cert_pem, &key_pem)
+    }
+
+    // Use a PEM certificate chain and private key that are already in memory. The server's own certificate comes first in the chain.
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, TlsError> {
+        let certs = parse_certificates(cert_pem)?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(|e| TlsError::InvalidKey(e.to_string()))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::SetupError(e.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| TlsError::InvalidKey(e.to_string()))?;
+        Ok(Self {
+            config: Arc::new(config)
+        })
+    }
+
+    // Generate a self-signed certificate for local development, valid for the given host names and IP addresses.
+    // Returns the certificate's PEM too, so clients can pin it with ClientTls::with_ca_pem.
+    pub fn self_signed(subject_alt_names: &[&str]) -> Result<(Self, String), TlsError> {
+        let names = subject_alt_names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+        let generated = rcgen::generate_simple_self_signed(names).map_err(|e| TlsError::InvalidCertificate(e.to_string()))?;
+        let cert_pem = generated.cert.pem();
+        let server_tls = Self::from_pem(cert_pem.as_bytes(), generated.key_pair.serialize_pem().as_bytes())?;
+        Ok((server_tls, cert_pem))
+    }
+
+    // Start the server side of a TLS session on a newly accepted connection.
+    fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let session = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        Ok(TlsStream::new(stream, session.into()))
+    }
+}
+
+// Which servers a client trusts when connecting over TLS.
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: Option<String> // Name the server's certificate must be valid for. The IP address connected to if None.
+}
+
+impl ClientTls {
+    // Only trust servers whose certificate was issued by one of the PEM certificate authorities in a file. A self-signed certificate can be pinned the same way.
+    pub fn with_ca_file(path: &str) -> Result<Self, TlsError> {
+        let pem = fs::read(path).map_err(|e| TlsError::ReadError(format!("{}: {}", path, e)))?;
+        Self::with_ca_pem(&pem)
+    }
+
+    // Only trust servers whose certificate was issued by one of the PEM certificate authorities given.
+    pub fn with_ca_pem(pem: &[u8]) -> Result<Self, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for cert in parse_certificates(pem)? {
+            roots.add(cert).map_err(|e| TlsError::InvalidCertificate(e.to_string()))?;
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::SetupError(e.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+            server_name: None
+        })
+    }
+
+    // Accept any certificate the server presents, so a server with a freshly generated self-signed certificate can be tested locally.
+    // Traffic is still encrypted, but anyone between the client and server could read it. Never use this outside of development.
+    pub fn insecure_development() -> Result<Self, TlsError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::SetupError(e.to_string()))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate { provider }))
+            .with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+            server_name: None
+        })
+    }
+
+    // Check the server's certificate against this host name instead of the IP address connected to.
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
+    // Start the client side of a TLS session on a connection to a server at the given IP address. The handshake is finished when the session starts.
+    pub(crate) fn connect(&self, stream: TcpStream, ip: &str) -> io::Result<TlsStream> {
+        let name = self.server_name.clone().unwrap_or_else(|| ip.to_string());
+        let server_name = ServerName::try_from(name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let session = ClientConnection::new(self.config.clone(), server_name).map_err(io::Error::other)?;
+        Ok(TlsStream::new(stream, session.into()))
+    }
+}
+
+// Parse every certificate in a PEM string. Fails if there are none.
+fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_slice_iter(pem).collect::<Result<Vec<_>, _>>().map_err(|e| TlsError::InvalidCertificate(e.to_string()))?;
+    if certs.is_empty() {
+        return Err(TlsError::InvalidCertificate("No PEM certificates found".to_string()));
+    }
+    Ok(certs)
+}
+
+// Certificate verifier for ClientTls::insecure_development. Checks the handshake signatures but trusts any certificate.
+#[derive(Debug)]
+struct AcceptAnyCertificate {
+    provider: Arc<CryptoProvider>
+}
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Accepts TCP connections and starts a TLS session on each one. The handshake is finished on the client's own thread.
+pub(crate) struct TlsListener {
+    listener: TcpListener,
+    tls: ServerTls
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, tls: ServerTls) -> Self {
+        Self {
+            listener,
+            tls
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    fn start(&self) -> io::Result<()> {
+        Listener::start(&self.listener)
+    }
+
+    fn accept(&self) -> io::Result<Option<Box<dyn Connection>>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                // Some platforms give accepted sockets the listener's non-blocking mode. Client threads expect blocking reads.
+                stream.set_nonblocking(false)?;
+                Ok(Some(Box::new(self.tls.accept(stream)?)))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+// A TCP connection carrying a TLS session. One thread can read while others write, like a TcpStream.
+// Reads wait on the socket without holding the session lock, so writes from other threads aren't blocked by them.
+pub struct TlsStream {
+    stream: TcpStream,
+    session: Mutex<rustls::Connection>
+}
+
+impl TlsStream {
+    fn new(stream: TcpStream, session: rustls::Connection) -> Self {
+        Self {
+            stream,
+            session: Mutex::new(session)
+        }
+    }
+}
+
+// Write every encrypted record the session has waiting to the socket.
+fn flush_records(session: &mut rustls::Connection, mut stream: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut stream)?;
+    }
+    Ok(())
+}
+
+impl Connection for TlsStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0u8; 4096];
+        loop {
+            // Hand back decrypted data the session already has. WouldBlock means more records need to be read first.
+            {
+                let mut session = self.session.lock().unwrap();
+                match session.reader().read(buf) {
+                    Ok(size) => return Ok(size),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e)
+                }
+            }
+
+            let size = Read::read(&mut &self.stream, &mut records)?;
+            if size == 0 {
+                return Ok(0);
+            }
+            let mut session = self.session.lock().unwrap();
+            let mut received = &records[..size];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                session.process_new_packets().map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+
+            // Reading may have left handshake messages or alerts queued to be sent
+            flush_records(&mut session, &self.stream)?;
+        }
+    }
+
+    // Encrypt and send all of the data while holding the session lock, so messages from different threads aren't interleaved.
+    fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let written = session.writer().write(remaining)?;
+            flush_records(&mut session, &self.stream)?;
+            remaining = &remaining[written..];
+        }
+        Ok(())
+    }
+
+    // Tell the other side the session is over, then shut down the socket. Skips the notice if another thread is in the middle of writing.
+    fn shutdown(&self) -> io::Result<()> {
+        if let Ok(mut session) = self.session.try_lock() {
+            session.send_close_notify();
+            let _ = flush_records(&mut session, &self.stream);
+        }
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    // Finish the TLS handshake before anything else is sent or read.
+    fn handshake(&self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        while session.is_handshaking() {
+            session.complete_io(&mut &self.stream)?;
+        }
+        Ok(())
+    }
+}
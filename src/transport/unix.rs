@@ -0,0 +1,87 @@
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::transport::{Connection, Listener};
+
+/*
+    Unix domain sockets, for servers reached by clients on the same machine, e.g. a sidecar, without opening a port.
+    Messages are the same as over TCP. The socket file is created when the listener is bound and removed when it is dropped,
+    which the server does when it shuts down.
+ */
+
+impl Connection for UnixStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut &*self, data)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    // Unix sockets have no IP address, so guests on them can't be banned or muted by IP address.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(ErrorKind::Unsupported, "Unix domain socket connections have no IP address"))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+// Accepts connections on a Unix domain socket and removes the socket file when dropped.
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf
+}
+
+impl UnixSocketListener {
+    // Create the socket file at the path and listen on it. A socket file left behind by a server that didn't shut down cleanly
+    // is replaced, but one that a running server is still listening on is not.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let is_socket = fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if is_socket && UnixStream::connect(&path).is_err_and(|e| e.kind() == ErrorKind::ConnectionRefused) {
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        Ok(Self {
+            listener,
+            path
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Listener for UnixSocketListener {
+    fn start(&self) -> io::Result<()> {
+        self.listener.set_nonblocking(true)
+    }
+
+    fn accept(&self) -> io::Result<Option<Box<dyn Connection>>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                Ok(Some(Box::new(stream)))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+use rand_core::{OsRng, RngCore};
+use tungstenite::http::Uri;
+use tungstenite::protocol::frame::coding::{Control, Data, OpCode};
+use tungstenite::protocol::frame::{Frame, FrameSocket};
+use crate::transport::{Connection, Listener};
+
+/*
+    WebSocket connections, so clients that can't open raw TCP sockets, like browsers, can connect.
+    Messages are sent in binary frames and are exactly the bytes that would be sent over TCP: headers, body size, checksum and body.
+    Frames only carry bytes, so a message can be split across frames or share one, and readers must not rely on one message per frame.
+    tungstenite does the opening handshake and frame encoding. Reading and writing are split here so one thread can listen while others send.
+    Only plain ws:// is supported. The server's TLS settings don't cover the WebSocket port, so wss:// clients, e.g. pages served over https,
+    need a TLS-terminating proxy in front of it.
+ */
+
+// Largest frame accepted. Bigger frames close the connection rather than being buffered.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Which side of the opening handshake a connection is still waiting to do.
+enum PendingHandshake {
+    Accept, // Answer a client's upgrade request
+    Connect(String) // Send an upgrade request for this URL
+}
+
+// Frames read so far and bytes from them the caller hasn't read yet. Only used by the reading thread.
+struct ReadState {
+    frames: FrameSocket<TcpStream>,
+    pending: VecDeque<u8>
+}
+
+// A WebSocket connection over TCP. Frames sent by clients are masked, as the WebSocket protocol requires.
+pub struct WebSocketConnection {
+    stream: TcpStream,
+    is_client: bool, // Clients mask the frames they send. Servers don't.
+    handshake: Mutex<Option<PendingHandshake>>, // Handshake to do before anything is sent. None once it's done.
+    reader: Mutex<ReadState>,
+    write_lock: Mutex<()> // Held while a frame is written so frames from different threads aren't interleaved
+}
+
+impl WebSocketConnection {
+    fn new(stream: TcpStream, is_client: bool, handshake: PendingHandshake) -> io::Result<Self> {
+        let read_stream = stream.try_clone()?;
+        Ok(Self {
+            stream,
+            is_client,
+            handshake: Mutex::new(Some(handshake)),
+            reader: Mutex::new(ReadState {
+                frames: FrameSocket::new(read_stream),
+                pending: VecDeque::new()
+            }),
+            write_lock: Mutex::new(())
+        })
+    }
+
+    // Server side of a connection that was just accepted. The client's upgrade request is answered in handshake.
+    pub fn accept(stream: TcpStream) -> io::Result<Self> {
+        Self::new(stream, false, PendingHandshake::Accept)
+    }
+
+    // Connect to a server at a ws:// URL, e.g. "ws://127.0.0.1:7879/". The upgrade request is sent in handshake.
+    pub fn connect(url: &str) -> io::Result<Self> {
+        let uri = url.parse::<Uri>().map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        if uri.scheme_str() != Some("ws") {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("{} is not a ws:// URL", url)));
+        }
+        let host = uri.host().ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("{} has no host", url)))?;
+        let stream = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), uri.port_u16().unwrap_or(80)))?;
+        Self::new(stream, true, PendingHandshake::Connect(url.to_string()))
+    }
+
+    // Encode a frame, masking it if this is the client side.
+    fn encode_frame(&self, mut frame: Frame) -> io::Result<Vec<u8>> {
+        if self.is_client {
+            let mut mask = [0u8; 4];
+            OsRng.fill_bytes(&mut mask);
+            frame.header_mut().mask = Some(mask);
+        }
+        let mut bytes = Vec::with_capacity(frame.len());
+        frame.format(&mut bytes).map_err(into_io_error)?;
+        Ok(bytes)
+    }
+
+    fn send_frame(&self, frame: Frame) -> io::Result<()> {
+        let bytes = self.encode_frame(frame)?;
+        let _write_guard = self.write_lock.lock().unwrap();
+        io::Write::write_all(&mut &self.stream, &bytes)
+    }
+}
+
+// Keep IO errors as they are so read timeouts are still recognized. Anything else means the other side broke the protocol.
+fn into_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+impl Connection for WebSocketConnection {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        while reader.pending.is_empty() {
+            let mut frame = match reader.frames.read(Some(MAX_FRAME_SIZE)).map_err(into_io_error)? {
+                Some(frame) => frame,
+                None => return Ok(0)
+            };
+
+            // Frames from clients must be masked, so undo it before using the payload. Frames from servers must not be.
+            match (frame.header_mut().mask.take(), self.is_client) {
+                (Some(mask), false) => {
+                    for (i, byte) in frame.payload_mut().iter_mut().enumerate() {
+                        *byte ^= mask[i % 4];
+                    }
+                }
+                (None, false) => return Err(io::Error::new(ErrorKind::InvalidData, "WebSocket client sent an unmasked frame")),
+                (Some(_), true) => return Err(io::Error::new(ErrorKind::InvalidData, "WebSocket server sent a masked frame")),
+                (None, true) => {}
+            }
+            match frame.header().opcode {
+                OpCode::Data(Data::Binary | Data::Continue) => reader.pending.extend(frame.into_data()),
+                OpCode::Data(_) => return Err(io::Error::new(ErrorKind::InvalidData, "Only binary WebSocket frames are supported")),
+                OpCode::Control(Control::Ping) => self.send_frame(Frame::pong(frame.into_data()))?,
+                OpCode::Control(Control::Close) => {
+                    // Answer the close and treat it like the connection being closed
+                    let _ = self.send_frame(Frame::close(None));
+                    return Ok(0);
+                }
+                OpCode::Control(_) => {}
+            }
+        }
+
+        let size = buf.len().min(reader.pending.len());
+        for (byte, value) in buf.iter_mut().zip(reader.pending.drain(..size)) {
+            *byte = value;
+        }
+        Ok(size)
+    }
+
+    fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(Frame::message(data.to_vec(), OpCode::Data(Data::Binary), true))
+    }
+
+    // Send a close frame, then shut down the socket. Skips the close frame if another thread is in the middle of writing.
+    fn shutdown(&self) -> io::Result<()> {
+        if let (Ok(_write_guard), Ok(bytes)) = (self.write_lock.try_lock(), self.encode_frame(Frame::close(None))) {
+            let _ = io::Write::write_all(&mut &self.stream, &bytes);
+        }
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    // Do the opening handshake. Clients wait for the server's answer before sending anything, so no frames are read along with it.
+    fn handshake(&self) -> io::Result<()> {
+        match self.handshake.lock().unwrap().take() {
+            Some(PendingHandshake::Accept) => {
+                tungstenite::accept(&self.stream).map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            }
+            Some(PendingHandshake::Connect(url)) => {
+                tungstenite::client(url.as_str(), &self.stream).map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+// Accepts WebSocket connections on a TCP listener. Any path is accepted.
+pub struct WebSocketListener {
+    listener: TcpListener
+}
+
+impl WebSocketListener {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Listener for WebSocketListener {
+    fn start(&self) -> io::Result<()> {
+        Listener::start(&self.listener)
+    }
+
+    fn accept(&self) -> io::Result<Option<Box<dyn Connection>>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                Ok(Some(Box::new(WebSocketConnection::accept(stream)?)))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    // A server and client connection over loopback, both past the opening handshake.
+    fn connected_pair() -> (WebSocketConnection, WebSocketConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let server = WebSocketConnection::accept(listener.accept().unwrap().0).unwrap();
+            server.handshake().unwrap();
+            server
+        });
+        let client = WebSocketConnection::connect(&url).unwrap();
+        client.handshake().unwrap();
+        let server = server.join().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (server, client)
+    }
+
+    // A server connection past the handshake, and a raw client socket that sends and reads frames as they are.
+    fn raw_client_pair() -> (WebSocketConnection, FrameSocket<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = WebSocketConnection::accept(listener.accept().unwrap().0).unwrap();
+        let server = thread::spawn(move || {
+            server.handshake().unwrap();
+            server
+        });
+        tungstenite::client(url.as_str(), &stream).unwrap();
+        let server = server.join().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (server, FrameSocket::new(stream))
+    }
+
+    fn masked(mut frame: Frame) -> Frame {
+        frame.header_mut().mask = Some([1, 2, 3, 4]);
+        frame
+    }
+
+    fn read_exact(connection: &WebSocketConnection, size: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; size];
+        let mut read = 0;
+        while read < size {
+            let count = connection.read(&mut bytes[read..]).unwrap();
+            assert_ne!(count, 0, "connection closed early");
+            read += count;
+        }
+        bytes
+    }
+
+    #[test]
+    fn handshake_connects_both_ways() {
+        let (server, client) = connected_pair();
+        client.write_all(b"from client").unwrap();
+        server.write_all(b"from server").unwrap();
+        assert_eq!(read_exact(&server, 11), b"from client");
+        assert_eq!(read_exact(&client, 11), b"from server");
+    }
+
+    #[test]
+    fn message_split_across_frames_is_read_whole() {
+        let (server, client) = connected_pair();
+        client.write_all(&[1, 2, 3]).unwrap();
+        client.write_all(&[4, 5]).unwrap();
+        assert_eq!(read_exact(&server, 5), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn messages_sharing_a_frame_are_read_separately() {
+        let (server, client) = connected_pair();
+        client.write_all(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(read_exact(&server, 2), vec![1, 2]);
+        assert_eq!(read_exact(&server, 4), vec![3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn ping_is_answered_with_a_pong() {
+        let (server, mut client) = raw_client_pair();
+        client.send(masked(Frame::ping(vec![7, 8]))).unwrap();
+        client.send(masked(Frame::message(vec![9], OpCode::Data(Data::Binary), true))).unwrap();
+        assert_eq!(read_exact(&server, 1), vec![9]);
+
+        let pong = client.read(Some(MAX_FRAME_SIZE)).unwrap().unwrap();
+        assert_eq!(pong.header().opcode, OpCode::Control(Control::Pong));
+        assert_eq!(pong.into_data(), vec![7, 8]);
+    }
+
+    #[test]
+    fn unmasked_client_frame_is_rejected() {
+        let (server, mut client) = raw_client_pair();
+        client.send(Frame::message(vec![1, 2, 3], OpCode::Data(Data::Binary), true)).unwrap();
+        let error = server.read(&mut [0u8; 3]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}